@@ -0,0 +1,90 @@
+package app.armorclaw.crypto
+
+import androidx.test.ext.junit.runners.AndroidJUnit4
+import org.junit.Assert.*
+import org.junit.Assume.assumeTrue
+import org.junit.Before
+import org.junit.Test
+import org.junit.runner.RunWith
+
+/**
+ * Instrumentation tests for the vodozemac JNI bindings
+ *
+ * These need the native library, so they run on device/emulator.
+ */
+@RunWith(AndroidJUnit4::class)
+class VodozemacNativeTest {
+
+    @Before
+    fun setUp() {
+        assumeTrue("Native library not available", VodozemacNative.isAvailable())
+        VodozemacNative.initialize()
+    }
+
+    // ========================================
+    // Handle Validation
+    // ========================================
+
+    @Test
+    fun zeroAccountHandleThrows() {
+        try {
+            VodozemacNative.getIdentityKeys(0)
+            fail("Expected VodozemacException for handle 0")
+        } catch (e: VodozemacException) {
+            assertTrue(e.message!!.contains("zero"))
+        }
+    }
+
+    @Test
+    fun freedAccountHandleThrows() {
+        val account = VodozemacNative.createOlmAccount()
+        assertNotEquals(0L, account)
+        assertNotNull(VodozemacNative.getIdentityKeys(account))
+
+        VodozemacNative.freeOlmAccount(account)
+
+        assertThrows(VodozemacException::class.java) {
+            VodozemacNative.getIdentityKeys(account)
+        }
+    }
+
+    // ========================================
+    // Key Agreement
+    // ========================================
+
+    @Test
+    fun shortPublicKeyThrows() {
+        val privateKey = ByteArray(32) { 7 }
+
+        try {
+            VodozemacNative.diffieHellman(privateKey, ByteArray(31))
+            fail("Expected VodozemacException for a 31-byte key")
+        } catch (e: VodozemacException) {
+            assertTrue(e.message!!.contains("got 31"))
+        }
+    }
+
+    // ========================================
+    // Megolm
+    // ========================================
+
+    @Test
+    fun encryptingWithInboundSessionThrows() {
+        val outbound = VodozemacNative.createOutboundMegolmSession()
+        val sessionKey = VodozemacNative.getMegolmSessionKey(outbound)!!
+        val inbound = VodozemacNative.createInboundMegolmSession(sessionKey)
+        assertNotEquals(0L, inbound)
+
+        try {
+            assertThrows(InboundSessionException::class.java) {
+                VodozemacNative.encryptMegolm(inbound, "hello".toByteArray())
+            }
+            assertThrows(InboundSessionException::class.java) {
+                VodozemacNative.getMegolmSessionKey(inbound)
+            }
+        } finally {
+            VodozemacNative.freeMegolmSession(inbound)
+            VodozemacNative.freeMegolmSession(outbound)
+        }
+    }
+}
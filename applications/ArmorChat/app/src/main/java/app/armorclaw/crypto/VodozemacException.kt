@@ -0,0 +1,30 @@
+package app.armorclaw.crypto
+
+/**
+ * Thrown by [VodozemacNative] when a call is made with a zero, unknown or
+ * already freed account/session handle.
+ *
+ * A zero handle usually means an earlier create call failed and its result
+ * wasn't checked.
+ */
+open class VodozemacException(message: String) : Exception(message)
+
+/**
+ * Thrown by [VodozemacNative] when an outbound-only operation, such as
+ * encrypting or exporting the session key, is called on an inbound Megolm
+ * session.
+ */
+class InboundSessionException(message: String) : VodozemacException(message)
+
+/**
+ * Thrown by [VodozemacNative] when an Olm message fails its MAC check, which
+ * means the ciphertext was corrupted or tampered with in transit.
+ */
+class BadMacException(message: String) : VodozemacException(message)
+
+/**
+ * Thrown by [VodozemacNative] when a pre-key message uses a one-time key that
+ * an earlier session already consumed, which means the message is a replay
+ * and not a new session attempt.
+ */
+class ReplayedPreKeyException(message: String) : VodozemacException(message)
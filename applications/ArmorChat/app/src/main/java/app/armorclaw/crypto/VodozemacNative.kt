@@ -10,6 +10,14 @@ import android.util.Log
  *
  * IMPORTANT: This requires the native library to be loaded.
  * The library must be built for the target architecture (arm64-v8a, armeabi-v7a).
+ *
+ * Accounts and sessions are referenced by opaque native handles. Calls on the
+ * same handle are serialized natively, so a single account can safely be used
+ * from several threads (e.g. encrypting while generating keys).
+ *
+ * Functions taking an account or session handle throw [VodozemacException]
+ * when the handle is zero or has already been freed. Freeing such a handle
+ * is a no-op.
  */
 object VodozemacNative {
 
@@ -59,6 +67,99 @@ object VodozemacNative {
     @JvmStatic
     external fun getVersion(): String?
 
+    /**
+     * List the algorithms and features this build of the library supports
+     *
+     * Optional entries (e.g. key backup) are only present if the library was
+     * built with them.
+     * @return JSON array of algorithm identifiers
+     */
+    @JvmStatic
+    external fun getCapabilities(): String?
+
+    /**
+     * List the Cargo features the native library was built with
+     *
+     * Include this in bug reports; e.g. `backup` is missing from builds
+     * without key backup support.
+     * @return JSON array of feature names, such as `backup` and
+     *         `debug-assertions`
+     */
+    @JvmStatic
+    external fun getEnabledFeatures(): String?
+
+    /**
+     * Count the native objects that have not been freed yet
+     *
+     * For leak checks: after freeing everything a test created, all counts
+     * should be back where they started. Olm sessions are counted across
+     * all accounts.
+     * @return JSON object like
+     *         `{"accounts": 1, "olm_sessions": 2, "megolm_sessions": 0}`
+     */
+    @JvmStatic
+    external fun getAllocatedHandleCount(): String?
+
+    /**
+     * Get Olm and Megolm operation timings since the library was loaded
+     *
+     * For profiling; only present in native builds with the `metrics`
+     * feature. Times are upper bounds accurate to a factor of two.
+     * @return JSON object keyed by operation (`olm_encrypt`, `olm_decrypt`,
+     *         `olm_session_creation` and the same for `megolm_`), each with
+     *         `count`, `mean_us`, `p50_us`, `p90_us` and `p99_us`
+     */
+    @JvmStatic
+    external fun getCryptoMetrics(): String?
+
+    /**
+     * Get the lowest PBKDF2 iteration count accepted for passphrase keys
+     *
+     * Backups whose passphrase parameters ask for fewer iterations are
+     * refused. The default is 100000.
+     * @return The current iteration floor
+     */
+    @JvmStatic
+    external fun getMinKdfIterations(): Int
+
+    /**
+     * Change the PBKDF2 iteration floor for the whole process
+     * @param minimum The new floor; values below 1 are raised to 1
+     */
+    @JvmStatic
+    external fun setMinKdfIterations(minimum: Int)
+
+    /**
+     * Get the largest plaintext Olm and Megolm encryption accept
+     *
+     * Larger plaintexts are refused before they are encrypted. The default
+     * is 64 MiB.
+     * @return The current limit in bytes
+     */
+    @JvmStatic
+    external fun getMaxPlaintextSize(): Int
+
+    /**
+     * Change the plaintext size limit for the whole process
+     * @param limit The new limit in bytes; values below 1 are raised to 1
+     */
+    @JvmStatic
+    external fun setMaxPlaintextSize(limit: Int)
+
+    /**
+     * Take the reason the last Olm, Megolm or utility call on this thread failed
+     *
+     * Reading it clears it, and each thread has its own. Every other native
+     * call clears it too, so call this right after the failed call. `code`
+     * is stable (e.g. `olm.bad_mac`, or `handle.unknown` after a call on a
+     * freed handle); `category` is one of `invalid_input`, `decryption`,
+     * `encryption`, `not_found` or `state`.
+     * @return JSON `{code, category, message}`, or null if the last call
+     *         succeeded or failed without a report
+     */
+    @JvmStatic
+    external fun getLastErrorJson(): String?
+
     // ========================================================================
     // Key Generation
     // ========================================================================
@@ -77,13 +178,86 @@ object VodozemacNative {
     @JvmStatic
     external fun generateSigningKeyPair(): ByteArray?
 
+    /**
+     * Extract the public key from a key pair buffer
+     * @param keyPair A buffer returned by [generateIdentityKeyPair] or
+     *        [generateSigningKeyPair]
+     * @return The 32-byte public key
+     * @throws VodozemacException if the buffer is malformed
+     */
+    @JvmStatic
+    external fun extractPublicKey(keyPair: ByteArray): ByteArray?
+
+    /**
+     * Derive a Curve25519 public key from its private scalar
+     * @param privateKey The 32-byte X25519 private key
+     * @return The 32-byte public key
+     * @throws VodozemacException if the private key isn't 32 bytes
+     */
+    @JvmStatic
+    external fun curve25519PublicFromPrivate(privateKey: ByteArray): ByteArray?
+
+    /**
+     * X25519 key agreement with a peer's Curve25519 public key
+     * @param privateKey Our 32-byte X25519 private key
+     * @param publicKey The peer's 32-byte Curve25519 public key
+     * @return The 32-byte shared secret, or null if the public key is a
+     *         low-order point
+     * @throws VodozemacException if either key isn't 32 bytes
+     */
+    @JvmStatic
+    external fun diffieHellman(privateKey: ByteArray, publicKey: ByteArray): ByteArray?
+
+    /**
+     * Derive a key from a passphrase with Argon2id
+     *
+     * Only present in native builds with the `argon2` feature (on by
+     * default). Parameters below 19 MiB of memory, 2 iterations or 1 lane,
+     * salts under 8 bytes and outputs under 16 bytes are refused; see
+     * [getLastErrorJson] for `utility.weak_argon2_params`. So are more than
+     * 1 GiB of memory, 64 iterations, 16 lanes or a 1024-byte output
+     * (`utility.excessive_argon2_params`).
+     * @param passphrase The passphrase bytes
+     * @param salt The salt
+     * @param memoryKib Memory cost in KiB
+     * @param iterations Number of passes over the memory
+     * @param parallelism Number of lanes
+     * @param length Length of the derived key in bytes
+     * @return The derived key, or null if the parameters are out of bounds
+     */
+    @JvmStatic
+    external fun argon2id(
+        passphrase: ByteArray,
+        salt: ByteArray,
+        memoryKib: Int,
+        iterations: Int,
+        parallelism: Int,
+        length: Int
+    ): ByteArray?
+
+    /**
+     * Convert an Ed25519 public key to its Curve25519 form for ECDH
+     * @param publicKey The 32-byte Ed25519 public key
+     * @return The 32-byte Curve25519 public key, or null if the key is invalid
+     */
+    @JvmStatic
+    external fun ed25519ToCurve25519(publicKey: ByteArray): ByteArray?
+
+    /**
+     * Convert an Ed25519 private key to the matching Curve25519 private key
+     * @param privateKey The 32-byte Ed25519 seed
+     * @return The 32-byte Curve25519 private key, or null if the key is invalid
+     */
+    @JvmStatic
+    external fun ed25519PrivateToCurve25519(privateKey: ByteArray): ByteArray?
+
     // ========================================================================
     // Signing
     // ========================================================================
 
     /**
      * Sign a message with Ed25519
-     * @param privateKey The Ed25519 private key
+     * @param privateKey The 32-byte Ed25519 private key seed
      * @param message The message to sign
      * @return The signature bytes, or null on error
      */
@@ -92,13 +266,121 @@ object VodozemacNative {
 
     /**
      * Verify an Ed25519 signature
-     * @param publicKey The Ed25519 public key
+     * @param publicKey The Ed25519 public key (32 bytes)
      * @param message The original message
-     * @param signature The signature to verify
+     * @param signature The signature to verify (64 bytes)
+     * @param strict Throw [VodozemacException] for a wrong-length key or
+     *        signature instead of returning false
      * @return true if signature is valid
      */
     @JvmStatic
-    external fun verify(publicKey: ByteArray, message: ByteArray, signature: ByteArray): Boolean
+    external fun verify(
+        publicKey: ByteArray,
+        message: ByteArray,
+        signature: ByteArray,
+        strict: Boolean = false
+    ): Boolean
+
+    /**
+     * Compare two base64-encoded public keys in constant time
+     *
+     * Use this for key pinning instead of string equality; padded and
+     * unpadded encodings of the same key compare equal.
+     * @param keyA The first Curve25519 or Ed25519 public key
+     * @param keyB The second public key
+     * @return true if both decode to the same key, false otherwise or if
+     *         either key is malformed
+     */
+    @JvmStatic
+    external fun publicKeysEqual(keyA: String, keyB: String): Boolean
+
+    /**
+     * Format an Ed25519 key as a fingerprint for QR verification and device lists
+     *
+     * The unpadded base64 key in space-separated groups, e.g. `nE6W 2fCb ...`
+     * for a group size of 4.
+     * @param ed25519Key The Ed25519 public key (base64, padded or not)
+     * @param groupSize Characters per group; 0 for no spacing
+     * @return The fingerprint, or null if the key is malformed
+     */
+    @JvmStatic
+    external fun keyFingerprint(ed25519Key: String, groupSize: Int): String?
+
+    /**
+     * Compare two safety numbers in constant time
+     *
+     * Use this instead of string equality when checking for a MITM.
+     * Whitespace is ignored, so differently grouped numbers still match.
+     * @param numberA The safety number computed locally
+     * @param numberB The safety number to check against
+     * @return true if both contain the same digits; false otherwise, or if
+     *         either contains anything but digits and whitespace
+     */
+    @JvmStatic
+    external fun safetyNumbersMatch(numberA: String, numberB: String): Boolean
+
+    /**
+     * Generate a random string from a secure RNG
+     *
+     * Every character of [alphabet] is equally likely, so this is suitable
+     * for transaction ids, nonces and backup versions.
+     * @param length Number of characters to generate
+     * @param alphabet Characters to draw from
+     * @return The random string, or null if length is negative or the
+     *         alphabet is empty
+     */
+    @JvmStatic
+    external fun randomString(length: Int, alphabet: String): String?
+
+    /**
+     * Compute the content hash of a Matrix event
+     *
+     * `hashes`, `signatures` and `unsigned` are ignored, so the event can
+     * be passed as received.
+     * @param eventJson The event as JSON
+     * @return The unpadded base64 SHA-256 of its canonical JSON, or null if
+     *         the JSON is not an object
+     */
+    @JvmStatic
+    external fun contentHash(eventJson: String): String?
+
+    /**
+     * Report which algorithm an encrypted event declares
+     *
+     * Use this to route an event to Olm or Megolm decryption; the
+     * algorithm string is matched exactly.
+     * @param eventJson The event, or just its content, as JSON
+     * @return "olm_v1", "megolm_v1" or "unknown", or null if the JSON is
+     *         not an object
+     */
+    @JvmStatic
+    external fun detectEventAlgorithm(eventJson: String): String?
+
+    /**
+     * Read the Megolm session id an encrypted event was sent with
+     *
+     * Use it to pick the stored inbound session to decrypt the event with.
+     * @param eventJson The event, or just its content, as JSON
+     * @return The session id, or null if it is missing or empty
+     */
+    @JvmStatic
+    external fun extractSessionId(eventJson: String): String?
+
+    /**
+     * Compute the id an Olm session will have, for logging and correlation
+     *
+     * Matches the session id both sides report once the session exists.
+     * @param aliceIdentity Curve25519 identity key of the session's creator
+     * @param aliceBaseKey The creator's ephemeral base key
+     * @param bobOneTimeKey The one-time (or fallback) key the creator claimed
+     * @return The unpadded base64 session id, or null if a key is invalid
+     */
+    @JvmStatic
+    external fun computeOlmSessionId(
+        aliceIdentity: String,
+        aliceBaseKey: String,
+        bobOneTimeKey: String
+    ): String?
 
     // ========================================================================
     // Olm (1:1 Sessions)
@@ -119,6 +401,17 @@ object VodozemacNative {
     @JvmStatic
     external fun getIdentityKeys(accountPtr: Long): String?
 
+    /**
+     * Get the account's public identity for sharing
+     *
+     * Only public material is included, so this is safe to upload or show.
+     * @param accountPtr Pointer to the Olm account
+     * @return JSON with `curve25519`, `ed25519` and a self-signature under
+     *         `signatures`, or null on error
+     */
+    @JvmStatic
+    external fun getPublicIdentity(accountPtr: Long): String?
+
     /**
      * Generate one-time keys for an Olm account
      * @param accountPtr Pointer to the Olm account
@@ -128,6 +421,118 @@ object VodozemacNative {
     @JvmStatic
     external fun generateOneTimeKeys(accountPtr: Long, count: Int): String?
 
+    /**
+     * List the one-time keys that haven't been marked as published
+     *
+     * Unlike [generateOneTimeKeys], this never creates new keys.
+     * @param accountPtr Pointer to the Olm account
+     * @return JSON array of `{ key_id, key }`, empty once [markKeysAsPublished]
+     *         was called
+     */
+    @JvmStatic
+    external fun getUnpublishedOneTimeKeys(accountPtr: Long): String?
+
+    /**
+     * Get how many one-time keys to generate to refill the server's supply
+     *
+     * Keys generated but not yet published are taken into account.
+     * @param accountPtr Pointer to the Olm account
+     * @param serverCount The `signed_curve25519` count reported by the server
+     * @return Number of keys to generate (0 if none), or -1 on error
+     */
+    @JvmStatic
+    external fun neededOneTimeKeys(accountPtr: Long, serverCount: Int): Int
+
+    /**
+     * Get the unpublished one-time keys, signed for upload
+     *
+     * Key ids carry the `signed_curve25519:` prefix the server expects.
+     * @param accountPtr Pointer to the Olm account
+     * @param userId The account's Matrix user id
+     * @param deviceId The account's device id
+     * @return JSON `one_time_keys` object for `/keys/upload`
+     */
+    @JvmStatic
+    external fun getSignedOneTimeKeys(accountPtr: Long, userId: String, deviceId: String): String?
+
+    /**
+     * Generate one-time keys and get them signed for upload in one call
+     *
+     * Same as [generateOneTimeKeys] followed by [getSignedOneTimeKeys];
+     * unpublished keys generated earlier are included.
+     * @param accountPtr Pointer to the Olm account
+     * @param count Number of keys to generate
+     * @param userId The account's Matrix user id
+     * @param deviceId The account's device id
+     * @return JSON `one_time_keys` object for `/keys/upload`
+     */
+    @JvmStatic
+    external fun generateAndSignOneTimeKeys(
+        accountPtr: Long,
+        count: Int,
+        userId: String,
+        deviceId: String
+    ): String?
+
+    /**
+     * Sign the unpublished one-time keys again after the signing key changed
+     *
+     * Call after recovery replaced the account, and upload the result in
+     * place of any signatures made before.
+     * @param accountPtr Pointer to the Olm account
+     * @param userId The account's Matrix user id
+     * @param deviceId The account's device id
+     * @return JSON `one_time_keys` object for `/keys/upload`, signed with the
+     *         current Ed25519 key
+     */
+    @JvmStatic
+    external fun resignOneTimeKeys(accountPtr: Long, userId: String, deviceId: String): String?
+
+    /**
+     * Generate a new fallback key for an Olm account
+     * @param accountPtr Pointer to the Olm account
+     * @return JSON string with the fallback key, or null on error
+     */
+    @JvmStatic
+    external fun generateFallbackKey(accountPtr: Long): String?
+
+    /**
+     * Mark the account's one-time keys and fallback key as uploaded
+     * @param accountPtr Pointer to the Olm account
+     * @return true on success
+     */
+    @JvmStatic
+    external fun markKeysAsPublished(accountPtr: Long): Boolean
+
+    /**
+     * Check whether the current fallback key has been uploaded
+     * @param accountPtr Pointer to the Olm account
+     * @return true once [markKeysAsPublished] was called after the fallback
+     *         key was generated; false if there is no fallback key yet
+     */
+    @JvmStatic
+    external fun isFallbackKeyPublished(accountPtr: Long): Boolean
+
+    /**
+     * Get the fallback key generated last, published or not
+     * @param accountPtr Pointer to the Olm account
+     * @return JSON string with the fallback key, or null if none was generated
+     */
+    @JvmStatic
+    external fun getActiveFallbackKey(accountPtr: Long): String?
+
+    /**
+     * Get the fallback key the active one replaced
+     *
+     * Pre-key messages made with this key are still accepted, so peers that
+     * claimed it before the rotation can establish sessions.
+     * @param accountPtr Pointer to the Olm account
+     * @return JSON string with the fallback key, or null if the account has
+     *         not rotated its fallback key yet
+     */
+    @JvmStatic
+    external fun getPreviousFallbackKey(accountPtr: Long): String?
+
     /**
      * Create an outbound Olm session
      * @param accountPtr Pointer to the Olm account
@@ -143,68 +548,1033 @@ object VodozemacNative {
     ): Long
 
     /**
-     * Encrypt a message with Olm
-     * @param sessionPtr Pointer to the Olm session
-     * @param plaintext The message to encrypt
-     * @return Encrypted message bytes
+     * Create an outbound Olm session and report its Matrix session id
+     * @param accountPtr Pointer to the Olm account
+     * @param theirIdentityKey Recipient's Curve25519 identity key
+     * @param theirOneTimeKey Recipient's one-time key
+     * @return JSON `{ "handle": ..., "session_id": ... }`, or null on error.
+     *         The session id is the base64 id other clients report.
      */
     @JvmStatic
-    external fun encryptOlm(sessionPtr: Long, plaintext: ByteArray): ByteArray?
+    external fun createOutboundSessionDetailed(
+        accountPtr: Long,
+        theirIdentityKey: ByteArray,
+        theirOneTimeKey: ByteArray
+    ): String?
 
     /**
-     * Decrypt a message with Olm
-     * @param sessionPtr Pointer to the Olm session
-     * @param ciphertext The encrypted message
-     * @param messageType The message type (0 = pre-key, 1 = normal)
-     * @return Decrypted message bytes
+     * Create an outbound Olm session on the one-time key the server claimed
+     * under a specific key id
+     *
+     * Fails if the claim holds any key other than [keyId], so the session is
+     * never built on a different key than intended. Check the claim's
+     * signature with [verifyClaimedOneTimeKey] first.
+     * @param accountPtr Pointer to the Olm account
+     * @param theirIdentityKey Recipient's Curve25519 identity key
+     * @param keyId The claimed key id, e.g. `signed_curve25519:AAAAAQ`
+     * @param claimJson The device's entry in the `/keys/claim` response
+     * @return JSON `{ "handle": ..., "session_id": ... }`, or null on error
      */
     @JvmStatic
-    external fun decryptOlm(sessionPtr: Long, ciphertext: ByteArray, messageType: Int): ByteArray?
+    external fun createOutboundSessionWithKeyId(
+        accountPtr: Long,
+        theirIdentityKey: String,
+        keyId: String,
+        claimJson: String
+    ): String?
 
-    // ========================================================================
-    // Megolm (Group Sessions)
-    // ========================================================================
+    /**
+     * Get the chain indices of an Olm session, for debugging ratchet desyncs
+     *
+     * Indices come from the last message sent and received on the session
+     * since it was loaded, and are null before the first one.
+     * @param accountPtr Pointer to the Olm account
+     * @param sessionId Matrix session id of the Olm session
+     * @return JSON `{ sending_chain_index, receiving_chain_index }`, or null
+     *         if the session doesn't exist
+     */
+    @JvmStatic
+    external fun getOlmRatchetState(accountPtr: Long, sessionId: String): String?
 
     /**
-     * Create an outbound Megolm group session
-     * @return Session pointer (as Long), or 0 on error
+     * Drop every Olm session of an account, keeping the account itself
+     *
+     * Use during recovery to force new sessions with all peers. Identity,
+     * one-time and fallback keys are untouched, so uploaded keys stay valid.
+     * @param accountPtr Pointer to the Olm account
+     * @return true on success
      */
     @JvmStatic
-    external fun createOutboundMegolmSession(): Long
+    external fun clearOlmSessions(accountPtr: Long): Boolean
 
     /**
-     * Get the session key for sharing with group members
-     * @param sessionPtr Pointer to the Megolm session
-     * @return Base64-encoded session key
+     * Remove the one-time keys an account hasn't published yet
+     *
+     * Keys already uploaded and the fallback key are kept, so devices that
+     * claimed them can still start sessions.
+     * @param accountPtr Pointer to the Olm account
+     * @return true on success
      */
     @JvmStatic
-    external fun getMegolmSessionKey(sessionPtr: Long): String?
+    external fun clearOneTimeKeys(accountPtr: Long): Boolean
 
     /**
-     * Create an inbound Megolm session from a session key
-     * @param sessionKey Base64-encoded session key
-     * @return Session pointer (as Long), or 0 on error
+     * Create two accounts with an established Olm session between them
+     *
+     * For integration tests; only present in native builds with the
+     * `testing` feature. Free both accounts with [freeOlmAccount].
+     * @return JSON {"alice", "bob", "session_id"} with the account pointers, or null
      */
     @JvmStatic
-    external fun createInboundMegolmSession(sessionKey: String): Long
+    external fun createTestSessionPair(): String?
 
     /**
-     * Encrypt a message with Megolm
-     * @param sessionPtr Pointer to the Megolm session
-     * @param plaintext The message to encrypt
-     * @return JSON string with encrypted message content
+     * Check that an account's key and session state is consistent
+     *
+     * Call right after unpickling or importing a store, so a corrupted
+     * pickle is caught before it is used. On failure, [getLastErrorJson]
+     * names what is inconsistent (`olm.invalid_state`).
+     * @param accountPtr Pointer to the Olm account
+     * @return true if the account is consistent
      */
     @JvmStatic
-    external fun encryptMegolm(sessionPtr: Long, plaintext: ByteArray): String?
+    external fun validateAccount(accountPtr: Long): Boolean
 
     /**
-     * Decrypt a message with Megolm
-     * @param sessionPtr Pointer to the Megolm session
-     * @param ciphertext JSON string with encrypted message content
+     * Check whether an account already has a working session with a device
+     *
+     * Use before claiming a one-time key, to reuse the session instead.
+     * Only sessions that have received a message from the device count.
+     * @param accountPtr Pointer to the Olm account
+     * @param identityKey The device's Curve25519 identity key
+     * @return true if such a session exists
+     */
+    @JvmStatic
+    external fun hasSessionWith(accountPtr: Long, identityKey: String): Boolean
+
+    /**
+     * Get the algorithm an Olm session is pinned to
+     *
+     * The first event decrypted on a session with an `algorithm` pins it;
+     * later events declaring another algorithm, or none, are rejected as a
+     * downgrade.
+     * @param accountPtr Pointer to the Olm account
+     * @param sessionId The Olm session id
+     * @return The pinned algorithm, or null if nothing has pinned it yet
+     */
+    @JvmStatic
+    external fun getOlmPinnedAlgorithm(accountPtr: Long, sessionId: String): String?
+
+    /**
+     * Check whether decrypting a pre-key message would use up a one-time key
+     *
+     * Nothing is decrypted or changed. Messages for an established session
+     * or built on a fallback key don't use one up, so they don't call for
+     * replenishing one-time keys.
+     * @param accountPtr Pointer to the Olm account
+     * @param prekeyMessage The base64 body of the type 0 message
+     * @return true if a one-time key would be consumed
+     */
+    @JvmStatic
+    external fun prekeyConsumesOneTimeKey(accountPtr: Long, prekeyMessage: String): Boolean
+
+    /**
+     * Encrypt a Megolm session key for another device
+     *
+     * Builds an `m.room_key` to-device payload and encrypts it on an existing
+     * Olm session with that device.
+     * @param accountPtr Pointer to the Olm account
+     * @param recipientSessionId Matrix session id of the Olm session to use
+     * @param roomId Room the Megolm session belongs to
+     * @param sessionKey The Megolm session key to share
+     * @return JSON with the Olm `message_type` and `body`, or null on error
+     */
+    @JvmStatic
+    external fun wrapMegolmSessionKey(
+        accountPtr: Long,
+        recipientSessionId: String,
+        roomId: String,
+        sessionKey: String
+    ): String?
+
+    /**
+     * Encrypt an `m.dummy` event to advance an Olm session's ratchet
+     *
+     * Send it to rotate the session after the other device's keys change.
+     * @param accountPtr Pointer to the Olm account
+     * @param sessionId Matrix session id of the Olm session to use
+     * @return JSON with the Olm `message_type` and `body`, or null on error
+     */
+    @JvmStatic
+    external fun encryptDummy(accountPtr: Long, sessionId: String): String?
+
+    /**
+     * Decrypt an incoming `m.room_key` to-device payload
+     * @param accountPtr Pointer to the Olm account
+     * @param senderIdentityKey The sender's Curve25519 identity key
+     * @param ciphertext The Olm ciphertext body
+     * @param messageType The message type (0 = pre-key, 1 = normal)
+     * @return JSON with `algorithm`, `room_id`, `session_id` and
+     *         `session_key`, or null if decryption or validation fails
+     */
+    @JvmStatic
+    external fun unwrapMegolmSessionKey(
+        accountPtr: Long,
+        senderIdentityKey: String,
+        ciphertext: ByteArray,
+        messageType: Int
+    ): String?
+
+    /**
+     * Decrypt an `m.forwarded_room_key` to-device event and import its key
+     *
+     * The chain depth counts every device the key passed through, including
+     * the one that sent it to us, so 1 accepts only keys forwarded by the
+     * device that created them and 0 refuses all forwarded keys. Check
+     * [getLastErrorJson] for `olm.forwarding_chain_too_long`. The imported
+     * session is never trusted; free it with [freeMegolmSession].
+     * @param accountPtr Pointer to the Olm account
+     * @param eventJson JSON `{ "sender_key", "type", "body" }` of the Olm message
+     * @param maxChainDepth The longest forwarding chain to accept
+     * @return JSON `{ handle, room_id, session_id, sender_key,
+     *         forwarding_curve25519_key_chain }`, or null on error
+     */
+    @JvmStatic
+    external fun importForwardedRoomKey(
+        accountPtr: Long,
+        eventJson: String,
+        maxChainDepth: Int
+    ): String?
+
+    /**
+     * Encrypt a message with Olm
+     *
+     * The body is already base64; it goes into the to-device `ciphertext`
+     * field as is.
+     * @param sessionPtr Pointer to the Olm session
+     * @param plaintext The message to encrypt; must not be empty
+     * @return JSON with the Olm `message_type` and base64 `body`, or null if
+     *         encryption failed
+     */
+    @JvmStatic
+    external fun encryptOlm(sessionPtr: Long, plaintext: ByteArray): String?
+
+    /**
+     * Encrypt a message with Olm, ready to send
+     *
+     * Like [encryptOlm], but the result also names the session it was sent
+     * on, so nothing else needs to be looked up before sending.
+     * @param sessionPtr Pointer to the Olm session
+     * @param plaintext The message to encrypt; must not be empty
+     * @return JSON `{ type, body, session_id }`, or null if encryption
+     *         failed
+     */
+    @JvmStatic
+    external fun encryptOlmTransport(sessionPtr: Long, plaintext: ByteArray): String?
+
+    /**
+     * Same as [encryptOlm]
+     */
+    @Deprecated("encryptOlm returns the same JSON", ReplaceWith("encryptOlm(sessionPtr, plaintext)"))
+    @JvmStatic
+    external fun encryptOlmBase64(sessionPtr: Long, plaintext: ByteArray): String?
+
+    /**
+     * Decrypt a message with Olm
+     * @param sessionPtr Pointer to the Olm session
+     * @param ciphertext The encrypted message
+     * @param messageType The message type (0 = pre-key, 1 = normal)
      * @return Decrypted message bytes
+     * @throws BadMacException if the message fails authentication
      */
     @JvmStatic
-    external fun decryptMegolm(sessionPtr: Long, ciphertext: String): ByteArray?
+    external fun decryptOlm(sessionPtr: Long, ciphertext: ByteArray, messageType: Int): ByteArray?
+
+    /**
+     * Decrypt a text message with Olm
+     * @param sessionPtr Pointer to the Olm session
+     * @param ciphertext The encrypted message
+     * @param messageType The message type (0 = pre-key, 1 = normal)
+     * @return Decrypted text, or null if decryption fails or the plaintext
+     *         is not valid UTF-8
+     * @throws BadMacException if the message fails authentication
+     */
+    @JvmStatic
+    external fun decryptOlmText(sessionPtr: Long, ciphertext: ByteArray, messageType: Int): String?
+
+    /**
+     * Decrypt an Olm message whose message type is not known
+     *
+     * Pre-key messages reuse a matching inbound session or create a new one;
+     * anything else is decrypted on the established sessions.
+     * @param sessionPtr Pointer to the Olm session
+     * @param theirIdentityKey The sender's Curve25519 identity key
+     * @param ciphertext The encrypted message, without a type byte
+     * @return Decrypted message, or null if decryption fails
+     * @throws BadMacException if the message fails authentication on the
+     *         sender's session; a message for a session this account doesn't
+     *         have just returns null
+     * @throws ReplayedPreKeyException if the message is a pre-key message
+     *         whose one-time key was already used
+     */
+    @JvmStatic
+    external fun decryptOlmAuto(sessionPtr: Long, theirIdentityKey: String, ciphertext: ByteArray): ByteArray?
+
+    /**
+     * Decrypt a batch of Olm to-device messages, each on its own
+     *
+     * One bad message doesn't fail the batch. Messages are decrypted in
+     * order, so a pre-key message can set up the session a later one uses.
+     * @param accountPtr Pointer to the Olm account
+     * @param eventsJson JSON array of `{ sender_key, type, body }`, each with
+     *        the `algorithm` its content declares; it must match the one its
+     *        session was pinned to (see [getOlmPinnedAlgorithm])
+     * @return JSON array in the same order, each entry either
+     *         `{ plaintext }` (base64) or `{ error }`, or null if the JSON is
+     *         malformed
+     */
+    @JvmStatic
+    external fun decryptOlmBatch(accountPtr: Long, eventsJson: String): String?
+
+    /**
+     * Export an account and all of its Olm sessions as a single blob
+     *
+     * Persist the result in one write so the account and its sessions can
+     * never get out of sync.
+     * @param accountPtr Pointer to the Olm account
+     * @param pickleKey Key the account and session pickles are encrypted with
+     * @return The versioned store blob, or null on error
+     */
+    @JvmStatic
+    external fun exportCryptoStore(accountPtr: Long, pickleKey: ByteArray): ByteArray?
+
+    /**
+     * Restore an account and its Olm sessions from [exportCryptoStore]
+     * @param store The store blob
+     * @param pickleKey Key the store was exported with
+     * @return Pointer to the restored account (as Long), or 0 on error
+     */
+    @JvmStatic
+    external fun importCryptoStore(store: ByteArray, pickleKey: ByteArray): Long
+
+    /**
+     * Re-encrypt [exportCryptoStore] output under a new pickle key
+     *
+     * For when the KeyStore master key rotates; the pickles are decrypted
+     * and re-encrypted in memory only. Write the result over the old blob.
+     * @param store The store blob
+     * @param oldKey Key the store was exported with
+     * @param newKey Key to encrypt the store with
+     * @return The re-encrypted store blob, or null on error
+     */
+    @JvmStatic
+    external fun rekeyOlmAccount(store: ByteArray, oldKey: ByteArray, newKey: ByteArray): ByteArray?
+
+    /**
+     * Read the identity keys from [exportCryptoStore] output
+     *
+     * Cheaper than [importCryptoStore] when only the keys are needed, e.g.
+     * for display; no sessions are restored and no handle is created.
+     * @param store The store blob
+     * @param pickleKey Key the store was exported with
+     * @return JSON string with curve25519 and ed25519 keys, or null on error
+     */
+    @JvmStatic
+    external fun identityKeysFromPickle(store: ByteArray, pickleKey: ByteArray): String?
+
+    /**
+     * Pickle an Olm account on its own
+     *
+     * Sessions aren't included; use [exportCryptoStore] to persist them
+     * together. The pickle is not encrypted, so encrypt it before storing.
+     * @param accountPtr Pointer to the Olm account
+     * @return The versioned pickle, or null on error
+     */
+    @JvmStatic
+    external fun pickleOlmAccount(accountPtr: Long): ByteArray?
+
+    /**
+     * Restore an Olm account from [pickleOlmAccount]
+     *
+     * Pickles from older versions are migrated. Call [validateAccount] on
+     * the result before using it.
+     * @param pickle The pickle bytes
+     * @return Pointer to the restored account (as Long), or 0 on error
+     */
+    @JvmStatic
+    external fun unpickleOlmAccount(pickle: ByteArray): Long
+
+    /**
+     * Verify the self-signature on a peer's device keys
+     *
+     * Only use the device's Curve25519 key for sessions if this returns true.
+     * @param deviceKeys JSON of the device keys object from /keys/query
+     * @return true if the keys are signed by the device's own Ed25519 key
+     */
+    @JvmStatic
+    external fun verifyDeviceKeys(deviceKeys: String): Boolean
+
+    /**
+     * Verify the self-signatures of every device in a /keys/query response
+     *
+     * One call instead of one [verifyDeviceKeys] per device. A device also
+     * counts as invalid if it is listed under another user or device id.
+     * @param query The /keys/query response, or just its `device_keys` map
+     * @return JSON `{ userId: { deviceId: valid } }`, or null if the JSON is
+     *         malformed
+     */
+    @JvmStatic
+    external fun verifyDeviceMap(query: String): String?
+
+    /**
+     * Check that a device is cross-signed under a trusted master key
+     *
+     * Both links must verify: the master key signs the self-signing key,
+     * and the self-signing key signs the device.
+     * @param masterKey The user's trusted Ed25519 master key (base64)
+     * @param selfSigningKey The user's self-signing key JSON from /keys/query
+     * @param deviceKeys The device's signed device keys JSON
+     * @return true if the whole chain verifies, false otherwise or on error
+     */
+    @JvmStatic
+    external fun verifyCrossSigningChain(
+        masterKey: String,
+        selfSigningKey: String,
+        deviceKeys: String
+    ): Boolean
+
+    /**
+     * Sign another user's master key with our user-signing key
+     *
+     * Existing signatures on the key are kept.
+     * @param userId Our own user id, which the signature is listed under
+     * @param userSigningKey Private user-signing key (unpadded base64 seed)
+     * @param theirMasterKey The other user's master key JSON from /keys/query
+     * @return The signed master key JSON for /keys/signatures/upload, or null on error
+     */
+    @JvmStatic
+    external fun signUserMasterKey(
+        userId: String,
+        userSigningKey: String,
+        theirMasterKey: String
+    ): String?
+
+    /**
+     * Build the body of a /keys/device_signing/upload request
+     *
+     * The master key is signed by itself and by this device's Ed25519 key;
+     * the self-signing and user-signing keys are signed by the master key.
+     * @param accountPtr Pointer to the account whose device key signs the master key
+     * @param userId Our own user id
+     * @param deviceId This device's id, which the device signature is listed under
+     * @param seedsJson JSON object with `master`, `self_signing` and `user_signing`
+     *   private keys (unpadded base64 seeds)
+     * @return The upload body JSON, or null on error
+     */
+    @JvmStatic
+    external fun getDeviceSigningUpload(
+        accountPtr: Long,
+        userId: String,
+        deviceId: String,
+        seedsJson: String
+    ): String?
+
+    /**
+     * Check a user-signing key's signature on another user's master key
+     * @param userId The signing user's id
+     * @param userSigningKey The signer's public user-signing key (base64)
+     * @param theirMasterKey The signed master key JSON
+     * @return true if the signature verifies, false otherwise or on error
+     */
+    @JvmStatic
+    external fun verifyUserSignature(
+        userId: String,
+        userSigningKey: String,
+        theirMasterKey: String
+    ): Boolean
+
+    /**
+     * Check the signature on a one-time key from /keys/claim
+     *
+     * Create the outbound session with the returned key, never with the
+     * unchecked one from the response.
+     * @param claim JSON of the device's entry in `one_time_keys`, holding a
+     *        single `signed_curve25519:<id>` key
+     * @param signerEd25519Key The device's verified Ed25519 key
+     * @return JSON with `key_id`, `key` and `fallback`, or null if the claim is
+     *         malformed or not signed by the device
+     */
+    @JvmStatic
+    external fun verifyClaimedOneTimeKey(claim: String, signerEd25519Key: String): String?
+
+    // ========================================================================
+    // Megolm (Group Sessions)
+    // ========================================================================
+
+    /**
+     * Create an outbound Megolm group session
+     * @return Session pointer (as Long), or 0 on error
+     */
+    @JvmStatic
+    external fun createOutboundMegolmSession(): Long
+
+    /**
+     * Get the session key for sharing with group members
+     * @param sessionPtr Pointer to the outbound Megolm session
+     * @return Base64-encoded session key
+     * @throws InboundSessionException if called on an inbound session
+     */
+    @JvmStatic
+    external fun getMegolmSessionKey(sessionPtr: Long): String?
+
+    /**
+     * Get the session key at the current ratchet index
+     *
+     * A member given this key can read messages sent from now on, but not
+     * the conversation history. Use it when inviting members mid-conversation.
+     * @param sessionPtr Pointer to the outbound Megolm session
+     * @return Base64-encoded session key
+     * @throws InboundSessionException if called on an inbound session
+     */
+    @JvmStatic
+    external fun getMegolmSessionKeyAtCurrentIndex(sessionPtr: Long): String?
+
+    /**
+     * Get how many messages an outbound Megolm session has encrypted
+     * @param sessionPtr Pointer to the Megolm session
+     * @return The message count (0 for inbound sessions), or -1 on error
+     */
+    @JvmStatic
+    external fun getMegolmMessagesSent(sessionPtr: Long): Int
+
+    /**
+     * Get the highest message index up to which every message was decrypted
+     *
+     * Messages at or below this index can be shown in ratchet order; later
+     * ones are still waiting for an earlier message to arrive.
+     * @param sessionPtr Pointer to the Megolm session
+     * @return The ordered index, or -1 on error
+     */
+    @JvmStatic
+    external fun getMegolmOrderedIndex(sessionPtr: Long): Long
+
+    /**
+     * Get a tag identifying a Megolm message, for audit logs
+     *
+     * Every device that can decrypt the message gets the same tag, so logs
+     * from several devices can be correlated. The tag reveals no key
+     * material.
+     * @param sessionPtr Pointer to the Megolm session
+     * @param index The message index
+     * @return The unpadded base64 tag, or null if the session can't reach
+     *         that index
+     */
+    @JvmStatic
+    external fun getMegolmMessageTag(sessionPtr: Long, index: Long): String?
+
+    /**
+     * Get how many ratchet steps one message may advance an inbound Megolm
+     * session
+     *
+     * Messages claiming an index further ahead of the furthest one
+     * decrypted so far fail to decrypt. The default is 2000.
+     * @return The current limit
+     */
+    @JvmStatic
+    external fun getMaxRatchetAdvance(): Int
+
+    /**
+     * Change the Megolm ratchet advance limit for the whole process
+     * @param limit The new limit; negative values are taken as 0
+     */
+    @JvmStatic
+    external fun setMaxRatchetAdvance(limit: Int)
+
+    /**
+     * Create an inbound Megolm session from a session key
+     * @param sessionKey Base64-encoded session key
+     * @param expectedSessionId If set, fail unless the key derives this
+     *        session id
+     * @return Session pointer (as Long), or 0 on error
+     */
+    @JvmStatic
+    external fun createInboundMegolmSession(
+        sessionKey: String,
+        expectedSessionId: String? = null
+    ): Long
+
+    /**
+     * Encrypt a message with Megolm
+     * @param sessionPtr Pointer to the Megolm session
+     * @param plaintext The message to encrypt; must not be empty
+     * @return JSON string with encrypted message content, or null if
+     *         encryption failed
+     * @throws InboundSessionException if called on an inbound session
+     */
+    @JvmStatic
+    external fun encryptMegolm(sessionPtr: Long, plaintext: ByteArray): String?
+
+    /**
+     * Check whether a stored Megolm message can be decrypted
+     *
+     * Does not decrypt or change the session, so it is safe to call before
+     * showing an event. A true result doesn't guarantee the MAC verifies.
+     * @param sessionPtr Pointer to the Megolm session
+     * @param ciphertext JSON string with encrypted message
+     * @return true if the message belongs to this session and its index is known
+     */
+    @JvmStatic
+    external fun canDecryptMegolmMessage(sessionPtr: Long, ciphertext: String): Boolean
+
+    /**
+     * Decrypt a text message with Megolm
+     * @param sessionPtr Pointer to the Megolm session
+     * @param ciphertext JSON string with encrypted message content
+     * @return Decrypted text, or null if decryption fails or the plaintext
+     *         is not valid UTF-8
+     */
+    @JvmStatic
+    external fun decryptMegolmText(sessionPtr: Long, ciphertext: String): String?
+
+    /**
+     * Encrypt a payload into the content of an `m.room.encrypted` event
+     *
+     * The result is the full content Element sends; it can be passed
+     * directly to [decryptMegolm].
+     * @param sessionPtr Pointer to the Megolm session
+     * @param plaintext The payload to encrypt
+     * @param deviceCurve25519 Our device's Curve25519 identity key
+     * @param deviceId Our device id
+     * @return JSON `{ algorithm, sender_key, ciphertext, session_id, device_id }`,
+     *         or null on error
+     * @throws InboundSessionException if called on an inbound session
+     */
+    @JvmStatic
+    external fun buildMegolmEncryptedEvent(
+        sessionPtr: Long,
+        plaintext: ByteArray,
+        deviceCurve25519: String,
+        deviceId: String
+    ): String?
+
+    /**
+     * Encrypt a room event with Megolm
+     *
+     * The event is wrapped as `{ room_id, type, content }` before encryption
+     * so recipients can verify the room binding.
+     * @param sessionPtr Pointer to the Megolm session
+     * @param roomId The room the event is sent to
+     * @param eventType The event type (e.g. m.room.message)
+     * @param content JSON event content
+     * @return JSON string with encrypted message content
+     * @throws InboundSessionException if called on an inbound session
+     */
+    @JvmStatic
+    external fun encryptMegolmEvent(
+        sessionPtr: Long,
+        roomId: String,
+        eventType: String,
+        content: String
+    ): String?
+
+    /**
+     * Decrypt a room event with Megolm
+     * @param sessionPtr Pointer to the Megolm session
+     * @param ciphertext JSON string with encrypted message content
+     * @param roomId The room the event was received in
+     * @return JSON `{ room_id, type, content }`, or null if decryption fails
+     *         or the event was encrypted for a different room
+     */
+    @JvmStatic
+    external fun decryptMegolmEvent(sessionPtr: Long, ciphertext: String, roomId: String): String?
+
+    /**
+     * Decrypt a message with Megolm
+     * @param sessionPtr Pointer to the Megolm session
+     * @param ciphertext JSON string with encrypted message content
+     * @return Decrypted message bytes
+     */
+    @JvmStatic
+    external fun decryptMegolm(sessionPtr: Long, ciphertext: String): ByteArray?
+
+    /**
+     * Decrypt a message with Megolm and report where it falls in order
+     *
+     * For strict ordering: `order` is `ordered` for the next expected index,
+     * `gap` if earlier messages are still missing, and `duplicate` if the
+     * index was already decrypted.
+     * @param sessionPtr Pointer to the Megolm session
+     * @param ciphertext JSON string with encrypted message content
+     * @return JSON `{ plaintext, message_index, order }` with the plaintext
+     *         in base64, or null if decryption fails
+     */
+    @JvmStatic
+    external fun decryptMegolmDetailed(sessionPtr: Long, ciphertext: String): String?
+
+    /**
+     * Decrypt a payload that was encrypted in chunks, one message per chunk
+     *
+     * The chunks must be consecutive messages of the session; a dropped,
+     * repeated or reordered chunk fails the whole payload.
+     * @param sessionPtr Pointer to the Megolm session
+     * @param chunksJson JSON array of the encrypted messages, in order
+     * @return The reassembled payload, or null if any chunk fails
+     */
+    @JvmStatic
+    external fun decryptMegolmChunked(sessionPtr: Long, chunksJson: String): ByteArray?
+
+    /**
+     * Import an inbound Megolm session from a key export entry
+     * @param exportJson JSON export entry (session_key, sender_claimed_keys, ...)
+     * @param expectedSessionId If set, fail unless the key derives this
+     *        session id
+     * @return Session pointer (as Long), or 0 on error
+     */
+    @JvmStatic
+    external fun importMegolmSession(exportJson: String, expectedSessionId: String? = null): Long
+
+    /**
+     * Pickle a Megolm session
+     *
+     * An outbound session keeps its sending ratchet and can still encrypt
+     * once restored. The pickle is not encrypted, so encrypt it before
+     * storing.
+     * @param sessionPtr Pointer to the Megolm session
+     * @return The versioned pickle, or null on error
+     */
+    @JvmStatic
+    external fun pickleMegolmSession(sessionPtr: Long): ByteArray?
+
+    /**
+     * Restore a Megolm session from [pickleMegolmSession]
+     * @param pickle The pickle bytes
+     * @return Session pointer (as Long), or 0 on error
+     */
+    @JvmStatic
+    external fun unpickleMegolmSession(pickle: ByteArray): Long
+
+    /**
+     * Pickle a Megolm session with its ratchet encrypted
+     *
+     * Like [pickleMegolmSession], outbound sessions stay outbound.
+     * @param sessionPtr Pointer to the Megolm session
+     * @param pickleKey 32-byte key the ratchets are encrypted with
+     * @return The versioned pickle, or null on error
+     */
+    @JvmStatic
+    external fun pickleMegolmSessionEncrypted(sessionPtr: Long, pickleKey: ByteArray): ByteArray?
+
+    /**
+     * Restore a Megolm session from [pickleMegolmSessionEncrypted]
+     * @param pickle The pickle bytes
+     * @param pickleKey 32-byte key the pickle was made with
+     * @return Session pointer (as Long), or 0 on error
+     */
+    @JvmStatic
+    external fun unpickleMegolmSessionEncrypted(pickle: ByteArray, pickleKey: ByteArray): Long
+
+    /**
+     * Re-encrypt [pickleMegolmSessionEncrypted] output under a new key
+     *
+     * For when the KeyStore master key rotates, as with [rekeyOlmAccount];
+     * the session isn't restored. Write the result over the old pickle.
+     * @param pickle The pickle bytes
+     * @param oldKey 32-byte key the pickle was made with
+     * @param newKey 32-byte key to encrypt the ratchet with
+     * @return The re-encrypted pickle, or null on error
+     */
+    @JvmStatic
+    external fun rekeyMegolmSession(
+        pickle: ByteArray,
+        oldKey: ByteArray,
+        newKey: ByteArray
+    ): ByteArray?
+
+    /**
+     * Get the Ed25519 key the sender claimed for an imported Megolm session
+     * @param sessionPtr Pointer to the Megolm session
+     * @return The claimed Ed25519 key, or null for locally created sessions
+     */
+    @JvmStatic
+    external fun getMegolmClaimedEd25519Key(sessionPtr: Long): String?
+
+    /**
+     * Get the algorithm a Megolm session is pinned to
+     *
+     * The first message decrypted pins the algorithm it declares; later
+     * messages declaring another one fail with `megolm.algorithm_mismatch`.
+     * @param sessionPtr Pointer to the Megolm session
+     * @return The pinned algorithm, or null before any message was decrypted
+     */
+    @JvmStatic
+    external fun getMegolmPinnedAlgorithm(sessionPtr: Long): String?
+
+    /**
+     * Check whether a Megolm session can encrypt
+     *
+     * Only sessions from [createOutboundMegolmSession] can, including
+     * after a pickle round trip.
+     * @param sessionPtr Pointer to the Megolm session
+     * @return true for an outbound session
+     */
+    @JvmStatic
+    external fun isMegolmOutbound(sessionPtr: Long): Boolean
+
+    /**
+     * File a Megolm session under its room so it's included in
+     * [exportRoomMegolmSessions]
+     *
+     * Filing a session again replaces the earlier entry. Freed sessions
+     * drop out on their own.
+     * @param sessionPtr Pointer to the Megolm session
+     * @param roomId Room the session belongs to
+     * @param senderKey Curve25519 identity key of the session's sender
+     */
+    @JvmStatic
+    external fun addMegolmSessionToRoom(sessionPtr: Long, roomId: String, senderKey: String)
+
+    /**
+     * Export every Megolm session filed under a room, for key backup
+     * @param roomId The room whose keys to export
+     * @return JSON array of sessions in key export format, empty if none are filed
+     */
+    @JvmStatic
+    external fun exportRoomMegolmSessions(roomId: String): String?
+
+    /**
+     * Check whether a Megolm session came from a key export or history share
+     *
+     * Unlike [isMegolmSessionTrusted], keys forwarded over Olm by another
+     * device count as imported too.
+     * @param sessionPtr Pointer to the Megolm session
+     * @return true if the session key didn't come from `m.room_key`
+     */
+    @JvmStatic
+    external fun isMegolmSessionImported(sessionPtr: Long): Boolean
+
+    /**
+     * Check whether a Megolm session's key came directly from its sender
+     *
+     * Sessions imported from a key export or a history share are untrusted;
+     * show a warning on messages decrypted with them.
+     * @param sessionPtr Pointer to the Megolm session
+     * @return true for local sessions and keys received over Olm
+     */
+    @JvmStatic
+    external fun isMegolmSessionTrusted(sessionPtr: Long): Boolean
+
+    // ========================================================================
+    // Attachments
+    // ========================================================================
+
+    /**
+     * Encrypt an attachment with a fresh AES-CTR key
+     * @param data The attachment contents
+     * @param keySize AES key size in bits: 256, or 128 for legacy peers
+     * @return JSON with the event's `file` object and the base64 `ciphertext`
+     *         to upload, or null on error (including unsupported key sizes)
+     */
+    @JvmStatic
+    external fun encryptAttachment(data: ByteArray, keySize: Int): String?
+
+    /**
+     * Decrypt a downloaded attachment
+     * @param ciphertext The downloaded, encrypted contents
+     * @param fileInfo JSON of the event's `file` object
+     * @return The attachment contents, or null if the hash doesn't match or
+     *         decryption fails
+     */
+    @JvmStatic
+    external fun decryptAttachment(ciphertext: ByteArray, fileInfo: String): ByteArray?
+
+    /**
+     * Check an attachment's cipher before downloading or decrypting it
+     *
+     * Only the key's algorithm and the hashes are read, so the key doesn't
+     * need to be present.
+     * @param fileInfo JSON of the event's `file` object
+     * @return JSON `{ algorithm, key_size, has_hashes }`, or null if the
+     *         algorithm is unsupported or `hashes` is missing
+     */
+    @JvmStatic
+    external fun inspectAttachment(fileInfo: String): String?
+
+    /**
+     * Start an HMAC-SHA-256 over data processed in chunks
+     *
+     * Feed the chunks with [updateHmacSha256], then call [finalizeHmacSha256];
+     * call [freeHmacSha256] instead if the stream is abandoned.
+     * @param key The HMAC key
+     * @return HMAC handle (as Long), or 0 on error
+     */
+    @JvmStatic
+    external fun createHmacSha256(key: ByteArray): Long
+
+    /**
+     * Feed the next chunk to a streaming HMAC
+     * @param hmacPtr Handle from [createHmacSha256]
+     * @param chunk The next chunk of data
+     * @return true on success
+     */
+    @JvmStatic
+    external fun updateHmacSha256(hmacPtr: Long, chunk: ByteArray): Boolean
+
+    /**
+     * Finish a streaming HMAC and free its handle
+     * @param hmacPtr Handle from [createHmacSha256]
+     * @return The 32-byte MAC over all chunks
+     */
+    @JvmStatic
+    external fun finalizeHmacSha256(hmacPtr: Long): ByteArray?
+
+    /**
+     * Free a streaming HMAC without finishing it
+     * @param hmacPtr Handle from [createHmacSha256]
+     */
+    @JvmStatic
+    external fun freeHmacSha256(hmacPtr: Long)
+
+    // ========================================================================
+    // Verification
+    // ========================================================================
+
+    /**
+     * Start tracking a SAS or QR verification flow
+     * @param flowId The flow's transaction id or request event id
+     * @return Verification handle (as Long), or 0 on error
+     */
+    @JvmStatic
+    external fun startVerification(flowId: String): Long
+
+    /**
+     * Check whether a verification flow has timed out
+     *
+     * Check before acting on a key or MAC event, and cancel the flow with
+     * `m.timeout` if this returns true.
+     * @param verificationPtr Handle from [startVerification]
+     * @param timeoutMs Timeout in milliseconds; 0 or less for the spec's
+     *        ten minutes
+     * @return true if the flow started longer ago than the timeout
+     */
+    @JvmStatic
+    external fun isVerificationExpired(verificationPtr: Long, timeoutMs: Long): Boolean
+
+    /**
+     * Stop tracking a verification flow
+     * @param verificationPtr Handle from [startVerification]
+     */
+    @JvmStatic
+    external fun freeVerification(verificationPtr: Long)
+
+    // ========================================================================
+    // Key Backup
+    // ========================================================================
+
+    /**
+     * Verify a key backup's auth data before importing sessions from it
+     * @param authData JSON auth data of the backup version
+     * @param recoveryPublicKey Curve25519 public key of our recovery key
+     * @param signingKey Ed25519 key expected to have signed the auth data
+     * @return true if the backup key matches and the signature is valid
+     */
+    @JvmStatic
+    external fun verifyBackupAuthData(
+        authData: String,
+        recoveryPublicKey: String,
+        signingKey: String
+    ): Boolean
+
+    /**
+     * Check a recovery passphrase before attempting a backup restore
+     * @param passphrase The passphrase the user entered
+     * @param salt The `m.pbkdf2` salt from the key's passphrase info
+     * @param iterations The `m.pbkdf2` iteration count
+     * @param backupPublicKey The backup's Curve25519 public key
+     * @return true if the passphrase derives the backup key; false if it
+     *         doesn't, or if [iterations] is below [getMinKdfIterations], in
+     *         which case [getLastErrorJson] reports `utility.too_few_iterations`
+     */
+    @JvmStatic
+    external fun checkBackupPassphrase(
+        passphrase: String,
+        salt: String,
+        iterations: Int,
+        backupPublicKey: String
+    ): Boolean
+
+    /**
+     * Format a recovery key the way it is shown to the user
+     *
+     * The key is split into space-separated groups of four characters.
+     * Whitespace already in the key is ignored, so a formatted key can be
+     * passed again.
+     * @param recoveryKey The base58 recovery key
+     * @return The key in display form
+     */
+    @JvmStatic
+    external fun formatRecoveryKeyForDisplay(recoveryKey: String): String?
+
+    /**
+     * Encode a backup private key as a recovery key
+     * @param privateKey The 32-byte Curve25519 backup private key
+     * @return The base58 recovery key without spaces, or null on error
+     */
+    @JvmStatic
+    external fun encodeRecoveryKey(privateKey: ByteArray): String?
+
+    /**
+     * Decode a recovery key as the user typed or pasted it
+     *
+     * Whitespace is ignored. The prefix and parity byte are checked, so most
+     * typos are caught here rather than when the backup fails to decrypt.
+     * @param recoveryKey The recovery key, with or without spaces
+     * @return The 32-byte backup private key, or null if it isn't valid
+     */
+    @JvmStatic
+    external fun parseRecoveryKey(recoveryKey: String): ByteArray?
+
+    /**
+     * Create a new backup key and its signed auth data
+     *
+     * Store `recovery_key` safely (e.g. in secret storage, or show it with
+     * [formatRecoveryKeyForDisplay]); the backup can't be read without it.
+     * @param accountPtr Pointer to the Olm account that signs the auth data
+     * @param userId The account's Matrix user id
+     * @param deviceId The account's device id
+     * @return JSON `{ public_key, recovery_key, auth_data_json }`
+     */
+    @JvmStatic
+    external fun createKeyBackup(accountPtr: Long, userId: String, deviceId: String): String?
+
+    /**
+     * Start importing exported Megolm sessions in the background
+     *
+     * Poll [backupImportProgress] until `done == total`, then collect the
+     * session handles with [finishBackupImport].
+     * @param sessionsJson JSON array of exported sessions
+     * @return Job handle (as Long), or 0 on error
+     */
+    @JvmStatic
+    external fun startBackupImport(sessionsJson: String): Long
+
+    /**
+     * Get the progress of a background import
+     * @param job Handle returned by [startBackupImport]
+     * @return JSON `{ done, total, errors }`; `done` includes failed entries
+     */
+    @JvmStatic
+    external fun backupImportProgress(job: Long): String?
+
+    /**
+     * Collect the sessions of a finished background import
+     *
+     * Frees the job once the import has finished.
+     * @param job Handle returned by [startBackupImport]
+     * @return JSON object mapping session ids to Megolm session handles, or
+     *         null if the import is still running
+     */
+    @JvmStatic
+    external fun finishBackupImport(job: Long): String?
 
     // ========================================================================
     // Cleanup
@@ -0,0 +1,264 @@
+//! Encrypted attachments
+//!
+//! Matrix attachments are encrypted with AES-CTR under a random key before
+//! upload. The key, IV and a SHA-256 hash of the ciphertext travel in the
+//! event's `file` object (an `EncryptedFile`), the ciphertext goes to the
+//! media repository.
+
+use std::sync::{LazyLock, Mutex};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::registry;
+use crate::utilities::{self, Base64Variant, NonceTracker};
+
+/// Every (key, IV) pair handed out by [`encrypt_attachment`] in this process
+///
+/// Keys are random, so a repeat only happens if the RNG is broken; refusing
+/// it is still better than uploading a keystream-reusing ciphertext.
+static ISSUED_NONCES: LazyLock<Mutex<NonceTracker>> =
+    LazyLock::new(|| Mutex::new(NonceTracker::new()));
+
+/// Attachment errors
+#[derive(Error, Debug)]
+pub enum AttachmentError {
+    #[error("Unsupported AES key size: {0} bits (expected 128 or 256)")]
+    UnsupportedKeySize(u32),
+
+    #[error("Encryption failed: {0}")]
+    EncryptionFailed(String),
+
+    #[error("Decryption failed: {0}")]
+    DecryptionFailed(String),
+
+    #[error("Ciphertext hash mismatch")]
+    HashMismatch,
+
+    #[error("Invalid file info: {0}")]
+    InvalidInfo(String),
+}
+
+/// The attachment key as a JSON Web Key
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JsonWebKey {
+    pub kty: String,
+    pub key_ops: Vec<String>,
+    pub alg: String,
+    /// Unpadded base64url key
+    pub k: String,
+    pub ext: bool,
+}
+
+/// Everything needed to decrypt an attachment (the event's `file` object)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EncryptedFileInfo {
+    pub key: JsonWebKey,
+    pub iv: String,
+    pub hashes: std::collections::BTreeMap<String, String>,
+    pub v: String,
+}
+
+/// What an `EncryptedFile` object says about its cipher, see [`inspect`]
+#[derive(Serialize, Debug, PartialEq)]
+pub struct AttachmentInfo {
+    /// JWK algorithm, e.g. `A256CTR`
+    pub algorithm: String,
+    pub key_size: u32,
+    /// Whether the info carries the SHA-256 hash decryption needs
+    pub has_hashes: bool,
+}
+
+/// The parts of an `EncryptedFile` object [`inspect`] reads
+#[derive(Deserialize)]
+struct FileInfoOutline {
+    key: KeyOutline,
+    hashes: std::collections::BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct KeyOutline {
+    alg: String,
+}
+
+/// An encrypted attachment ready for upload
+pub struct EncryptedAttachment {
+    pub ciphertext: Vec<u8>,
+    pub info: EncryptedFileInfo,
+}
+
+/// JWK algorithm name for an AES-CTR key size
+fn jwk_alg(key_size: u32) -> Result<&'static str, AttachmentError> {
+    match key_size {
+        128 => Ok("A128CTR"),
+        256 => Ok("A256CTR"),
+        other => Err(AttachmentError::UnsupportedKeySize(other)),
+    }
+}
+
+/// AES-CTR key size for a JWK algorithm name
+fn jwk_key_size(alg: &str) -> Option<u32> {
+    match alg {
+        "A128CTR" => Some(128),
+        "A256CTR" => Some(256),
+        _ => None,
+    }
+}
+
+/// Read the cipher parameters of an `EncryptedFile` object
+///
+/// Only the key's algorithm and the hashes are looked at, so this works
+/// without the key material and before the ciphertext is downloaded. The
+/// `hashes` field must be present; an unsupported algorithm is an error.
+pub fn inspect(info_json: &str) -> Result<AttachmentInfo, AttachmentError> {
+    let info: FileInfoOutline = serde_json::from_str(info_json)
+        .map_err(|e| AttachmentError::InvalidInfo(e.to_string()))?;
+    let key_size = jwk_key_size(&info.key.alg).ok_or_else(|| {
+        AttachmentError::InvalidInfo(format!("Unsupported algorithm {}", info.key.alg))
+    })?;
+
+    Ok(AttachmentInfo {
+        has_hashes: info.hashes.contains_key("sha256"),
+        algorithm: info.key.alg,
+        key_size,
+    })
+}
+
+/// Encrypt an attachment with a fresh AES-CTR key
+///
+/// `key_size` is 256 for current clients; 128 is only for legacy peers
+/// that can't handle anything else.
+pub fn encrypt_attachment(
+    data: &[u8],
+    key_size: u32,
+) -> Result<EncryptedAttachment, AttachmentError> {
+    let alg = jwk_alg(key_size)?;
+
+    let key = utilities::random_bytes(key_size as usize / 8);
+    // The low 64 bits are the block counter and must start at zero
+    let mut iv = utilities::random_bytes(8);
+    iv.extend_from_slice(&[0u8; 8]);
+
+    // Recorded up front so the lock isn't held while a large file encrypts
+    registry::lock(&ISSUED_NONCES)
+        .record(&key, &iv)
+        .map_err(|e| AttachmentError::EncryptionFailed(e.to_string()))?;
+    let ciphertext = utilities::aes_ctr_encrypt(&key, &iv, data, None)
+        .map_err(|e| AttachmentError::EncryptionFailed(e.to_string()))?;
+
+    let mut hashes = std::collections::BTreeMap::new();
+    let hash = utilities::sha256(&ciphertext);
+    hashes.insert(
+        "sha256".to_string(),
+        utilities::base64_encode_with(&hash, Base64Variant::StandardNoPad),
+    );
+
+    let info = EncryptedFileInfo {
+        key: JsonWebKey {
+            kty: "oct".to_string(),
+            key_ops: vec!["encrypt".to_string(), "decrypt".to_string()],
+            alg: alg.to_string(),
+            k: utilities::base64_encode_with(&key, Base64Variant::UrlSafeNoPad),
+            ext: true,
+        },
+        iv: utilities::base64_encode_with(&iv, Base64Variant::StandardNoPad),
+        hashes,
+        v: "v2".to_string(),
+    };
+
+    Ok(EncryptedAttachment { ciphertext, info })
+}
+
+/// Decrypt an attachment after checking its SHA-256 hash
+pub fn decrypt_attachment(
+    ciphertext: &[u8],
+    info: &EncryptedFileInfo,
+) -> Result<Vec<u8>, AttachmentError> {
+    let expected_hash = info
+        .hashes
+        .get("sha256")
+        .ok_or_else(|| AttachmentError::DecryptionFailed("Missing sha256 hash".into()))?;
+    let expected_hash = utilities::base64_decode_lenient(expected_hash)
+        .map_err(|_| AttachmentError::DecryptionFailed("Invalid sha256 hash".into()))?;
+
+    {
+        use subtle::ConstantTimeEq;
+        if !bool::from(utilities::sha256(ciphertext).ct_eq(&expected_hash)) {
+            return Err(AttachmentError::HashMismatch);
+        }
+    }
+
+    let key = utilities::base64_decode_with(&info.key.k, Base64Variant::UrlSafeNoPad)
+        .map_err(|_| AttachmentError::DecryptionFailed("Invalid key".into()))?;
+    let key_size = jwk_key_size(&info.key.alg).ok_or_else(|| {
+        AttachmentError::DecryptionFailed(format!("Unsupported algorithm {}", info.key.alg))
+    })?;
+    if key.len() * 8 != key_size as usize {
+        return Err(AttachmentError::DecryptionFailed("Key doesn't match algorithm".into()));
+    }
+
+    let iv = utilities::base64_decode_lenient(&info.iv)
+        .map_err(|_| AttachmentError::DecryptionFailed("Invalid IV".into()))?;
+
+    utilities::aes_ctr_decrypt(&key, &iv, ciphertext)
+        .map_err(|e| AttachmentError::DecryptionFailed(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_with_both_key_sizes() {
+        let data = b"a picture of a cat, presumably".repeat(10);
+
+        for (key_size, alg) in [(128, "A128CTR"), (256, "A256CTR")] {
+            let encrypted = encrypt_attachment(&data, key_size).unwrap();
+            assert_eq!(encrypted.info.key.alg, alg);
+            assert_ne!(encrypted.ciphertext, data);
+
+            let decrypted = decrypt_attachment(&encrypted.ciphertext, &encrypted.info).unwrap();
+            assert_eq!(decrypted, data);
+        }
+    }
+
+    #[test]
+    fn unsupported_key_size_is_rejected() {
+        assert!(matches!(
+            encrypt_attachment(b"data", 192),
+            Err(AttachmentError::UnsupportedKeySize(192))
+        ));
+    }
+
+    #[test]
+    fn modified_ciphertext_is_rejected() {
+        let mut encrypted = encrypt_attachment(b"data", 256).unwrap();
+        encrypted.ciphertext[0] ^= 1;
+
+        assert!(matches!(
+            decrypt_attachment(&encrypted.ciphertext, &encrypted.info),
+            Err(AttachmentError::HashMismatch)
+        ));
+    }
+
+    #[test]
+    fn info_is_inspected_without_the_key() {
+        let encrypted = encrypt_attachment(b"data", 256).unwrap();
+        let mut info = serde_json::to_value(&encrypted.info).unwrap();
+        info["key"].as_object_mut().unwrap().remove("k");
+
+        assert_eq!(
+            inspect(&info.to_string()).unwrap(),
+            AttachmentInfo { algorithm: "A256CTR".into(), key_size: 256, has_hashes: true }
+        );
+    }
+
+    #[test]
+    fn info_without_hashes_is_rejected() {
+        let encrypted = encrypt_attachment(b"data", 128).unwrap();
+        let mut info = serde_json::to_value(&encrypted.info).unwrap();
+        info.as_object_mut().unwrap().remove("hashes");
+
+        assert!(matches!(inspect(&info.to_string()), Err(AttachmentError::InvalidInfo(_))));
+    }
+}
@@ -0,0 +1,441 @@
+//! Server-side key backup
+//!
+//! Implements the checks needed for `m.megolm_backup.v1.curve25519-aes-sha2`
+//! backups. The backup's auth data must be verified before any sessions it
+//! holds are trusted, otherwise a compromised homeserver could swap in a
+//! backup key it controls.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::megolm::MegolmSession;
+use crate::olm::OlmSession;
+use crate::registry;
+use crate::utilities;
+
+/// Backup algorithm identifier
+pub const BACKUP_ALGORITHM: &str = "m.megolm_backup.v1.curve25519-aes-sha2";
+
+/// Backup errors
+#[derive(Error, Debug)]
+pub enum BackupError {
+    #[error("Invalid auth data: {0}")]
+    InvalidAuthData(String),
+
+    #[error("Invalid key: {0}")]
+    InvalidKey(String),
+
+    #[error("Signing failed: {0}")]
+    SigningFailed(String),
+
+    #[error("Key derivation refused: {0}")]
+    Kdf(utilities::UtilityError),
+}
+
+/// Bytes in front of the private key in an encoded recovery key
+const RECOVERY_KEY_PREFIX: [u8; 2] = [0x8b, 0x01];
+
+/// Characters per group when a recovery key is shown to the user
+const RECOVERY_KEY_GROUP: usize = 4;
+
+/// Auth data of a `m.megolm_backup.v1.curve25519-aes-sha2` backup version
+#[derive(Serialize, Deserialize)]
+pub struct AuthData {
+    pub public_key: String,
+    #[serde(default)]
+    pub signatures: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+/// Verify a backup's auth data before importing from it
+///
+/// The backup is only trusted if its `public_key` is the public part of our
+/// recovery key and at least one of its signatures verifies under the given
+/// Ed25519 signing key (usually our own device or master key).
+pub fn verify_backup_auth_data(
+    auth_data_json: &str,
+    recovery_pubkey: &str,
+    signing_key: &str,
+) -> Result<bool, BackupError> {
+    let value: serde_json::Value = serde_json::from_str(auth_data_json)
+        .map_err(|e| BackupError::InvalidAuthData(format!("Invalid JSON: {}", e)))?;
+    let auth_data: AuthData = serde_json::from_value(value.clone())
+        .map_err(|e| BackupError::InvalidAuthData(e.to_string()))?;
+
+    let backup_key = vodozemac::Curve25519PublicKey::from_base64(&auth_data.public_key)
+        .map_err(|e| BackupError::InvalidAuthData(format!("Invalid public key: {}", e)))?;
+    let recovery_key = vodozemac::Curve25519PublicKey::from_base64(recovery_pubkey)
+        .map_err(|e| BackupError::InvalidKey(format!("Invalid recovery key: {}", e)))?;
+
+    if backup_key.as_bytes() != recovery_key.as_bytes() {
+        return Ok(false);
+    }
+
+    let signing_key = vodozemac::Ed25519PublicKey::from_base64(signing_key)
+        .map_err(|e| BackupError::InvalidKey(format!("Invalid signing key: {}", e)))?;
+
+    let canonical = utilities::signable_json(&value);
+
+    let verified = auth_data
+        .signatures
+        .values()
+        .flat_map(|signatures| signatures.values())
+        .filter_map(|signature| vodozemac::Ed25519Signature::from_base64(signature).ok())
+        .any(|signature| signing_key.verify(canonical.as_bytes(), &signature).is_ok());
+
+    Ok(verified)
+}
+
+/// Check a recovery passphrase against the backup's public key
+///
+/// The backup key is derived from the passphrase with the `m.pbkdf2`
+/// parameters stored in the backup's secret storage info; the passphrase is
+/// right if its Curve25519 public key matches `expected_public_key`. The
+/// comparison is constant time. Parameters too weak to derive with fail
+/// with `Kdf` instead of looking like a wrong passphrase.
+pub fn check_passphrase(
+    passphrase: &str,
+    salt: &str,
+    iterations: u32,
+    expected_public_key: &str,
+) -> Result<bool, BackupError> {
+    let expected = utilities::decode_public_key(expected_public_key)
+        .map_err(|e| BackupError::InvalidKey(format!("Invalid backup public key: {}", e)))?;
+
+    let derived = utilities::pbkdf2_sha512(passphrase.as_bytes(), salt.as_bytes(), iterations, 32)
+        .map_err(BackupError::Kdf)?;
+    let derived = zeroize::Zeroizing::new(derived);
+    let mut private_key = zeroize::Zeroizing::new([0u8; 32]);
+    private_key.copy_from_slice(&derived);
+    let public_key = utilities::curve25519_public_from_private(&private_key);
+
+    use subtle::ConstantTimeEq;
+    Ok(public_key.ct_eq(&expected).into())
+}
+
+/// Encode a backup private key as a Matrix recovery key
+///
+/// The key is prefixed with `0x8b 0x01`, followed by a parity byte: the XOR
+/// of all bytes before it. The result is base58, without spaces.
+pub fn encode_recovery_key(private_key: &[u8; 32]) -> String {
+    let mut bytes = zeroize::Zeroizing::new(Vec::with_capacity(35));
+    bytes.extend_from_slice(&RECOVERY_KEY_PREFIX);
+    bytes.extend_from_slice(private_key);
+    let parity = bytes.iter().fold(0, |parity, byte| parity ^ byte);
+    bytes.push(parity);
+
+    utilities::base58_encode(&bytes)
+}
+
+/// Split a recovery key into space-separated groups of four characters
+///
+/// Any whitespace already in the key is dropped first, so formatting a
+/// formatted key gives the same result.
+pub fn format_recovery_key_for_display(recovery_key: &str) -> String {
+    let chars: Vec<char> = recovery_key.chars().filter(|c| !c.is_whitespace()).collect();
+    chars
+        .chunks(RECOVERY_KEY_GROUP)
+        .map(|group| group.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Decode a recovery key as the user typed or pasted it
+///
+/// Whitespace anywhere in the key is ignored. The prefix and parity byte
+/// must be right, so most typos are caught here.
+pub fn parse_display_recovery_key(
+    recovery_key: &str,
+) -> Result<zeroize::Zeroizing<[u8; 32]>, BackupError> {
+    let compact: String = recovery_key.chars().filter(|c| !c.is_whitespace()).collect();
+    let bytes = zeroize::Zeroizing::new(
+        utilities::base58_decode(&compact)
+            .map_err(|_| BackupError::InvalidKey("Recovery key is not base58".into()))?,
+    );
+
+    if bytes.len() != RECOVERY_KEY_PREFIX.len() + 32 + 1 || bytes[..2] != RECOVERY_KEY_PREFIX {
+        return Err(BackupError::InvalidKey("Not a recovery key".into()));
+    }
+    if bytes.iter().fold(0, |parity, byte| parity ^ byte) != 0 {
+        return Err(BackupError::InvalidKey("Recovery key parity check failed".into()));
+    }
+
+    let mut private_key = zeroize::Zeroizing::new([0u8; 32]);
+    private_key.copy_from_slice(&bytes[2..34]);
+    Ok(private_key)
+}
+
+/// A new backup version, ready to upload
+#[derive(Serialize)]
+pub struct CreatedBackup {
+    /// Curve25519 backup public key
+    pub public_key: String,
+    /// Private backup key as a recovery key (see [`encode_recovery_key`]);
+    /// the only way to read the backup, so it has to be kept (e.g. in
+    /// secret storage or written down by the user)
+    pub recovery_key: String,
+    /// Signed `auth_data` for the `/room_keys/version` request
+    pub auth_data_json: String,
+}
+
+/// Create a backup key and its auth data, signed by the account
+///
+/// The auth data is signed with the account's Ed25519 key as device
+/// `device_id`, so other devices can check it with
+/// [`verify_backup_auth_data`].
+pub fn create_backup(
+    account: &OlmSession,
+    user_id: &str,
+    device_id: &str,
+) -> Result<CreatedBackup, BackupError> {
+    let private_key = vodozemac::Curve25519SecretKey::new();
+    let public_key = vodozemac::Curve25519PublicKey::from(&private_key).to_base64();
+
+    let mut auth_data = serde_json::json!({ "public_key": public_key });
+    let signature = account
+        .sign(&utilities::signable_json(&auth_data))
+        .map_err(|e| BackupError::SigningFailed(e.to_string()))?;
+    auth_data["signatures"] = serde_json::json!({
+        user_id: { crate::olm::format_key_id("ed25519", device_id): signature }
+    });
+
+    Ok(CreatedBackup {
+        public_key,
+        recovery_key: encode_recovery_key(&private_key.to_bytes()),
+        auth_data_json: auth_data.to_string(),
+    })
+}
+
+/// Progress of a background session import
+///
+/// `done` counts every entry processed so far, including the `errors`
+/// that couldn't be imported.
+#[derive(Serialize, Default)]
+pub struct ImportProgress {
+    pub done: usize,
+    pub total: usize,
+    pub errors: usize,
+    /// Session id -> handle of each session imported so far
+    #[serde(skip)]
+    pub sessions: BTreeMap<String, i64>,
+}
+
+impl ImportProgress {
+    pub fn is_finished(&self) -> bool {
+        self.done == self.total
+    }
+}
+
+/// Import exported Megolm sessions on a background thread
+///
+/// Each imported session is handed to `store`, which keeps it and returns
+/// its handle. `progress` is updated after every entry, so it can be polled
+/// while the import runs.
+pub fn spawn_session_import<F>(
+    exports: Vec<serde_json::Value>,
+    progress: Arc<Mutex<ImportProgress>>,
+    mut store: F,
+) -> JoinHandle<()>
+where
+    F: FnMut(MegolmSession) -> i64 + Send + 'static,
+{
+    registry::lock(&progress).total = exports.len();
+
+    std::thread::spawn(move || {
+        for export in exports {
+            let imported = MegolmSession::import(&export.to_string(), None)
+                .map(|session| (session.session_id().to_string(), store(session)));
+
+            let mut progress = registry::lock(&progress);
+            match imported {
+                Ok((session_id, handle)) => {
+                    progress.sessions.insert(session_id, handle);
+                }
+                Err(e) => {
+                    log::warn!("Skipping backup session: {}", e);
+                    progress.errors += 1;
+                }
+            }
+            progress.done += 1;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed_auth_data(
+        signing_key: &vodozemac::Ed25519Keypair,
+        public_key: &str,
+    ) -> serde_json::Value {
+        let unsigned = serde_json::json!({ "public_key": public_key });
+        let signature = signing_key.sign(utilities::canonical_json(&unsigned).as_bytes());
+
+        serde_json::json!({
+            "public_key": public_key,
+            "signatures": {
+                "@alice:example.org": {
+                    "ed25519:DEVICEID": signature.to_base64(),
+                },
+            },
+        })
+    }
+
+    #[test]
+    fn valid_auth_data_verifies() {
+        let signing_key = vodozemac::Ed25519Keypair::new();
+        let recovery_key = vodozemac::Curve25519SecretKey::new();
+        let public_key = vodozemac::Curve25519PublicKey::from(&recovery_key).to_base64();
+
+        let auth_data = signed_auth_data(&signing_key, &public_key);
+
+        let verified = verify_backup_auth_data(
+            &auth_data.to_string(),
+            &public_key,
+            &signing_key.public_key().to_base64(),
+        )
+        .unwrap();
+        assert!(verified);
+    }
+
+    #[test]
+    fn tampered_auth_data_is_rejected() {
+        let signing_key = vodozemac::Ed25519Keypair::new();
+        let recovery_key = vodozemac::Curve25519SecretKey::new();
+        let public_key = vodozemac::Curve25519PublicKey::from(&recovery_key).to_base64();
+        let server_key = vodozemac::Curve25519PublicKey::from(&vodozemac::Curve25519SecretKey::new());
+
+        // The server swapped the backup key but kept the original signature
+        let mut auth_data = signed_auth_data(&signing_key, &public_key);
+        auth_data["public_key"] = server_key.to_base64().into();
+
+        let verified = verify_backup_auth_data(
+            &auth_data.to_string(),
+            &server_key.to_base64(),
+            &signing_key.public_key().to_base64(),
+        )
+        .unwrap();
+        assert!(!verified);
+
+        let verified = verify_backup_auth_data(
+            &auth_data.to_string(),
+            &public_key,
+            &signing_key.public_key().to_base64(),
+        )
+        .unwrap();
+        assert!(!verified);
+    }
+
+    #[test]
+    fn background_import_reports_progress() {
+        let mut exports: Vec<serde_json::Value> = (0..3)
+            .map(|_| {
+                let outbound = vodozemac::megolm::GroupSession::new(
+                    vodozemac::megolm::SessionConfig::version_1(),
+                );
+                let inbound = vodozemac::megolm::InboundGroupSession::new(
+                    &outbound.session_key(),
+                    vodozemac::megolm::SessionConfig::version_1(),
+                );
+                serde_json::json!({
+                    "algorithm": crate::megolm::MEGOLM_ALGORITHM,
+                    "room_id": "!room:example.org",
+                    "sender_key": "sender_curve25519_key",
+                    "session_id": outbound.session_id(),
+                    "session_key": inbound.export_at_first_known_index().to_base64(),
+                })
+            })
+            .collect();
+        exports.push(serde_json::json!({ "session_key": "not a key" }));
+
+        let progress = Arc::new(Mutex::new(ImportProgress::default()));
+        let stored = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&stored);
+        spawn_session_import(exports, Arc::clone(&progress), move |session| {
+            let mut stored = sink.lock().unwrap();
+            stored.push(session);
+            stored.len() as i64
+        });
+
+        loop {
+            let progress = registry::lock(&progress);
+            assert_eq!(progress.total, 4);
+            assert!(progress.done <= progress.total);
+            if progress.is_finished() {
+                break;
+            }
+            drop(progress);
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        let progress = registry::lock(&progress);
+        assert_eq!(progress.errors, 1);
+        assert_eq!(progress.sessions.len(), 3);
+        assert_eq!(stored.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn created_backup_is_signed_by_the_account() {
+        let account = OlmSession::create_account().unwrap();
+        let ed25519 = account.get_identity_keys().unwrap().ed25519;
+
+        let backup = create_backup(&account, "@alice:example.org", "DEVICEID").unwrap();
+        let auth_data: serde_json::Value = serde_json::from_str(&backup.auth_data_json).unwrap();
+        assert!(auth_data["signatures"]["@alice:example.org"]["ed25519:DEVICEID"].is_string());
+
+        assert!(verify_backup_auth_data(&backup.auth_data_json, &backup.public_key, &ed25519).unwrap());
+
+        // The recovery key holds the private half of the advertised key
+        let private_key = parse_display_recovery_key(&backup.recovery_key).unwrap();
+        let derived = vodozemac::Curve25519PublicKey::from(
+            &vodozemac::Curve25519SecretKey::from_slice(&private_key),
+        );
+        assert_eq!(derived.to_base64(), backup.public_key);
+    }
+
+    #[test]
+    fn recovery_passphrase_is_checked() {
+        let salt = "backupsalt";
+        let iterations = utilities::min_kdf_iterations();
+        let derived =
+            utilities::pbkdf2_sha512(b"correct horse", salt.as_bytes(), iterations, 32).unwrap();
+        let public_key =
+            utilities::curve25519_public_from_private(&derived.try_into().unwrap());
+        let public_key =
+            utilities::base64_encode_with(&public_key, utilities::Base64Variant::StandardNoPad);
+
+        assert!(check_passphrase("correct horse", salt, iterations, &public_key).unwrap());
+        assert!(!check_passphrase("battery staple", salt, iterations, &public_key).unwrap());
+        let weak = |iterations| {
+            matches!(
+                check_passphrase("correct horse", salt, iterations, &public_key),
+                Err(BackupError::Kdf(utilities::UtilityError::TooFewIterations { .. }))
+            )
+        };
+        assert!(weak(iterations - 1));
+        assert!(weak(0));
+    }
+
+    #[test]
+    fn recovery_key_display_round_trip() {
+        let private_key: [u8; 32] = std::array::from_fn(|i| i as u8);
+
+        let recovery_key = encode_recovery_key(&private_key);
+        assert_eq!(recovery_key, "EsSzykH7LCZx7CaecmKDwcmYJRXiYbtu8iQ3t8EznRwKpUY1");
+
+        let display = format_recovery_key_for_display(&recovery_key);
+        assert_eq!(display, "EsSz ykH7 LCZx 7Cae cmKD wcmY JRXi Ybtu 8iQ3 t8Ez nRwK pUY1");
+        assert_eq!(format_recovery_key_for_display(&display), display);
+        assert_eq!(*parse_display_recovery_key(&display).unwrap(), private_key);
+        let pasted = format!(" {}\n", recovery_key);
+        assert_eq!(*parse_display_recovery_key(&pasted).unwrap(), private_key);
+
+        // One character off breaks the parity check
+        let typo = display.replacen("EsSz", "EsSy", 1);
+        assert!(parse_display_recovery_key(&typo).is_err());
+    }
+}
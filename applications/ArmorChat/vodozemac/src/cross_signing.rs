@@ -0,0 +1,460 @@
+//! Cross-signing signature checks
+//!
+//! A user's master key signs their self-signing key, and the self-signing
+//! key signs each of their devices. A device only counts as cross-signed if
+//! both links of that chain verify; the master key itself is trusted out of
+//! band, e.g. through SAS verification.
+
+use serde::Deserialize;
+use thiserror::Error;
+use zeroize::Zeroize;
+
+use crate::olm::{format_key_id, OlmSession};
+use crate::utilities;
+
+/// Cross-signing errors
+#[derive(Error, Debug)]
+pub enum CrossSigningError {
+    #[error("Invalid JSON: {0}")]
+    InvalidJson(String),
+
+    #[error("Invalid key: {0}")]
+    InvalidKey(String),
+}
+
+/// Check the chain from a master key down to a device
+///
+/// `master_key` is the user's trusted Ed25519 master key in base64.
+/// `self_signing_key_json` is their self-signing key as returned in
+/// `/keys/query` (a `CrossSigningKey`), and `device_keys_json` the device's
+/// signed device keys. Returns `true` only if the self-signing key is
+/// marked for `self_signing`, belongs to the same user as the device, is
+/// signed by the master key, and signs the device.
+pub fn verify_chain(
+    master_key: &str,
+    self_signing_key_json: &str,
+    device_keys_json: &str,
+) -> Result<bool, CrossSigningError> {
+    let master_key = vodozemac::Ed25519PublicKey::from_base64(master_key)
+        .map_err(|e| CrossSigningError::InvalidKey(format!("Invalid master key: {}", e)))?;
+    let self_signing: serde_json::Value = serde_json::from_str(self_signing_key_json)
+        .map_err(|e| CrossSigningError::InvalidJson(format!("Self-signing key: {}", e)))?;
+    let device: serde_json::Value = serde_json::from_str(device_keys_json)
+        .map_err(|e| CrossSigningError::InvalidJson(format!("Device keys: {}", e)))?;
+
+    let user_id = self_signing
+        .get("user_id")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| CrossSigningError::InvalidJson("Missing user_id".into()))?;
+    if device.get("user_id").and_then(|v| v.as_str()) != Some(user_id) {
+        return Ok(false);
+    }
+
+    if !has_usage(&self_signing, "self_signing") {
+        return Ok(false);
+    }
+
+    let self_signing_key = single_key(&self_signing)?;
+
+    Ok(signed_by(&self_signing, user_id, &master_key)
+        && signed_by(&device, user_id, &self_signing_key))
+}
+
+/// Sign another user's master key with our user-signing key
+///
+/// `user_signing_key` is the private half of our user-signing key, as the
+/// unpadded base64 seed kept in secret storage. The signature is added
+/// under `user_id` (our own id) next to any signatures already on the key,
+/// and the signed master key is returned as JSON, ready to upload with
+/// `/keys/signatures/upload`.
+pub fn sign_user(
+    user_id: &str,
+    user_signing_key: &str,
+    their_master_key_json: &str,
+) -> Result<String, CrossSigningError> {
+    let signing_key = vodozemac::Ed25519SecretKey::from_base64(user_signing_key)
+        .map_err(|e| CrossSigningError::InvalidKey(format!("Invalid user-signing key: {}", e)))?;
+    let mut master_key: serde_json::Value = serde_json::from_str(their_master_key_json)
+        .map_err(|e| CrossSigningError::InvalidJson(format!("Master key: {}", e)))?;
+
+    if !has_usage(&master_key, "master") {
+        return Err(CrossSigningError::InvalidKey("Not a master key".into()));
+    }
+    single_key(&master_key)?;
+
+    let signature = signing_key.sign(utilities::signable_json(&master_key).as_bytes());
+    let key_id = format_key_id("ed25519", &signing_key.public_key().to_base64());
+
+    let signatures = master_key
+        .as_object_mut()
+        .ok_or_else(|| CrossSigningError::InvalidJson("Master key is not an object".into()))?
+        .entry("signatures")
+        .or_insert_with(|| serde_json::json!({}));
+    let ours = signatures
+        .as_object_mut()
+        .ok_or_else(|| CrossSigningError::InvalidJson("Invalid signatures".into()))?
+        .entry(user_id)
+        .or_insert_with(|| serde_json::json!({}));
+    ours.as_object_mut()
+        .ok_or_else(|| CrossSigningError::InvalidJson("Invalid signatures".into()))?
+        .insert(key_id, signature.to_base64().into());
+
+    Ok(master_key.to_string())
+}
+
+/// Check our user-signing key's signature on another user's master key
+///
+/// `user_signing_key` is the public half of the user-signing key of
+/// `user_id`, the user who signed.
+pub fn verify_user_signature(
+    user_id: &str,
+    user_signing_key: &str,
+    their_master_key_json: &str,
+) -> Result<bool, CrossSigningError> {
+    let signing_key = vodozemac::Ed25519PublicKey::from_base64(user_signing_key)
+        .map_err(|e| CrossSigningError::InvalidKey(format!("Invalid user-signing key: {}", e)))?;
+    let master_key: serde_json::Value = serde_json::from_str(their_master_key_json)
+        .map_err(|e| CrossSigningError::InvalidJson(format!("Master key: {}", e)))?;
+
+    Ok(has_usage(&master_key, "master") && signed_by(&master_key, user_id, &signing_key))
+}
+
+/// Private halves of a user's cross-signing keys, as unpadded base64 seeds
+#[derive(Deserialize)]
+pub struct CrossSigningSeeds {
+    pub master: String,
+    pub self_signing: String,
+    pub user_signing: String,
+}
+
+impl Drop for CrossSigningSeeds {
+    fn drop(&mut self) {
+        self.master.zeroize();
+        self.self_signing.zeroize();
+        self.user_signing.zeroize();
+    }
+}
+
+/// Build the body of a `/keys/device_signing/upload` request
+///
+/// The master key is signed by itself and by the device key of
+/// `account` (as `ed25519:<device_id>`); the self-signing and user-signing
+/// keys are signed by the master key. All signatures sit under `user_id`.
+pub fn device_signing_upload_json(
+    user_id: &str,
+    device_id: &str,
+    seeds: &CrossSigningSeeds,
+    account: &OlmSession,
+) -> Result<String, CrossSigningError> {
+    let secret = |seed: &str, name: &str| {
+        vodozemac::Ed25519SecretKey::from_base64(seed)
+            .map_err(|e| CrossSigningError::InvalidKey(format!("Invalid {} key: {}", name, e)))
+    };
+    let master = secret(&seeds.master, "master")?;
+    let self_signing = secret(&seeds.self_signing, "self-signing")?;
+    let user_signing = secret(&seeds.user_signing, "user-signing")?;
+
+    let cross_signing_key = |key: &vodozemac::Ed25519SecretKey, usage: &str| {
+        let public = key.public_key().to_base64();
+        serde_json::json!({
+            "user_id": user_id,
+            "usage": [usage],
+            "keys": { format_key_id("ed25519", &public): public },
+        })
+    };
+    let add_signature = |value: &mut serde_json::Value, key_id: String, signature: String| {
+        value["signatures"][user_id][key_id] = signature.into();
+    };
+    let sign_with = |value: &mut serde_json::Value, key: &vodozemac::Ed25519SecretKey| {
+        let signature = key.sign(utilities::signable_json(value).as_bytes()).to_base64();
+        add_signature(value, format_key_id("ed25519", &key.public_key().to_base64()), signature);
+    };
+
+    let mut master_key = cross_signing_key(&master, "master");
+    let device_signature = account
+        .sign(&utilities::signable_json(&master_key))
+        .map_err(|e| CrossSigningError::InvalidKey(format!("Device signature: {}", e)))?;
+    sign_with(&mut master_key, &master);
+    add_signature(&mut master_key, format_key_id("ed25519", device_id), device_signature);
+
+    let mut self_signing_key = cross_signing_key(&self_signing, "self_signing");
+    sign_with(&mut self_signing_key, &master);
+    let mut user_signing_key = cross_signing_key(&user_signing, "user_signing");
+    sign_with(&mut user_signing_key, &master);
+
+    Ok(serde_json::json!({
+        "master_key": master_key,
+        "self_signing_key": self_signing_key,
+        "user_signing_key": user_signing_key,
+    })
+    .to_string())
+}
+
+/// Whether a cross-signing key is marked for `usage`
+fn has_usage(cross_signing_key: &serde_json::Value, usage: &str) -> bool {
+    cross_signing_key
+        .get("usage")
+        .and_then(|v| v.as_array())
+        .is_some_and(|usages| usages.iter().any(|u| u.as_str() == Some(usage)))
+}
+
+/// The one Ed25519 key of a cross-signing key
+fn single_key(
+    cross_signing_key: &serde_json::Value,
+) -> Result<vodozemac::Ed25519PublicKey, CrossSigningError> {
+    let keys = cross_signing_key
+        .get("keys")
+        .and_then(|v| v.as_object())
+        .ok_or_else(|| CrossSigningError::InvalidJson("Missing keys".into()))?;
+
+    let key = match keys.values().collect::<Vec<_>>().as_slice() {
+        [key] => key.as_str(),
+        _ => None,
+    }
+    .ok_or_else(|| CrossSigningError::InvalidKey("Expected exactly one key".into()))?;
+
+    vodozemac::Ed25519PublicKey::from_base64(key)
+        .map_err(|e| CrossSigningError::InvalidKey(format!("Invalid cross-signing key: {}", e)))
+}
+
+/// Whether `user_id`'s signature from `key` over `value` verifies
+fn signed_by(value: &serde_json::Value, user_id: &str, key: &vodozemac::Ed25519PublicKey) -> bool {
+    let signature = value
+        .get("signatures")
+        .and_then(|signatures| signatures.get(user_id))
+        .and_then(|signatures| signatures.get(format_key_id("ed25519", &key.to_base64())))
+        .and_then(|v| v.as_str())
+        .and_then(|signature| vodozemac::Ed25519Signature::from_base64(signature).ok());
+
+    signature.is_some_and(|signature| {
+        let canonical = utilities::signable_json(value);
+        key.verify(canonical.as_bytes(), &signature).is_ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER_ID: &str = "@alice:example.org";
+
+    fn sign(value: &mut serde_json::Value, signing_key: &vodozemac::Ed25519Keypair) {
+        let signature = signing_key.sign(utilities::signable_json(value).as_bytes());
+        let key_id = format_key_id("ed25519", &signing_key.public_key().to_base64());
+        value["signatures"][USER_ID][key_id] = signature.to_base64().into();
+    }
+
+    struct Chain {
+        master: vodozemac::Ed25519Keypair,
+        self_signing: serde_json::Value,
+        device: serde_json::Value,
+    }
+
+    fn chain() -> Chain {
+        let master = vodozemac::Ed25519Keypair::new();
+        let self_signing_key = vodozemac::Ed25519Keypair::new();
+        let device_key = vodozemac::Ed25519Keypair::new();
+
+        let ssk_public = self_signing_key.public_key().to_base64();
+        let mut self_signing = serde_json::json!({
+            "user_id": USER_ID,
+            "usage": ["self_signing"],
+            "keys": { format_key_id("ed25519", &ssk_public): ssk_public },
+        });
+        sign(&mut self_signing, &master);
+
+        let mut device = serde_json::json!({
+            "user_id": USER_ID,
+            "device_id": "JLAFKJWSCS",
+            "algorithms": ["m.olm.v1.curve25519-aes-sha2", "m.megolm.v1.aes-sha2"],
+            "keys": {
+                "curve25519:JLAFKJWSCS": "3C5BFWi2Y8MaVvjM8M22DBmh24PmgR0nPvJOIArzgyI",
+                "ed25519:JLAFKJWSCS": device_key.public_key().to_base64(),
+            },
+        });
+        sign(&mut device, &device_key);
+        sign(&mut device, &self_signing_key);
+
+        Chain { master, self_signing, device }
+    }
+
+    fn verify(chain: &Chain) -> bool {
+        verify_chain(
+            &chain.master.public_key().to_base64(),
+            &chain.self_signing.to_string(),
+            &chain.device.to_string(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn valid_chain_verifies() {
+        assert!(verify(&chain()));
+    }
+
+    #[test]
+    fn self_signing_key_not_signed_by_master_is_rejected() {
+        let mut chain = chain();
+        chain.master = vodozemac::Ed25519Keypair::new();
+        assert!(!verify(&chain));
+
+        // Signed, but not under the signing user's id
+        let mut chain = self::chain();
+        let signatures = chain.self_signing["signatures"][USER_ID].take();
+        chain.self_signing["signatures"] =
+            serde_json::json!({ "@mallory:example.org": signatures });
+        assert!(!verify(&chain));
+    }
+
+    #[test]
+    fn device_not_signed_by_self_signing_key_is_rejected() {
+        let mut chain = chain();
+        chain.device["keys"]["curve25519:JLAFKJWSCS"] =
+            "ZmFrZSBrZXkgZnJvbSBhIG1hbGljaW91cyBzZXJ2ZXI".into();
+        assert!(!verify(&chain));
+
+        // A self-signing key the master key signed, but not the device's
+        let mut chain = self::chain();
+        let other = vodozemac::Ed25519Keypair::new();
+        let other_public = other.public_key().to_base64();
+        chain.self_signing["keys"] = serde_json::json!({
+            format_key_id("ed25519", &other_public): other_public,
+        });
+        chain.self_signing.as_object_mut().unwrap().remove("signatures");
+        sign(&mut chain.self_signing, &chain.master);
+        assert!(!verify(&chain));
+    }
+
+    #[test]
+    fn key_with_wrong_usage_or_user_is_rejected() {
+        let mut chain = chain();
+        chain.self_signing["usage"] = serde_json::json!(["user_signing"]);
+        chain.self_signing.as_object_mut().unwrap().remove("signatures");
+        sign(&mut chain.self_signing, &chain.master);
+        assert!(!verify(&chain));
+
+        let mut chain = self::chain();
+        chain.device["user_id"] = "@bob:example.org".into();
+        assert!(!verify(&chain));
+    }
+
+    #[test]
+    fn malformed_input_is_an_error() {
+        let chain = chain();
+        let master = chain.master.public_key().to_base64();
+        let self_signing = chain.self_signing.to_string();
+        let device = chain.device.to_string();
+
+        assert!(verify_chain("not a key", &self_signing, &device).is_err());
+        assert!(verify_chain(&master, "{", &device).is_err());
+        assert!(verify_chain(&master, &self_signing, "{").is_err());
+    }
+
+    fn master_key(user_id: &str) -> serde_json::Value {
+        let master = vodozemac::Ed25519Keypair::new();
+        let public = master.public_key().to_base64();
+        let mut master_key = serde_json::json!({
+            "user_id": user_id,
+            "usage": ["master"],
+            "keys": { format_key_id("ed25519", &public): public },
+        });
+        let signature = master.sign(utilities::signable_json(&master_key).as_bytes());
+        master_key["signatures"][user_id][format_key_id("ed25519", &public)] =
+            signature.to_base64().into();
+        master_key
+    }
+
+    #[test]
+    fn signed_master_key_verifies_under_user_signing_key() {
+        let bob = "@bob:example.org";
+        let user_signing_key = vodozemac::Ed25519SecretKey::new();
+        let public = user_signing_key.public_key().to_base64();
+        let their_master_key = master_key(bob);
+
+        let signed =
+            sign_user(USER_ID, &user_signing_key.to_base64(), &their_master_key.to_string())
+                .unwrap();
+        assert!(verify_user_signature(USER_ID, &public, &signed).unwrap());
+
+        // Bob's own signature is kept, and ours sits under our id
+        let signed: serde_json::Value = serde_json::from_str(&signed).unwrap();
+        assert_eq!(signed["signatures"][bob], their_master_key["signatures"][bob]);
+        assert!(signed["signatures"][USER_ID][format_key_id("ed25519", &public)].is_string());
+
+        // Not before signing, not under another key or user, not after tampering
+        let unsigned = their_master_key.to_string();
+        assert!(!verify_user_signature(USER_ID, &public, &unsigned).unwrap());
+        let other = vodozemac::Ed25519SecretKey::new().public_key().to_base64();
+        assert!(!verify_user_signature(USER_ID, &other, &signed.to_string()).unwrap());
+        assert!(!verify_user_signature(bob, &public, &signed.to_string()).unwrap());
+        let mut tampered = signed;
+        tampered["user_id"] = "@mallory:example.org".into();
+        assert!(!verify_user_signature(USER_ID, &public, &tampered.to_string()).unwrap());
+    }
+
+    #[test]
+    fn only_master_keys_are_signed() {
+        let user_signing_key = vodozemac::Ed25519SecretKey::new().to_base64();
+        let mut not_master = master_key("@bob:example.org");
+        not_master["usage"] = serde_json::json!(["self_signing"]);
+
+        assert!(sign_user(USER_ID, &user_signing_key, &not_master.to_string()).is_err());
+        assert!(sign_user(USER_ID, "not a key", &master_key("@bob:example.org").to_string())
+            .is_err());
+    }
+
+    #[test]
+    fn device_signing_upload_is_fully_signed() {
+        let account = OlmSession::create_account().unwrap();
+        let device_key =
+            vodozemac::Ed25519PublicKey::from_base64(&account.get_identity_keys().unwrap().ed25519)
+                .unwrap();
+        let seed = || vodozemac::Ed25519SecretKey::new().to_base64();
+        let seeds =
+            CrossSigningSeeds { master: seed(), self_signing: seed(), user_signing: seed() };
+
+        let upload = device_signing_upload_json(USER_ID, "JLAFKJWSCS", &seeds, &account).unwrap();
+        let upload: serde_json::Value = serde_json::from_str(&upload).unwrap();
+        let public =
+            |seed: &str| vodozemac::Ed25519SecretKey::from_base64(seed).unwrap().public_key();
+        let master = public(&seeds.master);
+
+        let uploaded_master = &upload["master_key"];
+        assert!(has_usage(uploaded_master, "master"));
+        assert_eq!(single_key(uploaded_master).unwrap(), master);
+        assert!(signed_by(uploaded_master, USER_ID, &master));
+        // The device signature is filed under the device id, not the key
+        let device_signature = uploaded_master["signatures"][USER_ID]["ed25519:JLAFKJWSCS"]
+            .as_str()
+            .map(|s| vodozemac::Ed25519Signature::from_base64(s).unwrap())
+            .unwrap();
+        let canonical = utilities::signable_json(uploaded_master);
+        assert!(device_key.verify(canonical.as_bytes(), &device_signature).is_ok());
+        assert_eq!(uploaded_master["signatures"][USER_ID].as_object().unwrap().len(), 2);
+
+        for (field, usage, seed) in [
+            ("self_signing_key", "self_signing", &seeds.self_signing),
+            ("user_signing_key", "user_signing", &seeds.user_signing),
+        ] {
+            let key = &upload[field];
+            assert_eq!(key["user_id"], USER_ID);
+            assert!(has_usage(key, usage));
+            assert_eq!(single_key(key).unwrap(), public(seed));
+            assert!(signed_by(key, USER_ID, &master));
+            assert_eq!(key["signatures"][USER_ID].as_object().unwrap().len(), 1);
+        }
+
+        // The result plugs into the chain and user-signing checks
+        let usk = public(&seeds.user_signing).to_base64();
+        let bob_master = master_key("@bob:example.org");
+        let signed = sign_user(USER_ID, &seeds.user_signing, &bob_master.to_string()).unwrap();
+        assert!(verify_user_signature(USER_ID, &usk, &signed).unwrap());
+
+        let bad = CrossSigningSeeds {
+            master: "not a key".into(),
+            self_signing: seeds.self_signing.clone(),
+            user_signing: seeds.user_signing.clone(),
+        };
+        assert!(device_signing_upload_json(USER_ID, "JLAFKJWSCS", &bad, &account).is_err());
+    }
+}
@@ -0,0 +1,299 @@
+//! Machine-readable error reports for Kotlin
+//!
+//! JNI calls fail with a null, `false` or 0 and log why. So the app can
+//! branch on the reason instead of matching log text, failed Olm, Megolm
+//! and utility calls, and calls on a zero or freed handle, also leave an
+//! [`ErrorReport`] behind for the calling thread, which Kotlin reads with
+//! `getLastErrorJson`. Each JNI call clears the previous report first, so
+//! a failure that isn't reported never shows an older one.
+
+use std::cell::RefCell;
+
+use serde::Serialize;
+
+use crate::megolm::MegolmError;
+use crate::olm::OlmError;
+use crate::registry::HandleError;
+use crate::utilities::UtilityError;
+
+/// Broad kind of failure, for deciding what to do about it
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    /// The caller passed something malformed; retrying won't help
+    InvalidInput,
+    /// The message or payload couldn't be decrypted or authenticated
+    Decryption,
+    /// Encryption or signing failed
+    Encryption,
+    /// The session or key it needs isn't there
+    NotFound,
+    /// Stored or in-memory state is unusable for this call
+    State,
+}
+
+/// A failure as reported to Kotlin
+///
+/// `code` is stable across releases and names the error variant, e.g.
+/// `olm.bad_mac`; `message` is the human-readable text and may change.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ErrorReport {
+    pub code: &'static str,
+    pub category: ErrorCategory,
+    pub message: String,
+}
+
+/// Errors that can be turned into an [`ErrorReport`]
+pub trait Reportable: std::fmt::Display {
+    /// Stable code and category of this error
+    fn classify(&self) -> (&'static str, ErrorCategory);
+
+    fn report(&self) -> ErrorReport {
+        let (code, category) = self.classify();
+        ErrorReport { code, category, message: self.to_string() }
+    }
+}
+
+impl Reportable for OlmError {
+    fn classify(&self) -> (&'static str, ErrorCategory) {
+        use ErrorCategory::*;
+
+        match self {
+            OlmError::AccountCreationFailed(_) => ("olm.account_creation_failed", State),
+            OlmError::KeyGenerationFailed(_) => ("olm.key_generation_failed", Encryption),
+            OlmError::SessionCreationFailed(_) => ("olm.session_creation_failed", State),
+            OlmError::EncryptionFailed(_) => ("olm.encryption_failed", Encryption),
+            OlmError::DecryptionFailed(_) => ("olm.decryption_failed", Decryption),
+            OlmError::BadMac => ("olm.bad_mac", Decryption),
+            OlmError::InvalidKey(_) => ("olm.invalid_key", InvalidInput),
+            OlmError::SessionNotFound => ("olm.session_not_found", NotFound),
+            OlmError::InvalidStore(_) => ("olm.invalid_store", State),
+            OlmError::InvalidState(_) => ("olm.invalid_state", State),
+            OlmError::ForwardingChainTooLong { .. } => {
+                ("olm.forwarding_chain_too_long", InvalidInput)
+            }
+            OlmError::AlgorithmMismatch { .. } => ("olm.algorithm_mismatch", Decryption),
+        }
+    }
+}
+
+impl Reportable for MegolmError {
+    fn classify(&self) -> (&'static str, ErrorCategory) {
+        use ErrorCategory::*;
+
+        match self {
+            MegolmError::SessionCreationFailed(_) => ("megolm.session_creation_failed", State),
+            MegolmError::EncryptionFailed(_) => ("megolm.encryption_failed", Encryption),
+            MegolmError::DecryptionFailed(_) => ("megolm.decryption_failed", Decryption),
+            MegolmError::InvalidSessionKey(_) => ("megolm.invalid_session_key", InvalidInput),
+            MegolmError::InvalidMessageIndex(_) => ("megolm.invalid_message_index", Decryption),
+            MegolmError::InboundSession(_) => ("megolm.inbound_session", State),
+            MegolmError::AlgorithmMismatch { .. } => ("megolm.algorithm_mismatch", Decryption),
+        }
+    }
+}
+
+impl Reportable for UtilityError {
+    fn classify(&self) -> (&'static str, ErrorCategory) {
+        use ErrorCategory::*;
+
+        match self {
+            #[cfg(feature = "argon2")]
+            UtilityError::KeyGenerationFailed(_) => ("utility.key_generation_failed", Encryption),
+            UtilityError::SigningFailed(_) => ("utility.signing_failed", Encryption),
+            UtilityError::VerificationFailed(_) => ("utility.verification_failed", InvalidInput),
+            UtilityError::InvalidKeyFormat => ("utility.invalid_key_format", InvalidInput),
+            UtilityError::EncryptionFailed(_) => ("utility.encryption_failed", Encryption),
+            UtilityError::NonceReuse => ("utility.nonce_reuse", Encryption),
+            #[cfg(feature = "backup")]
+            UtilityError::TooFewIterations { .. } => ("utility.too_few_iterations", InvalidInput),
+            UtilityError::InvalidJson(_) => ("utility.invalid_json", InvalidInput),
+            UtilityError::InvalidString => ("utility.invalid_string", InvalidInput),
+            UtilityError::InvalidSignatureLength(_) => {
+                ("utility.invalid_signature_length", InvalidInput)
+            }
+            UtilityError::MissingField(_) => ("utility.missing_field", InvalidInput),
+            #[cfg(feature = "argon2")]
+            UtilityError::WeakArgon2Params(_) => ("utility.weak_argon2_params", InvalidInput),
+            #[cfg(feature = "argon2")]
+            UtilityError::ExcessiveArgon2Params(_) => {
+                ("utility.excessive_argon2_params", InvalidInput)
+            }
+        }
+    }
+}
+
+impl Reportable for HandleError {
+    fn classify(&self) -> (&'static str, ErrorCategory) {
+        match self {
+            HandleError::Null => ("handle.null", ErrorCategory::InvalidInput),
+            HandleError::Unknown(_) => ("handle.unknown", ErrorCategory::NotFound),
+        }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<ErrorReport>> = const { RefCell::new(None) };
+}
+
+/// Keep `error` as the calling thread's last error
+pub fn set_last_error(error: &impl Reportable) {
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(error.report()));
+}
+
+/// Forget the calling thread's last error
+///
+/// Called at the start of every JNI entry point, so a report never
+/// outlives the call that made it.
+pub fn clear_last_error() {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+}
+
+/// Take the calling thread's last error, leaving none behind
+pub fn take_last_error() -> Option<ErrorReport> {
+    LAST_ERROR.with(|last| last.borrow_mut().take())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ErrorCategory::*;
+
+    fn assert_report(error: impl Reportable, code: &str, category: ErrorCategory) {
+        let report = error.report();
+        assert_eq!(report.code, code);
+        assert_eq!(report.category, category);
+        assert_eq!(report.message, error.to_string());
+    }
+
+    #[test]
+    fn olm_errors_map_to_codes() {
+        let x = || String::from("x");
+        assert_report(OlmError::AccountCreationFailed(x()), "olm.account_creation_failed", State);
+        assert_report(OlmError::KeyGenerationFailed(x()), "olm.key_generation_failed", Encryption);
+        assert_report(OlmError::SessionCreationFailed(x()), "olm.session_creation_failed", State);
+        assert_report(OlmError::EncryptionFailed(x()), "olm.encryption_failed", Encryption);
+        assert_report(OlmError::DecryptionFailed(x()), "olm.decryption_failed", Decryption);
+        assert_report(OlmError::BadMac, "olm.bad_mac", Decryption);
+        assert_report(OlmError::InvalidKey(x()), "olm.invalid_key", InvalidInput);
+        assert_report(OlmError::SessionNotFound, "olm.session_not_found", NotFound);
+        assert_report(OlmError::InvalidStore(x()), "olm.invalid_store", State);
+        assert_report(OlmError::InvalidState(x()), "olm.invalid_state", State);
+        assert_report(
+            OlmError::ForwardingChainTooLong { depth: 3, max: 2 },
+            "olm.forwarding_chain_too_long",
+            InvalidInput,
+        );
+        assert_report(
+            OlmError::AlgorithmMismatch { pinned: x(), declared: x() },
+            "olm.algorithm_mismatch",
+            Decryption,
+        );
+    }
+
+    #[test]
+    fn megolm_errors_map_to_codes() {
+        let x = || String::from("x");
+        assert_report(
+            MegolmError::SessionCreationFailed(x()),
+            "megolm.session_creation_failed",
+            State,
+        );
+        assert_report(MegolmError::EncryptionFailed(x()), "megolm.encryption_failed", Encryption);
+        assert_report(MegolmError::DecryptionFailed(x()), "megolm.decryption_failed", Decryption);
+        assert_report(
+            MegolmError::InvalidSessionKey(x()),
+            "megolm.invalid_session_key",
+            InvalidInput,
+        );
+        assert_report(
+            MegolmError::InvalidMessageIndex(x()),
+            "megolm.invalid_message_index",
+            Decryption,
+        );
+        assert_report(MegolmError::InboundSession("encrypt"), "megolm.inbound_session", State);
+        assert_report(
+            MegolmError::AlgorithmMismatch { pinned: x(), declared: x() },
+            "megolm.algorithm_mismatch",
+            Decryption,
+        );
+    }
+
+    #[test]
+    fn utility_errors_map_to_codes() {
+        let x = || String::from("x");
+        #[cfg(feature = "argon2")]
+        assert_report(
+            UtilityError::KeyGenerationFailed(x()),
+            "utility.key_generation_failed",
+            Encryption,
+        );
+        assert_report(UtilityError::SigningFailed(x()), "utility.signing_failed", Encryption);
+        assert_report(
+            UtilityError::VerificationFailed(x()),
+            "utility.verification_failed",
+            InvalidInput,
+        );
+        assert_report(UtilityError::InvalidKeyFormat, "utility.invalid_key_format", InvalidInput);
+        assert_report(UtilityError::EncryptionFailed(x()), "utility.encryption_failed", Encryption);
+        assert_report(UtilityError::NonceReuse, "utility.nonce_reuse", Encryption);
+        #[cfg(feature = "backup")]
+        assert_report(
+            UtilityError::TooFewIterations { iterations: 1, minimum: 2 },
+            "utility.too_few_iterations",
+            InvalidInput,
+        );
+        assert_report(UtilityError::InvalidJson(x()), "utility.invalid_json", InvalidInput);
+        assert_report(UtilityError::InvalidString, "utility.invalid_string", InvalidInput);
+        assert_report(
+            UtilityError::InvalidSignatureLength(63),
+            "utility.invalid_signature_length",
+            InvalidInput,
+        );
+        assert_report(UtilityError::MissingField("x"), "utility.missing_field", InvalidInput);
+        #[cfg(feature = "argon2")]
+        assert_report(
+            UtilityError::WeakArgon2Params(x()),
+            "utility.weak_argon2_params",
+            InvalidInput,
+        );
+        #[cfg(feature = "argon2")]
+        assert_report(
+            UtilityError::ExcessiveArgon2Params(x()),
+            "utility.excessive_argon2_params",
+            InvalidInput,
+        );
+    }
+
+    #[test]
+    fn handle_errors_map_to_codes() {
+        assert_report(HandleError::Null, "handle.null", InvalidInput);
+        assert_report(HandleError::Unknown(7), "handle.unknown", NotFound);
+    }
+
+    #[test]
+    fn last_error_is_per_thread_and_taken_once() {
+        set_last_error(&OlmError::BadMac);
+
+        let other = std::thread::spawn(take_last_error).join().unwrap();
+        assert!(other.is_none());
+
+        let report = take_last_error().unwrap();
+        assert_eq!(report.code, "olm.bad_mac");
+        assert!(take_last_error().is_none());
+
+        set_last_error(&OlmError::BadMac);
+        clear_last_error();
+        assert!(take_last_error().is_none());
+
+        let json = serde_json::to_value(OlmError::SessionNotFound.report()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "code": "olm.session_not_found",
+                "category": "not_found",
+                "message": "Session not found",
+            })
+        );
+    }
+}
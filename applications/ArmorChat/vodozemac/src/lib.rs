@@ -3,14 +3,27 @@
 //! This library provides JNI bindings for vodozemac, the Matrix E2EE
 //! implementation in Rust. It enables Android apps to use proper
 //! Matrix encryption compatible with Element and other clients.
+//!
+//! Accounts and sessions are handed to Kotlin as registry handles. Calls on
+//! the same handle are serialized, so one account may be used from several
+//! threads at once.
 
 use jni::JNIEnv;
-use jni::objects::{JClass, JObject, JString, JValue};
+use jni::objects::{JByteArray, JClass, JObject, JString};
 use jni::sys::{jint, jlong, jboolean, jbyteArray, jstring};
 
 mod olm;
 mod megolm;
 mod utilities;
+#[cfg(feature = "backup")]
+mod backup;
+mod registry;
+mod attachments;
+mod verification;
+mod cross_signing;
+mod errors;
+#[cfg(feature = "metrics")]
+mod metrics;
 
 use olm::OlmSession;
 use megolm::MegolmSession;
@@ -18,9 +31,11 @@ use megolm::MegolmSession;
 /// Initialize the native library
 #[no_mangle]
 pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_initialize(
-    mut env: JNIEnv,
+    _env: JNIEnv,
     _class: JClass,
 ) -> jboolean {
+    errors::clear_last_error();
+
     // Initialize logging for Android
     android_logger::init_once(
         android_logger::Config::default()
@@ -35,9 +50,11 @@ pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_initialize(
 /// Get the library version
 #[no_mangle]
 pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getVersion(
-    mut env: JNIEnv,
+    env: JNIEnv,
     _class: JClass,
 ) -> jstring {
+    errors::clear_last_error();
+
     let version = env.new_string("vodozemac-0.8.0-android").unwrap();
     version.into_raw()
 }
@@ -45,16 +62,18 @@ pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getVersion(
 /// Generate Curve25519 key pair for identity
 #[no_mangle]
 pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_generateIdentityKeyPair(
-    mut env: JNIEnv,
+    env: JNIEnv,
     _class: JClass,
 ) -> jbyteArray {
+    errors::clear_last_error();
+
     match utilities::generate_key_pair() {
         Ok(key_pair) => {
             let bytes = key_pair.to_bytes();
-            env.byte_array_from_slice(&bytes).unwrap()
+            env.byte_array_from_slice(&bytes).unwrap().into_raw()
         }
         Err(e) => {
-            log::error!("Failed to generate identity key pair: {}", e);
+            report_error("Failed to generate identity key pair", &e);
             std::ptr::null_mut()
         }
     }
@@ -63,389 +82,3949 @@ pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_generateIdentit
 /// Generate Ed25519 key pair for signing
 #[no_mangle]
 pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_generateSigningKeyPair(
-    mut env: JNIEnv,
+    env: JNIEnv,
     _class: JClass,
 ) -> jbyteArray {
+    errors::clear_last_error();
+
     match utilities::generate_signing_key_pair() {
         Ok(key_pair) => {
             let bytes = key_pair.to_bytes();
-            env.byte_array_from_slice(&bytes).unwrap()
+            env.byte_array_from_slice(&bytes).unwrap().into_raw()
         }
         Err(e) => {
-            log::error!("Failed to generate signing key pair: {}", e);
+            report_error("Failed to generate signing key pair", &e);
             std::ptr::null_mut()
         }
     }
 }
 
-/// Sign a message with Ed25519
+/// Extract the public key from a key pair buffer
+///
+/// Throws `VodozemacException` if the buffer isn't a valid key pair.
 #[no_mangle]
-pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_sign(
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_extractPublicKey(
     mut env: JNIEnv,
     _class: JClass,
-    private_key: jbyteArray,
-    message: jbyteArray,
+    key_pair: jbyteArray,
 ) -> jbyteArray {
-    let private_key = match env.convert_byte_array(private_key) {
-        Ok(bytes) => bytes,
-        Err(e) => {
-            log::error!("Failed to get private key: {}", e);
-            return std::ptr::null_mut();
-        }
+    errors::clear_last_error();
+
+    let key_pair = match get_rust_bytes(&mut env, key_pair) {
+        Some(bytes) => bytes,
+        None => return std::ptr::null_mut(),
     };
 
-    let message = match env.convert_byte_array(message) {
-        Ok(bytes) => bytes,
+    match utilities::KeyPair::from_bytes(&key_pair) {
+        Ok(key_pair) => env.byte_array_from_slice(key_pair.public_key()).unwrap().into_raw(),
         Err(e) => {
-            log::error!("Failed to get message: {}", e);
-            return std::ptr::null_mut();
+            throw_vodozemac_exception(&mut env, &format!("Key pair: {}", e));
+            std::ptr::null_mut()
         }
+    }
+}
+
+/// Derive a Curve25519 public key from a private X25519 scalar
+///
+/// Throws `VodozemacException` if the private key isn't 32 bytes.
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_curve25519PublicFromPrivate(
+    mut env: JNIEnv,
+    _class: JClass,
+    private_key: jbyteArray,
+) -> jbyteArray {
+    errors::clear_last_error();
+
+    let private_key = match get_rust_bytes(&mut env, private_key) {
+        Some(bytes) => zeroize::Zeroizing::new(bytes),
+        None => return std::ptr::null_mut(),
+    };
+    let Some(private_key) = require_curve25519_private_key(&mut env, &private_key) else {
+        return std::ptr::null_mut();
     };
 
-    match utilities::sign(&private_key, &message) {
-        Ok(signature) => {
-            env.byte_array_from_slice(&signature).unwrap()
+    let public_key = utilities::curve25519_public_from_private(&private_key);
+    env.byte_array_from_slice(&public_key).unwrap().into_raw()
+}
+
+/// X25519 key agreement with a peer's Curve25519 public key
+///
+/// Throws `VodozemacException` if either key isn't 32 bytes.
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_diffieHellman(
+    mut env: JNIEnv,
+    _class: JClass,
+    private_key: jbyteArray,
+    public_key: jbyteArray,
+) -> jbyteArray {
+    errors::clear_last_error();
+
+    let private_key = match get_rust_bytes(&mut env, private_key) {
+        Some(bytes) => zeroize::Zeroizing::new(bytes),
+        None => return std::ptr::null_mut(),
+    };
+    let public_key = match get_rust_bytes(&mut env, public_key) {
+        Some(bytes) => bytes,
+        None => return std::ptr::null_mut(),
+    };
+
+    let Some(private_key) = require_curve25519_private_key(&mut env, &private_key) else {
+        return std::ptr::null_mut();
+    };
+    let Some(public_key) = require_curve25519_key(&mut env, &public_key) else {
+        return std::ptr::null_mut();
+    };
+
+    match utilities::diffie_hellman(&private_key, &public_key) {
+        Ok(shared) => {
+            let shared = zeroize::Zeroizing::new(shared);
+            env.byte_array_from_slice(shared.as_slice()).unwrap().into_raw()
         }
         Err(e) => {
-            log::error!("Failed to sign: {}", e);
+            log::error!("Key agreement failed: {}", e);
             std::ptr::null_mut()
         }
     }
 }
 
-/// Verify an Ed25519 signature
+/// Derive a key from a passphrase with Argon2id
+#[cfg(feature = "argon2")]
 #[no_mangle]
-pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_verify(
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_argon2id(
     mut env: JNIEnv,
     _class: JClass,
-    public_key: jbyteArray,
-    message: jbyteArray,
-    signature: jbyteArray,
-) -> jboolean {
-    let public_key = match env.convert_byte_array(public_key) {
-        Ok(bytes) => bytes,
-        Err(_) => return false as jboolean,
-    };
+    passphrase: jbyteArray,
+    salt: jbyteArray,
+    memory_kib: jint,
+    iterations: jint,
+    parallelism: jint,
+    length: jint,
+) -> jbyteArray {
+    errors::clear_last_error();
 
-    let message = match env.convert_byte_array(message) {
-        Ok(bytes) => bytes,
-        Err(_) => return false as jboolean,
+    let passphrase = match get_rust_bytes(&mut env, passphrase) {
+        Some(bytes) => zeroize::Zeroizing::new(bytes),
+        None => return std::ptr::null_mut(),
+    };
+    let salt = match get_rust_bytes(&mut env, salt) {
+        Some(bytes) => bytes,
+        None => return std::ptr::null_mut(),
     };
 
-    let signature = match env.convert_byte_array(signature) {
-        Ok(bytes) => bytes,
-        Err(_) => return false as jboolean,
+    // Negative values become 0 and fail the minimums
+    let params = utilities::Argon2Params {
+        memory_kib: memory_kib.max(0) as u32,
+        iterations: iterations.max(0) as u32,
+        parallelism: parallelism.max(0) as u32,
     };
 
-    match utilities::verify(&public_key, &message, &signature) {
-        Ok(valid) => valid as jboolean,
-        Err(_) => false as jboolean,
+    match utilities::argon2id(&passphrase, &salt, params, length.max(0) as usize) {
+        Ok(key) => {
+            let key = zeroize::Zeroizing::new(key);
+            env.byte_array_from_slice(key.as_slice()).unwrap().into_raw()
+        }
+        Err(e) => {
+            report_error("Failed to derive Argon2id key", &e);
+            std::ptr::null_mut()
+        }
     }
 }
 
-// ============================================================================
-// Olm Session Management
-// ============================================================================
-
-/// Create an Olm account (generates identity and one-time keys)
+/// Convert an Ed25519 public key to Curve25519
 #[no_mangle]
-pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_createOlmAccount(
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_ed25519ToCurve25519(
     mut env: JNIEnv,
     _class: JClass,
-) -> jlong {
-    match OlmSession::create_account() {
-        Ok(account) => {
-            Box::into_raw(Box::new(account)) as jlong
-        }
+    public_key: jbyteArray,
+) -> jbyteArray {
+    errors::clear_last_error();
+
+    let public_key = match get_rust_bytes(&mut env, public_key) {
+        Some(bytes) => bytes,
+        None => return std::ptr::null_mut(),
+    };
+
+    match utilities::ed25519_to_curve25519(&public_key) {
+        Ok(key) => env.byte_array_from_slice(&key).unwrap().into_raw(),
         Err(e) => {
-            log::error!("Failed to create Olm account: {}", e);
-            0
+            report_error("Failed to convert Ed25519 key", &e);
+            std::ptr::null_mut()
         }
     }
 }
 
-/// Get identity keys from account
+/// Convert an Ed25519 private key (seed) to a Curve25519 private key
 #[no_mangle]
-pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getIdentityKeys(
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_ed25519PrivateToCurve25519(
     mut env: JNIEnv,
     _class: JClass,
-    account_ptr: jlong,
-) -> jstring {
-    let account = unsafe { &mut *(account_ptr as *mut OlmSession) };
+    private_key: jbyteArray,
+) -> jbyteArray {
+    errors::clear_last_error();
 
-    match account.get_identity_keys() {
-        Ok(keys) => {
-            match serde_json::to_string(&keys) {
-                Ok(json) => env.new_string(&json).unwrap().into_raw(),
-                Err(e) => {
-                    log::error!("Failed to serialize identity keys: {}", e);
-                    std::ptr::null_mut()
-                }
-            }
-        }
+    let private_key = match get_rust_bytes(&mut env, private_key) {
+        Some(bytes) => bytes,
+        None => return std::ptr::null_mut(),
+    };
+
+    match utilities::ed25519_private_to_curve25519(&private_key) {
+        Ok(key) => env.byte_array_from_slice(&key).unwrap().into_raw(),
         Err(e) => {
-            log::error!("Failed to get identity keys: {}", e);
+            report_error("Failed to convert Ed25519 key", &e);
             std::ptr::null_mut()
         }
     }
 }
 
-/// Generate one-time keys
+/// Sign a message with Ed25519
 #[no_mangle]
-pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_generateOneTimeKeys(
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_sign(
     mut env: JNIEnv,
     _class: JClass,
-    account_ptr: jlong,
-    count: jint,
-) -> jstring {
-    let account = unsafe { &mut *(account_ptr as *mut OlmSession) };
+    private_key: jbyteArray,
+    message: jbyteArray,
+) -> jbyteArray {
+    errors::clear_last_error();
 
-    match account.generate_one_time_keys(count as usize) {
-        Ok(keys) => {
-            match serde_json::to_string(&keys) {
-                Ok(json) => env.new_string(&json).unwrap().into_raw(),
-                Err(e) => {
-                    log::error!("Failed to serialize one-time keys: {}", e);
-                    std::ptr::null_mut()
-                }
-            }
-        }
+    let private_key = match get_rust_bytes(&mut env, private_key) {
+        Some(bytes) => zeroize::Zeroizing::new(bytes),
+        None => return std::ptr::null_mut(),
+    };
+    let message = match get_rust_bytes(&mut env, message) {
+        Some(bytes) => bytes,
+        None => return std::ptr::null_mut(),
+    };
+
+    match utilities::sign(&private_key, &message) {
+        Ok(signature) => env.byte_array_from_slice(&signature).unwrap().into_raw(),
         Err(e) => {
-            log::error!("Failed to generate one-time keys: {}", e);
+            report_error("Failed to sign", &e);
             std::ptr::null_mut()
         }
     }
 }
 
-/// Create outbound session
+/// Verify an Ed25519 signature
+///
+/// With `strict`, malformed input (a wrong-length key or signature) throws
+/// `VodozemacException` instead of returning `false`, so it can't pass for
+/// a signature that simply doesn't verify.
 #[no_mangle]
-pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_createOutboundSession(
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_verify(
     mut env: JNIEnv,
     _class: JClass,
-    account_ptr: jlong,
-    their_identity_key: jbyteArray,
-    their_one_time_key: jbyteArray,
-) -> jlong {
-    let account = unsafe { &mut *(account_ptr as *mut OlmSession) };
+    public_key: jbyteArray,
+    message: jbyteArray,
+    signature: jbyteArray,
+    strict: jboolean,
+) -> jboolean {
+    errors::clear_last_error();
 
-    let identity_key = match env.convert_byte_array(their_identity_key) {
-        Ok(bytes) => bytes,
-        Err(_) => return 0,
+    let public_key = match get_rust_bytes(&mut env, public_key) {
+        Some(bytes) => bytes,
+        None => return false as jboolean,
     };
 
-    let one_time_key = match env.convert_byte_array(their_one_time_key) {
-        Ok(bytes) => bytes,
-        Err(_) => return 0,
+    let message = match get_rust_bytes(&mut env, message) {
+        Some(bytes) => bytes,
+        None => return false as jboolean,
     };
 
-    match account.create_outbound_session(&identity_key, &one_time_key) {
-        Ok(session_id) => session_id as jlong,
+    let signature = match get_rust_bytes(&mut env, signature) {
+        Some(bytes) => bytes,
+        None => return false as jboolean,
+    };
+
+    match utilities::verify(&public_key, &message, &signature) {
+        Ok(valid) => valid as jboolean,
         Err(e) => {
-            log::error!("Failed to create outbound session: {}", e);
-            0
+            if strict != 0 {
+                throw_vodozemac_exception(&mut env, &format!("Signature verification: {}", e));
+            }
+            false as jboolean
         }
     }
 }
 
-/// Encrypt message with Olm
+/// Compare two base64 public keys in constant time
+///
+/// Padded and unpadded encodings of the same key compare equal.
 #[no_mangle]
-pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_encryptOlm(
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_publicKeysEqual(
     mut env: JNIEnv,
     _class: JClass,
-    session_ptr: jlong,
-    plaintext: jbyteArray,
-) -> jbyteArray {
-    let session = unsafe { &mut *(session_ptr as *mut OlmSession) };
+    key_a: jstring,
+    key_b: jstring,
+) -> jboolean {
+    errors::clear_last_error();
 
-    let plaintext = match env.convert_byte_array(plaintext) {
-        Ok(bytes) => bytes,
-        Err(_) => return std::ptr::null_mut(),
+    let key_a = match get_rust_string(&mut env, key_a) {
+        Some(s) => s,
+        None => return false as jboolean,
+    };
+    let key_b = match get_rust_string(&mut env, key_b) {
+        Some(s) => s,
+        None => return false as jboolean,
     };
 
-    match session.encrypt(&plaintext) {
-        Ok(ciphertext) => {
-            env.byte_array_from_slice(&ciphertext).unwrap()
-        }
+    match utilities::public_keys_equal(&key_a, &key_b) {
+        Ok(equal) => equal as jboolean,
         Err(e) => {
-            log::error!("Failed to encrypt: {}", e);
-            std::ptr::null_mut()
+            report_error("Failed to compare public keys", &e);
+            false as jboolean
         }
     }
 }
 
-/// Decrypt message with Olm
+/// Format an Ed25519 key as a grouped fingerprint for display
 #[no_mangle]
-pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_decryptOlm(
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_keyFingerprint(
     mut env: JNIEnv,
     _class: JClass,
-    session_ptr: jlong,
-    ciphertext: jbyteArray,
-    message_type: jint,
-) -> jbyteArray {
-    let session = unsafe { &mut *(session_ptr as *mut OlmSession) };
+    ed25519_key: jstring,
+    group_size: jint,
+) -> jstring {
+    errors::clear_last_error();
 
-    let ciphertext = match env.convert_byte_array(ciphertext) {
-        Ok(bytes) => bytes,
-        Err(_) => return std::ptr::null_mut(),
+    let ed25519_key = match get_rust_string(&mut env, ed25519_key) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
     };
 
-    match session.decrypt(&ciphertext, message_type as usize) {
-        Ok(plaintext) => {
-            env.byte_array_from_slice(&plaintext).unwrap()
-        }
+    match utilities::key_fingerprint(&ed25519_key, group_size.max(0) as usize) {
+        Ok(fingerprint) => env.new_string(&fingerprint).unwrap().into_raw(),
         Err(e) => {
-            log::error!("Failed to decrypt: {}", e);
+            report_error("Failed to compute key fingerprint", &e);
             std::ptr::null_mut()
         }
     }
 }
 
-// ============================================================================
-// Megolm Group Sessions
-// ============================================================================
+/// Compare two safety numbers in constant time, ignoring whitespace
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_safetyNumbersMatch(
+    mut env: JNIEnv,
+    _class: JClass,
+    number_a: jstring,
+    number_b: jstring,
+) -> jboolean {
+    errors::clear_last_error();
 
-/// Create outbound Megolm session
+    let number_a = match get_rust_string(&mut env, number_a) {
+        Some(s) => s,
+        None => return false as jboolean,
+    };
+    let number_b = match get_rust_string(&mut env, number_b) {
+        Some(s) => s,
+        None => return false as jboolean,
+    };
+
+    utilities::safety_numbers_match(&number_a, &number_b) as jboolean
+}
+
+/// Generate a random string of `length` characters drawn from `alphabet`
 #[no_mangle]
-pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_createOutboundMegolmSession(
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_randomString(
     mut env: JNIEnv,
     _class: JClass,
-) -> jlong {
-    match MegolmSession::create_outbound() {
-        Ok(session) => {
-            Box::into_raw(Box::new(session)) as jlong
-        }
-        Err(e) => {
-            log::error!("Failed to create Megolm session: {}", e);
-            0
-        }
+    length: jint,
+    alphabet: jstring,
+) -> jstring {
+    errors::clear_last_error();
+
+    let alphabet = match get_rust_string(&mut env, alphabet) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+    if length < 0 || alphabet.is_empty() {
+        log::error!("Invalid random string length {} or empty alphabet", length);
+        return std::ptr::null_mut();
     }
+
+    let random = utilities::random_string(length as usize, &alphabet);
+    env.new_string(&random).unwrap().into_raw()
 }
 
-/// Get Megolm session key for sharing
+/// Compute an Olm session id from the handshake keys
 #[no_mangle]
-pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getMegolmSessionKey(
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_computeOlmSessionId(
     mut env: JNIEnv,
     _class: JClass,
-    session_ptr: jlong,
+    alice_identity: jstring,
+    alice_base_key: jstring,
+    bob_one_time_key: jstring,
 ) -> jstring {
-    let session = unsafe { &*(session_ptr as *const MegolmSession) };
+    errors::clear_last_error();
 
-    match session.get_session_key() {
-        Ok(key) => env.new_string(&key).unwrap().into_raw(),
+    let alice_identity = match get_rust_string(&mut env, alice_identity) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+    let alice_base_key = match get_rust_string(&mut env, alice_base_key) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+    let bob_one_time_key = match get_rust_string(&mut env, bob_one_time_key) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+
+    match utilities::compute_olm_session_id(&alice_identity, &alice_base_key, &bob_one_time_key) {
+        Ok(session_id) => env.new_string(&session_id).unwrap().into_raw(),
         Err(e) => {
-            log::error!("Failed to get session key: {}", e);
+            report_error("Failed to compute Olm session id", &e);
             std::ptr::null_mut()
         }
     }
 }
 
-/// Encrypt message with Megolm
+/// Compute the content hash of a Matrix event
 #[no_mangle]
-pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_encryptMegolm(
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_contentHash(
     mut env: JNIEnv,
     _class: JClass,
-    session_ptr: jlong,
-    plaintext: jbyteArray,
+    event_json: jstring,
 ) -> jstring {
-    let session = unsafe { &mut *(session_ptr as *mut MegolmSession) };
+    errors::clear_last_error();
 
-    let plaintext = match env.convert_byte_array(plaintext) {
-        Ok(bytes) => bytes,
-        Err(_) => return std::ptr::null_mut(),
+    let event_json = match get_rust_string(&mut env, event_json) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
     };
 
-    match session.encrypt(&plaintext) {
-        Ok(encrypted) => {
-            match serde_json::to_string(&encrypted) {
-                Ok(json) => env.new_string(&json).unwrap().into_raw(),
-                Err(e) => {
-                    log::error!("Failed to serialize encrypted message: {}", e);
-                    std::ptr::null_mut()
-                }
-            }
-        }
+    match utilities::content_hash(&event_json) {
+        Ok(hash) => env.new_string(&hash).unwrap().into_raw(),
         Err(e) => {
-            log::error!("Failed to encrypt with Megolm: {}", e);
+            report_error("Failed to compute content hash", &e);
             std::ptr::null_mut()
         }
     }
 }
 
-/// Create inbound Megolm session
+/// Report which algorithm an encrypted event declares
+///
+/// Returns `olm_v1`, `megolm_v1` or `unknown`, or null for malformed JSON.
 #[no_mangle]
-pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_createInboundMegolmSession(
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_detectEventAlgorithm(
     mut env: JNIEnv,
     _class: JClass,
-    session_key: jstring,
-) -> jlong {
-    let session_key: JString = unsafe { JObject::from_raw(session_key).into() };
-    let session_key = match env.get_string(&session_key) {
-        Ok(s) => s.to_str().unwrap().to_string(),
-        Err(_) => return 0,
+    event_json: jstring,
+) -> jstring {
+    errors::clear_last_error();
+
+    let event_json = match get_rust_string(&mut env, event_json) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
     };
 
-    match MegolmSession::create_inbound(&session_key) {
-        Ok(session) => {
-            Box::into_raw(Box::new(session)) as jlong
-        }
+    match utilities::detect_algorithm(&event_json) {
+        Ok(algorithm) => env.new_string(algorithm.as_str()).unwrap().into_raw(),
         Err(e) => {
-            log::error!("Failed to create inbound Megolm session: {}", e);
-            0
+            report_error("Failed to read event algorithm", &e);
+            std::ptr::null_mut()
         }
     }
 }
 
-/// Decrypt message with Megolm
+/// Read the Megolm session id of an encrypted event
 #[no_mangle]
-pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_decryptMegolm(
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_extractSessionId(
     mut env: JNIEnv,
     _class: JClass,
-    session_ptr: jlong,
-    ciphertext: jstring,
-) -> jbyteArray {
-    let session = unsafe { &mut *(session_ptr as *mut MegolmSession) };
+    event_json: jstring,
+) -> jstring {
+    errors::clear_last_error();
 
-    let ciphertext: JString = unsafe { JObject::from_raw(ciphertext).into() };
-    let ciphertext = match env.get_string(&ciphertext) {
-        Ok(s) => s.to_str().unwrap().to_string(),
-        Err(_) => return std::ptr::null_mut(),
+    let event_json = match get_rust_string(&mut env, event_json) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
     };
 
-    match session.decrypt(&ciphertext) {
-        Ok(plaintext) => {
-            env.byte_array_from_slice(&plaintext).unwrap()
+    match utilities::extract_session_id(&event_json) {
+        Ok(session_id) => env.new_string(&session_id).unwrap().into_raw(),
+        Err(e) => {
+            report_error("Failed to read event session id", &e);
+            std::ptr::null_mut()
         }
+    }
+}
+
+/// List the algorithms and features compiled into this library, as JSON
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getCapabilities(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    errors::clear_last_error();
+
+    match serde_json::to_string(&utilities::capabilities()) {
+        Ok(json) => env.new_string(&json).unwrap().into_raw(),
         Err(e) => {
-            log::error!("Failed to decrypt with Megolm: {}", e);
+            log::error!("Failed to serialize capabilities: {}", e);
             std::ptr::null_mut()
         }
     }
 }
 
-/// Free Olm account
+/// List the Cargo features this library was built with, as JSON
 #[no_mangle]
-pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_freeOlmAccount(
-    _env: JNIEnv,
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getEnabledFeatures(
+    env: JNIEnv,
     _class: JClass,
-    account_ptr: jlong,
-) {
-    if account_ptr != 0 {
-        unsafe {
-            let _ = Box::from_raw(account_ptr as *mut OlmSession);
+) -> jstring {
+    errors::clear_last_error();
+
+    match serde_json::to_string(&utilities::enabled_features()) {
+        Ok(json) => env.new_string(&json).unwrap().into_raw(),
+        Err(e) => {
+            log::error!("Failed to serialize enabled features: {}", e);
+            std::ptr::null_mut()
         }
     }
 }
 
-/// Free Megolm session
+/// Count the native handles still allocated, as JSON
+///
+/// For leak checks in tests: returns `accounts`, `olm_sessions` and
+/// `megolm_sessions`.
 #[no_mangle]
-pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_freeMegolmSession(
-    _env: JNIEnv,
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getAllocatedHandleCount(
+    env: JNIEnv,
     _class: JClass,
-    session_ptr: jlong,
-) {
-    if session_ptr != 0 {
-        unsafe {
-            let _ = Box::from_raw(session_ptr as *mut MegolmSession);
+) -> jstring {
+    errors::clear_last_error();
+
+    match serde_json::to_string(&registry::handle_counts()) {
+        Ok(json) => env.new_string(&json).unwrap().into_raw(),
+        Err(e) => {
+            log::error!("Failed to serialize handle counts: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Get Olm and Megolm operation timings as JSON, for profiling
+///
+/// Returns count, mean and p50/p90/p99 in microseconds for each operation.
+#[cfg(feature = "metrics")]
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getCryptoMetrics(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    errors::clear_last_error();
+
+    match serde_json::to_string(&metrics::snapshot()) {
+        Ok(json) => env.new_string(&json).unwrap().into_raw(),
+        Err(e) => {
+            log::error!("Failed to serialize crypto metrics: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Get the lowest PBKDF2 iteration count passphrase derivation accepts
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getMinKdfIterations(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jint {
+    errors::clear_last_error();
+
+    utilities::min_kdf_iterations().min(jint::MAX as u32) as jint
+}
+
+/// Change the PBKDF2 iteration floor; values below 1 are raised to 1
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_setMinKdfIterations(
+    _env: JNIEnv,
+    _class: JClass,
+    minimum: jint,
+) {
+    errors::clear_last_error();
+
+    utilities::set_min_kdf_iterations(u32::try_from(minimum).unwrap_or(1));
+}
+
+/// Get the largest plaintext Olm and Megolm encryption accept, in bytes
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getMaxPlaintextSize(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jint {
+    errors::clear_last_error();
+
+    utilities::max_plaintext_size().min(jint::MAX as u32) as jint
+}
+
+/// Change the plaintext size limit; values below 1 are raised to 1
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_setMaxPlaintextSize(
+    _env: JNIEnv,
+    _class: JClass,
+    limit: jint,
+) {
+    errors::clear_last_error();
+
+    utilities::set_max_plaintext_size(u32::try_from(limit).unwrap_or(1));
+}
+
+// ============================================================================
+// Olm Session Management
+// ============================================================================
+
+/// Create an Olm account (generates identity and one-time keys)
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_createOlmAccount(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jlong {
+    errors::clear_last_error();
+
+    match OlmSession::create_account() {
+        Ok(account) => {
+            registry::ACCOUNTS.insert(account)
+        }
+        Err(e) => {
+            report_error("Failed to create Olm account", &e);
+            0
+        }
+    }
+}
+
+/// Get identity keys from account
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getIdentityKeys(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+) -> jstring {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let account = registry::lock(&account);
+
+    match account.get_identity_keys() {
+        Ok(keys) => {
+            match serde_json::to_string(&keys) {
+                Ok(json) => env.new_string(&json).unwrap().into_raw(),
+                Err(e) => {
+                    log::error!("Failed to serialize identity keys: {}", e);
+                    std::ptr::null_mut()
+                }
+            }
+        }
+        Err(e) => {
+            report_error("Failed to get identity keys", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Get the account's self-signed public identity, without private keys
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getPublicIdentity(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+) -> jstring {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let account = registry::lock(&account);
+
+    match account.public_identity_json() {
+        Ok(json) => env.new_string(&json).unwrap().into_raw(),
+        Err(e) => {
+            report_error("Failed to export public identity", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Generate one-time keys
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_generateOneTimeKeys(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+    count: jint,
+) -> jstring {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut account = registry::lock(&account);
+
+    match account.generate_one_time_keys(count as usize) {
+        Ok(keys) => {
+            match serde_json::to_string(&keys) {
+                Ok(json) => env.new_string(&json).unwrap().into_raw(),
+                Err(e) => {
+                    log::error!("Failed to serialize one-time keys: {}", e);
+                    std::ptr::null_mut()
+                }
+            }
+        }
+        Err(e) => {
+            report_error("Failed to generate one-time keys", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// List the one-time keys about to be uploaded, without generating any
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getUnpublishedOneTimeKeys(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+) -> jstring {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let account = registry::lock(&account);
+
+    match account.unpublished_one_time_keys() {
+        Ok(keys) => {
+            match serde_json::to_string(&keys) {
+                Ok(json) => env.new_string(&json).unwrap().into_raw(),
+                Err(e) => {
+                    log::error!("Failed to serialize one-time keys: {}", e);
+                    std::ptr::null_mut()
+                }
+            }
+        }
+        Err(e) => {
+            report_error("Failed to list one-time keys", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Get how many one-time keys to generate given the server's current count
+///
+/// Returns -1 on error.
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_neededOneTimeKeys(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+    server_count: jint,
+) -> jint {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return -1;
+        }
+    };
+    let account = registry::lock(&account);
+
+    match account.needs_one_time_keys(server_count.max(0) as usize) {
+        Ok(needed) => needed as jint,
+        Err(e) => {
+            report_error("Failed to count needed one-time keys", &e);
+            -1
+        }
+    }
+}
+
+/// Get the unpublished one-time keys, signed, for a `/keys/upload` request
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getSignedOneTimeKeys(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+    user_id: jstring,
+    device_id: jstring,
+) -> jstring {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let account = registry::lock(&account);
+
+    let user_id = match get_rust_string(&mut env, user_id) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+    let device_id = match get_rust_string(&mut env, device_id) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+
+    match account.signed_one_time_keys_json(&user_id, &device_id) {
+        Ok(json) => env.new_string(&json).unwrap().into_raw(),
+        Err(e) => {
+            report_error("Failed to sign one-time keys", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Generate one-time keys and return them signed for `/keys/upload`
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_generateAndSignOneTimeKeys(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+    count: jint,
+    user_id: jstring,
+    device_id: jstring,
+) -> jstring {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut account = registry::lock(&account);
+
+    let user_id = match get_rust_string(&mut env, user_id) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+    let device_id = match get_rust_string(&mut env, device_id) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+
+    match account.generate_and_sign_one_time_keys(count.max(0) as usize, &user_id, &device_id) {
+        Ok(json) => env.new_string(&json).unwrap().into_raw(),
+        Err(e) => {
+            report_error("Failed to generate signed one-time keys", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Sign the unpublished one-time keys again with the current Ed25519 key
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_resignOneTimeKeys(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+    user_id: jstring,
+    device_id: jstring,
+) -> jstring {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let account = registry::lock(&account);
+
+    let user_id = match get_rust_string(&mut env, user_id) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+    let device_id = match get_rust_string(&mut env, device_id) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+
+    match account.resign_one_time_keys(&user_id, &device_id) {
+        Ok(json) => env.new_string(&json).unwrap().into_raw(),
+        Err(e) => {
+            report_error("Failed to re-sign one-time keys", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Generate a new fallback key
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_generateFallbackKey(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+) -> jstring {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut account = registry::lock(&account);
+
+    match account.generate_fallback_key() {
+        Ok(key) => {
+            match serde_json::to_string(&key) {
+                Ok(json) => env.new_string(&json).unwrap().into_raw(),
+                Err(e) => {
+                    log::error!("Failed to serialize fallback key: {}", e);
+                    std::ptr::null_mut()
+                }
+            }
+        }
+        Err(e) => {
+            report_error("Failed to generate fallback key", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Mark one-time keys and the fallback key as uploaded
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_markKeysAsPublished(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+) -> jboolean {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return false as jboolean;
+        }
+    };
+    let mut account = registry::lock(&account);
+
+    match account.mark_keys_as_published() {
+        Ok(()) => true as jboolean,
+        Err(e) => {
+            report_error("Failed to mark keys as published", &e);
+            false as jboolean
+        }
+    }
+}
+
+/// Check whether the current fallback key has been uploaded
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_isFallbackKeyPublished(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+) -> jboolean {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return false as jboolean;
+        }
+    };
+    let account = registry::lock(&account);
+
+    account.fallback_key_published() as jboolean
+}
+
+/// Get the fallback key generated last
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getActiveFallbackKey(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+) -> jstring {
+    errors::clear_last_error();
+
+    fallback_key_json(&mut env, account_ptr, olm::OlmSession::active_fallback_key)
+}
+
+/// Get the fallback key the active one replaced
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getPreviousFallbackKey(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+) -> jstring {
+    errors::clear_last_error();
+
+    fallback_key_json(&mut env, account_ptr, olm::OlmSession::previous_fallback_key)
+}
+
+/// Serialize one of an account's fallback keys, or return null if it has none
+fn fallback_key_json(
+    env: &mut JNIEnv,
+    account_ptr: jlong,
+    select: fn(&olm::OlmSession) -> Option<&olm::OneTimeKey>,
+) -> jstring {
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let account = registry::lock(&account);
+
+    let Some(key) = select(&account) else {
+        return std::ptr::null_mut();
+    };
+    match serde_json::to_string(key) {
+        Ok(json) => env.new_string(&json).unwrap().into_raw(),
+        Err(e) => {
+            log::error!("Failed to serialize fallback key: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Create outbound session
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_createOutboundSession(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+    their_identity_key: jbyteArray,
+    their_one_time_key: jbyteArray,
+) -> jlong {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return 0;
+        }
+    };
+    let mut account = registry::lock(&account);
+
+    let identity_key = match get_rust_bytes(&mut env, their_identity_key) {
+        Some(bytes) => bytes,
+        None => return 0,
+    };
+
+    let one_time_key = match get_rust_bytes(&mut env, their_one_time_key) {
+        Some(bytes) => bytes,
+        None => return 0,
+    };
+
+    match account.create_outbound_session(&identity_key, &one_time_key) {
+        Ok(session_id) => session_id as jlong,
+        Err(e) => {
+            report_error("Failed to create outbound session", &e);
+            0
+        }
+    }
+}
+
+/// Create outbound session, returning JSON `{ handle, session_id }`
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_createOutboundSessionDetailed(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+    their_identity_key: jbyteArray,
+    their_one_time_key: jbyteArray,
+) -> jstring {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut account = registry::lock(&account);
+
+    let identity_key = match get_rust_bytes(&mut env, their_identity_key) {
+        Some(bytes) => bytes,
+        None => return std::ptr::null_mut(),
+    };
+    let one_time_key = match get_rust_bytes(&mut env, their_one_time_key) {
+        Some(bytes) => bytes,
+        None => return std::ptr::null_mut(),
+    };
+
+    match account.create_outbound_session_detailed(&identity_key, &one_time_key) {
+        Ok(session) => {
+            match serde_json::to_string(&session) {
+                Ok(json) => env.new_string(&json).unwrap().into_raw(),
+                Err(e) => {
+                    log::error!("Failed to serialize session: {}", e);
+                    std::ptr::null_mut()
+                }
+            }
+        }
+        Err(e) => {
+            report_error("Failed to create outbound session", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Create outbound session on the one-time key claimed under a key id,
+/// returning JSON `{ handle, session_id }`
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_createOutboundSessionWithKeyId(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+    their_identity_key: jstring,
+    key_id: jstring,
+    claim_json: jstring,
+) -> jstring {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut account = registry::lock(&account);
+
+    let identity_key = match get_rust_string(&mut env, their_identity_key) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+    let key_id = match get_rust_string(&mut env, key_id) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+    let claim_json = match get_rust_string(&mut env, claim_json) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+
+    match account.create_outbound_session_with_key_id(&identity_key, &key_id, &claim_json) {
+        Ok(session) => {
+            match serde_json::to_string(&session) {
+                Ok(json) => env.new_string(&json).unwrap().into_raw(),
+                Err(e) => {
+                    log::error!("Failed to serialize session: {}", e);
+                    std::ptr::null_mut()
+                }
+            }
+        }
+        Err(e) => {
+            report_error("Failed to create outbound session", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Get an Olm session's chain indices as JSON, for debugging
+///
+/// Returns `{ sending_chain_index, receiving_chain_index }`; no key
+/// material is exposed.
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getOlmRatchetState(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+    session_id: jstring,
+) -> jstring {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let account = registry::lock(&account);
+
+    let session_id = match get_rust_string(&mut env, session_id) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+
+    let state = match account.ratchet_state(&session_id) {
+        Ok(state) => state,
+        Err(e) => {
+            report_error("Failed to get ratchet state", &e);
+            return std::ptr::null_mut();
+        }
+    };
+
+    match serde_json::to_string(&state) {
+        Ok(json) => env.new_string(&json).unwrap().into_raw(),
+        Err(e) => {
+            log::error!("Failed to serialize ratchet state: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Drop all of an account's sessions, keeping the account and its keys
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_clearOlmSessions(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+) -> jboolean {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return false as jboolean;
+        }
+    };
+
+    registry::lock(&account).clear_sessions();
+    true as jboolean
+}
+
+/// Remove an account's unpublished one-time keys
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_clearOneTimeKeys(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+) -> jboolean {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return false as jboolean;
+        }
+    };
+
+    let cleared = registry::lock(&account).clear_one_time_keys();
+    match cleared {
+        Ok(()) => true as jboolean,
+        Err(e) => {
+            report_error("Failed to clear one-time keys", &e);
+            false as jboolean
+        }
+    }
+}
+
+/// Create two accounts with an established session between them
+///
+/// Returns `{"alice", "bob", "session_id"}` with both account handles.
+/// Only in builds with the `testing` feature.
+#[cfg(feature = "testing")]
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_createTestSessionPair(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    errors::clear_last_error();
+
+    match OlmSession::test_pair() {
+        Ok((alice, bob, session_id)) => {
+            let result = serde_json::json!({
+                "alice": registry::ACCOUNTS.insert(alice),
+                "bob": registry::ACCOUNTS.insert(bob),
+                "session_id": session_id,
+            });
+            env.new_string(result.to_string()).unwrap().into_raw()
+        }
+        Err(e) => {
+            report_error("Failed to create test session pair", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Check an account's state is consistent, e.g. right after unpickling
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_validateAccount(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+) -> jboolean {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return false as jboolean;
+        }
+    };
+
+    let validated = registry::lock(&account).validate();
+    match validated {
+        Ok(()) => true as jboolean,
+        Err(e) => {
+            report_error("Olm account failed validation", &e);
+            false as jboolean
+        }
+    }
+}
+
+/// Check for an established session with the device owning an identity key
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_hasSessionWith(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+    identity_key: jstring,
+) -> jboolean {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return false as jboolean;
+        }
+    };
+
+    let identity_key = match get_rust_string(&mut env, identity_key) {
+        Some(s) => s,
+        None => return false as jboolean,
+    };
+
+    let has_session = registry::lock(&account).has_session_with(&identity_key);
+    has_session as jboolean
+}
+
+/// Get the algorithm an Olm session was pinned to by its first event
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getOlmPinnedAlgorithm(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+    session_id: jstring,
+) -> jstring {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+
+    let session_id = match get_rust_string(&mut env, session_id) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+
+    let account = registry::lock(&account);
+    match account.pinned_algorithm(&session_id) {
+        Some(algorithm) => env.new_string(algorithm).unwrap().into_raw(),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Check whether decrypting a pre-key message would use up a one-time key
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_prekeyConsumesOneTimeKey(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+    prekey_message: jstring,
+) -> jboolean {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return false as jboolean;
+        }
+    };
+
+    let prekey_message = match get_rust_string(&mut env, prekey_message) {
+        Some(s) => s,
+        None => return false as jboolean,
+    };
+
+    let consumes = registry::lock(&account).prekey_consumes_otk(&prekey_message);
+    consumes as jboolean
+}
+
+/// Encrypt a Megolm session key to a device as an `m.room_key` payload
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_wrapMegolmSessionKey(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+    recipient_session_id: jstring,
+    room_id: jstring,
+    session_key: jstring,
+) -> jstring {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut account = registry::lock(&account);
+
+    let recipient_session_id = match get_rust_string(&mut env, recipient_session_id) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+    let room_id = match get_rust_string(&mut env, room_id) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+    let session_key = match get_rust_string(&mut env, session_key) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+
+    match account.wrap_session_key(&recipient_session_id, &room_id, &session_key) {
+        Ok(message) => {
+            match serde_json::to_string(&message) {
+                Ok(json) => env.new_string(&json).unwrap().into_raw(),
+                Err(e) => {
+                    log::error!("Failed to serialize wrapped session key: {}", e);
+                    std::ptr::null_mut()
+                }
+            }
+        }
+        Err(e) => {
+            report_error("Failed to wrap Megolm session key", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Encrypt an `m.dummy` event to advance an Olm session's ratchet
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_encryptDummy(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+    session_id: jstring,
+) -> jstring {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut account = registry::lock(&account);
+
+    let session_id = match get_rust_string(&mut env, session_id) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+
+    match account.encrypt_dummy(&session_id) {
+        Ok(message) => {
+            match serde_json::to_string(&message) {
+                Ok(json) => env.new_string(&json).unwrap().into_raw(),
+                Err(e) => {
+                    log::error!("Failed to serialize dummy event: {}", e);
+                    std::ptr::null_mut()
+                }
+            }
+        }
+        Err(e) => {
+            report_error("Failed to encrypt dummy event", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Decrypt an incoming `m.room_key` to-device payload
+///
+/// Returns JSON with the room id, session id and Megolm session key.
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_unwrapMegolmSessionKey(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+    sender_identity_key: jstring,
+    ciphertext: jbyteArray,
+    message_type: jint,
+) -> jstring {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut account = registry::lock(&account);
+
+    let sender_identity_key = match get_rust_string(&mut env, sender_identity_key) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+    let ciphertext = match get_rust_bytes(&mut env, ciphertext) {
+        Some(bytes) => bytes,
+        None => return std::ptr::null_mut(),
+    };
+
+    match account.unwrap_session_key(&sender_identity_key, &ciphertext, message_type as usize) {
+        Ok(room_key) => {
+            match serde_json::to_string(&room_key) {
+                Ok(json) => env.new_string(&json).unwrap().into_raw(),
+                Err(e) => {
+                    log::error!("Failed to serialize room key: {}", e);
+                    std::ptr::null_mut()
+                }
+            }
+        }
+        Err(e) => {
+            report_error("Failed to unwrap Megolm session key", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Decrypt an `m.forwarded_room_key` event and import its Megolm session
+///
+/// Returns JSON `{ handle, room_id, session_id, sender_key,
+/// forwarding_curve25519_key_chain }`, with `handle` a Megolm session handle.
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_importForwardedRoomKey(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+    event_json: jstring,
+    max_chain_depth: jint,
+) -> jstring {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut account = registry::lock(&account);
+
+    let event_json = match get_rust_string(&mut env, event_json) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+    let event: olm::EventInput = match serde_json::from_str(&event_json) {
+        Ok(event) => event,
+        Err(e) => {
+            log::error!("Invalid Olm event: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+
+    match account.import_forwarded_room_key(&event, max_chain_depth.max(0) as usize) {
+        Ok((key, session)) => {
+            let handle = registry::MEGOLM_SESSIONS.insert(session);
+            let json = serde_json::json!({
+                "handle": handle,
+                "room_id": key.room_id,
+                "session_id": key.session_id,
+                "sender_key": key.sender_key,
+                "forwarding_curve25519_key_chain": key.forwarding_curve25519_key_chain,
+            });
+            env.new_string(json.to_string()).unwrap().into_raw()
+        }
+        Err(e) => {
+            report_error("Failed to import forwarded room key", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Encrypt message with Olm, returning JSON `{ message_type, body }`
+///
+/// `body` is already base64, ready for the to-device `ciphertext` field.
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_encryptOlm(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+    plaintext: jbyteArray,
+) -> jstring {
+    errors::clear_last_error();
+
+    let session = match registry::ACCOUNTS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut session = registry::lock(&session);
+
+    let plaintext = match get_rust_bytes(&mut env, plaintext) {
+        Some(bytes) => bytes,
+        None => return std::ptr::null_mut(),
+    };
+
+    let encrypted = match session.encrypt(&plaintext) {
+        Ok(encrypted) => encrypted,
+        Err(e) => {
+            report_error("Failed to encrypt with Olm", &e);
+            return std::ptr::null_mut();
+        }
+    };
+
+    match serde_json::to_string(&encrypted) {
+        Ok(json) => env.new_string(&json).unwrap().into_raw(),
+        Err(e) => {
+            log::error!("Failed to serialize encrypted message: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Encrypt with Olm into transport JSON `{ type, body, session_id }`
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_encryptOlmTransport(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+    plaintext: jbyteArray,
+) -> jstring {
+    errors::clear_last_error();
+
+    let session = match registry::ACCOUNTS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut session = registry::lock(&session);
+
+    let plaintext = match get_rust_bytes(&mut env, plaintext) {
+        Some(bytes) => bytes,
+        None => return std::ptr::null_mut(),
+    };
+
+    let message = match session.encrypt_for_transport(&plaintext) {
+        Ok(message) => message,
+        Err(e) => {
+            report_error("Failed to encrypt with Olm", &e);
+            return std::ptr::null_mut();
+        }
+    };
+
+    match serde_json::to_string(&message) {
+        Ok(json) => env.new_string(&json).unwrap().into_raw(),
+        Err(e) => {
+            log::error!("Failed to serialize encrypted message: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Same as `encryptOlm`, kept for callers of the earlier API
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_encryptOlmBase64(
+    env: JNIEnv,
+    class: JClass,
+    session_ptr: jlong,
+    plaintext: jbyteArray,
+) -> jstring {
+    errors::clear_last_error();
+
+    Java_app_armorclaw_crypto_VodozemacNative_encryptOlm(env, class, session_ptr, plaintext)
+}
+
+/// Decrypt message with Olm
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_decryptOlm(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+    ciphertext: jbyteArray,
+    message_type: jint,
+) -> jbyteArray {
+    errors::clear_last_error();
+
+    let session = match registry::ACCOUNTS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut session = registry::lock(&session);
+
+    let ciphertext = match get_rust_bytes(&mut env, ciphertext) {
+        Some(bytes) => bytes,
+        None => return std::ptr::null_mut(),
+    };
+
+    match session.decrypt(&ciphertext, message_type as usize) {
+        Ok(plaintext) => {
+            env.byte_array_from_slice(&plaintext).unwrap().into_raw()
+        }
+        Err(e @ olm::OlmError::BadMac) => {
+            throw_bad_mac_exception(&mut env, &e.to_string());
+            std::ptr::null_mut()
+        }
+        Err(e) => {
+            report_error("Failed to decrypt", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Decrypt a text message with Olm
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_decryptOlmText(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+    ciphertext: jbyteArray,
+    message_type: jint,
+) -> jstring {
+    errors::clear_last_error();
+
+    let session = match registry::ACCOUNTS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut session = registry::lock(&session);
+
+    let ciphertext = match get_rust_bytes(&mut env, ciphertext) {
+        Some(bytes) => bytes,
+        None => return std::ptr::null_mut(),
+    };
+
+    match session.decrypt_text(&ciphertext, message_type as usize) {
+        Ok(plaintext) => env.new_string(&plaintext).unwrap().into_raw(),
+        Err(e @ olm::OlmError::BadMac) => {
+            throw_bad_mac_exception(&mut env, &e.to_string());
+            std::ptr::null_mut()
+        }
+        Err(e) => {
+            report_error("Failed to decrypt", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Decrypt an Olm message without a known message type
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_decryptOlmAuto(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+    their_identity_key: jstring,
+    ciphertext: jbyteArray,
+) -> jbyteArray {
+    errors::clear_last_error();
+
+    let session = match registry::ACCOUNTS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut session = registry::lock(&session);
+
+    let their_identity_key = match get_rust_string(&mut env, their_identity_key) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+    let ciphertext = match get_rust_bytes(&mut env, ciphertext) {
+        Some(bytes) => bytes,
+        None => return std::ptr::null_mut(),
+    };
+
+    match session.decrypt_auto(&their_identity_key, &ciphertext) {
+        Ok(plaintext) => env.byte_array_from_slice(&plaintext).unwrap().into_raw(),
+        Err(e @ olm::OlmError::BadMac) => {
+            throw_bad_mac_exception(&mut env, &e.to_string());
+            std::ptr::null_mut()
+        }
+        Err(olm::OlmError::InvalidKey(reason)) if reason == olm::REPLAYED_PREKEY => {
+            throw_replayed_prekey_exception(&mut env, &reason);
+            std::ptr::null_mut()
+        }
+        Err(e) => {
+            report_error("Failed to decrypt", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Decrypt a batch of Olm to-device messages, each on its own
+///
+/// `events_json` is a JSON array of `{sender_key, type, body}`, each with
+/// the `algorithm` that is held to the session's pinned one (see
+/// `OlmSession::decrypt_event`). Returns a JSON array in the same order,
+/// with `{"plaintext": base64}` for each message that decrypted and
+/// `{"error": reason}` for each that didn't.
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_decryptOlmBatch(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+    events_json: jstring,
+) -> jstring {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut account = registry::lock(&account);
+
+    let events_json = match get_rust_string(&mut env, events_json) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+    let events: Vec<olm::EventInput> = match serde_json::from_str(&events_json) {
+        Ok(events) => events,
+        Err(e) => {
+            log::error!("Invalid Olm event batch: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+
+    let results: Vec<serde_json::Value> = account
+        .decrypt_batch(&events)
+        .into_iter()
+        .map(|result| match result {
+            Ok(plaintext) => {
+                let plaintext = zeroize::Zeroizing::new(plaintext);
+                serde_json::json!({ "plaintext": utilities::base64_encode(&plaintext) })
+            }
+            Err(e) => serde_json::json!({ "error": e.to_string() }),
+        })
+        .collect();
+    env.new_string(serde_json::Value::from(results).to_string()).unwrap().into_raw()
+}
+
+/// Export an account and all of its sessions as one encrypted blob
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_exportCryptoStore(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+    pickle_key: jbyteArray,
+) -> jbyteArray {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let account = registry::lock(&account);
+
+    let pickle_key = match get_rust_bytes(&mut env, pickle_key) {
+        Some(bytes) => bytes,
+        None => return std::ptr::null_mut(),
+    };
+
+    match account.export_store(&pickle_key) {
+        Ok(store) => env.byte_array_from_slice(&store).unwrap().into_raw(),
+        Err(e) => {
+            report_error("Failed to export crypto store", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Restore an account and its sessions from an exported crypto store
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_importCryptoStore(
+    mut env: JNIEnv,
+    _class: JClass,
+    store: jbyteArray,
+    pickle_key: jbyteArray,
+) -> jlong {
+    errors::clear_last_error();
+
+    let store = match get_rust_bytes(&mut env, store) {
+        Some(bytes) => bytes,
+        None => return 0,
+    };
+    let pickle_key = match get_rust_bytes(&mut env, pickle_key) {
+        Some(bytes) => bytes,
+        None => return 0,
+    };
+
+    match OlmSession::import_store(&store, &pickle_key) {
+        Ok(account) => registry::ACCOUNTS.insert(account),
+        Err(e) => {
+            report_error("Failed to import crypto store", &e);
+            0
+        }
+    }
+}
+
+/// Re-encrypt an exported crypto store under a new pickle key
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_rekeyOlmAccount(
+    mut env: JNIEnv,
+    _class: JClass,
+    store: jbyteArray,
+    old_key: jbyteArray,
+    new_key: jbyteArray,
+) -> jbyteArray {
+    errors::clear_last_error();
+
+    let store = match get_rust_bytes(&mut env, store) {
+        Some(bytes) => bytes,
+        None => return std::ptr::null_mut(),
+    };
+    let old_key = match get_rust_bytes(&mut env, old_key) {
+        Some(bytes) => zeroize::Zeroizing::new(bytes),
+        None => return std::ptr::null_mut(),
+    };
+    let new_key = match get_rust_bytes(&mut env, new_key) {
+        Some(bytes) => zeroize::Zeroizing::new(bytes),
+        None => return std::ptr::null_mut(),
+    };
+
+    match OlmSession::rekey_pickle(&store, &old_key, &new_key) {
+        Ok(store) => env.byte_array_from_slice(&store).unwrap().into_raw(),
+        Err(e) => {
+            report_error("Failed to rekey crypto store", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Pickle an account on its own, without its sessions
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_pickleOlmAccount(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+) -> jbyteArray {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let account = registry::lock(&account);
+
+    match account.pickle() {
+        Ok(pickle) => env.byte_array_from_slice(&pickle).unwrap().into_raw(),
+        Err(e) => {
+            report_error("Failed to pickle Olm account", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Restore an account from `pickleOlmAccount`, migrating older versions
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_unpickleOlmAccount(
+    mut env: JNIEnv,
+    _class: JClass,
+    pickle: jbyteArray,
+) -> jlong {
+    errors::clear_last_error();
+
+    let pickle = match get_rust_bytes(&mut env, pickle) {
+        Some(bytes) => zeroize::Zeroizing::new(bytes),
+        None => return 0,
+    };
+
+    match OlmSession::unpickle(&pickle) {
+        Ok(account) => registry::ACCOUNTS.insert(account),
+        Err(e) => {
+            report_error("Failed to unpickle Olm account", &e);
+            0
+        }
+    }
+}
+
+/// Read an exported store's identity keys without restoring its sessions
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_identityKeysFromPickle(
+    mut env: JNIEnv,
+    _class: JClass,
+    store: jbyteArray,
+    pickle_key: jbyteArray,
+) -> jstring {
+    errors::clear_last_error();
+
+    let store = match get_rust_bytes(&mut env, store) {
+        Some(bytes) => bytes,
+        None => return std::ptr::null_mut(),
+    };
+    let pickle_key = match get_rust_bytes(&mut env, pickle_key) {
+        Some(bytes) => bytes,
+        None => return std::ptr::null_mut(),
+    };
+
+    let keys = match OlmSession::identity_keys_from_pickle(&store, &pickle_key) {
+        Ok(keys) => keys,
+        Err(e) => {
+            report_error("Failed to read identity keys from store", &e);
+            return std::ptr::null_mut();
+        }
+    };
+
+    match serde_json::to_string(&keys) {
+        Ok(json) => env.new_string(&json).unwrap().into_raw(),
+        Err(e) => {
+            log::error!("Failed to serialize identity keys: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Verify the self-signature on a peer's device keys
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_verifyDeviceKeys(
+    mut env: JNIEnv,
+    _class: JClass,
+    device_keys: jstring,
+) -> jboolean {
+    errors::clear_last_error();
+
+    let device_keys = match get_rust_string(&mut env, device_keys) {
+        Some(s) => s,
+        None => return false as jboolean,
+    };
+
+    match OlmSession::verify_device_keys(&device_keys) {
+        Ok(valid) => valid as jboolean,
+        Err(e) => {
+            report_error("Failed to verify device keys", &e);
+            false as jboolean
+        }
+    }
+}
+
+/// Verify every device in a `/keys/query` response
+///
+/// Returns JSON `{ user_id: { device_id: valid } }`.
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_verifyDeviceMap(
+    mut env: JNIEnv,
+    _class: JClass,
+    query: jstring,
+) -> jstring {
+    errors::clear_last_error();
+
+    let query = match get_rust_string(&mut env, query) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+
+    match OlmSession::verify_device_map(&query) {
+        Ok(results) => {
+            match serde_json::to_string(&results) {
+                Ok(json) => env.new_string(&json).unwrap().into_raw(),
+                Err(e) => {
+                    log::error!("Failed to serialize device results: {}", e);
+                    std::ptr::null_mut()
+                }
+            }
+        }
+        Err(e) => {
+            report_error("Failed to verify device map", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Check that a device is cross-signed under a trusted master key
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_verifyCrossSigningChain(
+    mut env: JNIEnv,
+    _class: JClass,
+    master_key: jstring,
+    self_signing_key: jstring,
+    device_keys: jstring,
+) -> jboolean {
+    errors::clear_last_error();
+
+    let master_key = match get_rust_string(&mut env, master_key) {
+        Some(s) => s,
+        None => return false as jboolean,
+    };
+    let self_signing_key = match get_rust_string(&mut env, self_signing_key) {
+        Some(s) => s,
+        None => return false as jboolean,
+    };
+    let device_keys = match get_rust_string(&mut env, device_keys) {
+        Some(s) => s,
+        None => return false as jboolean,
+    };
+
+    match cross_signing::verify_chain(&master_key, &self_signing_key, &device_keys) {
+        Ok(valid) => valid as jboolean,
+        Err(e) => {
+            log::error!("Failed to verify cross-signing chain: {}", e);
+            false as jboolean
+        }
+    }
+}
+
+/// Sign another user's master key with our user-signing key
+///
+/// Returns the signed master key JSON, or null on error.
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_signUserMasterKey(
+    mut env: JNIEnv,
+    _class: JClass,
+    user_id: jstring,
+    user_signing_key: jstring,
+    their_master_key: jstring,
+) -> jstring {
+    errors::clear_last_error();
+
+    let user_id = match get_rust_string(&mut env, user_id) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+    let user_signing_key = match get_rust_string(&mut env, user_signing_key) {
+        Some(s) => zeroize::Zeroizing::new(s),
+        None => return std::ptr::null_mut(),
+    };
+    let their_master_key = match get_rust_string(&mut env, their_master_key) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+
+    match cross_signing::sign_user(&user_id, &user_signing_key, &their_master_key) {
+        Ok(signed) => env.new_string(&signed).unwrap().into_raw(),
+        Err(e) => {
+            log::error!("Failed to sign master key: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Build a signed `/keys/device_signing/upload` body for the account's user
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getDeviceSigningUpload(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+    user_id: jstring,
+    device_id: jstring,
+    seeds_json: jstring,
+) -> jstring {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let user_id = match get_rust_string(&mut env, user_id) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+    let device_id = match get_rust_string(&mut env, device_id) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+    let seeds_json = match get_rust_string(&mut env, seeds_json) {
+        Some(s) => zeroize::Zeroizing::new(s),
+        None => return std::ptr::null_mut(),
+    };
+    let seeds: cross_signing::CrossSigningSeeds = match serde_json::from_str(&seeds_json) {
+        Ok(seeds) => seeds,
+        Err(e) => {
+            log::error!("Invalid cross-signing seeds: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+    let account = registry::lock(&account);
+
+    match cross_signing::device_signing_upload_json(&user_id, &device_id, &seeds, &account) {
+        Ok(upload) => env.new_string(&upload).unwrap().into_raw(),
+        Err(e) => {
+            log::error!("Failed to build device signing upload: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Check a user-signing key's signature on another user's master key
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_verifyUserSignature(
+    mut env: JNIEnv,
+    _class: JClass,
+    user_id: jstring,
+    user_signing_key: jstring,
+    their_master_key: jstring,
+) -> jboolean {
+    errors::clear_last_error();
+
+    let user_id = match get_rust_string(&mut env, user_id) {
+        Some(s) => s,
+        None => return false as jboolean,
+    };
+    let user_signing_key = match get_rust_string(&mut env, user_signing_key) {
+        Some(s) => s,
+        None => return false as jboolean,
+    };
+    let their_master_key = match get_rust_string(&mut env, their_master_key) {
+        Some(s) => s,
+        None => return false as jboolean,
+    };
+
+    match cross_signing::verify_user_signature(&user_id, &user_signing_key, &their_master_key) {
+        Ok(valid) => valid as jboolean,
+        Err(e) => {
+            log::error!("Failed to verify user signature: {}", e);
+            false as jboolean
+        }
+    }
+}
+
+/// Check the signature on a claimed one-time key
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_verifyClaimedOneTimeKey(
+    mut env: JNIEnv,
+    _class: JClass,
+    claim: jstring,
+    signer_ed25519_key: jstring,
+) -> jstring {
+    errors::clear_last_error();
+
+    let claim = match get_rust_string(&mut env, claim) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+    let signer_ed25519_key = match get_rust_string(&mut env, signer_ed25519_key) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+
+    match OlmSession::verify_claimed_key(&claim, &signer_ed25519_key) {
+        Ok(claimed) => {
+            match serde_json::to_string(&claimed) {
+                Ok(json) => env.new_string(&json).unwrap().into_raw(),
+                Err(e) => {
+                    log::error!("Failed to serialize claimed key: {}", e);
+                    std::ptr::null_mut()
+                }
+            }
+        }
+        Err(e) => {
+            report_error("Failed to verify claimed key", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+// ============================================================================
+// Megolm Group Sessions
+// ============================================================================
+
+/// Create outbound Megolm session
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_createOutboundMegolmSession(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jlong {
+    errors::clear_last_error();
+
+    match MegolmSession::create_outbound() {
+        Ok(session) => {
+            registry::MEGOLM_SESSIONS.insert(session)
+        }
+        Err(e) => {
+            report_error("Failed to create Megolm session", &e);
+            0
+        }
+    }
+}
+
+/// Get Megolm session key for sharing
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getMegolmSessionKey(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+) -> jstring {
+    errors::clear_last_error();
+
+    let session = match registry::MEGOLM_SESSIONS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Megolm session", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let session = registry::lock(&session);
+
+    match session.get_session_key() {
+        Ok(key) => env.new_string(&key).unwrap().into_raw(),
+        Err(e @ megolm::MegolmError::InboundSession(_)) => {
+            throw_inbound_session_exception(&mut env, &e.to_string());
+            std::ptr::null_mut()
+        }
+        Err(e) => {
+            report_error("Failed to get session key", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Get Megolm session key at the current ratchet index
+///
+/// Share this with members joining mid-conversation so they can't read
+/// earlier messages.
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getMegolmSessionKeyAtCurrentIndex(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+) -> jstring {
+    errors::clear_last_error();
+
+    let session = match registry::MEGOLM_SESSIONS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Megolm session", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let session = registry::lock(&session);
+
+    match session.session_key_at_current_index() {
+        Ok(key) => env.new_string(&key).unwrap().into_raw(),
+        Err(e @ megolm::MegolmError::InboundSession(_)) => {
+            throw_inbound_session_exception(&mut env, &e.to_string());
+            std::ptr::null_mut()
+        }
+        Err(e) => {
+            report_error("Failed to get session key", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Get how many messages an outbound Megolm session has encrypted
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getMegolmMessagesSent(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+) -> jint {
+    errors::clear_last_error();
+
+    let session = match registry::MEGOLM_SESSIONS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Megolm session", &e);
+            return -1;
+        }
+    };
+    let session = registry::lock(&session);
+
+    session.messages_sent() as jint
+}
+
+/// Get an opaque tag for the Megolm message at an index, for audit logs
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getMegolmMessageTag(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+    index: jlong,
+) -> jstring {
+    errors::clear_last_error();
+
+    let session = match registry::MEGOLM_SESSIONS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Megolm session", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut session = registry::lock(&session);
+
+    let index = match u32::try_from(index) {
+        Ok(index) => index,
+        Err(_) => {
+            log::error!("Invalid Megolm message index: {}", index);
+            return std::ptr::null_mut();
+        }
+    };
+
+    match session.message_tag(index) {
+        Ok(tag) => env.new_string(&tag).unwrap().into_raw(),
+        Err(e) => {
+            report_error("Failed to tag Megolm message", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Get the highest Megolm index up to which every message was decrypted
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getMegolmOrderedIndex(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+) -> jlong {
+    errors::clear_last_error();
+
+    let session = match registry::MEGOLM_SESSIONS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Megolm session", &e);
+            return -1;
+        }
+    };
+    let session = registry::lock(&session);
+
+    session.ordered_index() as jlong
+}
+
+/// Encrypt message with Megolm
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_encryptMegolm(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+    plaintext: jbyteArray,
+) -> jstring {
+    errors::clear_last_error();
+
+    let session = match registry::MEGOLM_SESSIONS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Megolm session", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut session = registry::lock(&session);
+
+    let plaintext = match get_rust_bytes(&mut env, plaintext) {
+        Some(bytes) => bytes,
+        None => return std::ptr::null_mut(),
+    };
+
+    match session.encrypt(&plaintext) {
+        Ok(encrypted) => {
+            match serde_json::to_string(&encrypted) {
+                Ok(json) => env.new_string(&json).unwrap().into_raw(),
+                Err(e) => {
+                    log::error!("Failed to serialize encrypted message: {}", e);
+                    std::ptr::null_mut()
+                }
+            }
+        }
+        Err(e @ megolm::MegolmError::InboundSession(_)) => {
+            throw_inbound_session_exception(&mut env, &e.to_string());
+            std::ptr::null_mut()
+        }
+        Err(e) => {
+            report_error("Failed to encrypt with Megolm", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Encrypt a payload into `m.room.encrypted` event content
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_buildMegolmEncryptedEvent(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+    plaintext: jbyteArray,
+    device_curve25519: jstring,
+    device_id: jstring,
+) -> jstring {
+    errors::clear_last_error();
+
+    let session = match registry::MEGOLM_SESSIONS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Megolm session", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut session = registry::lock(&session);
+
+    let plaintext = match get_rust_bytes(&mut env, plaintext) {
+        Some(bytes) => zeroize::Zeroizing::new(bytes),
+        None => return std::ptr::null_mut(),
+    };
+    let device_curve25519 = match get_rust_string(&mut env, device_curve25519) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+    let device_id = match get_rust_string(&mut env, device_id) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+
+    match session.build_encrypted_event(&plaintext, &device_curve25519, &device_id) {
+        Ok(content) => {
+            match serde_json::to_string(&content) {
+                Ok(json) => env.new_string(&json).unwrap().into_raw(),
+                Err(e) => {
+                    log::error!("Failed to serialize encrypted event: {}", e);
+                    std::ptr::null_mut()
+                }
+            }
+        }
+        Err(e @ megolm::MegolmError::InboundSession(_)) => {
+            throw_inbound_session_exception(&mut env, &e.to_string());
+            std::ptr::null_mut()
+        }
+        Err(e) => {
+            report_error("Failed to encrypt with Megolm", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Encrypt a room event with Megolm
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_encryptMegolmEvent(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+    room_id: jstring,
+    event_type: jstring,
+    content: jstring,
+) -> jstring {
+    errors::clear_last_error();
+
+    let session = match registry::MEGOLM_SESSIONS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Megolm session", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut session = registry::lock(&session);
+
+    let room_id = match get_rust_string(&mut env, room_id) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+
+    let event_type = match get_rust_string(&mut env, event_type) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+
+    let content = match get_rust_string(&mut env, content)
+        .and_then(|s| serde_json::from_str(&s).ok())
+    {
+        Some(content) => content,
+        None => {
+            log::error!("Invalid event content JSON");
+            return std::ptr::null_mut();
+        }
+    };
+
+    match session.encrypt_event(&room_id, &event_type, content) {
+        Ok(encrypted) => {
+            match serde_json::to_string(&encrypted) {
+                Ok(json) => env.new_string(&json).unwrap().into_raw(),
+                Err(e) => {
+                    log::error!("Failed to serialize encrypted message: {}", e);
+                    std::ptr::null_mut()
+                }
+            }
+        }
+        Err(e @ megolm::MegolmError::InboundSession(_)) => {
+            throw_inbound_session_exception(&mut env, &e.to_string());
+            std::ptr::null_mut()
+        }
+        Err(e) => {
+            report_error("Failed to encrypt event with Megolm", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Decrypt a room event with Megolm, checking it belongs to the given room
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_decryptMegolmEvent(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+    ciphertext: jstring,
+    room_id: jstring,
+) -> jstring {
+    errors::clear_last_error();
+
+    let session = match registry::MEGOLM_SESSIONS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Megolm session", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut session = registry::lock(&session);
+
+    let ciphertext = match get_rust_string(&mut env, ciphertext) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+
+    let room_id = match get_rust_string(&mut env, room_id) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+
+    match session.decrypt_event(&ciphertext, &room_id) {
+        Ok(payload) => {
+            match serde_json::to_string(&payload) {
+                Ok(json) => env.new_string(&json).unwrap().into_raw(),
+                Err(e) => {
+                    log::error!("Failed to serialize decrypted event: {}", e);
+                    std::ptr::null_mut()
+                }
+            }
+        }
+        Err(e) => {
+            report_error("Failed to decrypt event with Megolm", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Get how far one message may advance an inbound Megolm ratchet
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getMaxRatchetAdvance(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jint {
+    errors::clear_last_error();
+
+    megolm::max_ratchet_advance().min(jint::MAX as u32) as jint
+}
+
+/// Change the Megolm ratchet advance limit; negative values are taken as 0
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_setMaxRatchetAdvance(
+    _env: JNIEnv,
+    _class: JClass,
+    limit: jint,
+) {
+    errors::clear_last_error();
+
+    megolm::set_max_ratchet_advance(u32::try_from(limit).unwrap_or(0));
+}
+
+/// Create inbound Megolm session
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_createInboundMegolmSession(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_key: jstring,
+    expected_session_id: jstring,
+) -> jlong {
+    errors::clear_last_error();
+
+    let session_key = match get_rust_string(&mut env, session_key) {
+        Some(s) => s,
+        None => return 0,
+    };
+    let expected_session_id = if expected_session_id.is_null() {
+        None
+    } else {
+        match get_rust_string(&mut env, expected_session_id) {
+            Some(s) => Some(s),
+            None => return 0,
+        }
+    };
+
+    match MegolmSession::create_inbound(&session_key, expected_session_id.as_deref()) {
+        Ok(session) => {
+            registry::MEGOLM_SESSIONS.insert(session)
+        }
+        Err(e) => {
+            report_error("Failed to create inbound Megolm session", &e);
+            0
+        }
+    }
+}
+
+/// Import an inbound Megolm session from a key export entry
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_importMegolmSession(
+    mut env: JNIEnv,
+    _class: JClass,
+    export_json: jstring,
+    expected_session_id: jstring,
+) -> jlong {
+    errors::clear_last_error();
+
+    let export_json = match get_rust_string(&mut env, export_json) {
+        Some(s) => s,
+        None => return 0,
+    };
+    let expected_session_id = if expected_session_id.is_null() {
+        None
+    } else {
+        match get_rust_string(&mut env, expected_session_id) {
+            Some(s) => Some(s),
+            None => return 0,
+        }
+    };
+
+    match MegolmSession::import(&export_json, expected_session_id.as_deref()) {
+        Ok(session) => registry::MEGOLM_SESSIONS.insert(session),
+        Err(e) => {
+            report_error("Failed to import Megolm session", &e);
+            0
+        }
+    }
+}
+
+/// Pickle a Megolm session; outbound sessions keep their sending ratchet
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_pickleMegolmSession(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+) -> jbyteArray {
+    errors::clear_last_error();
+
+    let session = match registry::MEGOLM_SESSIONS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Megolm session", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let session = registry::lock(&session);
+
+    match session.pickle() {
+        Ok(pickle) => env.byte_array_from_slice(&pickle).unwrap().into_raw(),
+        Err(e) => {
+            report_error("Failed to pickle Megolm session", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Restore a Megolm session from `pickleMegolmSession`
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_unpickleMegolmSession(
+    mut env: JNIEnv,
+    _class: JClass,
+    pickle: jbyteArray,
+) -> jlong {
+    errors::clear_last_error();
+
+    let pickle = match get_rust_bytes(&mut env, pickle) {
+        Some(bytes) => zeroize::Zeroizing::new(bytes),
+        None => return 0,
+    };
+
+    match MegolmSession::unpickle(&pickle) {
+        Ok(session) => registry::MEGOLM_SESSIONS.insert(session),
+        Err(e) => {
+            report_error("Failed to unpickle Megolm session", &e);
+            0
+        }
+    }
+}
+
+/// Pickle a Megolm session with its ratchet encrypted under a 32-byte key
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_pickleMegolmSessionEncrypted(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+    pickle_key: jbyteArray,
+) -> jbyteArray {
+    errors::clear_last_error();
+
+    let session = match registry::MEGOLM_SESSIONS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Megolm session", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let Some(pickle_key) = get_megolm_pickle_key(&mut env, pickle_key) else {
+        return std::ptr::null_mut();
+    };
+    let session = registry::lock(&session);
+
+    match session.pickle_encrypted(&pickle_key) {
+        Ok(pickle) => env.byte_array_from_slice(&pickle).unwrap().into_raw(),
+        Err(e) => {
+            report_error("Failed to pickle Megolm session", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Restore a Megolm session from `pickleMegolmSessionEncrypted`
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_unpickleMegolmSessionEncrypted(
+    mut env: JNIEnv,
+    _class: JClass,
+    pickle: jbyteArray,
+    pickle_key: jbyteArray,
+) -> jlong {
+    errors::clear_last_error();
+
+    let pickle = match get_rust_bytes(&mut env, pickle) {
+        Some(bytes) => bytes,
+        None => return 0,
+    };
+    let Some(pickle_key) = get_megolm_pickle_key(&mut env, pickle_key) else {
+        return 0;
+    };
+
+    match MegolmSession::unpickle_encrypted(&pickle, &pickle_key) {
+        Ok(session) => registry::MEGOLM_SESSIONS.insert(session),
+        Err(e) => {
+            report_error("Failed to unpickle Megolm session", &e);
+            0
+        }
+    }
+}
+
+/// Re-encrypt an encrypted Megolm session pickle under a new key
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_rekeyMegolmSession(
+    mut env: JNIEnv,
+    _class: JClass,
+    pickle: jbyteArray,
+    old_key: jbyteArray,
+    new_key: jbyteArray,
+) -> jbyteArray {
+    errors::clear_last_error();
+
+    let pickle = match get_rust_bytes(&mut env, pickle) {
+        Some(bytes) => bytes,
+        None => return std::ptr::null_mut(),
+    };
+    let Some(old_key) = get_megolm_pickle_key(&mut env, old_key) else {
+        return std::ptr::null_mut();
+    };
+    let Some(new_key) = get_megolm_pickle_key(&mut env, new_key) else {
+        return std::ptr::null_mut();
+    };
+
+    match MegolmSession::rekey_pickle(&pickle, &old_key, &new_key) {
+        Ok(pickle) => env.byte_array_from_slice(&pickle).unwrap().into_raw(),
+        Err(e) => {
+            report_error("Failed to rekey Megolm session", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Get the Ed25519 key the sender claimed for an imported Megolm session
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getMegolmClaimedEd25519Key(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+) -> jstring {
+    errors::clear_last_error();
+
+    let session = match registry::MEGOLM_SESSIONS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Megolm session", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let session = registry::lock(&session);
+
+    match session.claimed_ed25519_key() {
+        Some(key) => env.new_string(&key).unwrap().into_raw(),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Get the algorithm a Megolm session was pinned to by its first message
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getMegolmPinnedAlgorithm(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+) -> jstring {
+    errors::clear_last_error();
+
+    let session = match registry::MEGOLM_SESSIONS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Megolm session", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let session = registry::lock(&session);
+
+    match session.pinned_algorithm() {
+        Some(algorithm) => env.new_string(algorithm).unwrap().into_raw(),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Check whether a Megolm session can encrypt
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_isMegolmOutbound(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+) -> jboolean {
+    errors::clear_last_error();
+
+    let session = match registry::MEGOLM_SESSIONS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Megolm session", &e);
+            return false as jboolean;
+        }
+    };
+    let session = registry::lock(&session);
+
+    session.is_outbound() as jboolean
+}
+
+/// File a Megolm session under its room for `exportRoomMegolmSessions`
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_addMegolmSessionToRoom(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+    room_id: jstring,
+    sender_key: jstring,
+) {
+    errors::clear_last_error();
+
+    let session = match registry::MEGOLM_SESSIONS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Megolm session", &e);
+            return;
+        }
+    };
+
+    let room_id = match get_rust_string(&mut env, room_id) {
+        Some(s) => s,
+        None => return,
+    };
+    let sender_key = match get_rust_string(&mut env, sender_key) {
+        Some(s) => s,
+        None => return,
+    };
+
+    registry::lock(&registry::ROOM_SESSIONS).add(&room_id, &sender_key, &session);
+}
+
+/// Export every Megolm session filed under a room, as a JSON array
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_exportRoomMegolmSessions(
+    mut env: JNIEnv,
+    _class: JClass,
+    room_id: jstring,
+) -> jstring {
+    errors::clear_last_error();
+
+    let room_id = match get_rust_string(&mut env, room_id) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+
+    let exports = registry::lock(&registry::ROOM_SESSIONS).export_room_sessions(&room_id);
+    let json = format!("[{}]", exports.join(","));
+    env.new_string(&json).unwrap().into_raw()
+}
+
+/// Check whether a Megolm session came from a key export or history share
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_isMegolmSessionImported(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+) -> jboolean {
+    errors::clear_last_error();
+
+    let session = match registry::MEGOLM_SESSIONS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Megolm session", &e);
+            return false as jboolean;
+        }
+    };
+    let session = registry::lock(&session);
+
+    session.is_imported() as jboolean
+}
+
+/// Check whether a Megolm session's key came directly from its sender
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_isMegolmSessionTrusted(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+) -> jboolean {
+    errors::clear_last_error();
+
+    let session = match registry::MEGOLM_SESSIONS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Megolm session", &e);
+            return false as jboolean;
+        }
+    };
+    let session = registry::lock(&session);
+
+    session.is_trusted() as jboolean
+}
+
+/// Decrypt message with Megolm
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_decryptMegolm(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+    ciphertext: jstring,
+) -> jbyteArray {
+    errors::clear_last_error();
+
+    let session = match registry::MEGOLM_SESSIONS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Megolm session", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut session = registry::lock(&session);
+
+    let ciphertext = match get_rust_string(&mut env, ciphertext) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+
+    match session.decrypt(&ciphertext) {
+        Ok(plaintext) => {
+            env.byte_array_from_slice(&plaintext).unwrap().into_raw()
+        }
+        Err(e) => {
+            report_error("Failed to decrypt with Megolm", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Decrypt a Megolm message, reporting its index and delivery order
+///
+/// Returns JSON `{ plaintext, message_index, order }` with the plaintext in
+/// base64 and `order` one of `ordered`, `gap` or `duplicate`.
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_decryptMegolmDetailed(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+    ciphertext: jstring,
+) -> jstring {
+    errors::clear_last_error();
+
+    let session = match registry::MEGOLM_SESSIONS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Megolm session", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut session = registry::lock(&session);
+
+    let ciphertext = match get_rust_string(&mut env, ciphertext) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+
+    match session.decrypt_with_index(&ciphertext) {
+        Ok(decrypted) => {
+            let plaintext = zeroize::Zeroizing::new(decrypted.plaintext);
+            let result = serde_json::json!({
+                "plaintext": utilities::base64_encode(&plaintext),
+                "message_index": decrypted.message_index,
+                "order": decrypted.order,
+            });
+            env.new_string(result.to_string()).unwrap().into_raw()
+        }
+        Err(e) => {
+            report_error("Failed to decrypt with Megolm", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Decrypt a payload split over consecutive Megolm messages
+///
+/// `chunks_json` is a JSON array of the messages, in order.
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_decryptMegolmChunked(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+    chunks_json: jstring,
+) -> jbyteArray {
+    errors::clear_last_error();
+
+    let session = match registry::MEGOLM_SESSIONS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Megolm session", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut session = registry::lock(&session);
+
+    let chunks_json = match get_rust_string(&mut env, chunks_json) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+    let chunks: Vec<megolm::MegolmMessage> = match serde_json::from_str(&chunks_json) {
+        Ok(chunks) => chunks,
+        Err(e) => {
+            log::error!("Invalid Megolm chunk list: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+
+    match session.decrypt_chunked(&chunks) {
+        Ok(plaintext) => {
+            let plaintext = zeroize::Zeroizing::new(plaintext);
+            env.byte_array_from_slice(&plaintext).unwrap().into_raw()
+        }
+        Err(e) => {
+            report_error("Failed to decrypt chunked Megolm payload", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Check whether a Megolm message can be decrypted without decrypting it
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_canDecryptMegolmMessage(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+    ciphertext: jstring,
+) -> jboolean {
+    errors::clear_last_error();
+
+    let session = match registry::MEGOLM_SESSIONS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Megolm session", &e);
+            return false as jboolean;
+        }
+    };
+    let session = registry::lock(&session);
+
+    let ciphertext = match get_rust_string(&mut env, ciphertext) {
+        Some(s) => s,
+        None => return false as jboolean,
+    };
+
+    session.can_decrypt_message(&ciphertext) as jboolean
+}
+
+/// Decrypt a text message with Megolm
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_decryptMegolmText(
+    mut env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+    ciphertext: jstring,
+) -> jstring {
+    errors::clear_last_error();
+
+    let session = match registry::MEGOLM_SESSIONS.lookup(session_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Megolm session", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let mut session = registry::lock(&session);
+
+    let ciphertext = match get_rust_string(&mut env, ciphertext) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+
+    match session.decrypt_text(&ciphertext) {
+        Ok(plaintext) => env.new_string(&plaintext).unwrap().into_raw(),
+        Err(e) => {
+            report_error("Failed to decrypt with Megolm", &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+// ============================================================================
+// Attachments
+// ============================================================================
+
+/// Encrypt an attachment
+///
+/// Returns JSON with the `file` info for the event and the base64
+/// `ciphertext` to upload.
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_encryptAttachment(
+    mut env: JNIEnv,
+    _class: JClass,
+    data: jbyteArray,
+    key_size: jint,
+) -> jstring {
+    errors::clear_last_error();
+
+    let data = match get_rust_bytes(&mut env, data) {
+        Some(bytes) => bytes,
+        None => return std::ptr::null_mut(),
+    };
+
+    let encrypted = match attachments::encrypt_attachment(&data, key_size as u32) {
+        Ok(encrypted) => encrypted,
+        Err(e) => {
+            log::error!("Failed to encrypt attachment: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+
+    let result = serde_json::json!({
+        "file": encrypted.info,
+        "ciphertext": utilities::base64_encode(&encrypted.ciphertext),
+    });
+    env.new_string(result.to_string()).unwrap().into_raw()
+}
+
+/// Decrypt an attachment given the event's `file` info JSON
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_decryptAttachment(
+    mut env: JNIEnv,
+    _class: JClass,
+    ciphertext: jbyteArray,
+    file_info: jstring,
+) -> jbyteArray {
+    errors::clear_last_error();
+
+    let ciphertext = match get_rust_bytes(&mut env, ciphertext) {
+        Some(bytes) => bytes,
+        None => return std::ptr::null_mut(),
+    };
+    let file_info = match get_rust_string(&mut env, file_info) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+
+    let file_info: attachments::EncryptedFileInfo = match serde_json::from_str(&file_info) {
+        Ok(info) => info,
+        Err(e) => {
+            log::error!("Invalid attachment info: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+
+    match attachments::decrypt_attachment(&ciphertext, &file_info) {
+        Ok(plaintext) => env.byte_array_from_slice(&plaintext).unwrap().into_raw(),
+        Err(e) => {
+            log::error!("Failed to decrypt attachment: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Read the cipher parameters of an attachment's `file` info JSON
+///
+/// Returns JSON `{ algorithm, key_size, has_hashes }`; the key itself isn't
+/// needed.
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_inspectAttachment(
+    mut env: JNIEnv,
+    _class: JClass,
+    file_info: jstring,
+) -> jstring {
+    errors::clear_last_error();
+
+    let file_info = match get_rust_string(&mut env, file_info) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+
+    let info = match attachments::inspect(&file_info) {
+        Ok(info) => info,
+        Err(e) => {
+            log::error!("Failed to inspect attachment: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+
+    match serde_json::to_string(&info) {
+        Ok(json) => env.new_string(&json).unwrap().into_raw(),
+        Err(e) => {
+            log::error!("Failed to serialize attachment info: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Start a streaming HMAC-SHA-256, returning its handle
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_createHmacSha256(
+    mut env: JNIEnv,
+    _class: JClass,
+    key: jbyteArray,
+) -> jlong {
+    errors::clear_last_error();
+
+    let key = match get_rust_bytes(&mut env, key) {
+        Some(bytes) => bytes,
+        None => return 0,
+    };
+
+    registry::HMACS.insert(utilities::HmacSha256Streaming::new(&key))
+}
+
+/// Feed the next chunk to a streaming HMAC
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_updateHmacSha256(
+    mut env: JNIEnv,
+    _class: JClass,
+    hmac_ptr: jlong,
+    chunk: jbyteArray,
+) -> jboolean {
+    errors::clear_last_error();
+
+    let mac = match registry::HMACS.lookup(hmac_ptr) {
+        Ok(mac) => mac,
+        Err(e) => {
+            throw_handle_error(&mut env, "HMAC", &e);
+            return false as jboolean;
+        }
+    };
+    let chunk = match get_rust_bytes(&mut env, chunk) {
+        Some(bytes) => bytes,
+        None => return false as jboolean,
+    };
+
+    registry::lock(&mac).update(&chunk);
+    true as jboolean
+}
+
+/// Finish a streaming HMAC and free its handle
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_finalizeHmacSha256(
+    mut env: JNIEnv,
+    _class: JClass,
+    hmac_ptr: jlong,
+) -> jbyteArray {
+    errors::clear_last_error();
+
+    let mac = match registry::HMACS.lookup(hmac_ptr) {
+        Ok(mac) => mac,
+        Err(e) => {
+            throw_handle_error(&mut env, "HMAC", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    registry::HMACS.remove(hmac_ptr);
+
+    let tag = registry::lock(&mac).clone().finalize();
+    env.byte_array_from_slice(&tag).unwrap().into_raw()
+}
+
+/// Free a streaming HMAC that won't be finished
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_freeHmacSha256(
+    _env: JNIEnv,
+    _class: JClass,
+    hmac_ptr: jlong,
+) {
+    errors::clear_last_error();
+
+    registry::HMACS.remove(hmac_ptr);
+}
+
+// ============================================================================
+// Verification
+// ============================================================================
+
+/// Start tracking a verification flow, returning its handle
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_startVerification(
+    mut env: JNIEnv,
+    _class: JClass,
+    flow_id: jstring,
+) -> jlong {
+    errors::clear_last_error();
+
+    let flow_id = match get_rust_string(&mut env, flow_id) {
+        Some(s) => s,
+        None => return 0,
+    };
+
+    registry::VERIFICATIONS.insert(verification::VerificationSession::new(&flow_id))
+}
+
+/// Check whether a verification flow has run past its timeout
+///
+/// A non-positive `timeout_ms` uses the spec's ten minutes.
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_isVerificationExpired(
+    mut env: JNIEnv,
+    _class: JClass,
+    verification_ptr: jlong,
+    timeout_ms: jlong,
+) -> jboolean {
+    errors::clear_last_error();
+
+    let session = match registry::VERIFICATIONS.lookup(verification_ptr) {
+        Ok(session) => session,
+        Err(e) => {
+            throw_handle_error(&mut env, "Verification", &e);
+            return true as jboolean;
+        }
+    };
+
+    let timeout = match u64::try_from(timeout_ms) {
+        Ok(ms) if ms > 0 => std::time::Duration::from_millis(ms),
+        _ => verification::VERIFICATION_TIMEOUT,
+    };
+    let expired = registry::lock(&session).check_step(timeout).is_err();
+    expired as jboolean
+}
+
+/// Stop tracking a verification flow
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_freeVerification(
+    _env: JNIEnv,
+    _class: JClass,
+    verification_ptr: jlong,
+) {
+    errors::clear_last_error();
+
+    registry::VERIFICATIONS.remove(verification_ptr);
+}
+
+// ============================================================================
+// Key Backup
+// ============================================================================
+
+/// Verify a key backup's auth data before importing sessions from it
+#[cfg(feature = "backup")]
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_verifyBackupAuthData(
+    mut env: JNIEnv,
+    _class: JClass,
+    auth_data: jstring,
+    recovery_public_key: jstring,
+    signing_key: jstring,
+) -> jboolean {
+    errors::clear_last_error();
+
+    let auth_data = match get_rust_string(&mut env, auth_data) {
+        Some(s) => s,
+        None => return false as jboolean,
+    };
+
+    let recovery_public_key = match get_rust_string(&mut env, recovery_public_key) {
+        Some(s) => s,
+        None => return false as jboolean,
+    };
+
+    let signing_key = match get_rust_string(&mut env, signing_key) {
+        Some(s) => s,
+        None => return false as jboolean,
+    };
+
+    match backup::verify_backup_auth_data(&auth_data, &recovery_public_key, &signing_key) {
+        Ok(valid) => valid as jboolean,
+        Err(e) => {
+            log::error!("Failed to verify backup auth data: {}", e);
+            false as jboolean
+        }
+    }
+}
+
+/// Check a recovery passphrase against a key backup's public key
+#[cfg(feature = "backup")]
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_checkBackupPassphrase(
+    mut env: JNIEnv,
+    _class: JClass,
+    passphrase: jstring,
+    salt: jstring,
+    iterations: jint,
+    backup_public_key: jstring,
+) -> jboolean {
+    errors::clear_last_error();
+
+    let passphrase = match get_rust_string(&mut env, passphrase) {
+        Some(s) => zeroize::Zeroizing::new(s),
+        None => return false as jboolean,
+    };
+    let salt = match get_rust_string(&mut env, salt) {
+        Some(s) => s,
+        None => return false as jboolean,
+    };
+    let backup_public_key = match get_rust_string(&mut env, backup_public_key) {
+        Some(s) => s,
+        None => return false as jboolean,
+    };
+    // A negative count is refused as too few iterations
+    let iterations = u32::try_from(iterations).unwrap_or(0);
+
+    match backup::check_passphrase(&passphrase, &salt, iterations, &backup_public_key) {
+        Ok(valid) => valid as jboolean,
+        Err(backup::BackupError::Kdf(e)) => {
+            report_error("Refused backup passphrase parameters", &e);
+            false as jboolean
+        }
+        Err(e) => {
+            log::error!("Failed to check backup passphrase: {}", e);
+            false as jboolean
+        }
+    }
+}
+
+/// Split a recovery key into groups of four characters for display
+#[cfg(feature = "backup")]
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_formatRecoveryKeyForDisplay(
+    mut env: JNIEnv,
+    _class: JClass,
+    recovery_key: jstring,
+) -> jstring {
+    errors::clear_last_error();
+
+    let recovery_key = match get_rust_string(&mut env, recovery_key) {
+        Some(s) => zeroize::Zeroizing::new(s),
+        None => return std::ptr::null_mut(),
+    };
+
+    let display = zeroize::Zeroizing::new(backup::format_recovery_key_for_display(&recovery_key));
+    env.new_string(display.as_str()).unwrap().into_raw()
+}
+
+/// Encode a backup private key as a base58 recovery key
+#[cfg(feature = "backup")]
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_encodeRecoveryKey(
+    mut env: JNIEnv,
+    _class: JClass,
+    private_key: jbyteArray,
+) -> jstring {
+    errors::clear_last_error();
+
+    let private_key = match get_rust_bytes(&mut env, private_key) {
+        Some(bytes) => zeroize::Zeroizing::new(bytes),
+        None => return std::ptr::null_mut(),
+    };
+    let private_key: zeroize::Zeroizing<[u8; 32]> = match private_key.as_slice().try_into() {
+        Ok(key) => zeroize::Zeroizing::new(key),
+        Err(_) => {
+            log::error!("Invalid backup private key length: {}", private_key.len());
+            return std::ptr::null_mut();
+        }
+    };
+
+    let recovery_key = zeroize::Zeroizing::new(backup::encode_recovery_key(&private_key));
+    env.new_string(recovery_key.as_str()).unwrap().into_raw()
+}
+
+/// Decode a recovery key as typed or pasted by the user
+#[cfg(feature = "backup")]
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_parseRecoveryKey(
+    mut env: JNIEnv,
+    _class: JClass,
+    recovery_key: jstring,
+) -> jbyteArray {
+    errors::clear_last_error();
+
+    let recovery_key = match get_rust_string(&mut env, recovery_key) {
+        Some(s) => zeroize::Zeroizing::new(s),
+        None => return std::ptr::null_mut(),
+    };
+
+    match backup::parse_display_recovery_key(&recovery_key) {
+        Ok(private_key) => env.byte_array_from_slice(private_key.as_slice()).unwrap().into_raw(),
+        Err(e) => {
+            log::error!("Failed to parse recovery key: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Create a backup key and auth data signed by the account
+///
+/// Returns JSON `{ public_key, recovery_key, auth_data_json }`.
+#[cfg(feature = "backup")]
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_createKeyBackup(
+    mut env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+    user_id: jstring,
+    device_id: jstring,
+) -> jstring {
+    errors::clear_last_error();
+
+    let account = match registry::ACCOUNTS.lookup(account_ptr) {
+        Ok(account) => account,
+        Err(e) => {
+            throw_handle_error(&mut env, "Olm account", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let account = registry::lock(&account);
+
+    let user_id = match get_rust_string(&mut env, user_id) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+    let device_id = match get_rust_string(&mut env, device_id) {
+        Some(s) => s,
+        None => return std::ptr::null_mut(),
+    };
+
+    let backup = match backup::create_backup(&account, &user_id, &device_id) {
+        Ok(backup) => backup,
+        Err(e) => {
+            log::error!("Failed to create key backup: {}", e);
+            return std::ptr::null_mut();
+        }
+    };
+
+    match serde_json::to_string(&backup) {
+        Ok(json) => env.new_string(&json).unwrap().into_raw(),
+        Err(e) => {
+            log::error!("Failed to serialize key backup: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Start importing exported Megolm sessions in the background
+///
+/// Returns a job handle to poll with `backupImportProgress`, or 0 if the
+/// export list is invalid.
+#[cfg(feature = "backup")]
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_startBackupImport(
+    mut env: JNIEnv,
+    _class: JClass,
+    sessions_json: jstring,
+) -> jlong {
+    errors::clear_last_error();
+
+    let sessions_json = match get_rust_string(&mut env, sessions_json) {
+        Some(s) => s,
+        None => return 0,
+    };
+
+    let exports: Vec<serde_json::Value> = match serde_json::from_str(&sessions_json) {
+        Ok(exports) => exports,
+        Err(e) => {
+            log::error!("Invalid backup session list: {}", e);
+            return 0;
+        }
+    };
+
+    let job = registry::BACKUP_IMPORTS.insert(backup::ImportProgress::default());
+    let progress = match registry::BACKUP_IMPORTS.get(job) {
+        Some(progress) => progress,
+        None => return 0,
+    };
+    backup::spawn_session_import(exports, progress, |session| {
+        registry::MEGOLM_SESSIONS.insert(session)
+    });
+
+    job
+}
+
+/// Get the progress of a background import as JSON `{ done, total, errors }`
+#[cfg(feature = "backup")]
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_backupImportProgress(
+    mut env: JNIEnv,
+    _class: JClass,
+    job: jlong,
+) -> jstring {
+    errors::clear_last_error();
+
+    let progress = match registry::BACKUP_IMPORTS.lookup(job) {
+        Ok(progress) => progress,
+        Err(e) => {
+            throw_handle_error(&mut env, "Backup import", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let progress = registry::lock(&progress);
+
+    match serde_json::to_string(&*progress) {
+        Ok(json) => env.new_string(&json).unwrap().into_raw(),
+        Err(e) => {
+            log::error!("Failed to serialize import progress: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Finish a background import, returning JSON `{ session_id: handle }`
+///
+/// Returns null while the import is still running; once it has finished,
+/// the job handle is freed.
+#[cfg(feature = "backup")]
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_finishBackupImport(
+    mut env: JNIEnv,
+    _class: JClass,
+    job: jlong,
+) -> jstring {
+    errors::clear_last_error();
+
+    let progress = match registry::BACKUP_IMPORTS.lookup(job) {
+        Ok(progress) => progress,
+        Err(e) => {
+            throw_handle_error(&mut env, "Backup import", &e);
+            return std::ptr::null_mut();
+        }
+    };
+    let sessions = {
+        let progress = registry::lock(&progress);
+        if !progress.is_finished() {
+            return std::ptr::null_mut();
+        }
+        serde_json::to_string(&progress.sessions)
+    };
+    registry::BACKUP_IMPORTS.remove(job);
+
+    match sessions {
+        Ok(json) => env.new_string(&json).unwrap().into_raw(),
+        Err(e) => {
+            log::error!("Failed to serialize imported sessions: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Free Olm account
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_freeOlmAccount(
+    _env: JNIEnv,
+    _class: JClass,
+    account_ptr: jlong,
+) {
+    errors::clear_last_error();
+
+    registry::ACCOUNTS.remove(account_ptr);
+}
+
+/// Free Megolm session
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_freeMegolmSession(
+    _env: JNIEnv,
+    _class: JClass,
+    session_ptr: jlong,
+) {
+    errors::clear_last_error();
+
+    registry::MEGOLM_SESSIONS.remove(session_ptr);
+}
+
+/// Take the calling thread's last Olm, Megolm or utility error as JSON
+///
+/// Every other entry point clears the report first, so this describes the
+/// call right before it. Returns `{code, category, message}`, or null if
+/// that call succeeded or failed without a report.
+#[no_mangle]
+pub extern "system" fn Java_app_armorclaw_crypto_VodozemacNative_getLastErrorJson(
+    env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    match errors::take_last_error() {
+        Some(report) => {
+            let json = serde_json::to_string(&report).unwrap();
+            env.new_string(&json).unwrap().into_raw()
+        }
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Log a failed call and keep it as this thread's last error
+fn report_error(context: &str, error: &impl errors::Reportable) {
+    log::error!("{}: {}", context, error);
+    errors::set_last_error(error);
+}
+
+/// Throw a `VodozemacException` with the given message
+///
+/// The caller must return right after this; the exception is raised once
+/// control goes back to the JVM.
+fn throw_vodozemac_exception(env: &mut JNIEnv, message: &str) {
+    throw_exception(env, "app/armorclaw/crypto/VodozemacException", message);
+}
+
+/// Throw a `VodozemacException` for a zero or freed handle
+///
+/// The handle's table no longer holds the object, so nothing was touched.
+/// The failure is also kept for `getLastErrorJson`, as `handle.null` or
+/// `handle.unknown`.
+fn throw_handle_error(env: &mut JNIEnv, what: &str, error: &registry::HandleError) {
+    errors::set_last_error(error);
+    throw_vodozemac_exception(env, &format!("{}: {}", what, error));
+}
+
+/// Throw an `InboundSessionException` for an outbound-only Megolm operation
+fn throw_inbound_session_exception(env: &mut JNIEnv, message: &str) {
+    throw_exception(env, "app/armorclaw/crypto/InboundSessionException", message);
+}
+
+/// Throw a `BadMacException` for an Olm message that failed authentication
+fn throw_bad_mac_exception(env: &mut JNIEnv, message: &str) {
+    throw_exception(env, "app/armorclaw/crypto/BadMacException", message);
+}
+
+/// Throw a `ReplayedPreKeyException` for a pre-key message whose one-time
+/// key was already used
+fn throw_replayed_prekey_exception(env: &mut JNIEnv, message: &str) {
+    throw_exception(env, "app/armorclaw/crypto/ReplayedPreKeyException", message);
+}
+
+fn throw_exception(env: &mut JNIEnv, class: &str, message: &str) {
+    log::error!("{}", message);
+    if env.throw_new(class, message).is_err() {
+        log::error!("Failed to throw {}", class);
+    }
+}
+
+/// Read a Java string argument into an owned Rust string
+///
+/// Throws `VodozemacException` if the string can't be read or holds a lone
+/// surrogate; the caller must return right after a `None`.
+fn get_rust_string(env: &mut JNIEnv, value: jstring) -> Option<String> {
+    let value: JString = unsafe { JObject::from_raw(value).into() };
+    let decoded = match env.get_string(&value) {
+        Ok(s) => utilities::decode_java_string(s.to_bytes()).map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+
+    match decoded {
+        Ok(s) => Some(s),
+        Err(e) => {
+            // Don't replace an exception JNI already raised, e.g. out of memory
+            if !env.exception_check().unwrap_or(true) {
+                throw_vodozemac_exception(env, &format!("String argument: {}", e));
+            }
+            None
+        }
+    }
+}
+
+/// Read a Java byte array argument into an owned Rust buffer
+fn get_rust_bytes(env: &mut JNIEnv, value: jbyteArray) -> Option<Vec<u8>> {
+    let value = unsafe { JByteArray::from_raw(value) };
+    env.convert_byte_array(&value).ok()
+}
+
+/// Read a 32-byte Megolm pickle key argument
+///
+/// Logs and returns `None` if it is missing or another length.
+fn get_megolm_pickle_key(
+    env: &mut JNIEnv,
+    key: jbyteArray,
+) -> Option<zeroize::Zeroizing<[u8; 32]>> {
+    let key = zeroize::Zeroizing::new(get_rust_bytes(env, key)?);
+    match key.as_slice().try_into() {
+        Ok(key) => Some(zeroize::Zeroizing::new(key)),
+        Err(_) => {
+            log::error!("Invalid Megolm pickle key length: {}", key.len());
+            None
+        }
+    }
+}
+
+/// Check that a public-key argument is a raw 32-byte Curve25519 key
+///
+/// Throws `VodozemacException` naming the actual length otherwise; the
+/// caller must return right after a `None`.
+fn require_curve25519_key(env: &mut JNIEnv, key: &[u8]) -> Option<[u8; 32]> {
+    match key.try_into() {
+        Ok(key) => Some(key),
+        Err(_) => {
+            throw_vodozemac_exception(
+                env,
+                &format!("Curve25519 public key must be 32 bytes, got {}", key.len()),
+            );
+            None
+        }
+    }
+}
+
+/// Check that a private-key argument is a raw 32-byte X25519 scalar
+///
+/// Like [`require_curve25519_key`], but the copy is wiped when dropped.
+fn require_curve25519_private_key(
+    env: &mut JNIEnv,
+    key: &[u8],
+) -> Option<zeroize::Zeroizing<[u8; 32]>> {
+    match key.try_into() {
+        Ok(key) => Some(zeroize::Zeroizing::new(key)),
+        Err(_) => {
+            throw_vodozemac_exception(
+                env,
+                &format!("Curve25519 private key must be 32 bytes, got {}", key.len()),
+            );
+            None
         }
     }
 }
@@ -1,11 +1,27 @@
 //! Megolm group session management
 //!
 //! Megolm provides efficient group encryption using a symmetric ratchet.
-//! The session key is shared via Olm with each group member.
+//! The session key is shared via Olm with each group member. The ratchet
+//! itself is vodozemac's `m.megolm.v1.aes-sha2` implementation.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, Weak};
 
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use vodozemac::megolm::{
+    ExportedSessionKey, GroupSession, GroupSessionPickle, InboundGroupSession,
+    InboundGroupSessionPickle, SessionConfig, SessionKey,
+};
+
+#[cfg(feature = "metrics")]
+use crate::metrics::{Operation, Timer};
+
+/// Megolm algorithm identifier
+pub const MEGOLM_ALGORITHM: &str = "m.megolm.v1.aes-sha2";
+
 /// Megolm errors
 #[derive(Error, Debug)]
 pub enum MegolmError {
@@ -24,8 +40,75 @@ pub enum MegolmError {
     #[error("Invalid message index: {0}")]
     InvalidMessageIndex(String),
 
-    #[error("Session not found")]
-    SessionNotFound,
+    #[error("Not possible with an inbound session: {0}")]
+    InboundSession(&'static str),
+
+    #[error("Session is pinned to {pinned}, message declares {declared}")]
+    AlgorithmMismatch { pinned: String, declared: String },
+}
+
+/// Default limit on how far one message may move an inbound ratchet forward
+pub const DEFAULT_MAX_RATCHET_ADVANCE: u32 = 2000;
+
+static MAX_RATCHET_ADVANCE: AtomicU32 = AtomicU32::new(DEFAULT_MAX_RATCHET_ADVANCE);
+
+/// How many ratchet steps past the furthest index decrypted so far an
+/// inbound session will take for a single message
+pub fn max_ratchet_advance() -> u32 {
+    MAX_RATCHET_ADVANCE.load(Ordering::Relaxed)
+}
+
+/// Change the ratchet advance limit for the whole process
+///
+/// Advancing the ratchet costs one hash per step, so without a limit a
+/// message claiming a huge index would keep the decrypting thread busy.
+pub fn set_max_ratchet_advance(limit: u32) {
+    MAX_RATCHET_ADVANCE.store(limit, Ordering::Relaxed);
+}
+
+/// Domain separation for [`MegolmSession::message_tag`]
+const MESSAGE_TAG_INFO: &[u8] = b"ArmorClaw Megolm message tag";
+
+/// Version byte of a shared Megolm session key (`m.room_key`)
+const SESSION_KEY_VERSION: u8 = 2;
+
+/// Length of a decoded session key: version, index, ratchet, Ed25519 key
+/// and signature
+const SESSION_KEY_LENGTH: usize = 1 + 4 + 128 + 32 + 64;
+
+/// Check that a session key derived the session id the caller expected
+fn check_session_id(
+    inbound: &InboundGroupSession,
+    expected_session_id: Option<&str>,
+) -> Result<(), MegolmError> {
+    match expected_session_id {
+        Some(expected) if expected != inbound.session_id() => {
+            Err(MegolmError::InvalidSessionKey("session id mismatch".into()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Check a base64 session key's length and version before parsing it
+fn check_session_key(session_key: &str) -> Result<(), MegolmError> {
+    let decoded = crate::utilities::base64_decode_lenient(session_key)
+        .map_err(|_| MegolmError::InvalidSessionKey("Session key is not valid base64".into()))?;
+
+    if decoded.len() != SESSION_KEY_LENGTH {
+        return Err(MegolmError::InvalidSessionKey(format!(
+            "Session key is {} bytes, expected {}",
+            decoded.len(),
+            SESSION_KEY_LENGTH
+        )));
+    }
+    if decoded[0] != SESSION_KEY_VERSION {
+        return Err(MegolmError::InvalidSessionKey(format!(
+            "Unsupported session key version {}, expected {}",
+            decoded[0], SESSION_KEY_VERSION
+        )));
+    }
+
+    Ok(())
 }
 
 /// Encrypted Megolm message
@@ -35,146 +118,843 @@ pub struct MegolmMessage {
     pub sender_key: String,
     pub session_id: String,
     pub ciphertext: String,
+    /// Informational; decryption reads the index from the ciphertext, so
+    /// event content without this field decrypts too
+    #[serde(default)]
     pub message_index: u32,
 }
 
+/// Content of an `m.room.encrypted` event, as Element sends it
+#[derive(Serialize, Deserialize)]
+pub struct EncryptedEventContent {
+    pub algorithm: String,
+    pub sender_key: String,
+    pub ciphertext: String,
+    pub session_id: String,
+    pub device_id: String,
+}
+
+/// Plaintext payload of a Megolm-encrypted room event
+///
+/// The room id is encrypted together with the content so a recipient can
+/// check the event was really sent to the room it arrived in.
+#[derive(Serialize, Deserialize)]
+pub struct EventPayload {
+    pub room_id: String,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub content: serde_json::Value,
+}
+
+/// A Megolm session as it appears in a key export or room-history share
+#[derive(Serialize, Deserialize)]
+pub struct ExportedSession {
+    pub algorithm: String,
+    #[serde(default)]
+    pub room_id: String,
+    pub sender_key: String,
+    pub session_id: String,
+    pub session_key: String,
+    #[serde(default)]
+    pub sender_claimed_keys: HashMap<String, String>,
+    #[serde(default)]
+    pub forwarding_curve25519_key_chain: Vec<String>,
+}
+
+/// Content of an `m.room_key` to-device event
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomKeyInfo {
+    pub algorithm: String,
+    pub room_id: String,
+    pub session_id: String,
+    pub session_key: String,
+}
+
+/// A decrypted Megolm message together with its verified ratchet index
+pub struct DecryptedMessage {
+    pub plaintext: Vec<u8>,
+    pub message_index: u32,
+    pub order: MessageOrder,
+}
+
+/// Where a decrypted message falls relative to the ones before it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageOrder {
+    /// The lowest index not decrypted yet
+    Ordered,
+    /// Later than that, so at least one message is still missing
+    Gap,
+    /// Already decrypted before, e.g. a replay
+    Duplicate,
+}
+
+/// Tracks which message indices have been decrypted, in ratchet order
+struct DeliveryOrder {
+    /// Lowest index not decrypted yet
+    next: u32,
+    /// Decrypted indices above `next`, waiting for the gap to be filled
+    ahead: BTreeSet<u32>,
+}
+
+impl DeliveryOrder {
+    fn new(first_known_index: u32) -> Self {
+        Self {
+            next: first_known_index,
+            ahead: BTreeSet::new(),
+        }
+    }
+
+    fn record(&mut self, index: u32) -> MessageOrder {
+        if index == self.next {
+            self.next += 1;
+            while self.ahead.remove(&self.next) {
+                self.next += 1;
+            }
+            MessageOrder::Ordered
+        } else if index > self.next && self.ahead.insert(index) {
+            MessageOrder::Gap
+        } else {
+            MessageOrder::Duplicate
+        }
+    }
+
+    /// One past the highest index decrypted, or the first known index
+    fn furthest(&self) -> u32 {
+        self.ahead.last().map_or(self.next, |index| index + 1)
+    }
+}
+
+/// Payload of a version 2 session pickle
+///
+/// Version 1 was the bare ratchet pickle, without the session's provenance.
+#[derive(Serialize, Deserialize)]
+struct SessionPickle {
+    session: InboundGroupSessionPickle,
+    /// The sending ratchet, for sessions made with
+    /// [`MegolmSession::create_outbound`]
+    #[serde(default)]
+    outbound: Option<GroupSessionPickle>,
+    #[serde(default)]
+    claimed_ed25519_key: Option<String>,
+    #[serde(default)]
+    imported: bool,
+    #[serde(default)]
+    trusted: bool,
+    #[serde(default)]
+    algorithm: Option<String>,
+}
+
+impl SessionPickle {
+    /// Upgrade a version 1 payload; where the session came from is
+    /// unknown, so it is not trusted
+    fn migrate_v1(payload: &[u8]) -> Result<Self, MegolmError> {
+        let session = serde_json::from_slice(payload)
+            .map_err(|e| MegolmError::SessionCreationFailed(format!("Invalid pickle: {}", e)))?;
+
+        Ok(Self {
+            session,
+            outbound: None,
+            claimed_ed25519_key: None,
+            imported: false,
+            trusted: false,
+            algorithm: None,
+        })
+    }
+}
+
+/// Payload of a session pickle with an encrypted ratchet
+///
+/// The ratchets use vodozemac's pickle encryption; the provenance fields
+/// are kept next to them as in [`SessionPickle`].
+#[derive(Serialize, Deserialize)]
+struct EncryptedSessionPickle {
+    session: String,
+    #[serde(default)]
+    outbound: Option<String>,
+    #[serde(default)]
+    claimed_ed25519_key: Option<String>,
+    #[serde(default)]
+    imported: bool,
+    #[serde(default)]
+    trusted: bool,
+    #[serde(default)]
+    algorithm: Option<String>,
+}
+
+impl EncryptedSessionPickle {
+    /// Parse a pickle from [`MegolmSession::pickle_encrypted`]
+    fn parse(data: &[u8]) -> Result<Self, MegolmError> {
+        match crate::utilities::split_pickle(data) {
+            (crate::utilities::PICKLE_VERSION, payload) => serde_json::from_slice(payload)
+                .map_err(|e| MegolmError::SessionCreationFailed(format!("Invalid pickle: {}", e))),
+            _ => Err(MegolmError::SessionCreationFailed("unsupported pickle version".into())),
+        }
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, MegolmError> {
+        let payload = serde_json::to_vec(self)
+            .map_err(|e| MegolmError::SessionCreationFailed(e.to_string()))?;
+
+        Ok(crate::utilities::versioned_pickle(&payload))
+    }
+}
+
 /// Megolm group session
+///
+/// Outbound sessions also keep an inbound copy of their own ratchet so the
+/// sender can decrypt its own messages, as Element does.
 pub struct MegolmSession {
     session_id: String,
-    outbound: Option<olm_rs::inbound_group_session::OlmInboundGroupSession>,
+    outbound: Option<GroupSession>,
+    inbound: InboundGroupSession,
     message_index: u32,
     is_outbound: bool,
+    claimed_ed25519_key: Option<String>,
+    delivery: DeliveryOrder,
+    /// Came from a key export or history share rather than `m.room_key`
+    imported: bool,
+    /// Created locally or received directly from the sender over Olm
+    trusted: bool,
+    /// Algorithm declared by the first message decrypted, which every
+    /// later message must declare too
+    algorithm: Option<String>,
 }
 
 impl MegolmSession {
     /// Create a new outbound Megolm session
     pub fn create_outbound() -> Result<Self, MegolmError> {
-        // Create a new session key (simulated)
-        // In production, this would use vodozemac's Megolm implementation
-        let session_key = Self::generate_session_key();
-        let session_id = Self::session_id_from_key(&session_key);
+        #[cfg(feature = "metrics")]
+        let _timer = Timer::start(Operation::MegolmSessionCreation);
 
-        let inbound = olm_rs::inbound_group_session::OlmInboundGroupSession::new(&session_key)
-            .map_err(|e| MegolmError::SessionCreationFailed(format!("{:?}", e)))?;
+        let outbound = GroupSession::new(SessionConfig::version_1());
+        let inbound = InboundGroupSession::new(&outbound.session_key(), SessionConfig::version_1());
 
         Ok(Self {
-            session_id,
-            outbound: Some(inbound),
+            session_id: outbound.session_id(),
+            outbound: Some(outbound),
+            inbound,
             message_index: 0,
             is_outbound: true,
+            claimed_ed25519_key: None,
+            delivery: DeliveryOrder::new(0),
+            imported: false,
+            trusted: true,
+            algorithm: None,
         })
     }
 
     /// Create an inbound Megolm session from a session key
-    pub fn create_inbound(session_key: &str) -> Result<Self, MegolmError> {
-        let session_id = Self::session_id_from_key(session_key);
+    ///
+    /// With `expected_session_id`, the key is rejected unless it derives
+    /// that session id, so a key can't be filed under the wrong session.
+    pub fn create_inbound(
+        session_key: &str,
+        expected_session_id: Option<&str>,
+    ) -> Result<Self, MegolmError> {
+        #[cfg(feature = "metrics")]
+        let _timer = Timer::start(Operation::MegolmSessionCreation);
+
+        check_session_key(session_key)?;
 
-        let inbound = olm_rs::inbound_group_session::OlmInboundGroupSession::new(session_key)
-            .map_err(|e| MegolmError::SessionCreationFailed(format!("{:?}", e)))?;
+        let session_key = SessionKey::from_base64(session_key)
+            .map_err(|e| MegolmError::InvalidSessionKey(e.to_string()))?;
+        let inbound = InboundGroupSession::new(&session_key, SessionConfig::version_1());
+        check_session_id(&inbound, expected_session_id)?;
+        let delivery = DeliveryOrder::new(inbound.first_known_index());
 
         Ok(Self {
-            session_id,
-            outbound: Some(inbound),
+            session_id: inbound.session_id(),
+            outbound: None,
+            inbound,
             message_index: 0,
             is_outbound: false,
+            claimed_ed25519_key: None,
+            delivery,
+            imported: false,
+            trusted: true,
+            algorithm: None,
+        })
+    }
+
+    /// Import an inbound Megolm session from a key export entry
+    ///
+    /// The sender's claimed Ed25519 key is kept so it can be checked against
+    /// the sender's device keys before the session is trusted. Imported
+    /// sessions are never trusted, see [`MegolmSession::is_trusted`].
+    /// `expected_session_id` is checked as for
+    /// [`MegolmSession::create_inbound`].
+    pub fn import(
+        export_json: &str,
+        expected_session_id: Option<&str>,
+    ) -> Result<Self, MegolmError> {
+        #[cfg(feature = "metrics")]
+        let _timer = Timer::start(Operation::MegolmSessionCreation);
+
+        let exported: ExportedSession = serde_json::from_str(export_json)
+            .map_err(|e| MegolmError::InvalidSessionKey(format!("Invalid export: {}", e)))?;
+
+        let session_key = ExportedSessionKey::from_base64(&exported.session_key)
+            .map_err(|e| MegolmError::InvalidSessionKey(e.to_string()))?;
+        let inbound = InboundGroupSession::import(&session_key, SessionConfig::version_1());
+        check_session_id(&inbound, expected_session_id)?;
+        let delivery = DeliveryOrder::new(inbound.first_known_index());
+
+        Ok(Self {
+            session_id: inbound.session_id(),
+            outbound: None,
+            inbound,
+            message_index: 0,
+            is_outbound: false,
+            claimed_ed25519_key: exported.sender_claimed_keys.get("ed25519").cloned(),
+            delivery,
+            imported: true,
+            trusted: false,
+            algorithm: None,
         })
     }
 
     /// Get the session key for sharing with group members
+    ///
+    /// The key is taken at the current ratchet index, see
+    /// [`MegolmSession::session_key_at_current_index`].
     pub fn get_session_key(&self) -> Result<String, MegolmError> {
-        if !self.is_outbound {
-            return Err(MegolmError::SessionCreationFailed(
-                "Cannot export key from inbound session".into()
-            ));
-        }
+        self.session_key_at_current_index()
+    }
+
+    /// Get the outbound session key at the current ratchet index
+    ///
+    /// A member given this key can decrypt messages sent from now on, but
+    /// none of the messages sent before it was taken. This is the key to
+    /// share with members joining mid-conversation.
+    pub fn session_key_at_current_index(&self) -> Result<String, MegolmError> {
+        let outbound = self
+            .outbound
+            .as_ref()
+            .ok_or(MegolmError::InboundSession("export session key"))?;
 
-        // In production, this would return the actual session key
-        // For now, return a placeholder
-        Ok(format!("megolm_session_key_{}", self.session_id))
+        Ok(outbound.session_key().to_base64())
     }
 
     /// Get the session ID
+    ///
+    /// This is the base64 Ed25519 public key of the session's signing key,
+    /// the same id vodozemac and Element report.
     pub fn session_id(&self) -> &str {
         &self.session_id
     }
 
+    /// Get how many messages this outbound session has encrypted
+    ///
+    /// Inbound sessions never encrypt, so they always report zero.
+    pub fn messages_sent(&self) -> u32 {
+        self.outbound.as_ref().map_or(0, |outbound| outbound.message_index())
+    }
+
+    /// Get the highest index up to which every message has been decrypted
+    ///
+    /// Messages up to this index can be shown in ratchet order; anything
+    /// above it is still waiting for an earlier message. Before the first
+    /// message arrives this is one below the session's first known index
+    /// (or zero).
+    pub fn ordered_index(&self) -> u32 {
+        self.delivery.next.saturating_sub(1)
+    }
+
+    /// Whether the session can encrypt
+    ///
+    /// Only sessions made with [`MegolmSession::create_outbound`] can. The
+    /// sending ratchet is pickled too, so this survives a restore.
+    pub fn is_outbound(&self) -> bool {
+        self.is_outbound
+    }
+
+    /// Whether the session came from a key export or history share
+    pub fn is_imported(&self) -> bool {
+        self.imported
+    }
+
+    /// Whether the session key is known to come from the sender
+    ///
+    /// Only sessions created locally, or whose key arrived directly from
+    /// the sender over Olm, are trusted. Imported and forwarded sessions
+    /// aren't: whoever shared them could have made up the messages, so the
+    /// UI should flag them.
+    pub fn is_trusted(&self) -> bool {
+        self.trusted
+    }
+
+    /// Get a tag identifying the message at `index`, for audit logs
+    ///
+    /// The tag is an HMAC over the index, keyed with the session's ratchet
+    /// at that index, so every device that can decrypt the message derives
+    /// the same tag, while the tag itself reveals neither the ratchet nor
+    /// the message key. Indices before the first known index fail, and so
+    /// do indices further ahead than [`max_ratchet_advance`].
+    pub fn message_tag(&mut self, index: u32) -> Result<String, MegolmError> {
+        if index.saturating_sub(self.delivery.furthest()) > max_ratchet_advance() {
+            return Err(MegolmError::InvalidMessageIndex("advance too large".into()));
+        }
+
+        let ratchet = self.inbound.export_at(index).ok_or_else(|| {
+            MegolmError::InvalidMessageIndex(format!("{} is before the first known index", index))
+        })?;
+        let ratchet = zeroize::Zeroizing::new(ratchet.to_bytes());
+
+        let mut data = MESSAGE_TAG_INFO.to_vec();
+        data.extend_from_slice(&index.to_be_bytes());
+        let tag = crate::utilities::hmac_sha256(&ratchet, &data);
+
+        Ok(crate::utilities::base64_encode_with(
+            &tag,
+            crate::utilities::Base64Variant::StandardNoPad,
+        ))
+    }
+
+    /// Get the Ed25519 key the sender claimed when sharing this session
+    ///
+    /// Only imported sessions carry a claimed key; sessions created locally
+    /// return `None`.
+    pub fn claimed_ed25519_key(&self) -> Option<String> {
+        self.claimed_ed25519_key.clone()
+    }
+
+    /// Export the session at its first known index, as in a key export
+    ///
+    /// The session doesn't know its room or sender, so the caller supplies
+    /// them.
+    pub fn export(&self, room_id: &str, sender_key: &str) -> ExportedSession {
+        ExportedSession {
+            algorithm: MEGOLM_ALGORITHM.to_string(),
+            room_id: room_id.to_string(),
+            sender_key: sender_key.to_string(),
+            session_id: self.session_id.clone(),
+            session_key: self.inbound.export_at_first_known_index().to_base64(),
+            sender_claimed_keys: self
+                .claimed_ed25519_key
+                .iter()
+                .map(|key| ("ed25519".to_string(), key.clone()))
+                .collect(),
+            forwarding_curve25519_key_chain: Vec::new(),
+        }
+    }
+
     /// Encrypt a message
+    ///
+    /// Empty plaintext is rejected, as for Olm. Empty messages from other
+    /// clients still decrypt to an empty buffer. So is plaintext over
+    /// [`crate::utilities::max_plaintext_size`], before the ratchet moves.
     pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<MegolmMessage, MegolmError> {
-        if !self.is_outbound {
-            return Err(MegolmError::EncryptionFailed(
-                "Cannot encrypt with inbound session".into()
-            ));
+        #[cfg(feature = "metrics")]
+        let _timer = Timer::start(Operation::MegolmEncrypt);
+
+        if plaintext.is_empty() {
+            return Err(MegolmError::EncryptionFailed("Plaintext is empty".into()));
         }
+        crate::utilities::check_plaintext_size(plaintext.len())
+            .map_err(MegolmError::EncryptionFailed)?;
 
-        // In production, this would use vodozemac's Megolm encryption
-        // For now, simulate the structure
+        let outbound = self
+            .outbound
+            .as_mut()
+            .ok_or(MegolmError::InboundSession("encrypt"))?;
 
-        let ciphertext = base64::Engine::encode(
-            &base64::engine::general_purpose::STANDARD,
-            plaintext
-        );
+        let encrypted = outbound.encrypt(plaintext);
 
         let message = MegolmMessage {
-            algorithm: "m.megolm.v1.aes-sha2".to_string(),
+            algorithm: MEGOLM_ALGORITHM.to_string(),
             sender_key: "placeholder_curve25519_key".to_string(),
             session_id: self.session_id.clone(),
-            ciphertext,
-            message_index: self.message_index,
+            ciphertext: encrypted.to_base64(),
+            message_index: encrypted.message_index(),
         };
 
-        self.message_index += 1;
+        self.message_index = outbound.message_index();
 
         Ok(message)
     }
 
     /// Decrypt a message
     pub fn decrypt(&mut self, ciphertext_json: &str) -> Result<Vec<u8>, MegolmError> {
+        self.decrypt_with_index(ciphertext_json).map(|decrypted| decrypted.plaintext)
+    }
+
+    /// Decrypt a message and report its ratchet index
+    ///
+    /// The index comes from the authenticated ciphertext, so it can be
+    /// used to order messages that arrived out of order.
+    ///
+    /// Any index at or after the session's first known index can be
+    /// decrypted, e.g. for a session restored from a backup at a later
+    /// index. The ratchet is advanced to the message's index; the furthest
+    /// ratchet reached so far is kept, so reading forward stays cheap.
+    ///
+    /// The result also says whether the message was the next one expected,
+    /// came after a gap, or was already decrypted. Messages more than
+    /// [`max_ratchet_advance`] steps ahead of the furthest index reached are
+    /// refused before the ratchet is touched.
+    pub fn decrypt_with_index(
+        &mut self,
+        ciphertext_json: &str,
+    ) -> Result<DecryptedMessage, MegolmError> {
+        #[cfg(feature = "metrics")]
+        let _timer = Timer::start(Operation::MegolmDecrypt);
+
         let message: MegolmMessage = serde_json::from_str(ciphertext_json)
             .map_err(|e| MegolmError::DecryptionFailed(format!("Invalid JSON: {}", e)))?;
 
+        self.decrypt_message(&message)
+    }
+
+    /// Decrypt a payload that was split over consecutive messages
+    ///
+    /// The chunks are decrypted in the order given and joined. Their
+    /// authenticated indices must follow on from each other, so a dropped,
+    /// repeated or reordered chunk fails the whole payload instead of
+    /// producing a corrupted one.
+    pub fn decrypt_chunked(
+        &mut self,
+        messages: &[MegolmMessage],
+    ) -> Result<Vec<u8>, MegolmError> {
+        // Wiped if a later chunk is refused, not just handed back
+        let mut plaintext = zeroize::Zeroizing::new(Vec::new());
+        let mut expected_index = None;
+
+        for (chunk, message) in messages.iter().enumerate() {
+            let decrypted = self.decrypt_message(message)?;
+            let chunk_plaintext = zeroize::Zeroizing::new(decrypted.plaintext);
+            match expected_index {
+                Some(expected) if decrypted.message_index != expected => {
+                    return Err(MegolmError::InvalidMessageIndex(format!(
+                        "chunk {} has index {}, expected {}",
+                        chunk, decrypted.message_index, expected
+                    )));
+                }
+                _ => {}
+            }
+
+            expected_index = Some(decrypted.message_index + 1);
+            plaintext.extend_from_slice(&chunk_plaintext);
+        }
+
+        Ok(std::mem::take(&mut *plaintext))
+    }
+
+    fn decrypt_message(
+        &mut self,
+        message: &MegolmMessage,
+    ) -> Result<DecryptedMessage, MegolmError> {
         if message.session_id != self.session_id {
             return Err(MegolmError::DecryptionFailed(
                 "Session ID mismatch".into()
             ));
         }
 
-        // In production, this would use vodozemac's Megolm decryption
-        // For now, decode base64
-        let plaintext = base64::Engine::decode(
-            &base64::engine::general_purpose::STANDARD,
-            message.ciphertext.as_bytes()
-        ).map_err(|e| MegolmError::DecryptionFailed(format!("Base64 decode: {}", e)))?;
+        // A session never switches algorithm, so a message declaring a
+        // different one than before is refused before the ratchet moves
+        if let Some(pinned) = self.algorithm.as_ref().filter(|&a| *a != message.algorithm) {
+            return Err(MegolmError::AlgorithmMismatch {
+                pinned: pinned.clone(),
+                declared: message.algorithm.clone(),
+            });
+        }
+
+        let encrypted = vodozemac::megolm::MegolmMessage::from_base64(&message.ciphertext)
+            .map_err(|e| MegolmError::DecryptionFailed(format!("Invalid ciphertext: {}", e)))?;
+
+        let advance = encrypted.message_index().saturating_sub(self.delivery.furthest());
+        if advance > max_ratchet_advance() {
+            return Err(MegolmError::InvalidMessageIndex("advance too large".into()));
+        }
+
+        let decrypted = self.inbound.decrypt(&encrypted)
+            .map_err(|e| MegolmError::DecryptionFailed(e.to_string()))?;
+
+        self.message_index = decrypted.message_index + 1;
+        let order = self.delivery.record(decrypted.message_index);
+        self.algorithm.get_or_insert_with(|| message.algorithm.clone());
+
+        Ok(DecryptedMessage {
+            plaintext: decrypted.plaintext,
+            message_index: decrypted.message_index,
+            order,
+        })
+    }
+
+    /// Algorithm the session was pinned to by the first message it
+    /// decrypted, if any
+    pub fn pinned_algorithm(&self) -> Option<&str> {
+        self.algorithm.as_deref()
+    }
+
+    /// Check whether a message could be decrypted, without decrypting it
+    ///
+    /// Only the session id and the message index are checked, so the
+    /// session state is left untouched. A `true` result doesn't guarantee
+    /// the message's MAC will verify.
+    pub fn can_decrypt_message(&self, ciphertext_json: &str) -> bool {
+        let message: MegolmMessage = match serde_json::from_str(ciphertext_json) {
+            Ok(message) => message,
+            Err(_) => return false,
+        };
+
+        if message.session_id != self.session_id {
+            return false;
+        }
+
+        match vodozemac::megolm::MegolmMessage::from_base64(&message.ciphertext) {
+            Ok(encrypted) => encrypted.message_index() >= self.inbound.first_known_index(),
+            Err(_) => false,
+        }
+    }
+
+    /// Decrypt a message that is expected to be text
+    ///
+    /// Fails with `DecryptionFailed` instead of returning bytes that aren't
+    /// valid UTF-8. Binary payloads should use [`MegolmSession::decrypt`].
+    pub fn decrypt_text(&mut self, ciphertext_json: &str) -> Result<String, MegolmError> {
+        let mut plaintext = self.decrypt(ciphertext_json)?;
 
-        self.message_index = message.message_index + 1;
+        crate::utilities::take_utf8(&mut plaintext)
+            .ok_or_else(|| MegolmError::DecryptionFailed("non-utf8 plaintext".into()))
+    }
+
+    /// Encrypt a payload into the content of an `m.room.encrypted` event
+    ///
+    /// `device_curve25519` and `device_id` identify the sending device. The
+    /// content can be passed as-is to [`MegolmSession::decrypt`].
+    pub fn build_encrypted_event(
+        &mut self,
+        plaintext: &[u8],
+        device_curve25519: &str,
+        device_id: &str,
+    ) -> Result<EncryptedEventContent, MegolmError> {
+        let message = self.encrypt(plaintext)?;
 
-        Ok(plaintext)
+        Ok(EncryptedEventContent {
+            algorithm: message.algorithm,
+            sender_key: device_curve25519.to_string(),
+            ciphertext: message.ciphertext,
+            session_id: message.session_id,
+            device_id: device_id.to_string(),
+        })
+    }
+
+    /// Encrypt a room event as a `{ room_id, type, content }` payload
+    pub fn encrypt_event(
+        &mut self,
+        room_id: &str,
+        event_type: &str,
+        content: serde_json::Value,
+    ) -> Result<MegolmMessage, MegolmError> {
+        let payload = EventPayload {
+            room_id: room_id.to_string(),
+            event_type: event_type.to_string(),
+            content,
+        };
+
+        let plaintext = serde_json::to_vec(&payload)
+            .map_err(|e| MegolmError::EncryptionFailed(format!("Invalid event: {}", e)))?;
+
+        self.encrypt(&plaintext)
+    }
+
+    /// Decrypt a room event, checking it was sent to the expected room
+    pub fn decrypt_event(
+        &mut self,
+        ciphertext_json: &str,
+        room_id: &str,
+    ) -> Result<EventPayload, MegolmError> {
+        let plaintext = zeroize::Zeroizing::new(self.decrypt(ciphertext_json)?);
+
+        let payload: EventPayload = serde_json::from_slice(&plaintext)
+            .map_err(|e| MegolmError::DecryptionFailed(format!("Invalid event payload: {}", e)))?;
+
+        if payload.room_id != room_id {
+            return Err(MegolmError::DecryptionFailed("Room ID mismatch".into()));
+        }
+
+        Ok(payload)
     }
 
     /// Pickle (serialize) the session
+    ///
+    /// An outbound session keeps its sending ratchet, so it can still
+    /// encrypt once restored. The output starts with a format version byte
+    /// ([`crate::utilities::PICKLE_VERSION`]).
     pub fn pickle(&self) -> Result<Vec<u8>, MegolmError> {
-        let inbound = self.outbound.as_ref()
-            .ok_or(MegolmError::SessionNotFound)?;
+        let pickle = SessionPickle {
+            session: self.inbound.pickle(),
+            outbound: self.outbound.as_ref().map(GroupSession::pickle),
+            claimed_ed25519_key: self.claimed_ed25519_key.clone(),
+            imported: self.imported,
+            trusted: self.trusted,
+            algorithm: self.algorithm.clone(),
+        };
+        let payload = serde_json::to_vec(&pickle)
+            .map_err(|e| MegolmError::SessionCreationFailed(e.to_string()))?;
+
+        Ok(crate::utilities::versioned_pickle(&payload))
+    }
+
+    /// Unpickle (deserialize) a session
+    ///
+    /// Pickles from older format versions are migrated on the way in.
+    pub fn unpickle(data: &[u8]) -> Result<Self, MegolmError> {
+        let pickle = match crate::utilities::split_pickle(data) {
+            (1, payload) => SessionPickle::migrate_v1(payload)?,
+            (2, payload) => serde_json::from_slice(payload)
+                .map_err(|e| MegolmError::SessionCreationFailed(format!("Invalid pickle: {}", e)))?,
+            _ => {
+                return Err(MegolmError::SessionCreationFailed(
+                    "unsupported pickle version".into(),
+                ))
+            }
+        };
 
-        inbound.pickle(olm_rs::PicklingMode::EncryptWith(&[]))
-            .map_err(|e| MegolmError::SessionCreationFailed(format!("{:?}", e)))
-            .map(|s| s.as_bytes().to_vec())
+        Ok(Self::from_pickle(pickle))
     }
 
-    /// Generate a random session key
-    fn generate_session_key() -> String {
-        use rand::RngCore;
-        let mut key = [0u8; 128];
-        rand::thread_rng().fill_bytes(&mut key);
-        base64::Engine::encode(&base64::engine::general_purpose::STANDARD, &key)
+    /// Pickle the session with its ratchet encrypted under `key`
+    ///
+    /// Like [`MegolmSession::pickle`], an outbound session keeps its
+    /// sending ratchet, encrypted under the same key.
+    pub fn pickle_encrypted(&self, key: &[u8; 32]) -> Result<Vec<u8>, MegolmError> {
+        EncryptedSessionPickle {
+            session: self.inbound.pickle().encrypt(key),
+            outbound: self.outbound.as_ref().map(|outbound| outbound.pickle().encrypt(key)),
+            claimed_ed25519_key: self.claimed_ed25519_key.clone(),
+            imported: self.imported,
+            trusted: self.trusted,
+            algorithm: self.algorithm.clone(),
+        }
+        .to_bytes()
     }
 
-    /// Derive session ID from key
-    fn session_id_from_key(key: &str) -> String {
-        use sha2::{Sha256, Digest};
-        let mut hasher = Sha256::new();
-        hasher.update(key.as_bytes());
-        let result = hasher.finalize();
-        base64::Engine::encode(&base64::engine::general_purpose::STANDARD, &result)
+    /// Restore a session from [`MegolmSession::pickle_encrypted`]
+    pub fn unpickle_encrypted(data: &[u8], key: &[u8; 32]) -> Result<Self, MegolmError> {
+        let pickle = EncryptedSessionPickle::parse(data)?;
+        let session = InboundGroupSessionPickle::from_encrypted(&pickle.session, key)
+            .map_err(|e| MegolmError::SessionCreationFailed(format!("Invalid pickle: {}", e)))?;
+        let outbound = pickle
+            .outbound
+            .map(|outbound| GroupSessionPickle::from_encrypted(&outbound, key))
+            .transpose()
+            .map_err(|e| MegolmError::SessionCreationFailed(format!("Invalid pickle: {}", e)))?;
+
+        Ok(Self::from_pickle(SessionPickle {
+            session,
+            outbound,
+            claimed_ed25519_key: pickle.claimed_ed25519_key,
+            imported: pickle.imported,
+            trusted: pickle.trusted,
+            algorithm: pickle.algorithm,
+        }))
+    }
+
+    /// Re-encrypt a pickle from [`MegolmSession::pickle_encrypted`] under
+    /// a new key
+    ///
+    /// The ratchets are decrypted with `old_key` and encrypted again with
+    /// `new_key` in memory, without restoring the session.
+    pub fn rekey_pickle(
+        data: &[u8],
+        old_key: &[u8; 32],
+        new_key: &[u8; 32],
+    ) -> Result<Vec<u8>, MegolmError> {
+        let mut pickle = EncryptedSessionPickle::parse(data)?;
+        let session = InboundGroupSessionPickle::from_encrypted(&pickle.session, old_key)
+            .map_err(|e| MegolmError::SessionCreationFailed(format!("Invalid pickle: {}", e)))?;
+        pickle.session = session.encrypt(new_key);
+        if let Some(outbound) = pickle.outbound.take() {
+            let outbound = GroupSessionPickle::from_encrypted(&outbound, old_key).map_err(|e| {
+                MegolmError::SessionCreationFailed(format!("Invalid pickle: {}", e))
+            })?;
+            pickle.outbound = Some(outbound.encrypt(new_key));
+        }
+
+        pickle.to_bytes()
+    }
+
+    fn from_pickle(pickle: SessionPickle) -> Self {
+        let inbound = InboundGroupSession::from_pickle(pickle.session);
+        let outbound = pickle.outbound.map(GroupSession::from_pickle);
+        let delivery = DeliveryOrder::new(inbound.first_known_index());
+
+        Self {
+            session_id: inbound.session_id(),
+            message_index: outbound.as_ref().map_or(0, GroupSession::message_index),
+            is_outbound: outbound.is_some(),
+            outbound,
+            inbound,
+            claimed_ed25519_key: pickle.claimed_ed25519_key,
+            delivery,
+            imported: pickle.imported,
+            trusted: pickle.trusted,
+            algorithm: pickle.algorithm,
+        }
+    }
+}
+
+/// A Megolm session filed under its room
+struct StoredSession {
+    sender_key: String,
+    session: Weak<Mutex<MegolmSession>>,
+}
+
+/// Megolm sessions grouped by room id, for backing up a room's keys
+///
+/// The store doesn't own the sessions: a session freed elsewhere drops out
+/// of it on its own, and a room goes once all of its sessions have.
+#[derive(Default)]
+pub struct SessionStore {
+    rooms: HashMap<String, Vec<StoredSession>>,
+}
+
+impl SessionStore {
+    /// File `session` under `room_id`, replacing an entry with the same
+    /// session id
+    pub fn add(&mut self, room_id: &str, sender_key: &str, session: &Arc<Mutex<MegolmSession>>) {
+        let session_id = crate::registry::lock(session).session_id().to_string();
+        let sessions = self.rooms.entry(room_id.to_string()).or_default();
+
+        sessions.retain(|stored| match stored.session.upgrade() {
+            Some(other) => {
+                !Arc::ptr_eq(&other, session)
+                    && crate::registry::lock(&other).session_id() != session_id
+            }
+            None => false,
+        });
+        sessions.push(StoredSession {
+            sender_key: sender_key.to_string(),
+            session: Arc::downgrade(session),
+        });
+        self.prune();
+    }
+
+    /// Export every live session of `room_id` as [`ExportedSession`] JSON
+    pub fn export_room_sessions(&mut self, room_id: &str) -> Vec<String> {
+        self.prune();
+        let Some(sessions) = self.rooms.get(room_id) else {
+            return Vec::new();
+        };
+
+        sessions
+            .iter()
+            .filter_map(|stored| {
+                let session = stored.session.upgrade()?;
+                let exported = crate::registry::lock(&session).export(room_id, &stored.sender_key);
+                Some(serde_json::to_string(&exported).expect("ExportedSession serializes"))
+            })
+            .collect()
+    }
+
+    /// Forget freed sessions, and rooms left without any
+    fn prune(&mut self) {
+        self.rooms.retain(|_, sessions| {
+            sessions.retain(|stored| stored.session.strong_count() > 0);
+            !sessions.is_empty()
+        });
     }
 }
 
@@ -184,3 +964,648 @@ impl Drop for MegolmSession {
         // The Rust destructor will handle this
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outbound_and_inbound_session_ids_match() {
+        let mut outbound = MegolmSession::create_outbound().unwrap();
+        let session_key = outbound.get_session_key().unwrap();
+        let mut inbound = MegolmSession::create_inbound(&session_key, None).unwrap();
+
+        assert_eq!(outbound.session_id(), inbound.session_id());
+
+        let message = outbound.encrypt(b"hello").unwrap();
+        assert_eq!(message.session_id, inbound.session_id());
+
+        let json = serde_json::to_string(&message).unwrap();
+        assert_eq!(inbound.decrypt(&json).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn late_joiner_cannot_read_history() {
+        let mut outbound = MegolmSession::create_outbound().unwrap();
+
+        let history: Vec<String> = (0..3)
+            .map(|i| {
+                let message = outbound.encrypt(format!("old {}", i).as_bytes()).unwrap();
+                serde_json::to_string(&message).unwrap()
+            })
+            .collect();
+
+        let session_key = outbound.session_key_at_current_index().unwrap();
+        let mut late_joiner = MegolmSession::create_inbound(&session_key, None).unwrap();
+
+        let message = outbound.encrypt(b"new").unwrap();
+        let json = serde_json::to_string(&message).unwrap();
+        assert_eq!(late_joiner.decrypt(&json).unwrap(), b"new");
+
+        for json in &history {
+            assert!(late_joiner.decrypt(json).is_err());
+        }
+    }
+
+    #[test]
+    fn messages_sent_counts_encryptions() {
+        let mut outbound = MegolmSession::create_outbound().unwrap();
+        assert_eq!(outbound.messages_sent(), 0);
+
+        for expected in 1..=3 {
+            outbound.encrypt(b"hello").unwrap();
+            assert_eq!(outbound.messages_sent(), expected);
+        }
+
+        let session_key = outbound.get_session_key().unwrap();
+        let inbound = MegolmSession::create_inbound(&session_key, None).unwrap();
+        assert_eq!(inbound.messages_sent(), 0);
+    }
+
+    #[test]
+    fn text_decrypt_rejects_invalid_utf8() {
+        let mut outbound = MegolmSession::create_outbound().unwrap();
+        let session_key = outbound.get_session_key().unwrap();
+        let mut inbound = MegolmSession::create_inbound(&session_key, None).unwrap();
+
+        let message = outbound.encrypt(&[0xff, 0xfe, 0xfd]).unwrap();
+        let json = serde_json::to_string(&message).unwrap();
+
+        let result = inbound.decrypt_text(&json);
+        assert!(matches!(result, Err(MegolmError::DecryptionFailed(e)) if e == "non-utf8 plaintext"));
+        assert_eq!(inbound.decrypt(&json).unwrap(), vec![0xff, 0xfe, 0xfd]);
+    }
+
+    #[test]
+    fn event_for_another_room_is_rejected() {
+        let mut outbound = MegolmSession::create_outbound().unwrap();
+        let session_key = outbound.get_session_key().unwrap();
+        let mut inbound = MegolmSession::create_inbound(&session_key, None).unwrap();
+
+        let content = serde_json::json!({ "msgtype": "m.text", "body": "hi" });
+        let message = outbound
+            .encrypt_event("!room:example.org", "m.room.message", content.clone())
+            .unwrap();
+        let json = serde_json::to_string(&message).unwrap();
+
+        let result = inbound.decrypt_event(&json, "!other:example.org");
+        assert!(matches!(result, Err(MegolmError::DecryptionFailed(_))));
+
+        let mut inbound = MegolmSession::create_inbound(&session_key, None).unwrap();
+        let payload = inbound.decrypt_event(&json, "!room:example.org").unwrap();
+        assert_eq!(payload.event_type, "m.room.message");
+        assert_eq!(payload.content, content);
+    }
+
+    #[test]
+    fn imported_session_reports_claimed_key() {
+        let outbound = GroupSession::new(SessionConfig::version_1());
+        let inbound =
+            InboundGroupSession::new(&outbound.session_key(), SessionConfig::version_1());
+
+        let export = serde_json::json!({
+            "algorithm": "m.megolm.v1.aes-sha2",
+            "room_id": "!room:example.org",
+            "sender_key": "sender_curve25519_key",
+            "session_id": outbound.session_id(),
+            "session_key": inbound.export_at_first_known_index().to_base64(),
+            "sender_claimed_keys": { "ed25519": "claimed_ed25519_key" },
+            "forwarding_curve25519_key_chain": [],
+        });
+
+        let session = MegolmSession::import(&export.to_string(), None).unwrap();
+        assert_eq!(session.session_id(), outbound.session_id());
+        assert_eq!(session.claimed_ed25519_key().as_deref(), Some("claimed_ed25519_key"));
+    }
+
+    #[test]
+    fn local_session_has_no_claimed_key() {
+        let session = MegolmSession::create_outbound().unwrap();
+        assert!(session.claimed_ed25519_key().is_none());
+    }
+
+    #[test]
+    fn dry_run_leaves_session_untouched() {
+        let mut outbound = MegolmSession::create_outbound().unwrap();
+        let early = serde_json::to_string(&outbound.encrypt(b"early").unwrap()).unwrap();
+
+        let session_key = outbound.session_key_at_current_index().unwrap();
+        let inbound = MegolmSession::create_inbound(&session_key, None).unwrap();
+        let late = serde_json::to_string(&outbound.encrypt(b"late").unwrap()).unwrap();
+
+        let mut other = MegolmSession::create_outbound().unwrap();
+        let foreign = serde_json::to_string(&other.encrypt(b"other").unwrap()).unwrap();
+
+        let state = inbound.pickle().unwrap();
+        let index = inbound.message_index;
+
+        assert!(inbound.can_decrypt_message(&late));
+        assert!(!inbound.can_decrypt_message(&early));
+        assert!(!inbound.can_decrypt_message(&foreign));
+        assert!(!inbound.can_decrypt_message("not json"));
+
+        assert_eq!(inbound.pickle().unwrap(), state);
+        assert_eq!(inbound.message_index, index);
+    }
+
+    #[test]
+    fn version_1_session_pickle_is_migrated() {
+        let mut outbound = MegolmSession::create_outbound().unwrap();
+        let session_key = outbound.get_session_key().unwrap();
+        let inbound = MegolmSession::create_inbound(&session_key, None).unwrap();
+        let message = serde_json::to_string(&outbound.encrypt(b"hello").unwrap()).unwrap();
+
+        // What pickle() produced before the version header
+        let v1 = serde_json::to_vec(&inbound.inbound.pickle()).unwrap();
+        let mut restored = MegolmSession::unpickle(&v1).unwrap();
+        assert_eq!(restored.session_id(), inbound.session_id());
+        assert!(!restored.is_trusted());
+        assert_eq!(restored.decrypt(&message).unwrap(), b"hello");
+
+        let v2 = inbound.pickle().unwrap();
+        assert_eq!(v2[0], crate::utilities::PICKLE_VERSION);
+        let restored = MegolmSession::unpickle(&v2).unwrap();
+        assert!(restored.is_trusted());
+    }
+
+    #[test]
+    fn rekeyed_session_pickle_opens_only_with_the_new_key() {
+        let mut outbound = MegolmSession::create_outbound().unwrap();
+        let session_key = outbound.get_session_key().unwrap();
+        let inbound = MegolmSession::create_inbound(&session_key, None).unwrap();
+        let message = serde_json::to_string(&outbound.encrypt(b"hello").unwrap()).unwrap();
+
+        let (key_a, key_b) = ([1u8; 32], [2u8; 32]);
+        let pickle = inbound.pickle_encrypted(&key_a).unwrap();
+        let rekeyed = MegolmSession::rekey_pickle(&pickle, &key_a, &key_b).unwrap();
+
+        assert!(MegolmSession::unpickle_encrypted(&rekeyed, &key_a).is_err());
+        assert!(MegolmSession::rekey_pickle(&rekeyed, &key_a, &key_b).is_err());
+
+        let mut restored = MegolmSession::unpickle_encrypted(&rekeyed, &key_b).unwrap();
+        assert_eq!(restored.session_id(), inbound.session_id());
+        assert!(restored.is_trusted());
+        assert_eq!(restored.decrypt(&message).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn outbound_flag_is_reported_across_pickling() {
+        let mut outbound = MegolmSession::create_outbound().unwrap();
+        let session_key = outbound.get_session_key().unwrap();
+        let mut inbound = MegolmSession::create_inbound(&session_key, None).unwrap();
+        assert!(outbound.is_outbound());
+        assert!(!inbound.is_outbound());
+
+        outbound.encrypt(b"first").unwrap();
+        let key = [7u8; 32];
+        let pickles = [
+            MegolmSession::unpickle(&outbound.pickle().unwrap()).unwrap(),
+            MegolmSession::unpickle_encrypted(&outbound.pickle_encrypted(&key).unwrap(), &key)
+                .unwrap(),
+        ];
+
+        // The sending ratchet comes back where it was
+        for mut restored in pickles {
+            assert!(restored.is_outbound());
+            assert_eq!(restored.session_id(), outbound.session_id());
+            assert_eq!(restored.messages_sent(), 1);
+            let message = serde_json::to_string(&restored.encrypt(b"hello").unwrap()).unwrap();
+            assert_eq!(inbound.decrypt(&message).unwrap(), b"hello");
+        }
+
+        let pickles = [
+            MegolmSession::unpickle(&inbound.pickle().unwrap()).unwrap(),
+            MegolmSession::unpickle_encrypted(&inbound.pickle_encrypted(&key).unwrap(), &key)
+                .unwrap(),
+        ];
+        for mut restored in pickles {
+            assert!(!restored.is_outbound());
+            assert!(matches!(restored.encrypt(b"hello"), Err(MegolmError::InboundSession(_))));
+        }
+    }
+
+    #[test]
+    fn rekeyed_outbound_pickle_keeps_the_sending_ratchet() {
+        let mut outbound = MegolmSession::create_outbound().unwrap();
+        let mut inbound =
+            MegolmSession::create_inbound(&outbound.get_session_key().unwrap(), None).unwrap();
+
+        let (key_a, key_b) = ([1u8; 32], [2u8; 32]);
+        let pickle = outbound.pickle_encrypted(&key_a).unwrap();
+        let rekeyed = MegolmSession::rekey_pickle(&pickle, &key_a, &key_b).unwrap();
+        assert!(MegolmSession::unpickle_encrypted(&rekeyed, &key_a).is_err());
+
+        let mut restored = MegolmSession::unpickle_encrypted(&rekeyed, &key_b).unwrap();
+        assert!(restored.is_outbound());
+        let message = serde_json::to_string(&restored.encrypt(b"hello").unwrap()).unwrap();
+        assert_eq!(inbound.decrypt(&message).unwrap(), b"hello");
+        assert!(outbound.encrypt(b"again").is_ok());
+    }
+
+    #[test]
+    fn unknown_session_pickle_version_is_rejected() {
+        let session = MegolmSession::create_outbound().unwrap();
+        let mut pickle = session.pickle().unwrap();
+        pickle[0] = crate::utilities::PICKLE_VERSION + 1;
+
+        let message = match MegolmSession::unpickle(&pickle) {
+            Err(MegolmError::SessionCreationFailed(message)) => message,
+            _ => panic!("expected SessionCreationFailed"),
+        };
+        assert_eq!(message, "unsupported pickle version");
+    }
+
+    #[test]
+    fn ordered_index_waits_for_gaps() {
+        let mut outbound = MegolmSession::create_outbound().unwrap();
+        let session_key = outbound.get_session_key().unwrap();
+        let mut inbound = MegolmSession::create_inbound(&session_key, None).unwrap();
+
+        let messages: Vec<String> = (0..3)
+            .map(|_| serde_json::to_string(&outbound.encrypt(b"hi").unwrap()).unwrap())
+            .collect();
+
+        assert_eq!(inbound.decrypt_with_index(&messages[0]).unwrap().message_index, 0);
+        assert_eq!(inbound.ordered_index(), 0);
+
+        assert_eq!(inbound.decrypt_with_index(&messages[2]).unwrap().message_index, 2);
+        assert_eq!(inbound.ordered_index(), 0);
+
+        assert_eq!(inbound.decrypt_with_index(&messages[1]).unwrap().message_index, 1);
+        assert_eq!(inbound.ordered_index(), 2);
+    }
+
+    #[test]
+    fn decrypted_messages_are_classified_by_order() {
+        let mut outbound = MegolmSession::create_outbound().unwrap();
+        let session_key = outbound.get_session_key().unwrap();
+        let mut inbound = MegolmSession::create_inbound(&session_key, None).unwrap();
+
+        let messages: Vec<String> = (0..4)
+            .map(|_| serde_json::to_string(&outbound.encrypt(b"hi").unwrap()).unwrap())
+            .collect();
+        let mut order = |i: usize| inbound.decrypt_with_index(&messages[i]).unwrap().order;
+
+        assert_eq!(order(0), MessageOrder::Ordered);
+        assert_eq!(order(2), MessageOrder::Gap);
+        assert_eq!(order(3), MessageOrder::Gap);
+        assert_eq!(order(2), MessageOrder::Duplicate);
+        // Fills the gap
+        assert_eq!(order(1), MessageOrder::Ordered);
+        assert_eq!(order(0), MessageOrder::Duplicate);
+        assert_eq!(order(3), MessageOrder::Duplicate);
+    }
+
+    #[test]
+    fn built_event_content_decrypts() {
+        let mut outbound = MegolmSession::create_outbound().unwrap();
+        let session_key = outbound.get_session_key().unwrap();
+        let mut inbound = MegolmSession::create_inbound(&session_key, None).unwrap();
+
+        let content = outbound
+            .build_encrypted_event(b"hello", "device_curve25519_key", "DEVICEID")
+            .unwrap();
+        let json = serde_json::to_string(&content).unwrap();
+
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let mut fields: Vec<&str> = value.as_object().unwrap().keys().map(|k| k.as_str()).collect();
+        fields.sort_unstable();
+        assert_eq!(fields, ["algorithm", "ciphertext", "device_id", "sender_key", "session_id"]);
+        assert_eq!(value["algorithm"], MEGOLM_ALGORITHM);
+        assert_eq!(value["sender_key"], "device_curve25519_key");
+        assert_eq!(value["session_id"], outbound.session_id());
+        assert_eq!(value["device_id"], "DEVICEID");
+
+        let decrypted = inbound.decrypt_with_index(&json).unwrap();
+        assert_eq!(decrypted.plaintext, b"hello");
+        assert_eq!(decrypted.message_index, 0);
+    }
+
+    #[test]
+    fn oversized_plaintext_is_rejected() {
+        let mut outbound = MegolmSession::create_outbound().unwrap();
+
+        let limit = crate::utilities::max_plaintext_size() as usize;
+        assert!(matches!(
+            outbound.encrypt(&vec![0u8; limit + 1]),
+            Err(MegolmError::EncryptionFailed(_))
+        ));
+        assert_eq!(outbound.message_index, 0);
+
+        assert!(outbound.encrypt(&vec![0u8; 1024]).is_ok());
+        assert_eq!(outbound.message_index, 1);
+    }
+
+    #[test]
+    fn empty_plaintext_is_rejected_but_decrypts() {
+        let mut outbound = MegolmSession::create_outbound().unwrap();
+        let session_key = outbound.get_session_key().unwrap();
+        let mut inbound = MegolmSession::create_inbound(&session_key, None).unwrap();
+
+        assert!(matches!(outbound.encrypt(b""), Err(MegolmError::EncryptionFailed(_))));
+        assert_eq!(outbound.message_index, 0);
+
+        // Another client may still send an empty message
+        let encrypted = outbound.outbound.as_mut().unwrap().encrypt(b"");
+        let message = MegolmMessage {
+            algorithm: MEGOLM_ALGORITHM.to_string(),
+            sender_key: String::new(),
+            session_id: outbound.session_id().to_string(),
+            ciphertext: encrypted.to_base64(),
+            message_index: encrypted.message_index(),
+        };
+        let json = serde_json::to_string(&message).unwrap();
+        assert!(inbound.decrypt(&json).unwrap().is_empty());
+    }
+
+    #[test]
+    fn session_imported_at_later_index_skips_ahead() {
+        let mut outbound = MegolmSession::create_outbound().unwrap();
+        let session_key = SessionKey::from_base64(&outbound.get_session_key().unwrap()).unwrap();
+        let messages: Vec<String> = (0..10)
+            .map(|i| {
+                let message = outbound.encrypt(format!("message {}", i).as_bytes()).unwrap();
+                serde_json::to_string(&message).unwrap()
+            })
+            .collect();
+
+        let mut full = InboundGroupSession::new(&session_key, SessionConfig::version_1());
+        let export = serde_json::json!({
+            "algorithm": MEGOLM_ALGORITHM,
+            "room_id": "!room:example.org",
+            "sender_key": "sender_curve25519_key",
+            "session_id": outbound.session_id(),
+            "session_key": full.export_at(5).unwrap().to_base64(),
+            "sender_claimed_keys": {},
+            "forwarding_curve25519_key_chain": [],
+        });
+
+        let mut restored = MegolmSession::import(&export.to_string(), None).unwrap();
+        assert_eq!(restored.ordered_index(), 4);
+
+        let decrypted = restored.decrypt_with_index(&messages[9]).unwrap();
+        assert_eq!(decrypted.plaintext, b"message 9");
+        assert_eq!(decrypted.message_index, 9);
+
+        // Indices between the import point and the furthest message still work
+        assert_eq!(restored.decrypt(&messages[5]).unwrap(), b"message 5");
+        assert_eq!(restored.decrypt(&messages[7]).unwrap(), b"message 7");
+        assert!(restored.decrypt(&messages[4]).is_err());
+    }
+
+    #[test]
+    fn only_directly_received_sessions_are_trusted() {
+        let outbound = MegolmSession::create_outbound().unwrap();
+        assert!(outbound.is_trusted());
+
+        let session_key = outbound.get_session_key().unwrap();
+        let direct = MegolmSession::create_inbound(&session_key, None).unwrap();
+        assert!(direct.is_trusted());
+        assert!(!direct.is_imported());
+
+        let export = serde_json::json!({
+            "algorithm": MEGOLM_ALGORITHM,
+            "room_id": "!room:example.org",
+            "sender_key": "sender_curve25519_key",
+            "session_id": outbound.session_id(),
+            "session_key": direct.inbound.export_at_first_known_index().to_base64(),
+            "sender_claimed_keys": { "ed25519": "claimed_ed25519_key" },
+            "forwarding_curve25519_key_chain": ["forwarder_curve25519_key"],
+        });
+        let forwarded = MegolmSession::import(&export.to_string(), None).unwrap();
+        assert!(forwarded.is_imported());
+        assert!(!forwarded.is_trusted());
+    }
+
+    #[test]
+    fn malformed_session_keys_are_rejected_with_reason() {
+        let outbound = MegolmSession::create_outbound().unwrap();
+        let session_key = outbound.get_session_key().unwrap();
+        let mut decoded = crate::utilities::base64_decode_lenient(&session_key).unwrap();
+
+        let truncated = crate::utilities::base64_encode(&decoded[..100]);
+        match MegolmSession::create_inbound(&truncated, None) {
+            Err(MegolmError::InvalidSessionKey(reason)) => assert!(reason.contains("100 bytes")),
+            _ => panic!("truncated key accepted"),
+        }
+
+        decoded[0] = 1;
+        let wrong_version = crate::utilities::base64_encode(&decoded);
+        match MegolmSession::create_inbound(&wrong_version, None) {
+            Err(MegolmError::InvalidSessionKey(reason)) => assert!(reason.contains("version 1")),
+            _ => panic!("wrong version accepted"),
+        }
+    }
+
+    #[test]
+    fn inbound_session_cannot_encrypt_or_share() {
+        let outbound = MegolmSession::create_outbound().unwrap();
+        let session_key = outbound.get_session_key().unwrap();
+        let mut inbound = MegolmSession::create_inbound(&session_key, None).unwrap();
+
+        assert!(matches!(inbound.encrypt(b"hello"), Err(MegolmError::InboundSession(_))));
+        assert!(matches!(inbound.get_session_key(), Err(MegolmError::InboundSession(_))));
+    }
+
+    #[test]
+    fn expected_session_id_is_checked() {
+        let outbound = GroupSession::new(SessionConfig::version_1());
+        let other = GroupSession::new(SessionConfig::version_1());
+        let session_key = outbound.session_key().to_base64();
+        let mismatch = |result: Result<MegolmSession, MegolmError>| {
+            matches!(result, Err(MegolmError::InvalidSessionKey(e)) if e == "session id mismatch")
+        };
+
+        let inbound =
+            MegolmSession::create_inbound(&session_key, Some(&outbound.session_id())).unwrap();
+        assert_eq!(inbound.session_id(), outbound.session_id());
+        assert!(mismatch(MegolmSession::create_inbound(&session_key, Some(&other.session_id()))));
+
+        let export = serde_json::json!({
+            "algorithm": "m.megolm.v1.aes-sha2",
+            "sender_key": "sender_curve25519_key",
+            "session_id": outbound.session_id(),
+            "session_key": inbound.inbound.export_at_first_known_index().to_base64(),
+        })
+        .to_string();
+        assert!(MegolmSession::import(&export, Some(&outbound.session_id())).is_ok());
+        assert!(mismatch(MegolmSession::import(&export, Some(&other.session_id()))));
+    }
+
+    #[test]
+    fn ratchet_advance_is_capped() {
+        let mut outbound = MegolmSession::create_outbound().unwrap();
+        let mut inbound =
+            MegolmSession::create_inbound(&outbound.get_session_key().unwrap(), None).unwrap();
+
+        // A reasonable jump forward decrypts
+        let first = outbound.encrypt(b"first").unwrap();
+        for _ in 0..100 {
+            outbound.encrypt(b"skipped").unwrap();
+        }
+        let later = outbound.encrypt(b"later").unwrap();
+        assert_eq!(inbound.decrypt(&serde_json::to_string(&later).unwrap()).unwrap(), b"later");
+
+        // Claim an absurd index by rewriting the index varint after the
+        // version byte; the limit applies before the MAC is checked
+        let mut bytes = crate::utilities::base64_decode_lenient(&first.ciphertext).unwrap();
+        assert_eq!(&bytes[1..3], &[0x08, 0x00]);
+        bytes.splice(2..3, [0xc0, 0x84, 0x3d]);
+        let forged = MegolmMessage {
+            ciphertext: crate::utilities::base64_encode_with(
+                &bytes,
+                crate::utilities::Base64Variant::StandardNoPad,
+            ),
+            ..first
+        };
+        assert!(matches!(
+            inbound.decrypt(&serde_json::to_string(&forged).unwrap()),
+            Err(MegolmError::InvalidMessageIndex(e)) if e == "advance too large"
+        ));
+    }
+
+    #[test]
+    fn chunked_payload_is_reassembled() {
+        let mut outbound = MegolmSession::create_outbound().unwrap();
+        let session_key = outbound.get_session_key().unwrap();
+        let mut inbound = MegolmSession::create_inbound(&session_key, None).unwrap();
+
+        let payload: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8 + 1).collect();
+        let chunks: Vec<_> = payload
+            .chunks(4096)
+            .map(|chunk| outbound.encrypt(chunk).unwrap())
+            .collect();
+
+        assert_eq!(inbound.decrypt_chunked(&chunks).unwrap(), payload);
+    }
+
+    #[test]
+    fn dropped_chunk_fails_reassembly() {
+        let mut outbound = MegolmSession::create_outbound().unwrap();
+        let session_key = outbound.get_session_key().unwrap();
+        let mut inbound = MegolmSession::create_inbound(&session_key, None).unwrap();
+
+        let mut chunks: Vec<_> = [b"one", b"two", b"six"]
+            .iter()
+            .map(|chunk| outbound.encrypt(*chunk).unwrap())
+            .collect();
+        chunks.remove(1);
+
+        let result = inbound.decrypt_chunked(&chunks);
+        assert!(matches!(
+            result,
+            Err(MegolmError::InvalidMessageIndex(e)) if e == "chunk 1 has index 2, expected 1"
+        ));
+    }
+
+    #[test]
+    fn message_tags_are_stable_and_opaque() {
+        let mut outbound = MegolmSession::create_outbound().unwrap();
+        let session_key = outbound.get_session_key().unwrap();
+        let mut inbound = MegolmSession::create_inbound(&session_key, None).unwrap();
+
+        let tag = outbound.message_tag(3).unwrap();
+        assert_eq!(outbound.message_tag(3).unwrap(), tag);
+        assert_eq!(inbound.message_tag(3).unwrap(), tag);
+        assert_ne!(inbound.message_tag(4).unwrap(), tag);
+
+        // The tag shares nothing with the ratchet it was derived from
+        let ratchet = inbound.inbound.export_at(3).unwrap().to_bytes();
+        let tag = crate::utilities::base64_decode_lenient(&tag).unwrap();
+        assert_eq!(tag.len(), 32);
+        assert!(!ratchet.windows(8).any(|window| tag.windows(8).any(|t| t == window)));
+
+        // A session received later can't tag messages from before it
+        outbound.encrypt(b"advance").unwrap();
+        let late_key = outbound.session_key_at_current_index().unwrap();
+        let mut late = MegolmSession::create_inbound(&late_key, None).unwrap();
+        assert!(matches!(late.message_tag(0), Err(MegolmError::InvalidMessageIndex(_))));
+        assert_eq!(late.message_tag(3).unwrap(), outbound.message_tag(3).unwrap());
+    }
+
+    #[test]
+    fn room_export_contains_only_that_room() {
+        let wrap = |session| Arc::new(Mutex::new(session));
+        let first = wrap(MegolmSession::create_outbound().unwrap());
+        let second = wrap(MegolmSession::create_outbound().unwrap());
+        let other_room = wrap(MegolmSession::create_outbound().unwrap());
+
+        let mut store = SessionStore::default();
+        store.add("!room:example.org", "alice_key", &first);
+        store.add("!room:example.org", "bob_key", &second);
+        store.add("!other:example.org", "alice_key", &other_room);
+        // Filing the same session again doesn't export it twice
+        store.add("!room:example.org", "alice_key", &first);
+
+        let exports: Vec<ExportedSession> = store
+            .export_room_sessions("!room:example.org")
+            .iter()
+            .map(|json| serde_json::from_str(json).unwrap())
+            .collect();
+        let mut ids: Vec<_> = exports.iter().map(|e| e.session_id.as_str()).collect();
+        ids.sort();
+        let first_id = first.lock().unwrap().session_id().to_string();
+        let second_id = second.lock().unwrap().session_id().to_string();
+        let mut expected = vec![first_id.as_str(), second_id.as_str()];
+        expected.sort();
+        assert_eq!(ids, expected);
+        assert!(exports.iter().all(|e| e.room_id == "!room:example.org"));
+
+        // Each export imports and decrypts the room's messages
+        let message = first.lock().unwrap().encrypt(b"hello room").unwrap();
+        let export = exports.iter().find(|e| e.session_id == first_id).unwrap();
+        assert_eq!(export.sender_key, "alice_key");
+        let mut imported =
+            MegolmSession::import(&serde_json::to_string(export).unwrap(), None).unwrap();
+        let message = serde_json::to_string(&message).unwrap();
+        assert_eq!(imported.decrypt(&message).unwrap(), b"hello room");
+
+        assert_eq!(store.export_room_sessions("!other:example.org").len(), 1);
+        assert!(store.export_room_sessions("!unknown:example.org").is_empty());
+
+        // Freed sessions drop out, and so does a room with none left
+        drop(second);
+        assert_eq!(store.export_room_sessions("!room:example.org").len(), 1);
+        drop(other_room);
+        assert!(store.export_room_sessions("!other:example.org").is_empty());
+        assert!(!store.rooms.contains_key("!other:example.org"));
+        drop(first);
+        let third = wrap(MegolmSession::create_outbound().unwrap());
+        store.add("!third:example.org", "alice_key", &third);
+        assert_eq!(store.rooms.keys().collect::<Vec<_>>(), ["!third:example.org"]);
+    }
+
+    #[test]
+    fn mid_stream_algorithm_switch_is_rejected() {
+        let mut outbound = MegolmSession::create_outbound().unwrap();
+        let session_key = outbound.get_session_key().unwrap();
+        let mut inbound = MegolmSession::create_inbound(&session_key, None).unwrap();
+        assert_eq!(inbound.pinned_algorithm(), None);
+
+        let first = serde_json::to_string(&outbound.encrypt(b"first").unwrap()).unwrap();
+        inbound.decrypt(&first).unwrap();
+        assert_eq!(inbound.pinned_algorithm(), Some(MEGOLM_ALGORITHM));
+
+        let mut second = outbound.encrypt(b"second").unwrap();
+        second.algorithm = "m.megolm.v0.legacy".into();
+        let downgraded = serde_json::to_string(&second).unwrap();
+        let result = inbound.decrypt(&downgraded);
+        assert!(matches!(
+            result,
+            Err(MegolmError::AlgorithmMismatch { pinned, declared })
+                if pinned == MEGOLM_ALGORITHM && declared == "m.megolm.v0.legacy"
+        ));
+
+        // The refused message didn't touch the ratchet, and the pin
+        // survives a pickle round trip
+        let mut restored = MegolmSession::unpickle(&inbound.pickle().unwrap()).unwrap();
+        assert_eq!(restored.pinned_algorithm(), Some(MEGOLM_ALGORITHM));
+        assert!(matches!(
+            restored.decrypt(&downgraded),
+            Err(MegolmError::AlgorithmMismatch { .. })
+        ));
+        second.algorithm = MEGOLM_ALGORITHM.into();
+        let second = serde_json::to_string(&second).unwrap();
+        assert_eq!(inbound.decrypt(&second).unwrap(), b"second");
+    }
+}
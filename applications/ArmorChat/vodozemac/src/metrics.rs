@@ -0,0 +1,205 @@
+//! Operation timings for profiling
+//!
+//! Only built with the `metrics` feature. Olm and Megolm operations start a
+//! [`Timer`] that records how long they took into a per-operation
+//! histogram when it is dropped. Without the feature neither the timers
+//! nor the histograms exist.
+//!
+//! Histograms use power-of-two microsecond buckets, so percentiles are
+//! upper bounds accurate to a factor of two. That is coarse, but recording
+//! is a couple of relaxed atomic adds and never locks.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use serde::Serialize;
+
+/// Operations that are timed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    OlmEncrypt,
+    OlmDecrypt,
+    OlmSessionCreation,
+    MegolmEncrypt,
+    MegolmDecrypt,
+    MegolmSessionCreation,
+}
+
+impl Operation {
+    const ALL: [Operation; 6] = [
+        Operation::OlmEncrypt,
+        Operation::OlmDecrypt,
+        Operation::OlmSessionCreation,
+        Operation::MegolmEncrypt,
+        Operation::MegolmDecrypt,
+        Operation::MegolmSessionCreation,
+    ];
+
+    /// Name used in the [`snapshot`] JSON
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Operation::OlmEncrypt => "olm_encrypt",
+            Operation::OlmDecrypt => "olm_decrypt",
+            Operation::OlmSessionCreation => "olm_session_creation",
+            Operation::MegolmEncrypt => "megolm_encrypt",
+            Operation::MegolmDecrypt => "megolm_decrypt",
+            Operation::MegolmSessionCreation => "megolm_session_creation",
+        }
+    }
+}
+
+/// Bucket `i` counts durations below `2^(i + 1)` microseconds
+const BUCKETS: usize = 32;
+
+struct Histogram {
+    count: AtomicU64,
+    total_micros: AtomicU64,
+    buckets: [AtomicU64; BUCKETS],
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            count: AtomicU64::new(0),
+            total_micros: AtomicU64::new(0),
+            buckets: [const { AtomicU64::new(0) }; BUCKETS],
+        }
+    }
+
+    fn record(&self, micros: u64) {
+        let bucket = (u64::BITS - micros.leading_zeros()).saturating_sub(1) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        self.total_micros.fetch_add(micros, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Upper bound of the bucket holding the `p`th percentile, in
+    /// microseconds
+    fn percentile(counts: &[u64; BUCKETS], total: u64, p: f64) -> u64 {
+        if total == 0 {
+            return 0;
+        }
+
+        let rank = ((total as f64) * p).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, &count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return 1 << (bucket + 1);
+            }
+        }
+        1 << BUCKETS
+    }
+
+    fn stats(&self) -> OperationStats {
+        let counts: [u64; BUCKETS] =
+            std::array::from_fn(|bucket| self.buckets[bucket].load(Ordering::Relaxed));
+        let count = counts.iter().sum();
+        let total_micros = self.total_micros.load(Ordering::Relaxed);
+
+        OperationStats {
+            count,
+            mean_us: total_micros.checked_div(count).unwrap_or(0),
+            p50_us: Self::percentile(&counts, count, 0.50),
+            p90_us: Self::percentile(&counts, count, 0.90),
+            p99_us: Self::percentile(&counts, count, 0.99),
+        }
+    }
+}
+
+static HISTOGRAMS: [Histogram; Operation::ALL.len()] =
+    [const { Histogram::new() }; Operation::ALL.len()];
+
+/// Timing summary of one operation since the process started
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct OperationStats {
+    pub count: u64,
+    pub mean_us: u64,
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p99_us: u64,
+}
+
+/// Times an operation from [`Timer::start`] until it is dropped
+#[must_use = "the operation is timed until the timer is dropped"]
+pub struct Timer {
+    operation: Operation,
+    started: Instant,
+}
+
+impl Timer {
+    pub fn start(operation: Operation) -> Self {
+        Self { operation, started: Instant::now() }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let micros = u64::try_from(self.started.elapsed().as_micros()).unwrap_or(u64::MAX);
+        HISTOGRAMS[self.operation as usize].record(micros);
+    }
+}
+
+/// Timing summaries of every operation, keyed by [`Operation::as_str`]
+pub fn snapshot() -> BTreeMap<&'static str, OperationStats> {
+    Operation::ALL
+        .into_iter()
+        .map(|operation| (operation.as_str(), HISTOGRAMS[operation as usize].stats()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::olm::tests::outbound_pair;
+
+    #[test]
+    fn percentiles_are_bucket_upper_bounds() {
+        let histogram = Histogram::new();
+        for micros in [0, 1, 3, 3, 100] {
+            histogram.record(micros);
+        }
+
+        let stats = histogram.stats();
+        assert_eq!(stats.count, 5);
+        assert_eq!(stats.mean_us, 21);
+        assert_eq!(stats.p50_us, 4);
+        assert_eq!(stats.p90_us, 128);
+        assert_eq!(stats.p99_us, 128);
+
+        assert_eq!(Histogram::new().stats().p50_us, 0);
+    }
+
+    #[test]
+    fn operations_accumulate_timings() {
+        let before = snapshot();
+
+        let mut outbound = crate::megolm::MegolmSession::create_outbound().unwrap();
+        let session_key = outbound.get_session_key().unwrap();
+        let mut inbound = crate::megolm::MegolmSession::create_inbound(&session_key, None).unwrap();
+        for _ in 0..3 {
+            let message = serde_json::to_string(&outbound.encrypt(b"hello").unwrap()).unwrap();
+            inbound.decrypt(&message).unwrap();
+        }
+
+        let (mut alice, alice_identity, mut bob, _) = outbound_pair();
+        let message = alice.encrypt(b"hello").unwrap();
+        bob.decrypt_or_create(&alice_identity, message.body.as_bytes()).unwrap();
+
+        // Other tests run alongside, so counts only ever grow by at least
+        // as much as this test did
+        let after = snapshot();
+        let grew = |name: &str| after[name].count - before[name].count;
+        assert!(grew("megolm_session_creation") >= 2);
+        assert!(grew("megolm_encrypt") >= 3);
+        assert!(grew("megolm_decrypt") >= 3);
+        assert!(grew("olm_session_creation") >= 2);
+        assert!(grew("olm_encrypt") >= 1);
+        assert!(grew("olm_decrypt") >= 1);
+        assert!(after["megolm_encrypt"].p99_us >= after["megolm_encrypt"].p50_us);
+
+        let json = serde_json::to_value(&after).unwrap();
+        assert!(json["olm_encrypt"]["p90_us"].is_u64());
+    }
+}
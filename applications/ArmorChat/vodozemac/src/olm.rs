@@ -3,9 +3,17 @@
 //! Olm provides the Double Ratchet algorithm for forward secrecy
 //! in one-to-one conversations.
 
+use std::collections::{BTreeMap, HashMap};
+
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+#[cfg(feature = "metrics")]
+use crate::metrics::{Operation, Timer};
+
+/// Olm algorithm identifier
+pub const OLM_ALGORITHM: &str = "m.olm.v1.curve25519-aes-sha2";
+
 /// Olm errors
 #[derive(Error, Debug)]
 pub enum OlmError {
@@ -24,11 +32,26 @@ pub enum OlmError {
     #[error("Decryption failed: {0}")]
     DecryptionFailed(String),
 
+    #[error("Message authentication failed; the message may have been tampered with")]
+    BadMac,
+
     #[error("Invalid key: {0}")]
     InvalidKey(String),
 
     #[error("Session not found")]
     SessionNotFound,
+
+    #[error("Invalid crypto store: {0}")]
+    InvalidStore(String),
+
+    #[error("Inconsistent account state: {0}")]
+    InvalidState(String),
+
+    #[error("Forwarded key passed through {depth} devices, more than the limit of {max}")]
+    ForwardingChainTooLong { depth: usize, max: usize },
+
+    #[error("Session is pinned to {pinned}, message declares {declared}")]
+    AlgorithmMismatch { pinned: String, declared: String },
 }
 
 /// Identity keys for an Olm account
@@ -39,12 +62,44 @@ pub struct IdentityKeys {
 }
 
 /// One-time key
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct OneTimeKey {
     pub key_id: String,
     pub key: String,
 }
 
+/// Per-device results of [`OlmSession::verify_device_map`], by user id then
+/// device id
+pub type DeviceVerificationResults = BTreeMap<String, BTreeMap<String, bool>>;
+
+/// A peer's one-time key from a `/keys/claim` response, signature checked
+#[derive(Serialize, Deserialize)]
+pub struct ClaimedKey {
+    /// Olm key id, without the `signed_curve25519:` prefix
+    pub key_id: String,
+    /// Curve25519 key to create the outbound session with
+    pub key: String,
+    /// Whether the server handed out the device's fallback key
+    pub fallback: bool,
+}
+
+/// Format a key id as it appears in uploads, e.g. `signed_curve25519:AAAAAQ`
+///
+/// `algorithm` is the Matrix key algorithm (`curve25519`, `ed25519`,
+/// `signed_curve25519`) and `id` the raw Olm key id or device id.
+pub fn format_key_id(algorithm: &str, id: &str) -> String {
+    format!("{}:{}", algorithm, id)
+}
+
+/// A newly created session, as reported to the app
+#[derive(Serialize, Deserialize)]
+pub struct CreatedSession {
+    /// Index of the session within its account
+    pub handle: usize,
+    /// Matrix session id, as other clients report it
+    pub session_id: String,
+}
+
 /// Encrypted message
 #[derive(Serialize, Deserialize)]
 pub struct EncryptedMessage {
@@ -52,6 +107,116 @@ pub struct EncryptedMessage {
     pub body: String,
 }
 
+/// One to-device message for [`OlmSession::decrypt_batch`]
+#[derive(Serialize, Deserialize)]
+pub struct EventInput {
+    /// Curve25519 identity key of the sender
+    pub sender_key: String,
+    #[serde(rename = "type")]
+    pub message_type: usize,
+    pub body: String,
+    /// Algorithm the event content declares, checked by
+    /// [`OlmSession::decrypt_event`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub algorithm: Option<String>,
+}
+
+/// Content of an `m.forwarded_room_key` to-device event
+#[derive(Serialize, Deserialize)]
+struct ForwardedRoomKeyContent {
+    algorithm: String,
+    room_id: String,
+    sender_key: String,
+    session_id: String,
+    session_key: String,
+    sender_claimed_ed25519_key: String,
+    #[serde(default)]
+    forwarding_curve25519_key_chain: Vec<String>,
+}
+
+/// A forwarded room key that was accepted, without its session key
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ForwardedRoomKey {
+    pub room_id: String,
+    pub session_id: String,
+    /// Curve25519 key of the device that created the Megolm session
+    pub sender_key: String,
+    /// Devices the key passed through, ending with the one that sent it to us
+    pub forwarding_curve25519_key_chain: Vec<String>,
+}
+
+/// Encrypted message ready to send, with the session it was sent on
+#[derive(Serialize, Deserialize)]
+pub struct TransportMessage {
+    #[serde(rename = "type")]
+    pub message_type: usize,
+    pub body: String,
+    pub session_id: String,
+}
+
+/// Chain indices of an Olm session's double ratchet, for debugging
+///
+/// Taken from the last message sent and received on the session since it
+/// was loaded; `None` until there has been one. Only indices are exposed,
+/// never chain keys.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct RatchetState {
+    pub sending_chain_index: Option<u32>,
+    pub receiving_chain_index: Option<u32>,
+}
+
+/// Version of the blob written by [`OlmSession::export_store`]
+pub const STORE_VERSION: u32 = 1;
+
+/// Account and session pickles persisted together
+#[derive(Serialize, Deserialize)]
+struct CryptoStore {
+    version: u32,
+    account: String,
+    sessions: Vec<String>,
+    current_session_id: usize,
+    #[serde(default)]
+    fallback_key_published: bool,
+    #[serde(default)]
+    fallback_key: Option<OneTimeKey>,
+    #[serde(default)]
+    previous_fallback_key: Option<OneTimeKey>,
+    #[serde(default)]
+    peers: HashMap<String, String>,
+    #[serde(default)]
+    algorithms: HashMap<String, String>,
+}
+
+/// Payload of a version 2 account pickle
+///
+/// Version 1 was the bare libolm pickle, which lost the fallback key state.
+#[derive(Serialize, Deserialize)]
+struct AccountPickle {
+    account: String,
+    #[serde(default)]
+    fallback_key_published: bool,
+    #[serde(default)]
+    fallback_key: Option<OneTimeKey>,
+    #[serde(default)]
+    previous_fallback_key: Option<OneTimeKey>,
+}
+
+impl AccountPickle {
+    /// Upgrade a version 1 payload; the fallback key state is unknown, so
+    /// it is treated as unpublished
+    fn migrate_v1(payload: &[u8]) -> Result<Self, OlmError> {
+        let account = std::str::from_utf8(payload)
+            .map_err(|_| OlmError::AccountCreationFailed("Invalid pickle data".into()))?;
+
+        Ok(Self {
+            account: account.to_string(),
+            fallback_key_published: false,
+            fallback_key: None,
+            previous_fallback_key: None,
+        })
+    }
+}
+
 /// Olm session for 1:1 encryption
 pub struct OlmSession {
     // In production, this would hold actual vodozemac types
@@ -59,18 +224,39 @@ pub struct OlmSession {
     account: Option<olm_rs::account::OlmAccount>,
     sessions: Vec<(String, olm_rs::session::OlmSession)>,
     current_session_id: usize,
+    fallback_key_published: bool,
+    /// Public halves of the current and previous fallback keys
+    ///
+    /// libolm keeps both private keys but only reports the current one
+    /// until it is published, so the wrapper remembers them itself.
+    fallback_key: Option<OneTimeKey>,
+    previous_fallback_key: Option<OneTimeKey>,
+    /// Chain indices seen on each session, keyed by session id
+    ratchet_states: HashMap<String, RatchetState>,
+    /// The other side's Curve25519 identity key, keyed by session id
+    ///
+    /// libolm doesn't expose it once the session exists.
+    peers: HashMap<String, String>,
+    /// Algorithm declared by the first event decrypted on each session,
+    /// keyed by session id
+    algorithms: HashMap<String, String>,
 }
 
 impl OlmSession {
     /// Create a new Olm account
     pub fn create_account() -> Result<Self, OlmError> {
-        let account = olm_rs::account::OlmAccount::new()
-            .map_err(|e| OlmError::AccountCreationFailed(format!("{:?}", e)))?;
+        let account = olm_rs::account::OlmAccount::new();
 
         Ok(Self {
             account: Some(account),
             sessions: Vec::new(),
             current_session_id: 0,
+            fallback_key_published: false,
+            fallback_key: None,
+            previous_fallback_key: None,
+            ratchet_states: HashMap::new(),
+            peers: HashMap::new(),
+            algorithms: HashMap::new(),
         })
     }
 
@@ -79,42 +265,259 @@ impl OlmSession {
         let account = self.account.as_ref()
             .ok_or(OlmError::AccountCreationFailed("No account".into()))?;
 
-        let curve25519 = account.parsed_identity_keys().curve25519;
-        let ed25519 = account.parsed_identity_keys().ed25519;
+        let keys = account.parsed_identity_keys();
 
         Ok(IdentityKeys {
-            curve25519,
-            ed25519,
+            curve25519: keys.curve25519().to_string(),
+            ed25519: keys.ed25519().to_string(),
         })
     }
 
+    /// Sign a message with the account's Ed25519 key
+    ///
+    /// Returns the unpadded base64 signature.
+    pub fn sign(&self, message: &str) -> Result<String, OlmError> {
+        let account = self.account.as_ref()
+            .ok_or(OlmError::AccountCreationFailed("No account".into()))?;
+
+        Ok(account.sign(message))
+    }
+
     /// Generate one-time keys
     pub fn generate_one_time_keys(&mut self, count: usize) -> Result<Vec<OneTimeKey>, OlmError> {
         let account = self.account.as_mut()
             .ok_or(OlmError::AccountCreationFailed("No account".into()))?;
 
-        account.generate_one_time_keys(count as u64)
-            .map_err(|e| OlmError::KeyGenerationFailed(format!("{:?}", e)))?;
+        account.generate_one_time_keys(count);
+
+        self.unpublished_one_time_keys()
+    }
+
+    /// List the one-time keys that haven't been marked as published
+    ///
+    /// Unlike [`OlmSession::generate_one_time_keys`], this never creates
+    /// keys. The list is sorted by key id.
+    pub fn unpublished_one_time_keys(&self) -> Result<Vec<OneTimeKey>, OlmError> {
+        let account = self.account.as_ref()
+            .ok_or(OlmError::AccountCreationFailed("No account".into()))?;
 
         let keys = account.parsed_one_time_keys();
 
-        let one_time_keys: Vec<OneTimeKey> = keys.curve25519
+        let mut one_time_keys: Vec<OneTimeKey> = keys.curve25519()
             .iter()
             .map(|(key_id, key)| OneTimeKey {
                 key_id: key_id.clone(),
                 key: key.clone(),
             })
             .collect();
+        one_time_keys.sort_by(|a, b| a.key_id.cmp(&b.key_id));
 
         Ok(one_time_keys)
     }
 
+    /// Remove the one-time keys that haven't been published
+    ///
+    /// libolm only removes a one-time key once a session has used it, so
+    /// each key is used up by a throwaway inbound session from a throwaway
+    /// sender. Published keys can't be listed and are left alone, as are
+    /// fallback keys.
+    ///
+    /// That costs a handshake per key, so dropping an account doesn't do
+    /// it: libolm wipes the account's memory, keys included, when it is
+    /// freed.
+    pub fn clear_one_time_keys(&mut self) -> Result<(), OlmError> {
+        let account = self.account.as_ref()
+            .ok_or(OlmError::AccountCreationFailed("No account".into()))?;
+
+        let keys: Vec<String> =
+            account.parsed_one_time_keys().curve25519().values().cloned().collect();
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        let identity_key = account.parsed_identity_keys().curve25519().to_string();
+        let sender = olm_rs::account::OlmAccount::new();
+        let failed = |e: &dyn std::fmt::Debug| {
+            OlmError::SessionCreationFailed(format!("Failed to clear one-time key: {:?}", e))
+        };
+
+        for key in keys {
+            let outbound = sender
+                .create_outbound_session(&identity_key, &key)
+                .map_err(|e| failed(&e))?;
+            let olm_rs::session::OlmMessage::PreKey(message) = outbound.encrypt("clear") else {
+                return Err(failed(&"not a pre-key message"));
+            };
+            let inbound = account.create_inbound_session(message).map_err(|e| failed(&e))?;
+            account.remove_one_time_keys(&inbound).map_err(|e| failed(&e))?;
+        }
+
+        Ok(())
+    }
+
+    /// How many one-time keys to generate to refill the server's supply
+    ///
+    /// `server_count` is the `signed_curve25519` count from the last sync.
+    /// The target is half of what the account can hold, which leaves room
+    /// for keys generated before the previous upload finished. Keys that
+    /// were generated but not yet published count towards the target.
+    pub fn needs_one_time_keys(&self, server_count: usize) -> Result<usize, OlmError> {
+        let account = self.account.as_ref()
+            .ok_or(OlmError::AccountCreationFailed("No account".into()))?;
+
+        let target = account.max_number_of_one_time_keys() / 2;
+        let unpublished = account.parsed_one_time_keys().curve25519().len();
+
+        Ok(target.saturating_sub(server_count + unpublished))
+    }
+
+    /// Build the signed one-time keys for a `/keys/upload` request
+    ///
+    /// Returns the `one_time_keys` object for the keys that haven't been
+    /// published yet, keyed by `signed_curve25519:<key id>` and signed with
+    /// the account's Ed25519 key for `device_id`.
+    pub fn signed_one_time_keys_json(
+        &self,
+        user_id: &str,
+        device_id: &str,
+    ) -> Result<String, OlmError> {
+        let account = self.account.as_ref()
+            .ok_or(OlmError::AccountCreationFailed("No account".into()))?;
+
+        let signing_key_id = format_key_id("ed25519", device_id);
+        let mut one_time_keys = serde_json::Map::new();
+
+        for (id, key) in account.parsed_one_time_keys().curve25519() {
+            let mut signed_key = serde_json::json!({ "key": key });
+            let signature = account.sign(&crate::utilities::signable_json(&signed_key));
+            signed_key["signatures"] = serde_json::json!({
+                user_id: { signing_key_id.as_str(): signature }
+            });
+
+            one_time_keys.insert(format_key_id("signed_curve25519", id), signed_key);
+        }
+
+        serde_json::to_string(&one_time_keys)
+            .map_err(|e| OlmError::KeyGenerationFailed(e.to_string()))
+    }
+
+    /// Generate one-time keys and sign them for upload in one step
+    ///
+    /// Same output as [`OlmSession::signed_one_time_keys_json`] after
+    /// [`OlmSession::generate_one_time_keys`], so keys generated earlier
+    /// and not yet published are included too.
+    pub fn generate_and_sign_one_time_keys(
+        &mut self,
+        count: usize,
+        user_id: &str,
+        device_id: &str,
+    ) -> Result<String, OlmError> {
+        self.generate_one_time_keys(count)?;
+        self.signed_one_time_keys_json(user_id, device_id)
+    }
+
+    /// Sign the unpublished one-time keys again with the current Ed25519 key
+    ///
+    /// Use after the account's signing key changed, e.g. when recovery put a
+    /// different account in place, so stale signatures aren't uploaded.
+    /// Signatures are never cached, so the output is always made with the
+    /// key [`OlmSession::get_identity_keys`] reports; the format is that of
+    /// [`OlmSession::signed_one_time_keys_json`].
+    pub fn resign_one_time_keys(
+        &self,
+        user_id: &str,
+        device_id: &str,
+    ) -> Result<String, OlmError> {
+        self.signed_one_time_keys_json(user_id, device_id)
+    }
+
+    /// The account's public identity, self-signed, for sharing
+    ///
+    /// Holds the Curve25519 and Ed25519 identity keys and an Ed25519
+    /// signature over them, under the key id `ed25519:<ed25519 key>`. No
+    /// private key material is included.
+    pub fn public_identity_json(&self) -> Result<String, OlmError> {
+        let account = self.account.as_ref()
+            .ok_or(OlmError::AccountCreationFailed("No account".into()))?;
+
+        let keys = self.get_identity_keys()?;
+        let mut identity = serde_json::json!({
+            "curve25519": keys.curve25519,
+            "ed25519": keys.ed25519,
+        });
+        let signature = account.sign(&crate::utilities::signable_json(&identity));
+        identity["signatures"] = serde_json::json!({
+            format_key_id("ed25519", &keys.ed25519): signature
+        });
+
+        Ok(identity.to_string())
+    }
+
+    /// Generate a new fallback key
+    ///
+    /// The new key starts out unpublished. The key it replaces becomes the
+    /// previous fallback key, so sessions started with it while the server
+    /// rotates can still be established. Any older key is forgotten.
+    pub fn generate_fallback_key(&mut self) -> Result<OneTimeKey, OlmError> {
+        let account = self.account.as_ref()
+            .ok_or(OlmError::AccountCreationFailed("No account".into()))?;
+
+        account.generate_fallback_key();
+        let fallback_key = account.parsed_fallback_key()
+            .ok_or_else(|| OlmError::KeyGenerationFailed("No fallback key".into()))?;
+
+        let key = OneTimeKey {
+            key_id: fallback_key.index().to_string(),
+            key: fallback_key.curve25519().to_string(),
+        };
+
+        self.fallback_key_published = false;
+        self.previous_fallback_key = self.fallback_key.replace(key.clone());
+
+        Ok(key)
+    }
+
+    /// The fallback key generated last, published or not
+    pub fn active_fallback_key(&self) -> Option<&OneTimeKey> {
+        self.fallback_key.as_ref()
+    }
+
+    /// The fallback key the active one replaced
+    ///
+    /// Pre-key messages made with either key are accepted.
+    pub fn previous_fallback_key(&self) -> Option<&OneTimeKey> {
+        self.previous_fallback_key.as_ref()
+    }
+
+    /// Mark the current one-time keys and fallback key as uploaded
+    pub fn mark_keys_as_published(&mut self) -> Result<(), OlmError> {
+        let account = self.account.as_ref()
+            .ok_or(OlmError::AccountCreationFailed("No account".into()))?;
+
+        if account.parsed_fallback_key().is_some() {
+            self.fallback_key_published = true;
+        }
+        account.mark_keys_as_published();
+
+        Ok(())
+    }
+
+    /// Whether the current fallback key has been uploaded
+    ///
+    /// `false` if no fallback key was generated yet.
+    pub fn fallback_key_published(&self) -> bool {
+        self.fallback_key_published
+    }
+
     /// Create an outbound session with a recipient
     pub fn create_outbound_session(
         &mut self,
         their_identity_key: &[u8],
         their_one_time_key: &[u8],
     ) -> Result<usize, OlmError> {
+        #[cfg(feature = "metrics")]
+        let _timer = Timer::start(Operation::OlmSessionCreation);
+
         let account = self.account.as_ref()
             .ok_or(OlmError::AccountCreationFailed("No account".into()))?;
 
@@ -123,38 +526,264 @@ impl OlmSession {
         let their_otk = std::str::from_utf8(their_one_time_key)
             .map_err(|_| OlmError::InvalidKey("Invalid one-time key".into()))?;
 
-        let session = olm_rs::session::OlmSession::new_outbound(
-            account,
-            their_identity,
-            their_otk,
-        ).map_err(|e| OlmError::SessionCreationFailed(format!("{:?}", e)))?;
+        let session = account.create_outbound_session(their_identity, their_otk)
+            .map_err(|e| OlmError::SessionCreationFailed(format!("{:?}", e)))?;
 
         let session_id = self.sessions.len();
+        self.peers.insert(session.session_id(), unpadded(their_identity).to_string());
         self.sessions.push((session.session_id(), session));
         self.current_session_id = session_id;
 
         Ok(session_id)
     }
 
+    /// Create an outbound session and report its Matrix session id
+    pub fn create_outbound_session_detailed(
+        &mut self,
+        their_identity_key: &[u8],
+        their_one_time_key: &[u8],
+    ) -> Result<CreatedSession, OlmError> {
+        let handle = self.create_outbound_session(their_identity_key, their_one_time_key)?;
+        let session_id = self.session_id(handle)
+            .ok_or(OlmError::SessionNotFound)?
+            .to_string();
+
+        Ok(CreatedSession { handle, session_id })
+    }
+
+    /// Create an outbound session with the one-time key claimed under
+    /// `key_id`
+    ///
+    /// `claim_json` is the device's entry in a `/keys/claim` response, e.g.
+    /// `{"signed_curve25519:AAAAAQ": {"key": "...", "signatures": {...}}}`.
+    /// It must hold exactly the key `key_id` names, with or without the
+    /// algorithm prefix, so the session can't be built on a different key
+    /// than the one the app meant to use. Signatures aren't checked here;
+    /// see [`OlmSession::verify_claimed_key`].
+    pub fn create_outbound_session_with_key_id(
+        &mut self,
+        their_identity_key: &str,
+        key_id: &str,
+        claim_json: &str,
+    ) -> Result<CreatedSession, OlmError> {
+        let claim: serde_json::Map<String, serde_json::Value> = serde_json::from_str(claim_json)
+            .map_err(|e| OlmError::InvalidKey(format!("Invalid key claim JSON: {}", e)))?;
+
+        let mut entries = claim.iter();
+        let (claimed_id, claimed_key) = match (entries.next(), entries.next()) {
+            (Some(entry), None) => entry,
+            _ => return Err(OlmError::InvalidKey("Expected exactly one claimed key".into())),
+        };
+        let bare_id = |id: &str| id.split_once(':').map_or(id, |(_, id)| id).to_string();
+        if bare_id(claimed_id) != bare_id(key_id) {
+            return Err(OlmError::InvalidKey(format!(
+                "Claimed key {} does not match key id {}",
+                claimed_id, key_id
+            )));
+        }
+
+        let key = claimed_key
+            .as_str()
+            .or_else(|| claimed_key.get("key").and_then(|v| v.as_str()))
+            .ok_or_else(|| OlmError::InvalidKey("Missing key".into()))?;
+
+        self.create_outbound_session_detailed(their_identity_key.as_bytes(), key.as_bytes())
+    }
+
+    /// Get the Matrix session id of a session
+    ///
+    /// This is the unpadded base64 id other clients log and store.
+    pub fn session_id(&self, handle: usize) -> Option<&str> {
+        self.sessions.get(handle).map(|(session_id, _)| session_id.as_str())
+    }
+
+    /// Number of Olm sessions held by this account
+    pub fn session_count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Drop every session, keeping the account and its keys
+    ///
+    /// Used during recovery to force new sessions with all peers. Identity
+    /// keys, one-time keys and fallback keys are left as they are, so keys
+    /// already uploaded stay valid.
+    pub fn clear_sessions(&mut self) {
+        self.sessions.clear();
+        self.current_session_id = 0;
+        self.ratchet_states.clear();
+        self.peers.clear();
+        self.algorithms.clear();
+    }
+
+    /// Whether there is a working session with the device owning
+    /// `identity_key`
+    ///
+    /// Only sessions that have received a message count: until the other
+    /// side replies, an outbound session may never have reached it.
+    pub fn has_session_with(&self, identity_key: &str) -> bool {
+        let identity_key = unpadded(identity_key);
+        self.sessions.iter().any(|(session_id, session)| {
+            self.peers.get(session_id).is_some_and(|peer| peer == identity_key)
+                && session.has_received_message()
+        })
+    }
+
+    /// Get the chain indices of the session with Matrix id `session_id`
+    ///
+    /// Best effort: libolm doesn't expose its ratchet, so the indices are
+    /// read from the last messages sent and received on the session.
+    pub fn ratchet_state(&self, session_id: &str) -> Result<RatchetState, OlmError> {
+        if !self.sessions.iter().any(|(id, _)| id == session_id) {
+            return Err(OlmError::SessionNotFound);
+        }
+
+        Ok(self.ratchet_states.get(session_id).cloned().unwrap_or_default())
+    }
+
+    fn record_sent(&mut self, index: usize, message_type: usize, body: &str) {
+        if let (Some((session_id, _)), Some(chain_index)) =
+            (self.sessions.get(index), message_chain_index(message_type, body))
+        {
+            self.ratchet_states.entry(session_id.clone()).or_default().sending_chain_index =
+                Some(chain_index);
+        }
+    }
+
+    fn record_received(&mut self, index: usize, message_type: usize, body: &str) {
+        if let (Some((session_id, _)), Some(chain_index)) =
+            (self.sessions.get(index), message_chain_index(message_type, body))
+        {
+            self.ratchet_states.entry(session_id.clone()).or_default().receiving_chain_index =
+                Some(chain_index);
+        }
+    }
+
     /// Encrypt a message
-    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, OlmError> {
+    ///
+    /// Returns the message type (0 for pre-key, 1 for normal messages) and
+    /// the base64 body. The body is what the to-device `ciphertext` field
+    /// expects, so it must not be encoded again.
+    ///
+    /// Empty plaintext is rejected: an empty to-device payload isn't a
+    /// valid event, and some transports drop or mangle it. Empty messages
+    /// from other clients still decrypt to an empty buffer.
+    /// Plaintext over [`crate::utilities::max_plaintext_size`] is rejected
+    /// before it is copied.
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<EncryptedMessage, OlmError> {
+        #[cfg(feature = "metrics")]
+        let _timer = Timer::start(Operation::OlmEncrypt);
+
+        if plaintext.is_empty() {
+            return Err(OlmError::EncryptionFailed("Plaintext is empty".into()));
+        }
+        crate::utilities::check_plaintext_size(plaintext.len())
+            .map_err(OlmError::EncryptionFailed)?;
+
         let session = self.sessions.get_mut(self.current_session_id)
             .map(|(_, s)| s)
             .ok_or(OlmError::SessionNotFound)?;
 
-        let message_type = session.message_type();
-        let ciphertext = session.encrypt(plaintext)
-            .map_err(|e| OlmError::EncryptionFailed(format!("{:?}", e)))?;
+        let plaintext = std::str::from_utf8(plaintext)
+            .map_err(|_| OlmError::EncryptionFailed("Plaintext is not valid UTF-8".into()))?;
+        let (message_type, body) = session.encrypt(plaintext).to_tuple();
+        let message_type = usize::from(message_type);
+
+        self.record_sent(self.current_session_id, message_type, &body);
+
+        Ok(EncryptedMessage { message_type, body })
+    }
+
+    /// Encrypt on the current session and tag the result with its session id
+    pub fn encrypt_for_transport(
+        &mut self,
+        plaintext: &[u8],
+    ) -> Result<TransportMessage, OlmError> {
+        let EncryptedMessage { message_type, body } = self.encrypt(plaintext)?;
+        let session_id = self
+            .session_id(self.current_session_id)
+            .ok_or(OlmError::SessionNotFound)?
+            .to_string();
+
+        Ok(TransportMessage { message_type, body, session_id })
+    }
+
+    /// Share a Megolm session key with the device on the given Olm session
+    ///
+    /// Builds the `m.room_key` to-device payload for `room_id` and encrypts
+    /// it on the session with Matrix id `recipient_session_id`. The result
+    /// is the `{ type, body }` pair for the to-device event's ciphertext.
+    pub fn wrap_session_key(
+        &mut self,
+        recipient_session_id: &str,
+        room_id: &str,
+        megolm_session_key: &str,
+    ) -> Result<EncryptedMessage, OlmError> {
+        let megolm_session = crate::megolm::MegolmSession::create_inbound(megolm_session_key, None)
+            .map_err(|e| OlmError::InvalidKey(e.to_string()))?;
+        let sender_keys = self.get_identity_keys()?;
+
+        let payload = serde_json::json!({
+            "type": "m.room_key",
+            "content": crate::megolm::RoomKeyInfo {
+                algorithm: crate::megolm::MEGOLM_ALGORITHM.to_string(),
+                room_id: room_id.to_string(),
+                session_id: megolm_session.session_id().to_string(),
+                session_key: megolm_session_key.to_string(),
+            },
+            "keys": { "ed25519": sender_keys.ed25519 },
+        });
+
+        self.encrypt_event(recipient_session_id, &payload)
+    }
+
+    /// Encrypt an `m.dummy` event on the session with Matrix id `session_id`
+    ///
+    /// Sending it advances that session's ratchet without carrying any
+    /// content, which is how a client rotates an Olm session after the
+    /// other side's keys change.
+    pub fn encrypt_dummy(&mut self, session_id: &str) -> Result<EncryptedMessage, OlmError> {
+        let sender_keys = self.get_identity_keys()?;
+        let payload = serde_json::json!({
+            "type": "m.dummy",
+            "content": {},
+            "keys": { "ed25519": sender_keys.ed25519 },
+        });
+
+        self.encrypt_event(session_id, &payload)
+    }
+
+    /// Encrypt a to-device payload on the session with Matrix id `session_id`
+    fn encrypt_event(
+        &mut self,
+        session_id: &str,
+        payload: &serde_json::Value,
+    ) -> Result<EncryptedMessage, OlmError> {
+        #[cfg(feature = "metrics")]
+        let _timer = Timer::start(Operation::OlmEncrypt);
+
+        let index = self.sessions.iter()
+            .position(|(id, _)| id == session_id)
+            .ok_or(OlmError::SessionNotFound)?;
+
+        let (message_type, body) = self.sessions[index].1
+            .encrypt(&payload.to_string())
+            .to_tuple();
+        let message_type = usize::from(message_type);
 
-        // Prepend message type byte
-        let mut result = vec![message_type as u8];
-        result.extend(ciphertext.as_bytes());
+        self.record_sent(index, message_type, &body);
 
-        Ok(result)
+        Ok(EncryptedMessage { message_type, body })
     }
 
     /// Decrypt a message
+    ///
+    /// Fails with `BadMac` if the message doesn't authenticate on the
+    /// current session, which points at tampering rather than a ratchet
+    /// problem.
     pub fn decrypt(&mut self, ciphertext: &[u8], message_type: usize) -> Result<Vec<u8>, OlmError> {
+        #[cfg(feature = "metrics")]
+        let _timer = Timer::start(Operation::OlmDecrypt);
+
         let session = self.sessions.get_mut(self.current_session_id)
             .map(|(_, s)| s)
             .ok_or(OlmError::SessionNotFound)?;
@@ -162,49 +791,2060 @@ impl OlmSession {
         let ciphertext_str = std::str::from_utf8(ciphertext)
             .map_err(|_| OlmError::DecryptionFailed("Invalid ciphertext".into()))?;
 
-        let msg_type = match message_type {
-            0 => olm_rs::session::OlmMessageType::PreKey,
-            1 => olm_rs::session::OlmMessageType::Message,
-            _ => return Err(OlmError::DecryptionFailed("Invalid message type".into())),
-        };
+        let message = olm_rs::session::OlmMessage::from_type_and_ciphertext(
+            message_type,
+            ciphertext_str.to_string(),
+        ).map_err(|_| OlmError::DecryptionFailed("Invalid message type".into()))?;
 
-        session.decrypt(&msg_type, ciphertext_str)
-            .map_err(|e| OlmError::DecryptionFailed(format!("{:?}", e)))
+        let plaintext = session.decrypt(message)
+            .map(String::into_bytes)
+            .map_err(|e| match e {
+                olm_rs::errors::OlmSessionError::BadMessageMac => OlmError::BadMac,
+                e => OlmError::DecryptionFailed(format!("{:?}", e)),
+            })?;
+
+        self.record_received(self.current_session_id, message_type, ciphertext_str);
+
+        Ok(plaintext)
     }
 
-    /// Pickle (serialize) the account
-    pub fn pickle(&self) -> Result<Vec<u8>, OlmError> {
+    /// Decrypt a pre-key message, creating an inbound session if needed
+    ///
+    /// A pre-key message that belongs to an established session (a resend,
+    /// or one delivered out of order) is decrypted on that session's
+    /// ratchet instead of starting a new session. The returned flag is
+    /// `true` only if a new inbound session was created.
+    pub fn decrypt_or_create(
+        &mut self,
+        their_identity_key: &str,
+        ciphertext: &[u8],
+    ) -> Result<(Vec<u8>, bool), OlmError> {
+        let ciphertext_str = std::str::from_utf8(ciphertext)
+            .map_err(|_| OlmError::DecryptionFailed("Invalid ciphertext".into()))?;
+
+        let (index, created) = self
+            .inbound_session_for(their_identity_key, ciphertext_str)?
+            .ok_or_else(|| OlmError::DecryptionFailed("Not a pre-key message".into()))?;
+
+        self.current_session_id = index;
+        let plaintext = self.decrypt(ciphertext, 0)?;
+
+        Ok((plaintext, created))
+    }
+
+    /// Find the session a pre-key message belongs to, or create it
+    ///
+    /// Returns the session's index and whether it was just created, or
+    /// `None` if the ciphertext isn't a pre-key message.
+    fn inbound_session_for(
+        &mut self,
+        their_identity_key: &str,
+        ciphertext: &str,
+    ) -> Result<Option<(usize, bool)>, OlmError> {
         let account = self.account.as_ref()
             .ok_or(OlmError::AccountCreationFailed("No account".into()))?;
 
-        account.pickle(olm_rs::PicklingMode::EncryptWith(&[]))
-            .map_err(|e| OlmError::AccountCreationFailed(format!("{:?}", e)))
-            .map(|s| s.as_bytes().to_vec())
+        let message = match olm_rs::session::OlmMessage::from_type_and_ciphertext(
+            0,
+            ciphertext.to_string(),
+        ) {
+            Ok(olm_rs::session::OlmMessage::PreKey(message)) => message,
+            _ => return Ok(None),
+        };
+
+        let existing = self.sessions.iter().position(|(_, session)| {
+            session
+                .matches_inbound_session_from(their_identity_key, message.clone())
+                .unwrap_or(false)
+        });
+        if let Some(index) = existing {
+            return Ok(Some((index, false)));
+        }
+
+        // libolm looks the key up among the one-time keys and both the
+        // current and previous fallback keys
+        let fallback = prekey_key(ciphertext, PREKEY_ONE_TIME_KEY)
+            .is_some_and(|key| self.is_fallback_key(&key));
+        let created = {
+            #[cfg(feature = "metrics")]
+            let _timer = Timer::start(Operation::OlmSessionCreation);
+            account.create_inbound_session_from(their_identity_key, message)
+        };
+        match created {
+            Ok(session) => {
+                // A one-time key is used up; a fallback key stays until it
+                // is rotated, so further senders can still use it
+                if !fallback {
+                    account.remove_one_time_keys(&session)
+                        .map_err(|e| OlmError::SessionCreationFailed(format!("{:?}", e)))?;
+                }
+                self.peers.insert(session.session_id(), unpadded(their_identity_key).to_string());
+                self.sessions.push((session.session_id(), session));
+                Ok(Some((self.sessions.len() - 1, true)))
+            }
+            // libolm reports a sender mismatch with the same error, so only
+            // a message from the expected sender means its one-time key was
+            // used up by an earlier session: a replay, not a new session
+            Err(olm_rs::errors::OlmSessionError::BadMessageKeyId)
+                if prekey_key(ciphertext, PREKEY_IDENTITY_KEY).as_deref()
+                    == Some(unpadded(their_identity_key)) =>
+            {
+                Err(OlmError::InvalidKey(REPLAYED_PREKEY.into()))
+            }
+            // Not a pre-key message after all
+            Err(_) => Ok(None),
+        }
     }
 
-    /// Unpickle (deserialize) the account
-    pub fn unpickle(data: &[u8]) -> Result<Self, OlmError> {
-        let pickle = std::str::from_utf8(data)
-            .map_err(|_| OlmError::AccountCreationFailed("Invalid pickle data".into()))?;
+    /// Whether decrypting `prekey_message` would use up a one-time key
+    ///
+    /// Reads the message without touching any state. A message for an
+    /// established session, or one built on a fallback key, doesn't use up
+    /// a key; neither does anything that isn't a pre-key message. Whether
+    /// the key named in the message still exists isn't checked, since
+    /// libolm doesn't list published one-time keys.
+    pub fn prekey_consumes_otk(&self, prekey_message: &str) -> bool {
+        let message = match olm_rs::session::OlmMessage::from_type_and_ciphertext(
+            0,
+            prekey_message.to_string(),
+        ) {
+            Ok(olm_rs::session::OlmMessage::PreKey(message)) => message,
+            _ => return false,
+        };
 
-        let account = olm_rs::account::OlmAccount::unpickle(
-            pickle.to_string(),
-            olm_rs::PicklingMode::EncryptWith(&[]),
-        ).map_err(|e| OlmError::AccountCreationFailed(format!("{:?}", e)))?;
+        let established = self.sessions.iter().any(|(_, session)| {
+            session.matches_inbound_session(message.clone()).unwrap_or(false)
+        });
+        if established {
+            return false;
+        }
 
-        Ok(Self {
-            account: Some(account),
-            sessions: Vec::new(),
-            current_session_id: 0,
-        })
+        prekey_key(prekey_message, PREKEY_ONE_TIME_KEY)
+            .is_some_and(|key| !self.is_fallback_key(&key))
     }
-}
 
-impl Drop for OlmSession {
-    fn drop(&mut self) {
-        // Clear sensitive data
-        if let Some(account) = &mut self.account {
-            let _ = account.generate_one_time_keys(0); // Clear one-time keys
+    /// Whether `key` is one of our current or previous fallback keys
+    fn is_fallback_key(&self, key: &str) -> bool {
+        [&self.fallback_key, &self.previous_fallback_key]
+            .into_iter()
+            .flatten()
+            .any(|fallback| fallback.key == key)
+    }
+
+    /// Decrypt a message whose type was lost in transport
+    ///
+    /// The ciphertext is first treated as a pre-key message from
+    /// `their_identity_key` (see [`OlmSession::decrypt_or_create`]). If it
+    /// isn't one, it is decrypted as a normal message on the established
+    /// sessions, current session first.
+    pub fn decrypt_auto(
+        &mut self,
+        their_identity_key: &str,
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, OlmError> {
+        let ciphertext_str = std::str::from_utf8(ciphertext)
+            .map_err(|_| OlmError::DecryptionFailed("Invalid ciphertext".into()))?;
+
+        if let Some((index, _)) = self.inbound_session_for(their_identity_key, ciphertext_str)? {
+            self.current_session_id = index;
+            return self.decrypt(ciphertext, 0);
+        }
+
+        self.decrypt_on_established(their_identity_key, ciphertext_str)
+    }
+
+    /// Decrypt a normal (type 1) message on whichever session it belongs to
+    ///
+    /// The current session is tried first. The session that decrypts the
+    /// message becomes the current one.
+    ///
+    /// libolm rejects the MAC on every session a message wasn't sent on, so
+    /// a MAC failure only means tampering on the session the message
+    /// belongs to. Normal messages carry no session id; that session is
+    /// known only when the sender has exactly one session here. `BadMac` is
+    /// returned if that session rejects the MAC, `SessionNotFound` if no
+    /// session decrypts the message for any other reason.
+    fn decrypt_on_established(
+        &mut self,
+        sender_identity_key: &str,
+        ciphertext: &str,
+    ) -> Result<Vec<u8>, OlmError> {
+        #[cfg(feature = "metrics")]
+        let _timer = Timer::start(Operation::OlmDecrypt);
+
+        let sender = unpadded(sender_identity_key);
+        let mut sender_sessions = self.sessions.iter().enumerate().filter(|(_, (session_id, _))| {
+            self.peers.get(session_id).is_some_and(|peer| peer == sender)
+        });
+        let owner = match (sender_sessions.next(), sender_sessions.next()) {
+            (Some((index, _)), None) => Some(index),
+            _ => None,
+        };
+
+        let current = self.current_session_id;
+        let order = std::iter::once(current)
+            .chain((0..self.sessions.len()).filter(|&index| index != current));
+
+        let mut bad_mac = false;
+        for index in order {
+            let Some((_, session)) = self.sessions.get(index) else {
+                continue;
+            };
+            let message = olm_rs::session::OlmMessage::from_type_and_ciphertext(
+                1,
+                ciphertext.to_string(),
+            ).map_err(|_| OlmError::DecryptionFailed("Invalid ciphertext".into()))?;
+
+            match session.decrypt(message) {
+                Ok(plaintext) => {
+                    self.current_session_id = index;
+                    self.record_received(index, 1, ciphertext);
+                    return Ok(plaintext.into_bytes());
+                }
+                Err(olm_rs::errors::OlmSessionError::BadMessageMac) if owner == Some(index) => {
+                    bad_mac = true;
+                }
+                Err(_) => {}
+            }
+        }
+
+        Err(if bad_mac { OlmError::BadMac } else { OlmError::SessionNotFound })
+    }
+
+    /// Decrypt a message of a known type from `sender_identity_key`
+    ///
+    /// Pre-key messages may create a new inbound session; normal messages
+    /// are decrypted on whichever established session they belong to.
+    fn decrypt_typed(
+        &mut self,
+        sender_identity_key: &str,
+        ciphertext: &[u8],
+        message_type: usize,
+    ) -> Result<Vec<u8>, OlmError> {
+        match message_type {
+            0 => Ok(self.decrypt_or_create(sender_identity_key, ciphertext)?.0),
+            1 => {
+                let ciphertext = std::str::from_utf8(ciphertext)
+                    .map_err(|_| OlmError::DecryptionFailed("Invalid ciphertext".into()))?;
+                self.decrypt_on_established(sender_identity_key, ciphertext)
+            }
+            _ => Err(OlmError::DecryptionFailed("Invalid message type".into())),
+        }
+    }
+
+    /// Decrypt a batch of to-device messages, each on its own
+    ///
+    /// A message that fails doesn't stop the rest; the results come back in
+    /// the order of `events`. Messages are decrypted in order, so a pre-key
+    /// message earlier in the batch can set up the session a later one
+    /// needs.
+    pub fn decrypt_batch(&mut self, events: &[EventInput]) -> Vec<Result<Vec<u8>, OlmError>> {
+        events
+            .iter()
+            .map(|event| self.decrypt_event(event))
+            .collect()
+    }
+
+    /// Decrypt a to-device message, holding its session to one algorithm
+    ///
+    /// The first event decrypted on a session pins the algorithm it
+    /// declares; a later event on that session declaring another one fails
+    /// with `AlgorithmMismatch` and its plaintext is wiped. Which session a
+    /// message belongs to is only known once it has been decrypted, so the
+    /// session's ratchet has still moved on. Once a session is pinned, an
+    /// event that declares no algorithm is refused the same way, so leaving
+    /// the field out doesn't get around the check; before that, such events
+    /// leave the session unpinned.
+    pub fn decrypt_event(&mut self, event: &EventInput) -> Result<Vec<u8>, OlmError> {
+        let mut plaintext = zeroize::Zeroizing::new(self.decrypt_typed(
+            &event.sender_key,
+            event.body.as_bytes(),
+            event.message_type,
+        )?);
+
+        let session_id = &self.sessions[self.current_session_id].0;
+        match (self.algorithms.get(session_id), &event.algorithm) {
+            (Some(pinned), declared) if declared.as_ref() != Some(pinned) => {
+                return Err(OlmError::AlgorithmMismatch {
+                    pinned: pinned.clone(),
+                    declared: declared.clone().unwrap_or_else(|| "nothing".into()),
+                });
+            }
+            (None, Some(declared)) => {
+                self.algorithms.insert(session_id.clone(), declared.clone());
+            }
+            _ => {}
         }
+
+        Ok(std::mem::take(&mut *plaintext))
+    }
+
+    /// Algorithm a session was pinned to by [`OlmSession::decrypt_event`],
+    /// if any
+    pub fn pinned_algorithm(&self, session_id: &str) -> Option<&str> {
+        self.algorithms.get(session_id).map(String::as_str)
+    }
+
+    /// Decrypt an incoming `m.room_key` to-device payload
+    ///
+    /// Pre-key messages may create a new inbound session. The payload must
+    /// be an `m.room_key` event for Megolm whose session key matches the
+    /// session id it claims.
+    pub fn unwrap_session_key(
+        &mut self,
+        sender_identity_key: &str,
+        ciphertext: &[u8],
+        message_type: usize,
+    ) -> Result<crate::megolm::RoomKeyInfo, OlmError> {
+        // Holds the Megolm session key, so wipe it whichever way this returns
+        let plaintext = zeroize::Zeroizing::new(
+            self.decrypt_typed(sender_identity_key, ciphertext, message_type)?,
+        );
+
+        let invalid = |reason: &str| OlmError::DecryptionFailed(format!("Invalid room key: {}", reason));
+
+        let payload: serde_json::Value = serde_json::from_slice(&plaintext)
+            .map_err(|_| invalid("not JSON"))?;
+        if payload.get("type").and_then(|t| t.as_str()) != Some("m.room_key") {
+            return Err(invalid("not an m.room_key event"));
+        }
+
+        let room_key: crate::megolm::RoomKeyInfo = payload
+            .get("content")
+            .cloned()
+            .ok_or_else(|| invalid("missing content"))
+            .and_then(|content| serde_json::from_value(content).map_err(|e| invalid(&e.to_string())))?;
+
+        if room_key.algorithm != crate::megolm::MEGOLM_ALGORITHM {
+            return Err(invalid("unsupported algorithm"));
+        }
+
+        crate::megolm::MegolmSession::create_inbound(
+            &room_key.session_key,
+            Some(&room_key.session_id),
+        )
+        .map_err(|e| invalid(&e.to_string()))?;
+
+        Ok(room_key)
+    }
+
+    /// Decrypt an `m.forwarded_room_key` event and import its Megolm session
+    ///
+    /// The forwarding chain is the chain in the event plus the device that
+    /// sent it, so a key forwarded straight from its creator's device has a
+    /// depth of 1. Keys that passed through more than `max_chain_depth`
+    /// devices are refused with `ForwardingChainTooLong` before anything is
+    /// imported; a limit of 0 refuses every forwarded key. The imported
+    /// session is never trusted.
+    pub fn import_forwarded_room_key(
+        &mut self,
+        event: &EventInput,
+        max_chain_depth: usize,
+    ) -> Result<(ForwardedRoomKey, crate::megolm::MegolmSession), OlmError> {
+        // Holds the Megolm session key, so wipe it whichever way this returns
+        let plaintext = zeroize::Zeroizing::new(self.decrypt_event(event)?);
+
+        let invalid = |reason: &str| {
+            OlmError::DecryptionFailed(format!("Invalid forwarded room key: {}", reason))
+        };
+
+        let payload: serde_json::Value = serde_json::from_slice(&plaintext)
+            .map_err(|_| invalid("not JSON"))?;
+        if payload.get("type").and_then(|t| t.as_str()) != Some("m.forwarded_room_key") {
+            return Err(invalid("not an m.forwarded_room_key event"));
+        }
+
+        let content: ForwardedRoomKeyContent = payload
+            .get("content")
+            .cloned()
+            .ok_or_else(|| invalid("missing content"))
+            .and_then(|content| {
+                serde_json::from_value(content).map_err(|e| invalid(&e.to_string()))
+            })?;
+
+        if content.algorithm != crate::megolm::MEGOLM_ALGORITHM {
+            return Err(invalid("unsupported algorithm"));
+        }
+
+        let mut chain = content.forwarding_curve25519_key_chain.clone();
+        chain.push(unpadded(&event.sender_key).to_string());
+        if chain.len() > max_chain_depth {
+            return Err(OlmError::ForwardingChainTooLong {
+                depth: chain.len(),
+                max: max_chain_depth,
+            });
+        }
+
+        let export = crate::megolm::ExportedSession {
+            algorithm: content.algorithm,
+            room_id: content.room_id.clone(),
+            sender_key: content.sender_key.clone(),
+            session_id: content.session_id.clone(),
+            session_key: content.session_key,
+            sender_claimed_keys: HashMap::from([(
+                "ed25519".to_string(),
+                content.sender_claimed_ed25519_key,
+            )]),
+            forwarding_curve25519_key_chain: chain.clone(),
+        };
+        let export = serde_json::to_string(&export).map_err(|e| invalid(&e.to_string()))?;
+        let session = crate::megolm::MegolmSession::import(&export, Some(&content.session_id))
+            .map_err(|e| invalid(&e.to_string()))?;
+
+        let key = ForwardedRoomKey {
+            room_id: content.room_id,
+            session_id: content.session_id,
+            sender_key: content.sender_key,
+            forwarding_curve25519_key_chain: chain,
+        };
+
+        Ok((key, session))
+    }
+
+    /// Decrypt a message that is expected to be text
+    ///
+    /// Fails with `DecryptionFailed` instead of returning bytes that aren't
+    /// valid UTF-8. Binary payloads should use [`OlmSession::decrypt`].
+    pub fn decrypt_text(
+        &mut self,
+        ciphertext: &[u8],
+        message_type: usize,
+    ) -> Result<String, OlmError> {
+        let mut plaintext = self.decrypt(ciphertext, message_type)?;
+
+        crate::utilities::take_utf8(&mut plaintext)
+            .ok_or_else(|| OlmError::DecryptionFailed("non-utf8 plaintext".into()))
+    }
+
+    /// Verify the self-signature on a peer's device keys
+    ///
+    /// The device's Curve25519 key must only be trusted for sessions if
+    /// this returns `true`: the object has to be signed by the Ed25519 key
+    /// it lists for the same device.
+    pub fn verify_device_keys(device_keys_json: &str) -> Result<bool, OlmError> {
+        let value: serde_json::Value = serde_json::from_str(device_keys_json)
+            .map_err(|e| OlmError::InvalidKey(format!("Invalid device keys JSON: {}", e)))?;
+
+        let field = |name: &str| {
+            value
+                .get(name)
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| OlmError::InvalidKey(format!("Missing {}", name)))
+        };
+        let user_id = field("user_id")?;
+        let device_id = field("device_id")?;
+        let key_id = format_key_id("ed25519", device_id);
+
+        let ed25519 = value
+            .pointer(&format!("/keys/{}", key_id))
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| OlmError::InvalidKey("Missing Ed25519 key".into()))?;
+        let ed25519 = vodozemac::Ed25519PublicKey::from_base64(ed25519)
+            .map_err(|e| OlmError::InvalidKey(format!("Invalid Ed25519 key: {}", e)))?;
+
+        let signature = match value
+            .get("signatures")
+            .and_then(|signatures| signatures.get(user_id))
+            .and_then(|signatures| signatures.get(&key_id))
+            .and_then(|v| v.as_str())
+        {
+            Some(signature) => signature,
+            None => return Ok(false),
+        };
+        let signature = match vodozemac::Ed25519Signature::from_base64(signature) {
+            Ok(signature) => signature,
+            Err(_) => return Ok(false),
+        };
+
+        let canonical = crate::utilities::signable_json(&value);
+        Ok(ed25519.verify(canonical.as_bytes(), &signature).is_ok())
+    }
+
+    /// Verify every device in a `/keys/query` response at once
+    ///
+    /// `query_json` is either the whole response or just its `device_keys`
+    /// map. A device is valid if its keys are self-signed (see
+    /// [`OlmSession::verify_device_keys`]) and were listed under its own user
+    /// and device id; anything malformed is reported as invalid rather than
+    /// failing the whole batch.
+    pub fn verify_device_map(query_json: &str) -> Result<DeviceVerificationResults, OlmError> {
+        let value: serde_json::Value = serde_json::from_str(query_json)
+            .map_err(|e| OlmError::InvalidKey(format!("Invalid device map JSON: {}", e)))?;
+        let users = value
+            .get("device_keys")
+            .unwrap_or(&value)
+            .as_object()
+            .ok_or_else(|| OlmError::InvalidKey("Device map is not an object".into()))?;
+
+        let mut results = DeviceVerificationResults::new();
+        for (user_id, devices) in users {
+            let user_results = results.entry(user_id.clone()).or_default();
+            let Some(devices) = devices.as_object() else {
+                continue;
+            };
+
+            for (device_id, device_keys) in devices {
+                let field = |name| device_keys.get(name).and_then(|v| v.as_str());
+                let valid = field("user_id") == Some(user_id.as_str())
+                    && field("device_id") == Some(device_id.as_str())
+                    && Self::verify_device_keys(&device_keys.to_string()).unwrap_or(false);
+                user_results.insert(device_id.clone(), valid);
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Check the signature on a key from a `/keys/claim` response
+    ///
+    /// `claim_json` is the device's entry in `one_time_keys`, a map with a
+    /// single `signed_curve25519:<id>` key. The key must be signed by
+    /// `signer_ed25519_key`, the device's verified Ed25519 key.
+    pub fn verify_claimed_key(
+        claim_json: &str,
+        signer_ed25519_key: &str,
+    ) -> Result<ClaimedKey, OlmError> {
+        let claim: serde_json::Map<String, serde_json::Value> = serde_json::from_str(claim_json)
+            .map_err(|e| OlmError::InvalidKey(format!("Invalid key claim JSON: {}", e)))?;
+
+        let mut entries = claim.iter();
+        let (key_id, signed_key) = match (entries.next(), entries.next()) {
+            (Some(entry), None) => entry,
+            _ => return Err(OlmError::InvalidKey("Expected exactly one claimed key".into())),
+        };
+        let key_id = key_id
+            .strip_prefix("signed_curve25519:")
+            .ok_or_else(|| OlmError::InvalidKey(format!("Unsigned key algorithm: {}", key_id)))?;
+
+        let key = signed_key
+            .get("key")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| OlmError::InvalidKey("Missing key".into()))?;
+        vodozemac::Curve25519PublicKey::from_base64(key)
+            .map_err(|e| OlmError::InvalidKey(format!("Invalid Curve25519 key: {}", e)))?;
+
+        let signer = vodozemac::Ed25519PublicKey::from_base64(signer_ed25519_key)
+            .map_err(|e| OlmError::InvalidKey(format!("Invalid Ed25519 key: {}", e)))?;
+        let canonical = crate::utilities::signable_json(signed_key);
+
+        // The signature sits under the owner's user id and the device's key id
+        let signed = signed_key
+            .get("signatures")
+            .and_then(|v| v.as_object())
+            .into_iter()
+            .flat_map(|users| users.values())
+            .filter_map(|signatures| signatures.as_object())
+            .flat_map(|signatures| signatures.iter())
+            .filter(|(id, _)| id.starts_with("ed25519:"))
+            .filter_map(|(_, signature)| signature.as_str())
+            .filter_map(|signature| vodozemac::Ed25519Signature::from_base64(signature).ok())
+            .any(|signature| signer.verify(canonical.as_bytes(), &signature).is_ok());
+
+        if !signed {
+            return Err(OlmError::InvalidKey("Claimed key is not signed by the device".into()));
+        }
+
+        Ok(ClaimedKey {
+            key_id: key_id.to_string(),
+            key: key.to_string(),
+            fallback: signed_key.get("fallback").and_then(|v| v.as_bool()).unwrap_or(false),
+        })
+    }
+
+    /// Pickle (serialize) the account
+    ///
+    /// The output starts with a format version byte
+    /// ([`crate::utilities::PICKLE_VERSION`]); sessions aren't included.
+    pub fn pickle(&self) -> Result<Vec<u8>, OlmError> {
+        let account = self.account.as_ref()
+            .ok_or(OlmError::AccountCreationFailed("No account".into()))?;
+
+        let pickle = AccountPickle {
+            account: account.pickle(olm_rs::PicklingMode::Unencrypted),
+            fallback_key_published: self.fallback_key_published,
+            fallback_key: self.fallback_key.clone(),
+            previous_fallback_key: self.previous_fallback_key.clone(),
+        };
+        let payload = serde_json::to_vec(&pickle)
+            .map_err(|e| OlmError::AccountCreationFailed(e.to_string()))?;
+
+        Ok(crate::utilities::versioned_pickle(&payload))
+    }
+
+    /// Unpickle (deserialize) the account
+    ///
+    /// Pickles from older format versions are migrated on the way in.
+    pub fn unpickle(data: &[u8]) -> Result<Self, OlmError> {
+        let pickle = match crate::utilities::split_pickle(data) {
+            (1, payload) => AccountPickle::migrate_v1(payload)?,
+            (2, payload) => serde_json::from_slice(payload)
+                .map_err(|e| OlmError::AccountCreationFailed(format!("Invalid pickle: {}", e)))?,
+            _ => return Err(OlmError::AccountCreationFailed("unsupported pickle version".into())),
+        };
+
+        let account = olm_rs::account::OlmAccount::unpickle(
+            pickle.account,
+            olm_rs::PicklingMode::Unencrypted,
+        ).map_err(|e| OlmError::AccountCreationFailed(format!("{:?}", e)))?;
+
+        Ok(Self {
+            account: Some(account),
+            sessions: Vec::new(),
+            current_session_id: 0,
+            fallback_key_published: pickle.fallback_key_published,
+            fallback_key: pickle.fallback_key,
+            previous_fallback_key: pickle.previous_fallback_key,
+            ratchet_states: HashMap::new(),
+            peers: HashMap::new(),
+            algorithms: HashMap::new(),
+        })
+    }
+
+    /// Check that the account's state is consistent
+    ///
+    /// Meant to run right after [`OlmSession::unpickle`] or
+    /// [`OlmSession::import_store`], so a corrupted pickle is reported
+    /// there instead of failing later during encryption. Checks that every
+    /// key is a well-formed 32-byte key, that there are no more one-time
+    /// keys than libolm can hold, and that the session list agrees with
+    /// itself.
+    pub fn validate(&self) -> Result<(), OlmError> {
+        let account = self.account.as_ref()
+            .ok_or(OlmError::AccountCreationFailed("No account".into()))?;
+        let invalid = |what: String| Err(OlmError::InvalidState(what));
+        let well_formed = |key: &str| crate::utilities::decode_public_key(key).is_ok();
+
+        let identity = account.parsed_identity_keys();
+        if !well_formed(identity.curve25519()) {
+            return invalid("malformed Curve25519 identity key".into());
+        }
+        if vodozemac::Ed25519PublicKey::from_base64(identity.ed25519()).is_err() {
+            return invalid("malformed Ed25519 identity key".into());
+        }
+
+        let one_time_keys = account.parsed_one_time_keys();
+        let one_time_keys = one_time_keys.curve25519();
+        if one_time_keys.len() > account.max_number_of_one_time_keys() {
+            return invalid(format!(
+                "{} one-time keys, more than the maximum of {}",
+                one_time_keys.len(),
+                account.max_number_of_one_time_keys()
+            ));
+        }
+        if let Some((key_id, _)) = one_time_keys.iter().find(|(_, key)| !well_formed(key)) {
+            return invalid(format!("malformed one-time key {}", key_id));
+        }
+
+        for (name, fallback) in [
+            ("fallback", &self.fallback_key),
+            ("previous fallback", &self.previous_fallback_key),
+        ] {
+            if let Some(key) = fallback.as_ref().filter(|key| !well_formed(&key.key)) {
+                return invalid(format!("malformed {} key {}", name, key.key_id));
+            }
+        }
+        if self.fallback_key.is_none() && self.previous_fallback_key.is_some() {
+            return invalid("previous fallback key without a current one".into());
+        }
+
+        if !self.sessions.is_empty() && self.current_session_id >= self.sessions.len() {
+            return invalid(format!(
+                "current session {} out of range for {} sessions",
+                self.current_session_id,
+                self.sessions.len()
+            ));
+        }
+        let mut session_ids = std::collections::HashSet::new();
+        for (session_id, session) in &self.sessions {
+            if *session_id != session.session_id() {
+                return invalid(format!("session {} stored under the wrong id", session_id));
+            }
+            if !session_ids.insert(session_id) {
+                return invalid(format!("duplicate session {}", session_id));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Serialize the account and all of its sessions into one blob
+    ///
+    /// Everything is pickled with `key`, so the app can persist the whole
+    /// store in a single write and never ends up with an account that
+    /// doesn't match its sessions.
+    pub fn export_store(&self, key: &[u8]) -> Result<Vec<u8>, OlmError> {
+        let account = self.account.as_ref()
+            .ok_or(OlmError::AccountCreationFailed("No account".into()))?;
+
+        let mode = || olm_rs::PicklingMode::Encrypted { key: key.to_vec() };
+
+        let store = CryptoStore {
+            version: STORE_VERSION,
+            account: account.pickle(mode()),
+            sessions: self.sessions.iter().map(|(_, session)| session.pickle(mode())).collect(),
+            current_session_id: self.current_session_id,
+            fallback_key_published: self.fallback_key_published,
+            fallback_key: self.fallback_key.clone(),
+            previous_fallback_key: self.previous_fallback_key.clone(),
+            peers: self.peers.clone(),
+            algorithms: self.algorithms.clone(),
+        };
+
+        serde_json::to_vec(&store).map_err(|e| OlmError::InvalidStore(e.to_string()))
+    }
+
+    /// Restore an account and its sessions from [`OlmSession::export_store`]
+    pub fn import_store(data: &[u8], key: &[u8]) -> Result<Self, OlmError> {
+        let store = CryptoStore::parse(data)?;
+
+        let mode = || olm_rs::PicklingMode::Encrypted { key: key.to_vec() };
+
+        let account = olm_rs::account::OlmAccount::unpickle(store.account, mode())
+            .map_err(|e| OlmError::InvalidStore(format!("Account: {:?}", e)))?;
+
+        let sessions = store
+            .sessions
+            .into_iter()
+            .map(|pickle| {
+                let session = olm_rs::session::OlmSession::unpickle(pickle, mode())
+                    .map_err(|e| OlmError::InvalidStore(format!("Session: {:?}", e)))?;
+                Ok((session.session_id(), session))
+            })
+            .collect::<Result<Vec<_>, OlmError>>()?;
+
+        Ok(Self {
+            account: Some(account),
+            sessions,
+            current_session_id: store.current_session_id,
+            fallback_key_published: store.fallback_key_published,
+            fallback_key: store.fallback_key,
+            previous_fallback_key: store.previous_fallback_key,
+            ratchet_states: HashMap::new(),
+            peers: store.peers,
+            algorithms: store.algorithms,
+        })
+    }
+
+    /// Read the identity keys from an exported store without restoring it
+    ///
+    /// Only the account is unpickled; the sessions are left alone, so this
+    /// stays cheap for stores with many sessions.
+    pub fn identity_keys_from_pickle(data: &[u8], key: &[u8]) -> Result<IdentityKeys, OlmError> {
+        let store = CryptoStore::parse(data)?;
+
+        let account = olm_rs::account::OlmAccount::unpickle(
+            store.account,
+            olm_rs::PicklingMode::Encrypted { key: key.to_vec() },
+        )
+        .map_err(|e| OlmError::InvalidStore(format!("Account: {:?}", e)))?;
+
+        let keys = account.parsed_identity_keys();
+
+        Ok(IdentityKeys {
+            curve25519: keys.curve25519().to_string(),
+            ed25519: keys.ed25519().to_string(),
+        })
+    }
+
+    /// Re-encrypt an exported store under a new pickle key
+    ///
+    /// For when the pickle key itself rotates: every pickle in the store is
+    /// decrypted with `old_key` and encrypted again with `new_key` in
+    /// memory, so the plaintext never reaches disk. No account is
+    /// restored, so nothing else about the store changes.
+    pub fn rekey_pickle(data: &[u8], old_key: &[u8], new_key: &[u8]) -> Result<Vec<u8>, OlmError> {
+        let mut store = CryptoStore::parse(data)?;
+
+        let old_mode = || olm_rs::PicklingMode::Encrypted { key: old_key.to_vec() };
+        let new_mode = || olm_rs::PicklingMode::Encrypted { key: new_key.to_vec() };
+
+        let account = olm_rs::account::OlmAccount::unpickle(store.account, old_mode())
+            .map_err(|e| OlmError::InvalidStore(format!("Account: {:?}", e)))?;
+        store.account = account.pickle(new_mode());
+
+        store.sessions = store
+            .sessions
+            .into_iter()
+            .map(|pickle| {
+                let session = olm_rs::session::OlmSession::unpickle(pickle, old_mode())
+                    .map_err(|e| OlmError::InvalidStore(format!("Session: {:?}", e)))?;
+                Ok(session.pickle(new_mode()))
+            })
+            .collect::<Result<_, OlmError>>()?;
+
+        serde_json::to_vec(&store).map_err(|e| OlmError::InvalidStore(e.to_string()))
+    }
+
+    /// Two accounts with an established session, for integration harnesses
+    ///
+    /// Bob publishes one one-time key, Alice starts a session on it and
+    /// each side sends the other one message, so both ends are past the
+    /// pre-key stage. The steps are fixed; the keys are not, since libolm
+    /// draws them from the system RNG and can't be seeded. Returns Alice,
+    /// Bob and the session's Matrix id.
+    #[cfg(feature = "testing")]
+    pub fn test_pair() -> Result<(OlmSession, OlmSession, String), OlmError> {
+        let mut alice = Self::create_account()?;
+        let alice_identity = alice.get_identity_keys()?.curve25519;
+
+        let mut bob = Self::create_account()?;
+        let bob_identity = bob.get_identity_keys()?.curve25519;
+        let bob_otk = bob.generate_one_time_keys(1)?.remove(0);
+        bob.mark_keys_as_published()?;
+
+        let created = alice
+            .create_outbound_session_detailed(bob_identity.as_bytes(), bob_otk.key.as_bytes())?;
+
+        let hello = alice.encrypt(b"hello")?;
+        bob.decrypt_or_create(&alice_identity, hello.body.as_bytes())?;
+
+        let reply = bob.encrypt(b"hello")?;
+        alice.decrypt(reply.body.as_bytes(), reply.message_type)?;
+
+        Ok((alice, bob, created.session_id))
+    }
+}
+
+impl CryptoStore {
+    /// Parse a store blob, checking its version
+    fn parse(data: &[u8]) -> Result<Self, OlmError> {
+        let store: CryptoStore = serde_json::from_slice(data)
+            .map_err(|e| OlmError::InvalidStore(e.to_string()))?;
+
+        if store.version != STORE_VERSION {
+            return Err(OlmError::InvalidStore(format!(
+                "Unsupported version {}",
+                store.version
+            )));
+        }
+
+        Ok(store)
+    }
+}
+
+/// Read the chain index (the `counter` field) of an Olm message
+///
+/// Normal messages are a version byte, protobuf-style fields and an 8-byte
+/// MAC. Pre-key messages carry a normal message in field 4.
+fn message_chain_index(message_type: usize, body: &str) -> Option<u32> {
+    let bytes = crate::utilities::base64_decode_lenient(body).ok()?;
+
+    let message = if message_type == 0 {
+        match message_field(bytes.get(1..)?, 4)? {
+            MessageField::Bytes(inner) => inner.to_vec(),
+            MessageField::Varint(_) => return None,
+        }
+    } else {
+        bytes
+    };
+
+    let fields = message.get(1..message.len().checked_sub(8)?)?;
+    match message_field(fields, 2)? {
+        MessageField::Varint(counter) => u32::try_from(counter).ok(),
+        MessageField::Bytes(_) => None,
+    }
+}
+
+enum MessageField<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+/// [`OlmError::InvalidKey`] reason for a pre-key message whose one-time
+/// key was already used
+pub const REPLAYED_PREKEY: &str = "replayed prekey";
+
+/// Strip base64 padding, so keys compare equal however they were encoded
+fn unpadded(key: &str) -> &str {
+    key.trim_end_matches('=')
+}
+
+/// Pre-key message field holding the recipient's one-time key
+const PREKEY_ONE_TIME_KEY: u64 = 1;
+
+/// Pre-key message field holding the sender's identity key
+const PREKEY_IDENTITY_KEY: u64 = 3;
+
+/// Read a Curve25519 key field of a pre-key message, as unpadded base64
+fn prekey_key(ciphertext: &str, field: u64) -> Option<String> {
+    let bytes = crate::utilities::base64_decode_lenient(ciphertext).ok()?;
+    match message_field(bytes.get(1..)?, field)? {
+        MessageField::Bytes(key) => Some(crate::utilities::base64_encode_with(
+            key,
+            crate::utilities::Base64Variant::StandardNoPad,
+        )),
+        _ => None,
+    }
+}
+
+/// Find a field by number in a run of protobuf-style fields
+fn message_field(mut buf: &[u8], number: u64) -> Option<MessageField<'_>> {
+    fn varint(buf: &mut &[u8]) -> Option<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = buf.split_first()?;
+            *buf = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    while !buf.is_empty() {
+        let tag = varint(&mut buf)?;
+        let field = match tag & 7 {
+            0 => MessageField::Varint(varint(&mut buf)?),
+            2 => {
+                let len = usize::try_from(varint(&mut buf)?).ok()?;
+                let (value, rest) = (buf.get(..len)?, buf.get(len..)?);
+                buf = rest;
+                MessageField::Bytes(value)
+            }
+            _ => return None,
+        };
+        if tag >> 3 == number {
+            return Some(field);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Alice with an outbound session to Bob, and both curve25519 keys
+    ///
+    /// Bob has no session yet; one is created from Alice's first message.
+    pub(crate) fn outbound_pair() -> (OlmSession, String, OlmSession, String) {
+        let mut alice = OlmSession::create_account().unwrap();
+        let alice_identity = alice.get_identity_keys().unwrap().curve25519;
+
+        let mut bob = OlmSession::create_account().unwrap();
+        let bob_identity = bob.get_identity_keys().unwrap().curve25519;
+        let bob_otk = bob.generate_one_time_keys(1).unwrap().remove(0);
+        alice.create_outbound_session(bob_identity.as_bytes(), bob_otk.key.as_bytes()).unwrap();
+
+        (alice, alice_identity, bob, bob_identity)
+    }
+
+    #[test]
+    fn decrypt_auto_handles_pre_key_and_established_messages() {
+        let (mut alice, alice_identity, mut bob, bob_identity) = outbound_pair();
+
+        // Pre-key message, type lost in transport
+        let ciphertext = alice.encrypt(b"first").unwrap();
+        assert_eq!(ciphertext.message_type, 0);
+        let plaintext = bob.decrypt_auto(&alice_identity, ciphertext.body.as_bytes()).unwrap();
+        assert_eq!(plaintext, b"first");
+
+        // Bob replies on the session the pre-key message created
+        let reply = bob.encrypt(b"reply").unwrap();
+        assert_eq!(reply.message_type, 1);
+        let plaintext = alice.decrypt_auto(&bob_identity, reply.body.as_bytes()).unwrap();
+        assert_eq!(plaintext, b"reply");
+
+        // Established session in the other direction
+        let ciphertext = alice.encrypt(b"second").unwrap();
+        assert_eq!(ciphertext.message_type, 1);
+        let plaintext = bob.decrypt_auto(&alice_identity, ciphertext.body.as_bytes()).unwrap();
+        assert_eq!(plaintext, b"second");
+        assert_eq!(bob.sessions.len(), 1);
+    }
+
+    #[test]
+    fn repeated_pre_key_messages_reuse_the_session() {
+        let (mut alice, alice_identity, mut bob, _) = outbound_pair();
+
+        // Alice hasn't heard back yet, so both are pre-key messages
+        let first = alice.encrypt(b"first").unwrap();
+        let second = alice.encrypt(b"second").unwrap();
+        assert_eq!(first.message_type, 0);
+        assert_eq!(second.message_type, 0);
+
+        // Delivered out of order
+        let (plaintext, created) = bob.decrypt_or_create(&alice_identity, second.body.as_bytes()).unwrap();
+        assert_eq!(plaintext, b"second");
+        assert!(created);
+
+        let (plaintext, created) = bob.decrypt_or_create(&alice_identity, first.body.as_bytes()).unwrap();
+        assert_eq!(plaintext, b"first");
+        assert!(!created);
+        assert_eq!(bob.sessions.len(), 1);
+
+        // A resend of an already decrypted message must not start a new session
+        assert!(bob.decrypt_or_create(&alice_identity, second.body.as_bytes()).is_err());
+        assert_eq!(bob.sessions.len(), 1);
+    }
+
+    #[test]
+    fn rekeyed_store_opens_only_with_the_new_key() {
+        let (alice, alice_identity, mut bob, _) = outbound_pair();
+        let alice_ed25519 = alice.get_identity_keys().unwrap().ed25519;
+
+        let (key_a, key_b) = ([1u8; 32], [2u8; 32]);
+        let blob = alice.export_store(&key_a).unwrap();
+        let rekeyed = OlmSession::rekey_pickle(&blob, &key_a, &key_b).unwrap();
+
+        assert!(OlmSession::import_store(&rekeyed, &key_a).is_err());
+        assert!(OlmSession::rekey_pickle(&rekeyed, &key_a, &key_b).is_err());
+
+        let mut restored = OlmSession::import_store(&rekeyed, &key_b).unwrap();
+        assert_eq!(restored.get_identity_keys().unwrap().ed25519, alice_ed25519);
+        assert_eq!(restored.session_count(), 1);
+
+        // The rekeyed session still talks to Bob
+        let message = restored.encrypt(b"hello").unwrap();
+        let (plaintext, _) = bob
+            .decrypt_or_create(&alice_identity, message.body.as_bytes())
+            .unwrap();
+        assert_eq!(plaintext, b"hello");
+    }
+
+    #[test]
+    fn corrupt_event_does_not_fail_the_batch() {
+        let (mut alice, alice_identity, mut bob, _) = outbound_pair();
+
+        let event = |message: EncryptedMessage| EventInput {
+            sender_key: alice_identity.clone(),
+            message_type: message.message_type,
+            body: message.body,
+            algorithm: None,
+        };
+        let first = event(alice.encrypt(b"first").unwrap());
+        let mut corrupt = event(alice.encrypt(b"second").unwrap());
+        corrupt.body = corrupt.body.chars().rev().collect();
+        let third = event(alice.encrypt(b"third").unwrap());
+        let unknown_type = EventInput { message_type: 7, ..event(alice.encrypt(b"x").unwrap()) };
+
+        let results = bob.decrypt_batch(&[first, corrupt, third, unknown_type]);
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].as_ref().unwrap(), b"first");
+        assert!(results[1].is_err());
+        assert_eq!(results[2].as_ref().unwrap(), b"third");
+        assert!(results[3].is_err());
+    }
+
+    #[test]
+    fn crypto_store_round_trip() {
+        let mut alice = OlmSession::create_account().unwrap();
+        let alice_keys = alice.get_identity_keys().unwrap();
+
+        let mut bob = OlmSession::create_account().unwrap();
+        let mut carol = OlmSession::create_account().unwrap();
+        for peer in [&mut bob, &mut carol] {
+            let identity = peer.get_identity_keys().unwrap().curve25519;
+            let otk = peer.generate_one_time_keys(1).unwrap().remove(0);
+            alice
+                .create_outbound_session(identity.as_bytes(), otk.key.as_bytes())
+                .unwrap();
+        }
+
+        let key = [3u8; 32];
+        let blob = alice.export_store(&key).unwrap();
+        let restored = OlmSession::import_store(&blob, &key).unwrap();
+
+        let restored_keys = restored.get_identity_keys().unwrap();
+        assert_eq!(restored_keys.curve25519, alice_keys.curve25519);
+        assert_eq!(restored_keys.ed25519, alice_keys.ed25519);
+
+        let session_ids = |account: &OlmSession| {
+            account.sessions.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>()
+        };
+        assert_eq!(session_ids(&restored).len(), 2);
+        assert_eq!(session_ids(&restored), session_ids(&alice));
+        assert_eq!(restored.current_session_id, alice.current_session_id);
+
+        // The restored session to Carol still talks to Carol
+        let mut restored = restored;
+        let alice_identity = alice_keys.curve25519;
+        let ciphertext = restored.encrypt(b"still here").unwrap();
+        let plaintext = carol.decrypt_auto(&alice_identity, ciphertext.body.as_bytes()).unwrap();
+        assert_eq!(plaintext, b"still here");
+
+        assert!(OlmSession::import_store(&blob, &[4u8; 32]).is_err());
+    }
+
+    fn self_signed_device_keys(signing_key: &vodozemac::Ed25519Keypair) -> serde_json::Value {
+        self_signed_device_keys_for(signing_key, "@alice:example.org", "JLAFKJWSCS")
+    }
+
+    fn self_signed_device_keys_for(
+        signing_key: &vodozemac::Ed25519Keypair,
+        user_id: &str,
+        device_id: &str,
+    ) -> serde_json::Value {
+        let mut device_keys = serde_json::json!({
+            "user_id": user_id,
+            "device_id": device_id,
+            "algorithms": ["m.olm.v1.curve25519-aes-sha2", "m.megolm.v1.aes-sha2"],
+            "keys": {
+                format_key_id("curve25519", device_id): "3C5BFWi2Y8MaVvjM8M22DBmh24PmgR0nPvJOIArzgyI",
+                format_key_id("ed25519", device_id): signing_key.public_key().to_base64(),
+            },
+        });
+        let signature = signing_key.sign(crate::utilities::canonical_json(&device_keys).as_bytes());
+        device_keys["signatures"] = serde_json::json!({
+            user_id: { format_key_id("ed25519", device_id): signature.to_base64() },
+        });
+        device_keys
+    }
+
+    #[test]
+    fn self_signed_device_keys_verify() {
+        let signing_key = vodozemac::Ed25519Keypair::new();
+        let device_keys = self_signed_device_keys(&signing_key);
+
+        assert!(OlmSession::verify_device_keys(&device_keys.to_string()).unwrap());
+    }
+
+    #[test]
+    fn tampered_device_keys_are_rejected() {
+        let signing_key = vodozemac::Ed25519Keypair::new();
+        let mut device_keys = self_signed_device_keys(&signing_key);
+        device_keys["keys"]["curve25519:JLAFKJWSCS"] =
+            "ZmFrZSBrZXkgZnJvbSBhIG1hbGljaW91cyBzZXJ2ZXI".into();
+
+        assert!(!OlmSession::verify_device_keys(&device_keys.to_string()).unwrap());
+    }
+
+    #[test]
+    fn device_map_reports_each_device() {
+        let key = vodozemac::Ed25519Keypair::new;
+        let mut tampered = self_signed_device_keys_for(&key(), "@bob:example.org", "BOBPHONE");
+        tampered["keys"]["curve25519:BOBPHONE"] =
+            "ZmFrZSBrZXkgZnJvbSBhIG1hbGljaW91cyBzZXJ2ZXI".into();
+
+        let response = serde_json::json!({
+            "failures": {},
+            "device_keys": {
+                "@alice:example.org": {
+                    "JLAFKJWSCS": self_signed_device_keys(&key()),
+                    // Valid keys, but listed under another device's id
+                    "OTHERDEVICE": self_signed_device_keys(&key()),
+                },
+                "@bob:example.org": {
+                    "BOBLAPTOP": self_signed_device_keys_for(&key(), "@bob:example.org", "BOBLAPTOP"),
+                    "BOBPHONE": tampered,
+                    "BROKEN": "not a device",
+                },
+            },
+        });
+
+        let results = OlmSession::verify_device_map(&response.to_string()).unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results["@alice:example.org"]["JLAFKJWSCS"]);
+        assert!(!results["@alice:example.org"]["OTHERDEVICE"]);
+        assert!(results["@bob:example.org"]["BOBLAPTOP"]);
+        assert!(!results["@bob:example.org"]["BOBPHONE"]);
+        assert!(!results["@bob:example.org"]["BROKEN"]);
+
+        // The bare device_keys map works too
+        let device_keys = response["device_keys"].to_string();
+        assert_eq!(OlmSession::verify_device_map(&device_keys).unwrap(), results);
+    }
+
+    #[test]
+    fn fallback_key_is_published_after_marking() {
+        let mut account = OlmSession::create_account().unwrap();
+        assert!(!account.fallback_key_published());
+
+        account.generate_fallback_key().unwrap();
+        assert!(!account.fallback_key_published());
+
+        account.mark_keys_as_published().unwrap();
+        assert!(account.fallback_key_published());
+
+        // A rotated fallback key has to be uploaded again
+        account.generate_fallback_key().unwrap();
+        assert!(!account.fallback_key_published());
+    }
+
+    #[test]
+    fn session_with_previous_fallback_key_after_rotation() {
+        let mut alice = OlmSession::create_account().unwrap();
+        let alice_identity = alice.get_identity_keys().unwrap().curve25519;
+
+        let mut bob = OlmSession::create_account().unwrap();
+        let bob_identity = bob.get_identity_keys().unwrap().curve25519;
+        assert!(bob.active_fallback_key().is_none());
+
+        let first = bob.generate_fallback_key().unwrap();
+        bob.mark_keys_as_published().unwrap();
+        assert!(bob.previous_fallback_key().is_none());
+
+        // Alice claimed the first key before Bob rotated
+        alice
+            .create_outbound_session(bob_identity.as_bytes(), first.key.as_bytes())
+            .unwrap();
+
+        let second = bob.generate_fallback_key().unwrap();
+        assert_eq!(bob.active_fallback_key().unwrap().key, second.key);
+        assert_eq!(bob.previous_fallback_key().unwrap().key, first.key);
+
+        let ciphertext = alice.encrypt(b"hello").unwrap();
+        assert_eq!(ciphertext.message_type, 0);
+        let (plaintext, created) = bob
+            .decrypt_or_create(&alice_identity, ciphertext.body.as_bytes())
+            .unwrap();
+        assert_eq!(plaintext, b"hello");
+        assert!(created);
+
+        // Both keys survive a store round trip
+        let key = [5u8; 32];
+        let restored = OlmSession::import_store(&bob.export_store(&key).unwrap(), &key).unwrap();
+        assert_eq!(restored.active_fallback_key().unwrap().key, second.key);
+        assert_eq!(restored.previous_fallback_key().unwrap().key, first.key);
+    }
+
+    #[test]
+    fn replayed_prekey_is_rejected() {
+        let (mut alice, alice_identity, mut bob, _) = outbound_pair();
+
+        let message = alice.encrypt(b"hello").unwrap();
+        bob.decrypt_or_create(&alice_identity, message.body.as_bytes()).unwrap();
+
+        // Without the session it created, only the used-up key is left
+        bob.clear_sessions();
+        let result = bob.decrypt_or_create(&alice_identity, message.body.as_bytes());
+        assert!(matches!(result, Err(OlmError::InvalidKey(e)) if e == "replayed prekey"));
+    }
+
+    #[test]
+    fn fallback_key_serves_several_inbound_sessions() {
+        let mut bob = OlmSession::create_account().unwrap();
+        let bob_identity = bob.get_identity_keys().unwrap().curve25519;
+        let fallback = bob.generate_fallback_key().unwrap();
+        bob.mark_keys_as_published().unwrap();
+
+        let mut senders: Vec<_> = (0..2).map(|_| OlmSession::create_account().unwrap()).collect();
+        for (i, sender) in senders.iter_mut().enumerate() {
+            let identity = sender.get_identity_keys().unwrap().curve25519;
+            sender
+                .create_outbound_session(bob_identity.as_bytes(), fallback.key.as_bytes())
+                .unwrap();
+
+            let plaintext = format!("hello from {}", i);
+            let message = sender.encrypt(plaintext.as_bytes()).unwrap();
+            let (decrypted, created) =
+                bob.decrypt_or_create(&identity, message.body.as_bytes()).unwrap();
+            assert_eq!(decrypted, plaintext.as_bytes());
+            assert!(created);
+        }
+
+        assert_eq!(bob.sessions.len(), 2);
+        assert_eq!(bob.active_fallback_key().unwrap().key, fallback.key);
+    }
+
+    #[test]
+    fn outbound_session_id_matches_inbound_side() {
+        let mut alice = OlmSession::create_account().unwrap();
+        let alice_identity = alice.get_identity_keys().unwrap().curve25519;
+
+        let mut bob = OlmSession::create_account().unwrap();
+        let bob_identity = bob.get_identity_keys().unwrap().curve25519;
+        let bob_otk = bob.generate_one_time_keys(1).unwrap().remove(0);
+
+        let created = alice
+            .create_outbound_session_detailed(bob_identity.as_bytes(), bob_otk.key.as_bytes())
+            .unwrap();
+        assert_eq!(alice.session_id(created.handle), Some(created.session_id.as_str()));
+
+        let ciphertext = alice.encrypt(b"hello").unwrap();
+        bob.decrypt_or_create(&alice_identity, ciphertext.body.as_bytes()).unwrap();
+
+        assert_eq!(bob.session_id(0), Some(created.session_id.as_str()));
+    }
+
+    #[test]
+    fn session_counts_once_a_message_was_received() {
+        let (mut alice, alice_identity, mut bob, bob_identity) = outbound_pair();
+
+        assert!(!alice.has_session_with(&bob_identity));
+        assert!(!bob.has_session_with(&alice_identity));
+
+        let message = alice.encrypt(b"hello").unwrap();
+        bob.decrypt_or_create(&alice_identity, message.body.as_bytes()).unwrap();
+        assert!(bob.has_session_with(&alice_identity));
+        assert!(!bob.has_session_with(&bob_identity));
+
+        let reply = bob.encrypt(b"hi").unwrap();
+        alice.decrypt(reply.body.as_bytes(), reply.message_type).unwrap();
+        assert!(alice.has_session_with(&bob_identity));
+
+        let restored = OlmSession::import_store(&alice.export_store(b"key").unwrap(), b"key");
+        assert!(restored.unwrap().has_session_with(&bob_identity));
+    }
+
+    #[test]
+    fn transport_message_decrypts_as_sent() {
+        let (mut alice, alice_identity, mut bob, _) = outbound_pair();
+
+        for plaintext in ["first", "second"] {
+            let message = alice.encrypt_for_transport(plaintext.as_bytes()).unwrap();
+            let json = serde_json::to_string(&message).unwrap();
+            let transport: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+            let body = transport["body"].as_str().unwrap();
+            let (decrypted, _) = bob.decrypt_or_create(&alice_identity, body.as_bytes()).unwrap();
+            assert_eq!(decrypted, plaintext.as_bytes());
+            // Pre-key messages until Bob replies
+            assert_eq!(transport["type"], 0);
+            assert_eq!(bob.session_id(0), transport["session_id"].as_str());
+        }
+    }
+
+    #[test]
+    fn wrapped_session_key_can_be_imported() {
+        let (mut alice, alice_identity, mut bob, _) = outbound_pair();
+        let session_id = alice.session_id(0).unwrap().to_string();
+
+        let mut group = crate::megolm::MegolmSession::create_outbound().unwrap();
+        let session_key = group.get_session_key().unwrap();
+
+        let wrapped = alice
+            .wrap_session_key(&session_id, "!room:example.org", &session_key)
+            .unwrap();
+        assert_eq!(wrapped.message_type, 0);
+
+        let (payload, _) = bob.decrypt_or_create(&alice_identity, wrapped.body.as_bytes()).unwrap();
+        let payload: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(payload["type"], "m.room_key");
+        assert_eq!(payload["content"]["room_id"], "!room:example.org");
+        assert_eq!(payload["content"]["session_id"], group.session_id());
+
+        let mut inbound = crate::megolm::MegolmSession::create_inbound(
+            payload["content"]["session_key"].as_str().unwrap(),
+            None,
+        )
+        .unwrap();
+        let message = serde_json::to_string(&group.encrypt(b"hello room").unwrap()).unwrap();
+        assert_eq!(inbound.decrypt(&message).unwrap(), b"hello room");
+
+        assert!(matches!(
+            alice.wrap_session_key("unknown", "!room:example.org", &session_key),
+            Err(OlmError::SessionNotFound)
+        ));
+    }
+
+    #[test]
+    fn unwrapped_room_key_imports() {
+        let (mut alice, alice_identity, mut bob, bob_identity) = outbound_pair();
+        let session_id = alice.session_id(0).unwrap().to_string();
+
+        let mut group = crate::megolm::MegolmSession::create_outbound().unwrap();
+        let session_key = group.get_session_key().unwrap();
+
+        let wrapped = alice
+            .wrap_session_key(&session_id, "!room:example.org", &session_key)
+            .unwrap();
+        let room_key = bob
+            .unwrap_session_key(&alice_identity, wrapped.body.as_bytes(), wrapped.message_type)
+            .unwrap();
+        assert_eq!(room_key.room_id, "!room:example.org");
+        assert_eq!(room_key.session_id, group.session_id());
+
+        let mut inbound =
+            crate::megolm::MegolmSession::create_inbound(&room_key.session_key, None).unwrap();
+        let message = serde_json::to_string(&group.encrypt(b"hello room").unwrap()).unwrap();
+        assert_eq!(inbound.decrypt(&message).unwrap(), b"hello room");
+
+        // Anything that isn't an m.room_key event is refused
+        let reply = bob.encrypt(br#"{"type":"m.dummy","content":{}}"#).unwrap();
+        assert!(alice.unwrap_session_key(&bob_identity, reply.body.as_bytes(), reply.message_type).is_err());
+    }
+
+    #[test]
+    fn oversized_plaintext_is_rejected() {
+        let (mut alice, _, _, _) = outbound_pair();
+
+        let limit = crate::utilities::max_plaintext_size() as usize;
+        let message = match alice.encrypt(&vec![b'a'; limit + 1]) {
+            Err(OlmError::EncryptionFailed(message)) => message,
+            _ => panic!("expected EncryptionFailed"),
+        };
+        assert!(message.contains("limit"));
+        assert!(alice.encrypt(b"small").is_ok());
+    }
+
+    #[test]
+    fn empty_plaintext_is_rejected_but_decrypts() {
+        let (mut alice, _, mut bob, _) = outbound_pair();
+
+        let first = alice.encrypt(b"first").unwrap();
+        let alice_identity = alice.get_identity_keys().unwrap().curve25519;
+        bob.decrypt_or_create(&alice_identity, first.body.as_bytes()).unwrap();
+
+        assert!(matches!(alice.encrypt(b""), Err(OlmError::EncryptionFailed(_))));
+
+        // Another client may still send an empty message
+        let (message_type, ciphertext) = alice.sessions[0].1.encrypt("").to_tuple();
+        let plaintext = bob
+            .decrypt(ciphertext.as_bytes(), usize::from(message_type))
+            .unwrap();
+        assert!(plaintext.is_empty());
+    }
+
+    #[test]
+    fn signed_one_time_keys_carry_algorithm_prefix() {
+        let mut account = OlmSession::create_account().unwrap();
+        let ed25519 = account.get_identity_keys().unwrap().ed25519;
+        let generated = account.generate_one_time_keys(2).unwrap();
+
+        let json = account.signed_one_time_keys_json("@alice:example.org", "DEVICE").unwrap();
+        let keys: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let keys = keys.as_object().unwrap();
+        assert_eq!(keys.len(), 2);
+
+        let ed25519 = vodozemac::Ed25519PublicKey::from_base64(&ed25519).unwrap();
+        for otk in &generated {
+            let key_id = format!("signed_curve25519:{}", otk.key_id);
+            let signed_key = &keys[&key_id];
+            assert_eq!(signed_key["key"], otk.key.as_str());
+
+            let signature = signed_key["signatures"]["@alice:example.org"]["ed25519:DEVICE"]
+                .as_str()
+                .unwrap();
+            let signature = vodozemac::Ed25519Signature::from_base64(signature).unwrap();
+            let canonical = crate::utilities::signable_json(signed_key);
+            assert!(ed25519.verify(canonical.as_bytes(), &signature).is_ok());
+        }
+    }
+
+    #[test]
+    fn generated_keys_come_back_signed() {
+        let mut account = OlmSession::create_account().unwrap();
+        let ed25519 = account.get_identity_keys().unwrap().ed25519;
+        let ed25519 = vodozemac::Ed25519PublicKey::from_base64(&ed25519).unwrap();
+
+        let json = account
+            .generate_and_sign_one_time_keys(5, "@alice:example.org", "DEVICE")
+            .unwrap();
+        let keys: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let keys = keys.as_object().unwrap();
+        assert_eq!(keys.len(), 5);
+        assert_eq!(account.unpublished_one_time_keys().unwrap().len(), 5);
+
+        for (key_id, signed_key) in keys {
+            assert!(key_id.starts_with("signed_curve25519:"));
+            let signature = signed_key["signatures"]["@alice:example.org"]["ed25519:DEVICE"]
+                .as_str()
+                .unwrap();
+            let signature = vodozemac::Ed25519Signature::from_base64(signature).unwrap();
+            let canonical = crate::utilities::signable_json(signed_key);
+            assert!(ed25519.verify(canonical.as_bytes(), &signature).is_ok());
+        }
+    }
+
+    #[test]
+    fn claimed_key_signature_is_checked() {
+        let mut bob = OlmSession::create_account().unwrap();
+        let bob_ed25519 = bob.get_identity_keys().unwrap().ed25519;
+        let otk = bob.generate_one_time_keys(1).unwrap().remove(0);
+
+        let json = bob.signed_one_time_keys_json("@bob:example.org", "BOBDEVICE").unwrap();
+        let claimed = OlmSession::verify_claimed_key(&json, &bob_ed25519).unwrap();
+        assert_eq!(claimed.key_id, otk.key_id);
+        assert_eq!(claimed.key, otk.key);
+        assert!(!claimed.fallback);
+
+        // Swap in a key the server controls
+        let mallory = OlmSession::create_account().unwrap();
+        let mut tampered: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let key_id = format!("signed_curve25519:{}", otk.key_id);
+        tampered[&key_id]["key"] = mallory.get_identity_keys().unwrap().curve25519.into();
+        let result = OlmSession::verify_claimed_key(&tampered.to_string(), &bob_ed25519);
+        assert!(matches!(result, Err(OlmError::InvalidKey(_))));
+
+        // Properly signed, but by another device
+        let mallory_ed25519 = mallory.get_identity_keys().unwrap().ed25519;
+        assert!(OlmSession::verify_claimed_key(&json, &mallory_ed25519).is_err());
+    }
+
+    #[test]
+    fn resigned_one_time_keys_verify_under_the_new_key() {
+        let mut account = OlmSession::create_account().unwrap();
+        let old_key = account.get_identity_keys().unwrap().ed25519;
+        account.generate_one_time_keys(2).unwrap();
+        let stale = account.signed_one_time_keys_json("@alice:example.org", "DEVICE").unwrap();
+
+        // Recovery puts an account with another signing key in place
+        account.account = Some(olm_rs::account::OlmAccount::new());
+        account.generate_one_time_keys(2).unwrap();
+        let new_key = account.get_identity_keys().unwrap().ed25519;
+        assert_ne!(new_key, old_key);
+
+        let claims = |json: &str| -> Vec<String> {
+            let keys: serde_json::Map<String, serde_json::Value> =
+                serde_json::from_str(json).unwrap();
+            assert_eq!(keys.len(), 2);
+            keys.into_iter()
+                .map(|(key_id, signed_key)| serde_json::json!({ key_id: signed_key }).to_string())
+                .collect()
+        };
+
+        let resigned = account.resign_one_time_keys("@alice:example.org", "DEVICE").unwrap();
+        for claim in claims(&resigned) {
+            assert!(OlmSession::verify_claimed_key(&claim, &new_key).is_ok());
+            assert!(OlmSession::verify_claimed_key(&claim, &old_key).is_err());
+        }
+
+        // What was signed before the switch still only verifies under the old key
+        for claim in claims(&stale) {
+            assert!(OlmSession::verify_claimed_key(&claim, &old_key).is_ok());
+            assert!(OlmSession::verify_claimed_key(&claim, &new_key).is_err());
+        }
+    }
+
+    #[test]
+    fn one_time_keys_refill_to_half_the_max() {
+        let mut account = OlmSession::create_account().unwrap();
+        let target = account.account.as_ref().unwrap().max_number_of_one_time_keys() / 2;
+
+        assert_eq!(account.needs_one_time_keys(0).unwrap(), target);
+        assert_eq!(account.needs_one_time_keys(10).unwrap(), target - 10);
+        assert_eq!(account.needs_one_time_keys(target).unwrap(), 0);
+        assert_eq!(account.needs_one_time_keys(target + 5).unwrap(), 0);
+
+        // Unpublished local keys count towards the target
+        account.generate_one_time_keys(5).unwrap();
+        assert_eq!(account.needs_one_time_keys(10).unwrap(), target - 15);
+
+        account.mark_keys_as_published().unwrap();
+        assert_eq!(account.needs_one_time_keys(10).unwrap(), target - 10);
+    }
+
+    #[test]
+    fn identity_keys_read_from_store_without_sessions() {
+        let (alice, _, _, _) = outbound_pair();
+
+        let key = [5u8; 32];
+        let blob = alice.export_store(&key).unwrap();
+
+        let keys = OlmSession::identity_keys_from_pickle(&blob, &key).unwrap();
+        let restored = OlmSession::import_store(&blob, &key).unwrap().get_identity_keys().unwrap();
+        assert_eq!(keys.curve25519, restored.curve25519);
+        assert_eq!(keys.ed25519, restored.ed25519);
+
+        assert!(OlmSession::identity_keys_from_pickle(&blob, &[6u8; 32]).is_err());
+    }
+
+    #[test]
+    fn encrypted_message_serializes_type_and_base64_body() {
+        let (mut alice, _, mut bob, _) = outbound_pair();
+
+        let encrypted = alice.encrypt(b"encoded").unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&encrypted).unwrap()).unwrap();
+
+        assert_eq!(json["message_type"], 0);
+        let body = json["body"].as_str().unwrap();
+        assert!(!crate::utilities::base64_decode_lenient(body).unwrap().is_empty());
+
+        let alice_identity = alice.get_identity_keys().unwrap().curve25519;
+        let (plaintext, _) = bob.decrypt_or_create(&alice_identity, body.as_bytes()).unwrap();
+        assert_eq!(plaintext, b"encoded");
+    }
+
+    #[test]
+    fn ratchet_state_tracks_chain_indices() {
+        let (mut alice, alice_identity, mut bob, _) = outbound_pair();
+        let session_id = alice.session_id(0).unwrap().to_string();
+        assert_eq!(alice.ratchet_state(&session_id).unwrap(), RatchetState::default());
+
+        let mut messages = Vec::new();
+        for expected in 0..3 {
+            messages.push(alice.encrypt(b"hello").unwrap());
+            let state = alice.ratchet_state(&session_id).unwrap();
+            assert_eq!(state.sending_chain_index, Some(expected));
+        }
+
+        for message in &messages {
+            bob.decrypt_auto(&alice_identity, message.body.as_bytes()).unwrap();
+        }
+        let state = bob.ratchet_state(&session_id).unwrap();
+        assert_eq!(state.receiving_chain_index, Some(2));
+        assert_eq!(state.sending_chain_index, None);
+
+        assert!(matches!(alice.ratchet_state("unknown"), Err(OlmError::SessionNotFound)));
+    }
+
+    #[test]
+    fn tampered_message_fails_with_bad_mac() {
+        let (mut alice, alice_identity, mut bob, _) = outbound_pair();
+
+        let first = alice.encrypt(b"first").unwrap();
+        bob.decrypt_or_create(&alice_identity, first.body.as_bytes()).unwrap();
+
+        let reply = bob.encrypt(b"reply").unwrap();
+        assert_eq!(reply.message_type, 1);
+
+        // Flip a ciphertext byte just ahead of the 8-byte MAC
+        let mut bytes = crate::utilities::base64_decode_lenient(&reply.body).unwrap();
+        let index = bytes.len() - 9;
+        bytes[index] ^= 1;
+        let tampered = crate::utilities::base64_encode_with(
+            &bytes,
+            crate::utilities::Base64Variant::StandardNoPad,
+        );
+
+        assert!(matches!(alice.decrypt(tampered.as_bytes(), 1), Err(OlmError::BadMac)));
+        assert_eq!(alice.decrypt(reply.body.as_bytes(), 1).unwrap(), b"reply");
+    }
+
+    #[test]
+    fn tampered_message_on_auto_path_is_bad_mac() {
+        let (mut alice, alice_identity, mut bob, bob_identity) = outbound_pair();
+
+        let first = alice.encrypt(b"first").unwrap();
+        bob.decrypt_auto(&alice_identity, first.body.as_bytes()).unwrap();
+
+        let reply = bob.encrypt(b"reply").unwrap();
+        let mut bytes = crate::utilities::base64_decode_lenient(&reply.body).unwrap();
+        let index = bytes.len() - 9;
+        bytes[index] ^= 1;
+        let tampered = crate::utilities::base64_encode_with(
+            &bytes,
+            crate::utilities::Base64Variant::StandardNoPad,
+        );
+
+        let result = alice.decrypt_auto(&bob_identity, tampered.as_bytes());
+        assert!(matches!(result, Err(OlmError::BadMac)));
+        assert_eq!(alice.decrypt_auto(&bob_identity, reply.body.as_bytes()).unwrap(), b"reply");
+
+        // With no session to try, there is no MAC to reject
+        let mut carol = OlmSession::create_account().unwrap();
+        let result = carol.decrypt_auto(&bob_identity, reply.body.as_bytes());
+        assert!(matches!(result, Err(OlmError::SessionNotFound)));
+    }
+
+    #[test]
+    fn message_for_a_missing_session_is_not_bad_mac() {
+        let (mut alice, alice_identity, mut bob, bob_identity) = outbound_pair();
+        let first = alice.encrypt(b"first").unwrap();
+        bob.decrypt_or_create(&alice_identity, first.body.as_bytes()).unwrap();
+        let reply = bob.encrypt(b"reply").unwrap();
+
+        // Carol's only session is with Dave, so it rejects the MAC of Bob's
+        // message without the message having been tampered with
+        let (mut carol, carol_identity, mut dave, _) = outbound_pair();
+        let hello = carol.encrypt(b"hello").unwrap();
+        dave.decrypt_or_create(&carol_identity, hello.body.as_bytes()).unwrap();
+
+        let result = carol.decrypt_auto(&bob_identity, reply.body.as_bytes());
+        assert!(matches!(result, Err(OlmError::SessionNotFound)));
+
+        let event = EventInput {
+            sender_key: bob_identity,
+            message_type: 1,
+            body: reply.body,
+            algorithm: None,
+        };
+        assert!(matches!(carol.decrypt_event(&event), Err(OlmError::SessionNotFound)));
+    }
+
+    #[test]
+    fn computed_session_id_matches_outbound_session() {
+        let mut alice = OlmSession::create_account().unwrap();
+        let alice_identity = alice.get_identity_keys().unwrap().curve25519;
+
+        let mut bob = OlmSession::create_account().unwrap();
+        let bob_identity = bob.get_identity_keys().unwrap().curve25519;
+        let bob_otk = bob.generate_one_time_keys(1).unwrap().remove(0);
+
+        let created = alice
+            .create_outbound_session_detailed(bob_identity.as_bytes(), bob_otk.key.as_bytes())
+            .unwrap();
+
+        // libolm doesn't expose the base key, so read it from the pre-key message
+        let message = alice.encrypt(b"hello").unwrap();
+        let bytes = crate::utilities::base64_decode_lenient(&message.body).unwrap();
+        let base_key = match message_field(&bytes[1..], 2) {
+            Some(MessageField::Bytes(key)) => {
+                crate::utilities::base64_encode_with(key, crate::utilities::Base64Variant::StandardNoPad)
+            }
+            _ => panic!("pre-key message without a base key"),
+        };
+
+        let computed =
+            crate::utilities::compute_olm_session_id(&alice_identity, &base_key, &bob_otk.key)
+                .unwrap();
+        assert_eq!(computed, created.session_id);
+
+        // Argument order matters
+        let swapped =
+            crate::utilities::compute_olm_session_id(&base_key, &alice_identity, &bob_otk.key)
+                .unwrap();
+        assert_ne!(swapped, created.session_id);
+    }
+
+    #[test]
+    fn clearing_sessions_keeps_the_account() {
+        let (mut alice, _, _, _) = outbound_pair();
+        let identity = alice.get_identity_keys().unwrap();
+        let otks = alice.generate_one_time_keys(2).unwrap();
+        let session_id = alice.session_id(0).unwrap().to_string();
+        alice.encrypt(b"hello").unwrap();
+
+        alice.clear_sessions();
+
+        let keys = alice.get_identity_keys().unwrap();
+        assert_eq!(keys.curve25519, identity.curve25519);
+        assert_eq!(keys.ed25519, identity.ed25519);
+        let unpublished = alice.account.as_ref().unwrap().parsed_one_time_keys();
+        for otk in &otks {
+            assert_eq!(unpublished.curve25519().get(&otk.key_id), Some(&otk.key));
+        }
+
+        assert!(alice.session_id(0).is_none());
+        assert!(matches!(alice.ratchet_state(&session_id), Err(OlmError::SessionNotFound)));
+        assert!(matches!(alice.encrypt(b"again"), Err(OlmError::SessionNotFound)));
+    }
+
+    #[test]
+    fn unpublished_one_time_keys_clear_once_published() {
+        let mut account = OlmSession::create_account().unwrap();
+        assert!(account.unpublished_one_time_keys().unwrap().is_empty());
+
+        let generated = account.generate_one_time_keys(3).unwrap();
+        let unpublished = account.unpublished_one_time_keys().unwrap();
+        assert_eq!(unpublished.len(), 3);
+        for otk in &generated {
+            assert!(unpublished.iter().any(|k| k.key_id == otk.key_id && k.key == otk.key));
+        }
+
+        // Reading the list doesn't generate more
+        assert_eq!(account.unpublished_one_time_keys().unwrap().len(), 3);
+
+        account.mark_keys_as_published().unwrap();
+        assert!(account.unpublished_one_time_keys().unwrap().is_empty());
+    }
+
+    #[test]
+    fn public_identity_holds_only_public_keys() {
+        let account = OlmSession::create_account().unwrap();
+        let keys = account.get_identity_keys().unwrap();
+
+        let json = account.public_identity_json().unwrap();
+        let identity: serde_json::Value = serde_json::from_str(&json).unwrap();
+        // The only key material is the two public keys and the signature
+        let fields: Vec<&str> = identity.as_object().unwrap().keys().map(|k| k.as_str()).collect();
+        assert_eq!(fields, ["curve25519", "ed25519", "signatures"]);
+        assert_eq!(identity["curve25519"].as_str(), Some(keys.curve25519.as_str()));
+        assert_eq!(identity["ed25519"].as_str(), Some(keys.ed25519.as_str()));
+
+        let key_id = format_key_id("ed25519", &keys.ed25519);
+        let signatures = identity["signatures"].as_object().unwrap();
+        let signers: Vec<&str> = signatures.keys().map(|k| k.as_str()).collect();
+        assert_eq!(signers, [key_id.as_str()]);
+        let signature = signatures[&key_id].as_str().unwrap();
+
+        let ed25519 = vodozemac::Ed25519PublicKey::from_base64(&keys.ed25519).unwrap();
+        let signature = vodozemac::Ed25519Signature::from_base64(signature).unwrap();
+        let canonical = crate::utilities::signable_json(&identity);
+        assert!(ed25519.verify(canonical.as_bytes(), &signature).is_ok());
+    }
+
+    #[test]
+    fn version_1_account_pickle_is_migrated() {
+        let account = OlmSession::create_account().unwrap();
+        let keys = account.get_identity_keys().unwrap();
+
+        // What pickle() produced before the version header
+        let v1 = account.account.as_ref().unwrap().pickle(olm_rs::PicklingMode::Unencrypted);
+        let restored = OlmSession::unpickle(v1.as_bytes()).unwrap();
+        assert_eq!(restored.get_identity_keys().unwrap().ed25519, keys.ed25519);
+        assert!(!restored.fallback_key_published());
+
+        // Re-pickling writes the current version
+        let v2 = restored.pickle().unwrap();
+        assert_eq!(v2[0], crate::utilities::PICKLE_VERSION);
+        let restored = OlmSession::unpickle(&v2).unwrap();
+        assert_eq!(restored.get_identity_keys().unwrap().curve25519, keys.curve25519);
+    }
+
+    #[test]
+    fn account_pickle_keeps_fallback_state() {
+        let mut account = OlmSession::create_account().unwrap();
+        let fallback = account.generate_fallback_key().unwrap();
+        account.mark_keys_as_published().unwrap();
+
+        let restored = OlmSession::unpickle(&account.pickle().unwrap()).unwrap();
+        assert!(restored.fallback_key_published());
+        assert_eq!(restored.active_fallback_key().unwrap().key, fallback.key);
+    }
+
+    #[test]
+    fn unknown_pickle_version_is_rejected() {
+        let account = OlmSession::create_account().unwrap();
+        let mut pickle = account.pickle().unwrap();
+        pickle[0] = crate::utilities::PICKLE_VERSION + 1;
+
+        let message = match OlmSession::unpickle(&pickle) {
+            Err(OlmError::AccountCreationFailed(message)) => message,
+            _ => panic!("expected AccountCreationFailed"),
+        };
+        assert_eq!(message, "unsupported pickle version");
+    }
+
+    #[test]
+    fn one_time_keys_are_cleared() {
+        let mut account = OlmSession::create_account().unwrap();
+        account.generate_one_time_keys(5).unwrap();
+        account.generate_fallback_key().unwrap();
+
+        account.clear_one_time_keys().unwrap();
+        assert!(account.unpublished_one_time_keys().unwrap().is_empty());
+        assert_eq!(account.session_count(), 0);
+        assert!(account.active_fallback_key().is_some());
+
+        // The account keeps working afterwards
+        assert_eq!(account.generate_one_time_keys(1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn corrupted_pickle_fails_validation() {
+        let mut account = OlmSession::create_account().unwrap();
+        account.generate_one_time_keys(5).unwrap();
+        account.generate_fallback_key().unwrap();
+        let pickle = account.pickle().unwrap();
+
+        let restored = OlmSession::unpickle(&pickle).unwrap();
+        assert!(restored.validate().is_ok());
+
+        // Cut short, the pickle doesn't load at all
+        assert!(OlmSession::unpickle(&pickle[..pickle.len() / 2]).is_err());
+
+        // A truncated fallback key loads, but is caught by validation
+        let (_, payload) = crate::utilities::split_pickle(&pickle);
+        let mut payload: serde_json::Value = serde_json::from_slice(payload).unwrap();
+        let key = payload["fallback_key"]["key"].as_str().unwrap();
+        payload["fallback_key"]["key"] = serde_json::json!(key[..key.len() - 4]);
+        let corrupted = crate::utilities::versioned_pickle(&serde_json::to_vec(&payload).unwrap());
+
+        let error = OlmSession::unpickle(&corrupted).unwrap().validate().unwrap_err();
+        assert!(matches!(error, OlmError::InvalidState(_)));
+        assert!(error.to_string().contains("malformed fallback key"));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_pair_exchanges_messages_both_ways() {
+        let (mut alice, mut bob, session_id) = OlmSession::test_pair().unwrap();
+        let alice_identity = alice.get_identity_keys().unwrap().curve25519;
+        let bob_identity = bob.get_identity_keys().unwrap().curve25519;
+
+        assert_eq!(alice.session_id(0), Some(session_id.as_str()));
+        assert_eq!(bob.session_id(0), Some(session_id.as_str()));
+        assert!(alice.has_session_with(&bob_identity));
+        assert!(bob.has_session_with(&alice_identity));
+
+        // Past the pre-key stage in both directions
+        let message = alice.encrypt(b"ping").unwrap();
+        assert_eq!(message.message_type, 1);
+        assert_eq!(bob.decrypt(message.body.as_bytes(), 1).unwrap(), b"ping");
+
+        let message = bob.encrypt(b"pong").unwrap();
+        assert_eq!(message.message_type, 1);
+        assert_eq!(alice.decrypt(message.body.as_bytes(), 1).unwrap(), b"pong");
+    }
+
+    #[test]
+    fn dummy_event_advances_ratchet() {
+        let (mut alice, alice_identity, mut bob, _) = outbound_pair();
+        let session_id = alice.session_id(0).unwrap().to_string();
+
+        let first = alice.encrypt_dummy(&session_id).unwrap();
+        assert_eq!(alice.ratchet_state(&session_id).unwrap().sending_chain_index, Some(0));
+        let second = alice.encrypt_dummy(&session_id).unwrap();
+        assert_eq!(alice.ratchet_state(&session_id).unwrap().sending_chain_index, Some(1));
+
+        for message in [&first, &second] {
+            let payload = bob.decrypt_auto(&alice_identity, message.body.as_bytes()).unwrap();
+            let payload: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+            assert_eq!(payload["type"], "m.dummy");
+            assert_eq!(payload["content"], serde_json::json!({}));
+        }
+        assert_eq!(bob.ratchet_state(&session_id).unwrap().receiving_chain_index, Some(1));
+
+        assert!(matches!(alice.encrypt_dummy("unknown"), Err(OlmError::SessionNotFound)));
+    }
+
+    #[test]
+    fn outbound_session_with_key_id_checks_the_id() {
+        let mut alice = OlmSession::create_account().unwrap();
+        let alice_identity = alice.get_identity_keys().unwrap().curve25519;
+
+        let mut bob = OlmSession::create_account().unwrap();
+        let bob_identity = bob.get_identity_keys().unwrap().curve25519;
+        let bob_otk = bob.generate_one_time_keys(1).unwrap().remove(0);
+        let claim = serde_json::json!({
+            format_key_id("signed_curve25519", &bob_otk.key_id): { "key": bob_otk.key },
+        })
+        .to_string();
+
+        let mismatched = alice.create_outbound_session_with_key_id(
+            &bob_identity,
+            "signed_curve25519:AAAAAAAAAAA",
+            &claim,
+        );
+        assert!(matches!(mismatched, Err(OlmError::InvalidKey(_))));
+        assert_eq!(alice.session_count(), 0);
+
+        let created = alice
+            .create_outbound_session_with_key_id(&bob_identity, &bob_otk.key_id, &claim)
+            .unwrap();
+        let message = alice.encrypt(b"hello").unwrap();
+        let (plaintext, _) =
+            bob.decrypt_or_create(&alice_identity, message.body.as_bytes()).unwrap();
+        assert_eq!(plaintext, b"hello");
+        assert_eq!(alice.session_id(created.handle), Some(created.session_id.as_str()));
+
+        // The prefixed id works the same; several keys at once don't
+        let prefixed = format_key_id("signed_curve25519", &bob_otk.key_id);
+        assert!(alice
+            .create_outbound_session_with_key_id(&bob_identity, &prefixed, &claim)
+            .is_ok());
+        let two = serde_json::json!({ "a:1": bob_otk.key, "a:2": bob_otk.key }).to_string();
+        assert!(alice.create_outbound_session_with_key_id(&bob_identity, "a:1", &two).is_err());
+    }
+
+    /// Alice forwards a Megolm session key to Bob with the given chain
+    fn forwarded_key_event(
+        chain: &[&str],
+    ) -> (OlmSession, EventInput, crate::megolm::MegolmSession) {
+        let (mut alice, alice_identity, bob, _) = outbound_pair();
+        let session_id = alice.session_id(0).unwrap().to_string();
+
+        let group = crate::megolm::MegolmSession::create_outbound().unwrap();
+        let exported = group.export("!room:example.org", "creator_curve25519_key");
+        let payload = serde_json::json!({
+            "type": "m.forwarded_room_key",
+            "content": {
+                "algorithm": crate::megolm::MEGOLM_ALGORITHM,
+                "room_id": "!room:example.org",
+                "sender_key": "creator_curve25519_key",
+                "session_id": exported.session_id,
+                "session_key": exported.session_key,
+                "sender_claimed_ed25519_key": "creator_ed25519_key",
+                "forwarding_curve25519_key_chain": chain,
+            },
+        });
+        let message = alice.encrypt_event(&session_id, &payload).unwrap();
+
+        let event = EventInput {
+            sender_key: alice_identity,
+            message_type: message.message_type,
+            body: message.body,
+            algorithm: None,
+        };
+        (bob, event, group)
+    }
+
+    #[test]
+    fn forwarded_room_key_within_depth_imports() {
+        let (mut bob, event, mut group) = forwarded_key_event(&["first_forwarder"]);
+
+        let (key, mut session) = bob.import_forwarded_room_key(&event, 2).unwrap();
+        assert_eq!(key.room_id, "!room:example.org");
+        assert_eq!(key.session_id, group.session_id());
+        assert_eq!(key.sender_key, "creator_curve25519_key");
+        assert_eq!(
+            key.forwarding_curve25519_key_chain,
+            ["first_forwarder", unpadded(&event.sender_key)]
+        );
+
+        assert!(session.is_imported());
+        assert!(!session.is_trusted());
+        assert_eq!(session.claimed_ed25519_key().as_deref(), Some("creator_ed25519_key"));
+        let message = serde_json::to_string(&group.encrypt(b"hello room").unwrap()).unwrap();
+        assert_eq!(session.decrypt(&message).unwrap(), b"hello room");
+    }
+
+    #[test]
+    fn forwarded_room_key_beyond_depth_is_refused() {
+        let (mut bob, event, _) = forwarded_key_event(&["first_forwarder", "second_forwarder"]);
+
+        assert!(matches!(
+            bob.import_forwarded_room_key(&event, 2),
+            Err(OlmError::ForwardingChainTooLong { depth: 3, max: 2 })
+        ));
+
+        // A zero limit refuses even a key forwarded by its creator
+        let (mut bob, event, _) = forwarded_key_event(&[]);
+        assert!(matches!(
+            bob.import_forwarded_room_key(&event, 0),
+            Err(OlmError::ForwardingChainTooLong { depth: 1, max: 0 })
+        ));
+    }
+
+    #[test]
+    fn prekey_consumes_otk_only_for_new_sessions() {
+        let (mut alice, alice_identity, mut bob, bob_identity) = outbound_pair();
+
+        let first = alice.encrypt(b"first").unwrap();
+        assert_eq!(first.message_type, 0);
+        assert!(bob.prekey_consumes_otk(&first.body));
+        // Asking doesn't change anything
+        assert!(bob.prekey_consumes_otk(&first.body));
+        assert_eq!(bob.session_count(), 0);
+
+        bob.decrypt_or_create(&alice_identity, first.body.as_bytes()).unwrap();
+        let resend = alice.encrypt(b"second").unwrap();
+        assert_eq!(resend.message_type, 0);
+        assert!(!bob.prekey_consumes_otk(&first.body));
+        assert!(!bob.prekey_consumes_otk(&resend.body));
+
+        // Fallback keys stay, so using one doesn't count
+        let fallback = bob.generate_fallback_key().unwrap();
+        let mut carol = OlmSession::create_account().unwrap();
+        carol
+            .create_outbound_session(bob_identity.as_bytes(), fallback.key.as_bytes())
+            .unwrap();
+        let from_carol = carol.encrypt(b"hi").unwrap();
+        assert!(!bob.prekey_consumes_otk(&from_carol.body));
+
+        assert!(!bob.prekey_consumes_otk("not a pre-key message"));
+    }
+
+    #[test]
+    fn mid_stream_algorithm_switch_is_rejected() {
+        let (mut alice, alice_identity, mut bob, _) = outbound_pair();
+
+        let event = |message: EncryptedMessage, algorithm: Option<&str>| EventInput {
+            sender_key: alice_identity.clone(),
+            message_type: message.message_type,
+            body: message.body,
+            algorithm: algorithm.map(String::from),
+        };
+
+        let first = event(alice.encrypt(b"first").unwrap(), Some(OLM_ALGORITHM));
+        assert_eq!(bob.decrypt_event(&first).unwrap(), b"first");
+        let session_id = bob.session_id(0).unwrap().to_string();
+        assert_eq!(bob.pinned_algorithm(&session_id), Some(OLM_ALGORITHM));
+
+        let downgraded = event(alice.encrypt(b"second").unwrap(), Some("m.olm.v0.legacy"));
+        let result = bob.decrypt_event(&downgraded);
+        assert!(matches!(
+            result,
+            Err(OlmError::AlgorithmMismatch { pinned, declared })
+                if pinned == OLM_ALGORITHM && declared == "m.olm.v0.legacy"
+        ));
+
+        let undeclared = event(alice.encrypt(b"third").unwrap(), None);
+        assert!(matches!(
+            bob.decrypt_event(&undeclared),
+            Err(OlmError::AlgorithmMismatch { declared, .. }) if declared == "nothing"
+        ));
+        assert_eq!(bob.pinned_algorithm(&session_id), Some(OLM_ALGORITHM));
+
+        let fourth = event(alice.encrypt(b"fourth").unwrap(), Some(OLM_ALGORITHM));
+        assert_eq!(bob.decrypt_event(&fourth).unwrap(), b"fourth");
+
+        bob.clear_sessions();
+        assert_eq!(bob.pinned_algorithm(&session_id), None);
     }
 }
@@ -0,0 +1,286 @@
+//! Handle registry for native objects shared with Kotlin
+//!
+//! Kotlin holds opaque handles instead of raw pointers. Every entry sits
+//! behind its own `Mutex`, so all operations on one account (or one Megolm
+//! session) serialize, even when the app calls in from several threads.
+//! Operations on different handles still run in parallel.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, MutexGuard};
+
+use jni::sys::jlong;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::megolm::MegolmSession;
+use crate::olm::OlmSession;
+
+/// Olm accounts (with their sessions) owned by the app
+pub static ACCOUNTS: LazyLock<Registry<OlmSession>> = LazyLock::new(Registry::new);
+
+/// Megolm group sessions owned by the app
+pub static MEGOLM_SESSIONS: LazyLock<Registry<MegolmSession>> = LazyLock::new(Registry::new);
+
+/// Megolm sessions filed by room, for room key export
+pub static ROOM_SESSIONS: LazyLock<Mutex<crate::megolm::SessionStore>> =
+    LazyLock::new(Default::default);
+
+/// Streaming HMACs over chunked attachments
+pub static HMACS: LazyLock<Registry<crate::utilities::HmacSha256Streaming>> =
+    LazyLock::new(Registry::new);
+
+/// Verification flows in progress
+pub static VERIFICATIONS: LazyLock<Registry<crate::verification::VerificationSession>> =
+    LazyLock::new(Registry::new);
+
+/// Background key backup imports
+#[cfg(feature = "backup")]
+pub static BACKUP_IMPORTS: LazyLock<Registry<crate::backup::ImportProgress>> =
+    LazyLock::new(Registry::new);
+
+/// How many native objects are alive, for leak checks
+#[derive(Serialize, Debug, PartialEq)]
+pub struct HandleCounts {
+    pub accounts: usize,
+    /// Olm sessions across all accounts; they have no handles of their own
+    pub olm_sessions: usize,
+    pub megolm_sessions: usize,
+}
+
+/// Count the accounts, Olm sessions and Megolm sessions still allocated
+pub fn handle_counts() -> HandleCounts {
+    let accounts = ACCOUNTS.entries();
+    HandleCounts {
+        accounts: accounts.len(),
+        olm_sessions: accounts.iter().map(|account| lock(account).session_count()).sum(),
+        megolm_sessions: MEGOLM_SESSIONS.len(),
+    }
+}
+
+/// Why a handle couldn't be resolved
+#[derive(Error, Debug, PartialEq)]
+pub enum HandleError {
+    #[error("Handle is zero")]
+    Null,
+
+    #[error("Unknown or freed handle: {0}")]
+    Unknown(jlong),
+}
+
+/// A table of handle -> object entries
+pub struct Registry<T> {
+    next_handle: AtomicI64,
+    entries: Mutex<HashMap<jlong, Arc<Mutex<T>>>>,
+}
+
+impl<T> Registry<T> {
+    pub fn new() -> Self {
+        Self {
+            // Handle 0 is reserved for "no object"
+            next_handle: AtomicI64::new(1),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take ownership of a value and return its handle
+    pub fn insert(&self, value: T) -> jlong {
+        let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        lock(&self.entries).insert(handle, Arc::new(Mutex::new(value)));
+        handle
+    }
+
+    /// Look up the entry for a handle
+    pub fn get(&self, handle: jlong) -> Option<Arc<Mutex<T>>> {
+        lock(&self.entries).get(&handle).cloned()
+    }
+
+    /// Look up the entry for a handle that Kotlin passed in
+    ///
+    /// Unlike [`Registry::get`], this says why the lookup failed, so the
+    /// JNI layer can report a zero handle (from a failed create call)
+    /// differently from a stale one.
+    pub fn lookup(&self, handle: jlong) -> Result<Arc<Mutex<T>>, HandleError> {
+        if handle == 0 {
+            return Err(HandleError::Null);
+        }
+        self.get(handle).ok_or(HandleError::Unknown(handle))
+    }
+
+    /// Remove the entry for a handle
+    ///
+    /// Safe to race with operations on the same handle: one that already
+    /// looked the entry up keeps its own reference and finishes normally,
+    /// and the object is dropped after the last of them. Lookups after the
+    /// removal fail with [`HandleError::Unknown`].
+    pub fn remove(&self, handle: jlong) -> Option<Arc<Mutex<T>>> {
+        lock(&self.entries).remove(&handle)
+    }
+
+    /// Number of live handles
+    pub fn len(&self) -> usize {
+        lock(&self.entries).len()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Snapshot of every live entry
+    ///
+    /// The table lock is released before returning, so callers can lock
+    /// the entries one by one without blocking inserts and removals.
+    pub fn entries(&self) -> Vec<Arc<Mutex<T>>> {
+        lock(&self.entries).values().cloned().collect()
+    }
+}
+
+impl<T> Default for Registry<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Lock an entry, recovering it if a previous holder panicked
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::olm::tests::outbound_pair;
+    use std::thread;
+
+    #[test]
+    fn concurrent_operations_on_one_account() {
+        let (alice, _, _, _) = outbound_pair();
+
+        let registry = Arc::new(Registry::new());
+        let alice_handle = registry.insert(alice);
+
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let registry = Arc::clone(&registry);
+                thread::spawn(move || {
+                    for _ in 0..10 {
+                        let account = registry.get(alice_handle).unwrap();
+                        let mut account = lock(&account);
+                        if i % 2 == 0 {
+                            account.encrypt(b"hello").unwrap();
+                        } else {
+                            account.generate_one_time_keys(1).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert!(registry.remove(alice_handle).is_some());
+        assert!(registry.get(alice_handle).is_none());
+    }
+
+    #[test]
+    fn zero_and_freed_handles_are_rejected() {
+        let registry = Registry::new();
+        assert_eq!(registry.lookup(0).err(), Some(HandleError::Null));
+
+        let handle = registry.insert(OlmSession::create_account().unwrap());
+        assert!(registry.lookup(handle).is_ok());
+
+        registry.remove(handle);
+        assert_eq!(registry.lookup(handle).err(), Some(HandleError::Unknown(handle)));
+    }
+
+    #[test]
+    fn generating_keys_on_a_freed_account_fails_cleanly() {
+        // What generateOneTimeKeys does with a stale handle from Kotlin
+        let generate = |registry: &Registry<OlmSession>, handle| {
+            registry
+                .lookup(handle)
+                .map(|account| lock(&account).generate_one_time_keys(1).unwrap().len())
+        };
+
+        let registry = Registry::new();
+        let handle = registry.insert(OlmSession::create_account().unwrap());
+        assert_eq!(generate(&registry, handle), Ok(1));
+
+        assert!(registry.remove(handle).is_some());
+        let error = generate(&registry, handle).unwrap_err();
+        assert_eq!(error, HandleError::Unknown(handle));
+
+        use crate::errors::Reportable;
+        let report = error.report();
+        assert_eq!(report.code, "handle.unknown");
+        assert_eq!(report.category, crate::errors::ErrorCategory::NotFound);
+
+        // Freeing it again is a no-op
+        assert!(registry.remove(handle).is_none());
+    }
+
+    #[test]
+    fn handle_counts_return_to_zero() {
+        // The only test touching the global registries, so the counts are
+        // not disturbed by tests running in parallel
+        let (alice, _, bob, _) = outbound_pair();
+
+        let alice = ACCOUNTS.insert(alice);
+        let bob = ACCOUNTS.insert(bob);
+        let group = MEGOLM_SESSIONS.insert(MegolmSession::create_outbound().unwrap());
+        assert_eq!(
+            handle_counts(),
+            HandleCounts { accounts: 2, olm_sessions: 1, megolm_sessions: 1 }
+        );
+
+        ACCOUNTS.remove(alice);
+        ACCOUNTS.remove(bob);
+        MEGOLM_SESSIONS.remove(group);
+        assert_eq!(
+            handle_counts(),
+            HandleCounts { accounts: 0, olm_sessions: 0, megolm_sessions: 0 }
+        );
+    }
+
+    #[test]
+    fn concurrent_free_and_use_of_one_handle() {
+        let (alice, _, _, _) = outbound_pair();
+
+        let registry = Arc::new(Registry::new());
+        let handle = registry.insert(alice);
+
+        let users: Vec<_> = (0..8)
+            .map(|_| {
+                let registry = Arc::clone(&registry);
+                thread::spawn(move || {
+                    loop {
+                        match registry.lookup(handle) {
+                            Ok(account) => {
+                                lock(&account).encrypt(b"hello").unwrap();
+                            }
+                            Err(e) => return e,
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        let freer = {
+            let registry = Arc::clone(&registry);
+            thread::spawn(move || {
+                thread::sleep(std::time::Duration::from_millis(20));
+                registry.remove(handle).is_some()
+            })
+        };
+
+        assert!(freer.join().unwrap());
+        for user in users {
+            assert_eq!(user.join().unwrap(), HandleError::Unknown(handle));
+        }
+        assert!(registry.is_empty());
+    }
+}
@@ -1,10 +1,14 @@
 //! Cryptographic utilities for Matrix E2EE
 
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU32, Ordering};
+
 use thiserror::Error;
 
 /// Utility errors
 #[derive(Error, Debug)]
 pub enum UtilityError {
+    #[cfg(feature = "argon2")]
     #[error("Key generation failed: {0}")]
     KeyGenerationFailed(String),
 
@@ -16,6 +20,83 @@ pub enum UtilityError {
 
     #[error("Invalid key format")]
     InvalidKeyFormat,
+
+    #[error("Encryption failed: {0}")]
+    EncryptionFailed(String),
+
+    #[error("Nonce already used with this key")]
+    NonceReuse,
+
+    #[cfg(feature = "backup")]
+    #[error("{iterations} KDF iterations is below the minimum of {minimum}")]
+    TooFewIterations { iterations: u32, minimum: u32 },
+
+    #[error("Invalid JSON: {0}")]
+    InvalidJson(String),
+
+    #[error("String is not valid modified UTF-8")]
+    InvalidString,
+
+    #[error("Invalid signature length: {0} bytes (expected 64)")]
+    InvalidSignatureLength(usize),
+
+    #[error("Missing or empty field: {0}")]
+    MissingField(&'static str),
+
+    #[cfg(feature = "argon2")]
+    #[error("Argon2 parameters too weak: {0}")]
+    WeakArgon2Params(String),
+
+    #[cfg(feature = "argon2")]
+    #[error("Argon2 parameters too costly: {0}")]
+    ExcessiveArgon2Params(String),
+}
+
+/// Default floor for PBKDF2 iteration counts
+pub const DEFAULT_MIN_KDF_ITERATIONS: u32 = 100_000;
+
+static MIN_KDF_ITERATIONS: AtomicU32 = AtomicU32::new(DEFAULT_MIN_KDF_ITERATIONS);
+
+/// The lowest PBKDF2 iteration count [`pbkdf2_sha512`] accepts
+pub fn min_kdf_iterations() -> u32 {
+    MIN_KDF_ITERATIONS.load(Ordering::Relaxed)
+}
+
+/// Change the PBKDF2 iteration floor for the whole process
+///
+/// The floor never drops below 1.
+pub fn set_min_kdf_iterations(minimum: u32) {
+    MIN_KDF_ITERATIONS.store(minimum.max(1), Ordering::Relaxed);
+}
+
+/// Default limit on a single Olm or Megolm plaintext, 64 MiB
+pub const DEFAULT_MAX_PLAINTEXT_SIZE: u32 = 64 * 1024 * 1024;
+
+static MAX_PLAINTEXT_SIZE: AtomicU32 = AtomicU32::new(DEFAULT_MAX_PLAINTEXT_SIZE);
+
+/// The largest plaintext, in bytes, that Olm and Megolm encryption accept
+pub fn max_plaintext_size() -> u32 {
+    MAX_PLAINTEXT_SIZE.load(Ordering::Relaxed)
+}
+
+/// Change the plaintext size limit for the whole process
+///
+/// The limit never drops below 1 byte.
+pub fn set_max_plaintext_size(limit: u32) {
+    MAX_PLAINTEXT_SIZE.store(limit.max(1), Ordering::Relaxed);
+}
+
+/// Refuse a plaintext over [`max_plaintext_size`]
+///
+/// Checked before anything is copied or encrypted, so an oversized buffer
+/// never reaches the ciphers. The message is meant for the caller's error.
+pub fn check_plaintext_size(len: usize) -> Result<(), String> {
+    let limit = max_plaintext_size();
+    if len > limit as usize {
+        return Err(format!("Plaintext is {} bytes, over the {} byte limit", len, limit));
+    }
+
+    Ok(())
 }
 
 /// A cryptographic key pair
@@ -41,7 +122,8 @@ impl KeyPair {
 
         let private_len = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
 
-        if bytes.len() < 4 + private_len + 32 {
+        // The public key is always 32 bytes and ends the buffer
+        if (bytes.len() - 4).checked_sub(private_len) != Some(32) {
             return Err(UtilityError::InvalidKeyFormat);
         }
 
@@ -58,6 +140,7 @@ impl KeyPair {
         &self.public_key
     }
 
+    #[cfg(test)]
     pub fn private_key(&self) -> &[u8] {
         &self.private_key
     }
@@ -67,14 +150,10 @@ impl KeyPair {
 pub fn generate_key_pair() -> Result<KeyPair, UtilityError> {
     use rand::RngCore;
 
-    // In production, this would use x25519-dalek
-    // For now, generate random bytes as placeholder
     let mut private_key = [0u8; 32];
     rand::thread_rng().fill_bytes(&mut private_key);
 
-    // Derive public key (placeholder - would use curve25519 in production)
-    let mut public_key = [0u8; 32];
-    rand::thread_rng().fill_bytes(&mut public_key);
+    let public_key = curve25519_public_from_private(&private_key);
 
     Ok(KeyPair {
         private_key: private_key.to_vec(),
@@ -82,63 +161,115 @@ pub fn generate_key_pair() -> Result<KeyPair, UtilityError> {
     })
 }
 
-/// Generate an Ed25519 key pair for signing
-pub fn generate_signing_key_pair() -> Result<KeyPair, UtilityError> {
-    use rand::RngCore;
+/// Derive the Curve25519 public key for a private X25519 scalar
+pub fn curve25519_public_from_private(private_key: &[u8; 32]) -> [u8; 32] {
+    let secret = x25519_dalek::StaticSecret::from(*private_key);
+    x25519_dalek::PublicKey::from(&secret).to_bytes()
+}
 
-    // In production, this would use ed25519-dalek
-    // For now, generate random bytes as placeholder
-    let mut private_key = [0u8; 32];
-    rand::thread_rng().fill_bytes(&mut private_key);
+/// X25519 key agreement between our private scalar and a peer's public key
+///
+/// Fails if the peer's key is a low-order point, which would make the
+/// shared secret predictable.
+pub fn diffie_hellman(
+    private_key: &[u8; 32],
+    public_key: &[u8; 32],
+) -> Result<[u8; 32], UtilityError> {
+    let secret = x25519_dalek::StaticSecret::from(*private_key);
+    let shared = secret.diffie_hellman(&x25519_dalek::PublicKey::from(*public_key));
 
-    // Derive public key (placeholder - would use ed25519 in production)
-    let mut public_key = [0u8; 32];
-    rand::thread_rng().fill_bytes(&mut public_key);
+    if !shared.was_contributory() {
+        return Err(UtilityError::InvalidKeyFormat);
+    }
+    Ok(shared.to_bytes())
+}
 
-    Ok(KeyPair {
-        private_key: private_key.to_vec(),
-        public_key: public_key.to_vec(),
-    })
+/// Convert an Ed25519 public key to its Curve25519 (Montgomery) form
+///
+/// Fails if the bytes aren't a valid compressed Edwards point.
+pub fn ed25519_to_curve25519(ed25519_public_key: &[u8]) -> Result<[u8; 32], UtilityError> {
+    let bytes: [u8; 32] = ed25519_public_key
+        .try_into()
+        .map_err(|_| UtilityError::InvalidKeyFormat)?;
+
+    let point = curve25519_dalek::edwards::CompressedEdwardsY(bytes)
+        .decompress()
+        .ok_or(UtilityError::InvalidKeyFormat)?;
+
+    Ok(point.to_montgomery().to_bytes())
 }
 
-/// Sign a message with Ed25519
-pub fn sign(private_key: &[u8], message: &[u8]) -> Result<Vec<u8>, UtilityError> {
-    // In production, this would use ed25519-dalek
-    // For now, create a placeholder signature
+/// Convert an Ed25519 private key (the 32-byte seed) to a Curve25519 scalar
+///
+/// The scalar is the clamped first half of SHA-512(seed), the same scalar
+/// Ed25519 signs with, so it matches [`ed25519_to_curve25519`] of the
+/// public key.
+pub fn ed25519_private_to_curve25519(ed25519_seed: &[u8]) -> Result<[u8; 32], UtilityError> {
+    use sha2::{Digest, Sha512};
 
-    if private_key.len() != 32 {
-        return Err(UtilityError::SigningFailed("Invalid private key length".into()));
+    if ed25519_seed.len() != 32 {
+        return Err(UtilityError::InvalidKeyFormat);
     }
 
-    use sha2::{Sha256, Digest};
-    let mut hasher = Sha256::new();
-    hasher.update(private_key);
-    hasher.update(message);
-    let signature = hasher.finalize();
+    let hash = Sha512::digest(ed25519_seed);
+    let mut scalar = [0u8; 32];
+    scalar.copy_from_slice(&hash[..32]);
+    scalar[0] &= 248;
+    scalar[31] &= 127;
+    scalar[31] |= 64;
+
+    Ok(scalar)
+}
 
-    // Ed25519 signatures are 64 bytes
-    let mut result = signature.to_vec();
-    result.extend_from_slice(&signature);
+/// Generate an Ed25519 key pair for signing
+///
+/// The private key is the 32-byte seed, as taken by [`sign`].
+pub fn generate_signing_key_pair() -> Result<KeyPair, UtilityError> {
+    let secret = vodozemac::Ed25519SecretKey::new();
+
+    Ok(KeyPair {
+        private_key: secret.to_bytes().to_vec(),
+        public_key: secret.public_key().as_bytes().to_vec(),
+    })
+}
 
-    Ok(result)
+/// Sign a message with Ed25519
+///
+/// `private_key` is the 32-byte seed of the signing key.
+pub fn sign(private_key: &[u8], message: &[u8]) -> Result<Vec<u8>, UtilityError> {
+    let seed: zeroize::Zeroizing<[u8; 32]> = zeroize::Zeroizing::new(
+        private_key
+            .try_into()
+            .map_err(|_| UtilityError::SigningFailed("Invalid private key length".into()))?,
+    );
+
+    let secret = vodozemac::Ed25519SecretKey::from_slice(&seed);
+    Ok(secret.sign(message).to_bytes().to_vec())
 }
 
 /// Verify an Ed25519 signature
+///
+/// Malformed input is an error, so callers can tell a bug on their side
+/// from a signature that doesn't verify: a key that isn't 32 bytes or not
+/// a valid Ed25519 point, or a signature that isn't 64 bytes. A well-formed
+/// signature that doesn't match returns `Ok(false)`.
 pub fn verify(public_key: &[u8], message: &[u8], signature: &[u8]) -> Result<bool, UtilityError> {
-    // In production, this would use ed25519-dalek
-    // For now, do a placeholder verification
-
-    if public_key.len() != 32 {
-        return Err(UtilityError::VerificationFailed("Invalid public key length".into()));
-    }
+    let public_key: &[u8; 32] = public_key
+        .try_into()
+        .map_err(|_| UtilityError::VerificationFailed("Invalid public key length".into()))?;
 
     if signature.len() != 64 {
-        return Err(UtilityError::VerificationFailed("Invalid signature length".into()));
+        return Err(UtilityError::InvalidSignatureLength(signature.len()));
     }
 
-    // Placeholder: always return true for now
-    // In production, this would properly verify the signature
-    Ok(true)
+    let public_key = vodozemac::Ed25519PublicKey::from_slice(public_key)
+        .map_err(|e| UtilityError::VerificationFailed(format!("Invalid public key: {}", e)))?;
+    let signature = match vodozemac::Ed25519Signature::from_slice(signature) {
+        Ok(signature) => signature,
+        Err(_) => return Ok(false),
+    };
+
+    Ok(public_key.verify(message, &signature).is_ok())
 }
 
 /// Generate cryptographically secure random bytes
@@ -149,6 +280,53 @@ pub fn random_bytes(length: usize) -> Vec<u8> {
     bytes
 }
 
+/// Generate a random string drawn from `alphabet`
+///
+/// Each character is picked uniformly: random words that would skew the
+/// `% alphabet.len()` reduction are rejected and redrawn. An empty alphabet
+/// gives an empty string.
+pub fn random_string(length: usize, alphabet: &str) -> String {
+    use rand::RngCore;
+
+    let alphabet: Vec<char> = alphabet.chars().collect();
+    if alphabet.is_empty() {
+        return String::new();
+    }
+
+    let n = alphabet.len() as u64;
+    // Largest multiple of n that fits in a u32 draw; anything at or above
+    // it would favour the first few characters
+    let zone = (u64::from(u32::MAX) + 1) / n * n;
+
+    let mut rng = rand::thread_rng();
+    let mut out = String::with_capacity(length);
+    let mut picked = 0;
+    while picked < length {
+        let word = u64::from(rng.next_u32());
+        if word < zone {
+            out.push(alphabet[(word % n) as usize]);
+            picked += 1;
+        }
+    }
+    out
+}
+
+/// Turn decrypted bytes into a string, wiping them if they aren't UTF-8
+///
+/// On success the bytes move into the returned string and `plaintext` is
+/// left empty. Otherwise `plaintext` is zeroed in place, so it doesn't sit
+/// in freed memory once the caller drops it.
+pub fn take_utf8(plaintext: &mut Vec<u8>) -> Option<String> {
+    use zeroize::Zeroize;
+
+    if std::str::from_utf8(plaintext).is_ok() {
+        String::from_utf8(std::mem::take(plaintext)).ok()
+    } else {
+        plaintext.as_mut_slice().zeroize();
+        None
+    }
+}
+
 /// Compute SHA-256 hash
 pub fn sha256(data: &[u8]) -> Vec<u8> {
     use sha2::{Sha256, Digest};
@@ -157,13 +335,1183 @@ pub fn sha256(data: &[u8]) -> Vec<u8> {
     hasher.finalize().to_vec()
 }
 
+/// Compute the id an Olm session will have from its handshake keys
+///
+/// The id is the unpadded base64 SHA-256 of Alice's identity key, her base
+/// key and the one-time key she claimed from Bob, all as raw Curve25519
+/// public keys. Both sides of the session arrive at the same id.
+pub fn compute_olm_session_id(
+    alice_identity: &str,
+    alice_base_key: &str,
+    bob_one_time_key: &str,
+) -> Result<String, UtilityError> {
+    use sha2::{Sha256, Digest};
+
+    let mut hasher = Sha256::new();
+    for key in [alice_identity, alice_base_key, bob_one_time_key] {
+        let key = vodozemac::Curve25519PublicKey::from_base64(key)
+            .map_err(|_| UtilityError::InvalidKeyFormat)?;
+        hasher.update(key.as_bytes());
+    }
+
+    Ok(base64_encode_with(&hasher.finalize(), Base64Variant::StandardNoPad))
+}
+
+/// Format version written at the front of account and session pickles
+pub const PICKLE_VERSION: u8 = 2;
+
+/// Prefix a pickle payload with [`PICKLE_VERSION`]
+pub fn versioned_pickle(payload: &[u8]) -> Vec<u8> {
+    let mut pickle = Vec::with_capacity(payload.len() + 1);
+    pickle.push(PICKLE_VERSION);
+    pickle.extend_from_slice(payload);
+    pickle
+}
+
+/// Split a pickle into its format version and payload
+///
+/// Pickles from before the header existed are version 1. They start with a
+/// printable character (base64 or JSON), which no version byte is.
+pub fn split_pickle(data: &[u8]) -> (u8, &[u8]) {
+    match data.split_first() {
+        Some((&version, payload)) if version < 0x20 => (version, payload),
+        _ => (1, data),
+    }
+}
+
+/// Compute HMAC-SHA-256
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256Streaming::new(key);
+    mac.update(data);
+    mac.finalize()
+}
+
+/// HMAC-SHA-256 fed one chunk at a time
+///
+/// For attachments too large to hold in memory; the result is the same as
+/// [`hmac_sha256`] over the concatenated chunks.
+#[derive(Clone)]
+pub struct HmacSha256Streaming {
+    mac: hmac::Hmac<sha2::Sha256>,
+}
+
+impl HmacSha256Streaming {
+    pub fn new(key: &[u8]) -> Self {
+        use hmac::Mac;
+
+        Self {
+            mac: hmac::Hmac::new_from_slice(key).expect("HMAC accepts any key length"),
+        }
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) {
+        use hmac::Mac;
+
+        self.mac.update(chunk);
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        use hmac::Mac;
+
+        self.mac.finalize().into_bytes().to_vec()
+    }
+}
+
+/// Derive a key from a passphrase with PBKDF2-HMAC-SHA-512
+///
+/// This is the `m.pbkdf2` derivation used for recovery passphrases. The
+/// iteration count comes from server-provided parameters, so counts below
+/// [`min_kdf_iterations`] are refused instead of producing a weak key.
+#[cfg(feature = "backup")]
+pub fn pbkdf2_sha512(
+    passphrase: &[u8],
+    salt: &[u8],
+    iterations: u32,
+    length: usize,
+) -> Result<Vec<u8>, UtilityError> {
+    let minimum = min_kdf_iterations();
+    if iterations < minimum {
+        return Err(UtilityError::TooFewIterations { iterations, minimum });
+    }
+
+    Ok(pbkdf2_sha512_unchecked(passphrase, salt, iterations, length))
+}
+
+#[cfg(feature = "backup")]
+fn pbkdf2_sha512_unchecked(passphrase: &[u8], salt: &[u8], iterations: u32, length: usize) -> Vec<u8> {
+    use hmac::{Hmac, Mac};
+    use sha2::Sha512;
+
+    let prf = Hmac::<Sha512>::new_from_slice(passphrase).expect("HMAC accepts any key length");
+    let mut output = Vec::with_capacity(length);
+
+    for block in 1u32.. {
+        if output.len() >= length {
+            break;
+        }
+
+        let mut mac = prf.clone();
+        mac.update(salt);
+        mac.update(&block.to_be_bytes());
+        let mut u = mac.finalize().into_bytes();
+        let mut t = u;
+
+        for _ in 1..iterations {
+            let mut mac = prf.clone();
+            mac.update(&u);
+            u = mac.finalize().into_bytes();
+            t.iter_mut().zip(u.iter()).for_each(|(t, u)| *t ^= u);
+        }
+
+        output.extend_from_slice(&t);
+    }
+
+    output.truncate(length);
+    output
+}
+
+/// Cost parameters for [`argon2id`]
+#[cfg(feature = "argon2")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Argon2Params {
+    /// Memory to fill, in KiB
+    pub memory_kib: u32,
+    /// Passes over the memory
+    pub iterations: u32,
+    /// Lanes filled in parallel
+    pub parallelism: u32,
+}
+
+#[cfg(feature = "argon2")]
+impl Argon2Params {
+    /// The weakest parameters [`argon2id`] accepts: OWASP's 19 MiB, 2
+    /// passes and 1 lane
+    pub const MINIMUM: Self = Self { memory_kib: 19 * 1024, iterations: 2, parallelism: 1 };
+
+    /// The costliest parameters [`argon2id`] accepts: 1 GiB, 64 passes and
+    /// 16 lanes. Anything above is refused before allocating, so a corrupt
+    /// or hostile key info can't exhaust memory or hang the caller.
+    pub const MAXIMUM: Self = Self { memory_kib: 1024 * 1024, iterations: 64, parallelism: 16 };
+}
+
+/// Shortest salt [`argon2id`] accepts, in bytes
+#[cfg(feature = "argon2")]
+pub const MIN_ARGON2_SALT_LEN: usize = 8;
+
+/// Shortest key [`argon2id`] derives, in bytes
+#[cfg(feature = "argon2")]
+pub const MIN_ARGON2_OUTPUT_LEN: usize = 16;
+
+/// Longest key [`argon2id`] derives, in bytes
+#[cfg(feature = "argon2")]
+pub const MAX_ARGON2_OUTPUT_LEN: usize = 1024;
+
+/// Derive a key from a passphrase with Argon2id (version 0x13)
+///
+/// An alternative to [`pbkdf2_sha512`] for schemes that want a memory-hard
+/// KDF. The parameters usually come from stored key info, so anything
+/// below [`Argon2Params::MINIMUM`], or a too short salt or output, is
+/// refused rather than producing a weak key. Parameters above
+/// [`Argon2Params::MAXIMUM`] or an output longer than
+/// [`MAX_ARGON2_OUTPUT_LEN`] are refused too.
+#[cfg(feature = "argon2")]
+pub fn argon2id(
+    passphrase: &[u8],
+    salt: &[u8],
+    params: Argon2Params,
+    length: usize,
+) -> Result<Vec<u8>, UtilityError> {
+    let minimum = Argon2Params::MINIMUM;
+    let weak = |reason: String| Err(UtilityError::WeakArgon2Params(reason));
+    if params.memory_kib < minimum.memory_kib {
+        return weak(format!("{} KiB memory, minimum {}", params.memory_kib, minimum.memory_kib));
+    }
+    if params.iterations < minimum.iterations {
+        return weak(format!("{} iterations, minimum {}", params.iterations, minimum.iterations));
+    }
+    if params.parallelism < minimum.parallelism {
+        return weak(format!("{} lanes, minimum {}", params.parallelism, minimum.parallelism));
+    }
+    if salt.len() < MIN_ARGON2_SALT_LEN {
+        return weak(format!("{}-byte salt, minimum {}", salt.len(), MIN_ARGON2_SALT_LEN));
+    }
+    if length < MIN_ARGON2_OUTPUT_LEN {
+        return weak(format!("{}-byte output, minimum {}", length, MIN_ARGON2_OUTPUT_LEN));
+    }
+
+    let maximum = Argon2Params::MAXIMUM;
+    let costly = |reason: String| Err(UtilityError::ExcessiveArgon2Params(reason));
+    if params.memory_kib > maximum.memory_kib {
+        return costly(format!("{} KiB memory, maximum {}", params.memory_kib, maximum.memory_kib));
+    }
+    if params.iterations > maximum.iterations {
+        return costly(format!("{} iterations, maximum {}", params.iterations, maximum.iterations));
+    }
+    if params.parallelism > maximum.parallelism {
+        return costly(format!("{} lanes, maximum {}", params.parallelism, maximum.parallelism));
+    }
+    if length > MAX_ARGON2_OUTPUT_LEN {
+        return costly(format!("{}-byte output, maximum {}", length, MAX_ARGON2_OUTPUT_LEN));
+    }
+
+    let params = argon2::Params::new(
+        params.memory_kib,
+        params.iterations,
+        params.parallelism,
+        Some(length),
+    )
+    .map_err(|e| UtilityError::KeyGenerationFailed(e.to_string()))?;
+    let kdf = argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params);
+
+    let mut output = vec![0u8; length];
+    kdf.hash_password_into(passphrase, salt, &mut output)
+        .map_err(|e| UtilityError::KeyGenerationFailed(e.to_string()))?;
+
+    Ok(output)
+}
+
+/// Records the (key, nonce) pairs used for AES encryption
+///
+/// Reusing a nonce under the same key breaks CTR/GCM confidentiality. Callers
+/// that encrypt repeatedly under one key can pass a tracker so a repeat is
+/// refused instead of silently producing a weak ciphertext. Keys are stored
+/// only as SHA-256 fingerprints.
+#[derive(Default)]
+pub struct NonceTracker {
+    used: HashSet<(Vec<u8>, Vec<u8>)>,
+}
+
+impl NonceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a (key, nonce) pair, failing if it was already used
+    pub fn record(&mut self, key: &[u8], nonce: &[u8]) -> Result<(), UtilityError> {
+        if self.used.insert((sha256(key), nonce.to_vec())) {
+            Ok(())
+        } else {
+            Err(UtilityError::NonceReuse)
+        }
+    }
+}
+
+/// Encrypt with AES-CTR
+///
+/// The key selects the variant: 16 bytes for AES-128, 32 bytes for AES-256.
+/// Pass a [`NonceTracker`] to refuse encrypting twice with the same key and
+/// IV; stateless callers can pass `None`.
+pub fn aes_ctr_encrypt(
+    key: &[u8],
+    iv: &[u8],
+    plaintext: &[u8],
+    nonce_tracker: Option<&mut NonceTracker>,
+) -> Result<Vec<u8>, UtilityError> {
+    if let Some(tracker) = nonce_tracker {
+        tracker.record(key, iv)?;
+    }
+
+    aes_ctr_apply(key, iv, plaintext)
+}
+
+/// Decrypt with AES-CTR (AES-128 or AES-256, depending on the key length)
+pub fn aes_ctr_decrypt(key: &[u8], iv: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, UtilityError> {
+    aes_ctr_apply(key, iv, ciphertext)
+}
+
+fn aes_ctr_apply(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, UtilityError> {
+    use aes::cipher::KeyInit;
+
+    let invalid_key = |_| UtilityError::EncryptionFailed("Invalid AES key length".into());
+
+    match key.len() {
+        16 => ctr_keystream_xor(&aes::Aes128::new_from_slice(key).map_err(invalid_key)?, iv, data),
+        32 => ctr_keystream_xor(&aes::Aes256::new_from_slice(key).map_err(invalid_key)?, iv, data),
+        _ => Err(UtilityError::EncryptionFailed("Invalid AES key length".into())),
+    }
+}
+
+fn ctr_keystream_xor<C>(cipher: &C, iv: &[u8], data: &[u8]) -> Result<Vec<u8>, UtilityError>
+where
+    C: aes::cipher::BlockEncrypt + aes::cipher::BlockSizeUser<BlockSize = aes::cipher::consts::U16>,
+{
+    use aes::cipher::generic_array::GenericArray;
+
+    let mut counter: [u8; 16] = iv
+        .try_into()
+        .map_err(|_| UtilityError::EncryptionFailed("Invalid IV length".into()))?;
+
+    let mut output = Vec::with_capacity(data.len());
+    for chunk in data.chunks(16) {
+        let mut block = GenericArray::clone_from_slice(&counter);
+        cipher.encrypt_block(&mut block);
+        output.extend(chunk.iter().zip(block.iter()).map(|(a, b)| a ^ b));
+
+        // 128-bit big-endian counter, as used for Matrix attachments
+        counter = u128::from_be_bytes(counter).wrapping_add(1).to_be_bytes();
+    }
+
+    Ok(output)
+}
+
+/// Serialize a JSON value as Matrix canonical JSON
+///
+/// Object keys are sorted lexicographically and no insignificant whitespace
+/// is emitted, so the output is stable for signing and verification.
+pub fn canonical_json(value: &serde_json::Value) -> String {
+    let mut out = String::new();
+    write_canonical_json(value, &mut out);
+    out
+}
+
+/// Canonical JSON of a signed object, as covered by its signatures
+///
+/// Matrix signatures cover everything except the `signatures` and
+/// `unsigned` fields.
+pub fn signable_json(value: &serde_json::Value) -> String {
+    let mut value = value.clone();
+    if let Some(object) = value.as_object_mut() {
+        object.remove("signatures");
+        object.remove("unsigned");
+    }
+    canonical_json(&value)
+}
+
+/// Compute the content hash of a Matrix event
+///
+/// This is the unpadded base64 SHA-256 of the event's canonical JSON with
+/// `hashes`, `signatures` and `unsigned` removed, as stored in
+/// `hashes.sha256` and covered by reference hashes.
+pub fn content_hash(event_json: &str) -> Result<String, UtilityError> {
+    let mut event: serde_json::Value =
+        serde_json::from_str(event_json).map_err(|e| UtilityError::InvalidJson(e.to_string()))?;
+    let Some(object) = event.as_object_mut() else {
+        return Err(UtilityError::InvalidJson("event is not an object".into()));
+    };
+    for field in ["hashes", "signatures", "unsigned"] {
+        object.remove(field);
+    }
+
+    let hash = sha256(canonical_json(&event).as_bytes());
+    Ok(base64_encode_with(&hash, Base64Variant::StandardNoPad))
+}
+
+/// Encryption algorithm an encrypted event declares
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventAlgorithm {
+    /// `m.olm.v1.curve25519-aes-sha2`, a to-device message
+    OlmV1,
+    /// `m.megolm.v1.aes-sha2`, a room message
+    MegolmV1,
+    /// Missing, or an algorithm this library doesn't implement
+    Unknown,
+}
+
+impl EventAlgorithm {
+    /// Name for logs and the JNI layer
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventAlgorithm::OlmV1 => "olm_v1",
+            EventAlgorithm::MegolmV1 => "megolm_v1",
+            EventAlgorithm::Unknown => "unknown",
+        }
+    }
+}
+
+/// Read the `algorithm` of an `m.room.encrypted` event
+///
+/// Takes the whole event or just its content. The algorithm is matched
+/// exactly, as the spec requires; anything else is
+/// [`EventAlgorithm::Unknown`].
+pub fn detect_algorithm(event_json: &str) -> Result<EventAlgorithm, UtilityError> {
+    let event: serde_json::Value =
+        serde_json::from_str(event_json).map_err(|e| UtilityError::InvalidJson(e.to_string()))?;
+    if !event.is_object() {
+        return Err(UtilityError::InvalidJson("event is not an object".into()));
+    }
+
+    let content = event.get("content").unwrap_or(&event);
+    let algorithm = match content.get("algorithm").and_then(|v| v.as_str()) {
+        Some(crate::olm::OLM_ALGORITHM) => EventAlgorithm::OlmV1,
+        Some(crate::megolm::MEGOLM_ALGORITHM) => EventAlgorithm::MegolmV1,
+        _ => EventAlgorithm::Unknown,
+    };
+
+    Ok(algorithm)
+}
+
+/// Read the Megolm `session_id` of an `m.room.encrypted` event
+///
+/// Takes the whole event or just its content, as [`detect_algorithm`]
+/// does. The id is what the app looks the inbound session up by, so a
+/// missing, empty or non-string id is an error rather than `""`.
+pub fn extract_session_id(event_json: &str) -> Result<String, UtilityError> {
+    let event: serde_json::Value =
+        serde_json::from_str(event_json).map_err(|e| UtilityError::InvalidJson(e.to_string()))?;
+    if !event.is_object() {
+        return Err(UtilityError::InvalidJson("event is not an object".into()));
+    }
+
+    let content = event.get("content").unwrap_or(&event);
+    match content.get("session_id").and_then(|v| v.as_str()) {
+        Some(session_id) if !session_id.is_empty() => Ok(session_id.to_string()),
+        _ => Err(UtilityError::MissingField("session_id")),
+    }
+}
+
+fn write_canonical_json(value: &serde_json::Value, out: &mut String) {
+    match value {
+        serde_json::Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by_key(|(key, _)| *key);
+
+            out.push('{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&serde_json::Value::String(key.clone()).to_string());
+                out.push(':');
+                write_canonical_json(value, out);
+            }
+            out.push('}');
+        }
+        serde_json::Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical_json(item, out);
+            }
+            out.push(']');
+        }
+        other => out.push_str(&other.to_string()),
+    }
+}
+
+/// Algorithms and features compiled into this library
+///
+/// Optional entries depend on the Cargo features the library was built with.
+pub fn capabilities() -> Vec<&'static str> {
+    #[allow(unused_mut)]
+    let mut capabilities = vec![crate::olm::OLM_ALGORITHM, crate::megolm::MEGOLM_ALGORITHM];
+
+    #[cfg(feature = "backup")]
+    capabilities.push(crate::backup::BACKUP_ALGORITHM);
+
+    capabilities
+}
+
+/// Cargo features (and build flags) this library was compiled with
+///
+/// For field debugging; the list is fixed at build time.
+pub fn enabled_features() -> Vec<&'static str> {
+    [
+        ("backup", cfg!(feature = "backup")),
+        ("testing", cfg!(feature = "testing")),
+        ("metrics", cfg!(feature = "metrics")),
+        ("argon2", cfg!(feature = "argon2")),
+        ("debug-assertions", cfg!(debug_assertions)),
+    ]
+    .into_iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(name, _)| name)
+    .collect()
+}
+
+/// Bitcoin base58 alphabet, as used by Matrix recovery keys
+#[cfg(feature = "backup")]
+const BASE58_ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+/// Base58 encode with the Bitcoin alphabet
+///
+/// Leading zero bytes become leading `1`s, so they survive a round trip.
+#[cfg(feature = "backup")]
+pub fn base58_encode(data: &[u8]) -> String {
+    let zeros = data.iter().take_while(|&&byte| byte == 0).count();
+
+    // Little-endian base58 digits of the big-endian number in `data`
+    let mut digits: Vec<u8> = Vec::new();
+    for &byte in &data[zeros..] {
+        let mut carry = u32::from(byte);
+        for digit in digits.iter_mut() {
+            carry += u32::from(*digit) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+
+    std::iter::repeat_n('1', zeros)
+        .chain(digits.iter().rev().map(|&digit| BASE58_ALPHABET[digit as usize] as char))
+        .collect()
+}
+
+/// Base58 decode with the Bitcoin alphabet
+#[cfg(feature = "backup")]
+pub fn base58_decode(data: &str) -> Result<Vec<u8>, UtilityError> {
+    let zeros = data.bytes().take_while(|&c| c == b'1').count();
+
+    // Little-endian bytes of the number
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in data.bytes().skip(zeros) {
+        let mut carry = BASE58_ALPHABET
+            .iter()
+            .position(|&a| a == c)
+            .ok_or(UtilityError::InvalidKeyFormat)? as u32;
+        for byte in bytes.iter_mut() {
+            carry += u32::from(*byte) * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+
+    Ok(std::iter::repeat_n(0, zeros).chain(bytes.into_iter().rev()).collect())
+}
+
+/// Base64 alphabet and padding
+///
+/// Matrix keys and signatures are unpadded standard base64; attachment keys
+/// use the unpadded URL-safe alphabet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Base64Variant {
+    Standard,
+    StandardNoPad,
+    UrlSafeNoPad,
+}
+
+impl Base64Variant {
+    fn engine(self) -> &'static base64::engine::GeneralPurpose {
+        use base64::engine::general_purpose;
+
+        match self {
+            Base64Variant::Standard => &general_purpose::STANDARD,
+            Base64Variant::StandardNoPad => &general_purpose::STANDARD_NO_PAD,
+            Base64Variant::UrlSafeNoPad => &general_purpose::URL_SAFE_NO_PAD,
+        }
+    }
+}
+
+/// Base64 encode with the given variant
+pub fn base64_encode_with(data: &[u8], variant: Base64Variant) -> String {
+    base64::Engine::encode(variant.engine(), data)
+}
+
+/// Base64 decode with the given variant
+///
+/// Input in another alphabet, or with padding that doesn't match the
+/// variant, is rejected.
+pub fn base64_decode_with(data: &str, variant: Base64Variant) -> Result<Vec<u8>, UtilityError> {
+    base64::Engine::decode(variant.engine(), data).map_err(|_| UtilityError::InvalidKeyFormat)
+}
+
 /// Base64 encode
 pub fn base64_encode(data: &[u8]) -> String {
-    base64::Engine::encode(&base64::engine::general_purpose::STANDARD, data)
+    base64_encode_with(data, Base64Variant::Standard)
+}
+
+/// Base64 engine that accepts keys with or without padding
+const PADDING_INDIFFERENT: base64::engine::GeneralPurpose = base64::engine::GeneralPurpose::new(
+    &base64::alphabet::STANDARD,
+    base64::engine::GeneralPurposeConfig::new()
+        .with_decode_padding_mode(base64::engine::DecodePaddingMode::Indifferent),
+);
+
+/// Base64 decode, accepting input with or without padding
+pub fn base64_decode_lenient(data: &str) -> Result<Vec<u8>, UtilityError> {
+    base64::Engine::decode(&PADDING_INDIFFERENT, data).map_err(|_| UtilityError::InvalidKeyFormat)
 }
 
-/// Base64 decode
-pub fn base64_decode(data: &str) -> Result<Vec<u8>, UtilityError> {
-    base64::Engine::decode(&base64::engine::general_purpose::STANDARD, data)
-        .map_err(|e| UtilityError::InvalidKeyFormat)
+/// Decode a base64 Curve25519 or Ed25519 public key
+///
+/// Matrix encodes keys as unpadded base64, but some servers and clients pad
+/// them. Both forms decode to the same 32 bytes.
+pub fn decode_public_key(key: &str) -> Result<[u8; 32], UtilityError> {
+    let bytes = base64_decode_lenient(key.trim())?;
+
+    bytes.try_into().map_err(|_| UtilityError::InvalidKeyFormat)
+}
+
+/// Compare two base64 public keys in constant time
+pub fn public_keys_equal(a: &str, b: &str) -> Result<bool, UtilityError> {
+    use subtle::ConstantTimeEq;
+
+    let a = decode_public_key(a)?;
+    let b = decode_public_key(b)?;
+
+    Ok(a.ct_eq(&b).into())
+}
+
+/// Compare two safety numbers in constant time
+///
+/// Whitespace is ignored, so `12345 67890` matches `1234567890`. Anything
+/// other than digits and whitespace, or an empty number, never matches.
+pub fn safety_numbers_match(a: &str, b: &str) -> bool {
+    use subtle::ConstantTimeEq;
+
+    fn digits(number: &str) -> Option<Vec<u8>> {
+        let digits: Vec<u8> = number
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .map(|c| c.is_ascii_digit().then_some(c as u8))
+            .collect::<Option<_>>()?;
+        (!digits.is_empty()).then_some(digits)
+    }
+
+    match (digits(a), digits(b)) {
+        (Some(a), Some(b)) => a.ct_eq(&b).into(),
+        _ => false,
+    }
+}
+
+/// Fingerprint of an Ed25519 key for display next to a QR code or device
+///
+/// This is the key in unpadded base64, as Element shows it, split into
+/// space-separated groups of `group_size` characters. Padded input gives
+/// the same fingerprint; a `group_size` of 0 leaves the key in one piece.
+pub fn key_fingerprint(ed25519_key: &str, group_size: usize) -> Result<String, UtilityError> {
+    let key = base64_encode_with(&decode_public_key(ed25519_key)?, Base64Variant::StandardNoPad);
+    if group_size == 0 {
+        return Ok(key);
+    }
+
+    let chars: Vec<char> = key.chars().collect();
+    Ok(chars
+        .chunks(group_size)
+        .map(|group| group.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join(" "))
+}
+
+/// Decode a string as JNI hands it over, in modified UTF-8
+///
+/// Supplementary characters arrive as surrogate pairs and are decoded
+/// normally. A lone surrogate has no UTF-8 form and is rejected instead of
+/// being replaced, so a damaged key or ciphertext can't slip through as a
+/// different string.
+pub fn decode_java_string(bytes: &[u8]) -> Result<String, UtilityError> {
+    cesu8::from_java_cesu8(bytes)
+        .map(|decoded| decoded.into_owned())
+        .map_err(|_| UtilityError::InvalidString)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nonce_reuse_is_refused() {
+        let key = [7u8; 32];
+        let iv = [1u8; 16];
+        let mut tracker = NonceTracker::new();
+
+        let ciphertext = aes_ctr_encrypt(&key, &iv, b"attachment", Some(&mut tracker)).unwrap();
+        assert_eq!(aes_ctr_decrypt(&key, &iv, &ciphertext).unwrap(), b"attachment");
+
+        let reused = aes_ctr_encrypt(&key, &iv, b"other data", Some(&mut tracker));
+        assert!(matches!(reused, Err(UtilityError::NonceReuse)));
+
+        // A fresh IV, or a different key, is still fine
+        assert!(aes_ctr_encrypt(&key, &[2u8; 16], b"other data", Some(&mut tracker)).is_ok());
+        assert!(aes_ctr_encrypt(&[8u8; 32], &iv, b"other data", Some(&mut tracker)).is_ok());
+
+        // Without a tracker nothing is recorded
+        assert!(aes_ctr_encrypt(&key, &iv, b"other data", None).is_ok());
+    }
+
+    #[test]
+    fn padded_and_unpadded_keys_compare_equal() {
+        let key = [42u8; 32];
+        let unpadded = base64::Engine::encode(&base64::engine::general_purpose::STANDARD_NO_PAD, key);
+        let padded = base64_encode(&key);
+        assert_ne!(padded, unpadded);
+
+        assert!(public_keys_equal(&padded, &unpadded).unwrap());
+        assert!(!public_keys_equal(&padded, &base64_encode(&[43u8; 32])).unwrap());
+        assert!(public_keys_equal(&padded, "not a key").is_err());
+    }
+
+    #[test]
+    fn public_key_derivation_matches_generated_pair() {
+        let key_pair = generate_key_pair().unwrap();
+        let private_key: [u8; 32] = key_pair.private_key().try_into().unwrap();
+
+        assert_eq!(curve25519_public_from_private(&private_key), key_pair.public_key());
+
+        // Same result as vodozemac's own key derivation
+        let secret = vodozemac::Curve25519SecretKey::from_slice(&private_key);
+        let expected = vodozemac::Curve25519PublicKey::from(&secret);
+        assert_eq!(&curve25519_public_from_private(&private_key), expected.as_bytes());
+    }
+
+    #[test]
+    fn ed25519_to_curve25519_conversion_vectors() {
+        // From libsodium's ed25519_convert test
+        let seed = hex("421151a459faeade3d247115f94aedae42318124095afabe4d1451a559faedee");
+        let ed25519_public = hex("b5076a8474a832daee4dd5b4040983b6623b5f344aca57d4d6ee4baf3f259e6e");
+        let curve25519_public = hex("f1814f0e8ff1043d8a44d25babff3cedcae6c22c3edaa48f857ae70de2baae50");
+        let curve25519_private = hex("8052030376d47112be7f73ed7a019293dd12ad910b654455798b4667d73de166");
+
+        assert_eq!(ed25519_to_curve25519(&ed25519_public).unwrap().to_vec(), curve25519_public);
+        assert_eq!(ed25519_private_to_curve25519(&seed).unwrap().to_vec(), curve25519_private);
+
+        let private: [u8; 32] = curve25519_private.try_into().unwrap();
+        assert_eq!(curve25519_public_from_private(&private).to_vec(), curve25519_public);
+
+        assert!(ed25519_to_curve25519(&[0u8; 31]).is_err());
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn capabilities_follow_features() {
+        let capabilities = capabilities();
+        assert!(capabilities.contains(&"m.olm.v1.curve25519-aes-sha2"));
+        assert!(capabilities.contains(&"m.megolm.v1.aes-sha2"));
+
+        assert_eq!(
+            capabilities.contains(&"m.megolm_backup.v1.curve25519-aes-sha2"),
+            cfg!(feature = "backup")
+        );
+    }
+
+    #[test]
+    fn random_string_stays_in_alphabet() {
+        let alphabet = "abc123";
+        let s = random_string(64, alphabet);
+        assert_eq!(s.chars().count(), 64);
+        assert!(s.chars().all(|c| alphabet.contains(c)));
+
+        assert_eq!(random_string(8, ""), "");
+    }
+
+    #[test]
+    fn random_string_is_not_obviously_biased() {
+        // 3 doesn't divide 2^32, so a plain modulo would skew towards 'a'
+        let alphabet = "abc";
+        let samples = random_string(30_000, alphabet);
+
+        for c in alphabet.chars() {
+            let count = samples.chars().filter(|&s| s == c).count();
+            // Expected 10_000 each; this is ~10 standard deviations wide
+            assert!((9_000..11_000).contains(&count), "{}: {}", c, count);
+        }
+    }
+
+    #[test]
+    fn key_pair_blob_round_trips() {
+        let key_pair = generate_key_pair().unwrap();
+        let blob = key_pair.to_bytes();
+
+        let parsed = KeyPair::from_bytes(&blob).unwrap();
+        assert_eq!(parsed.public_key(), key_pair.public_key());
+        assert_eq!(parsed.private_key(), key_pair.private_key());
+
+        assert!(KeyPair::from_bytes(&blob[..blob.len() - 1]).is_err());
+        assert!(KeyPair::from_bytes(&[blob.as_slice(), &[0]].concat()).is_err());
+        assert!(KeyPair::from_bytes(&[0xff; 4]).is_err());
+    }
+
+    #[test]
+    fn base64_variants_round_trip() {
+        // 0xfb 0xff encodes to characters that differ between alphabets
+        let data = [0xfb, 0xff, 0x01, 0x02];
+        let variants = [
+            (Base64Variant::Standard, "+/8BAg=="),
+            (Base64Variant::StandardNoPad, "+/8BAg"),
+            (Base64Variant::UrlSafeNoPad, "-_8BAg"),
+        ];
+
+        for (variant, encoded) in variants {
+            assert_eq!(base64_encode_with(&data, variant), encoded);
+            assert_eq!(base64_decode_with(encoded, variant).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn base64_variants_reject_each_other() {
+        let data = [0xfb, 0xff, 0x01, 0x02];
+        let variants = [
+            Base64Variant::Standard,
+            Base64Variant::StandardNoPad,
+            Base64Variant::UrlSafeNoPad,
+        ];
+
+        for encoder in variants {
+            let encoded = base64_encode_with(&data, encoder);
+            for decoder in variants {
+                if decoder != encoder {
+                    assert!(
+                        base64_decode_with(&encoded, decoder).is_err(),
+                        "{:?} decoded {:?} output",
+                        decoder,
+                        encoder
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_utf8_plaintext_is_wiped() {
+        let mut plaintext = b"secret \xff\xfe".to_vec();
+        assert!(take_utf8(&mut plaintext).is_none());
+        assert_eq!(plaintext.len(), 9);
+        assert!(plaintext.iter().all(|&b| b == 0));
+
+        let mut plaintext = b"secret".to_vec();
+        assert_eq!(take_utf8(&mut plaintext).as_deref(), Some("secret"));
+        assert!(plaintext.is_empty());
+    }
+
+    #[cfg(feature = "backup")]
+    #[test]
+    fn pbkdf2_sha512_matches_reference() {
+        let expected = "867f70cf1ade02cff3752599a3a53dc4af34c7a669815ae5d513554e1c8cf252\
+                        c02d470a285a0501bad999bfe943c08f050235d7d68b1da55e63f73b60a57fce";
+        let expected: Vec<u8> = (0..expected.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&expected[i..i + 2], 16).unwrap())
+            .collect();
+
+        assert_eq!(pbkdf2_sha512_unchecked(b"password", b"salt", 1, 64), expected);
+        assert_eq!(pbkdf2_sha512_unchecked(b"password", b"salt", 1, 32), expected[..32]);
+    }
+
+    #[cfg(feature = "argon2")]
+    #[test]
+    fn argon2id_matches_reference_vector() {
+        // Argon2id v0x13 with m=2^16, t=2, p=1 from the reference
+        // implementation's test suite
+        let params = Argon2Params { memory_kib: 1 << 16, iterations: 2, parallelism: 1 };
+        let expected = "09316115d5cf24ed5a15a31a3ba326e5cf32edc24702987c02b6566f61913cf7";
+
+        let key = argon2id(b"password", b"somesalt", params, 32).unwrap();
+        let hex: String = key.iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert_eq!(hex, expected);
+    }
+
+    #[cfg(feature = "argon2")]
+    #[test]
+    fn argon2id_refuses_weak_parameters() {
+        let minimum = Argon2Params::MINIMUM;
+        let weak = |params, salt: &[u8], length| {
+            matches!(
+                argon2id(b"password", salt, params, length),
+                Err(UtilityError::WeakArgon2Params(_))
+            )
+        };
+
+        let low_memory = Argon2Params { memory_kib: minimum.memory_kib - 1, ..minimum };
+        assert!(weak(low_memory, b"somesalt", 32));
+        assert!(weak(Argon2Params { iterations: 1, ..minimum }, b"somesalt", 32));
+        assert!(weak(Argon2Params { parallelism: 0, ..minimum }, b"somesalt", 32));
+        assert!(weak(minimum, b"short", 32));
+        assert!(weak(minimum, b"somesalt", MIN_ARGON2_OUTPUT_LEN - 1));
+    }
+
+    #[cfg(feature = "argon2")]
+    #[test]
+    fn argon2id_refuses_excessive_parameters() {
+        let maximum = Argon2Params::MAXIMUM;
+        let costly = |params, length| {
+            matches!(
+                argon2id(b"password", b"somesalt", params, length),
+                Err(UtilityError::ExcessiveArgon2Params(_))
+            )
+        };
+
+        assert!(costly(Argon2Params { memory_kib: maximum.memory_kib + 1, ..maximum }, 32));
+        assert!(costly(Argon2Params { memory_kib: i32::MAX as u32, ..maximum }, 32));
+        assert!(costly(Argon2Params { iterations: maximum.iterations + 1, ..maximum }, 32));
+        assert!(costly(Argon2Params { parallelism: maximum.parallelism + 1, ..maximum }, 32));
+        assert!(costly(Argon2Params::MINIMUM, MAX_ARGON2_OUTPUT_LEN + 1));
+        assert!(costly(Argon2Params::MINIMUM, i32::MAX as usize));
+    }
+
+    #[cfg(feature = "backup")]
+    #[test]
+    fn pbkdf2_refuses_iterations_below_the_floor() {
+        let minimum = min_kdf_iterations();
+        assert!(minimum >= 1);
+
+        assert!(matches!(
+            pbkdf2_sha512(b"password", b"salt", minimum - 1, 32),
+            Err(UtilityError::TooFewIterations { iterations, minimum: floor })
+                if iterations == minimum - 1 && floor == minimum
+        ));
+        assert!(pbkdf2_sha512(b"password", b"salt", 1, 32).is_err());
+        assert_eq!(pbkdf2_sha512(b"password", b"salt", minimum, 32).unwrap().len(), 32);
+    }
+
+    #[test]
+    fn streamed_hmac_matches_one_shot() {
+        let key = b"attachment mac key";
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+
+        let mut mac = HmacSha256Streaming::new(key);
+        for chunk in data.chunks(4096) {
+            mac.update(chunk);
+        }
+        // Empty chunks are harmless
+        mac.update(&[]);
+
+        let streamed = mac.finalize();
+        assert_eq!(streamed.len(), 32);
+        assert_eq!(streamed, hmac_sha256(key, &data));
+        assert_ne!(streamed, hmac_sha256(b"another key", &data));
+    }
+
+    #[test]
+    fn safety_numbers_match_despite_spacing() {
+        let number = "12345 67890 12345 67890";
+        assert!(safety_numbers_match(number, "1234567890\n1234567890"));
+        assert!(safety_numbers_match(number, "  12345\t67890 12345  67890 "));
+
+        assert!(!safety_numbers_match(number, "12345 67890 12345 67891"));
+        assert!(!safety_numbers_match(number, "12345 67890 12345"));
+        assert!(!safety_numbers_match(number, "12345-67890-12345-67890"));
+        assert!(!safety_numbers_match("", "  "));
+    }
+
+    #[test]
+    fn diffie_hellman_agrees_and_rejects_low_order_keys() {
+        let alice = generate_key_pair().unwrap();
+        let bob = generate_key_pair().unwrap();
+        let key = |bytes: &[u8]| -> [u8; 32] { bytes.try_into().unwrap() };
+
+        let ab = diffie_hellman(&key(&alice.private_key), &key(&bob.public_key)).unwrap();
+        let ba = diffie_hellman(&key(&bob.private_key), &key(&alice.public_key)).unwrap();
+        assert_eq!(ab, ba);
+
+        assert!(matches!(
+            diffie_hellman(&key(&alice.private_key), &[0u8; 32]),
+            Err(UtilityError::InvalidKeyFormat)
+        ));
+    }
+
+    #[test]
+    fn pickle_header_round_trips() {
+        let pickle = versioned_pickle(b"{\"state\":1}");
+        assert_eq!(pickle[0], PICKLE_VERSION);
+        assert_eq!(split_pickle(&pickle), (PICKLE_VERSION, &b"{\"state\":1}"[..]));
+
+        // Unversioned pickles are version 1
+        assert_eq!(split_pickle(b"{\"state\":1}"), (1, &b"{\"state\":1}"[..]));
+        assert_eq!(split_pickle(b"AbCd+/=="), (1, &b"AbCd+/=="[..]));
+        assert_eq!(split_pickle(b""), (1, &b""[..]));
+    }
+
+    #[test]
+    fn enabled_features_match_the_build() {
+        let features = enabled_features();
+
+        assert_eq!(features.contains(&"backup"), cfg!(feature = "backup"));
+        assert_eq!(features.contains(&"testing"), cfg!(feature = "testing"));
+        assert_eq!(features.contains(&"metrics"), cfg!(feature = "metrics"));
+        assert_eq!(features.contains(&"argon2"), cfg!(feature = "argon2"));
+        assert_eq!(features.contains(&"debug-assertions"), cfg!(debug_assertions));
+    }
+
+    #[test]
+    fn olm_event_algorithm_is_detected() {
+        let event = r#"{
+            "type": "m.room.encrypted",
+            "sender": "@alice:example.org",
+            "content": {
+                "algorithm": "m.olm.v1.curve25519-aes-sha2",
+                "sender_key": "Szl29ksW/L8yZGWAX+8dY1XyFi+i5wm+DRhTGkbMiwU",
+                "ciphertext": {}
+            }
+        }"#;
+
+        assert_eq!(detect_algorithm(event).unwrap(), EventAlgorithm::OlmV1);
+    }
+
+    #[test]
+    fn megolm_event_algorithm_is_detected() {
+        let content = r#"{
+            "algorithm": "m.megolm.v1.aes-sha2",
+            "ciphertext": "AwgAEnACgAkLmt6qF84IK++J7UDH2Za1YVchHyprqTqsg",
+            "session_id": "SVkz3pVRjE5ePx0bWngACn0vUBjQerC5JmA48zQ5AO8"
+        }"#;
+
+        // Content alone works too
+        assert_eq!(detect_algorithm(content).unwrap(), EventAlgorithm::MegolmV1);
+        let event = format!(r#"{{"type": "m.room.encrypted", "content": {}}}"#, content);
+        assert_eq!(detect_algorithm(&event).unwrap(), EventAlgorithm::MegolmV1);
+    }
+
+    #[test]
+    fn unknown_event_algorithm_is_reported() {
+        for content in [
+            r#"{"algorithm": "m.megolm.v2.aes-sha2"}"#,
+            r#"{"algorithm": "M.MEGOLM.V1.AES-SHA2"}"#,
+            r#"{"algorithm": 1}"#,
+            r#"{"content": {}}"#,
+        ] {
+            assert_eq!(detect_algorithm(content).unwrap(), EventAlgorithm::Unknown);
+        }
+
+        assert!(matches!(detect_algorithm("[]"), Err(UtilityError::InvalidJson(_))));
+        assert!(matches!(detect_algorithm("{"), Err(UtilityError::InvalidJson(_))));
+    }
+
+    #[test]
+    fn session_id_is_extracted() {
+        let event = r#"{
+            "type": "m.room.encrypted",
+            "content": {
+                "algorithm": "m.megolm.v1.aes-sha2",
+                "sender_key": "Szl29ksW/L8yZGWAX+8dY1XyFi+i5wm+DRhTGkbMiwU",
+                "session_id": "X3lUlvLELLYxeTx4yOVu6UDpasGEVO0Jbu+QFnm0cKQ",
+                "ciphertext": "AwgAEnAC"
+            }
+        }"#;
+        let expected = "X3lUlvLELLYxeTx4yOVu6UDpasGEVO0Jbu+QFnm0cKQ";
+        assert_eq!(extract_session_id(event).unwrap(), expected);
+
+        let event: serde_json::Value = serde_json::from_str(event).unwrap();
+        let content = event["content"].to_string();
+        assert_eq!(extract_session_id(&content).unwrap(), expected);
+    }
+
+    #[test]
+    fn missing_session_id_is_an_error() {
+        for event in [
+            r#"{"content": {"algorithm": "m.megolm.v1.aes-sha2"}}"#,
+            r#"{"content": {"session_id": ""}}"#,
+            r#"{"content": {"session_id": 5}}"#,
+        ] {
+            assert!(matches!(
+                extract_session_id(event),
+                Err(UtilityError::MissingField("session_id"))
+            ));
+        }
+
+        assert!(matches!(extract_session_id("[]"), Err(UtilityError::InvalidJson(_))));
+    }
+
+    #[test]
+    fn content_hash_matches_spec_example() {
+        // The event hashing example from the Matrix server-server API
+        let event = r#"{
+            "auth_events": [],
+            "content": {},
+            "depth": 3,
+            "hashes": {"sha256": "5jM4wQpv6lnBo7CLIghJuHdW+s2CMBJPUOGOC89ncos"},
+            "origin": "domain",
+            "origin_server_ts": 1000000,
+            "prev_events": [],
+            "room_id": "!x:domain",
+            "sender": "@a:domain",
+            "signatures": {"domain": {"ed25519:1": "KxwGjPSDEtvnFgU00fwFz+l6d2pJM6XBIaMEn81SXPTRl16AqLAYqfIReFGZlHi5KLjAWbOoMszkwsQma+lYAg"}},
+            "type": "X",
+            "unsigned": {"age_ts": 1000000}
+        }"#;
+
+        assert_eq!(content_hash(event).unwrap(), "5jM4wQpv6lnBo7CLIghJuHdW+s2CMBJPUOGOC89ncos");
+        assert!(content_hash("[]").is_err());
+    }
+
+    #[cfg(feature = "backup")]
+    #[test]
+    fn base58_round_trips() {
+        for data in [&b""[..], b"\0\0\x01", b"hello world", &[0xff; 35]] {
+            assert_eq!(base58_decode(&base58_encode(data)).unwrap(), data);
+        }
+        assert_eq!(base58_encode(b"\0\0\x01"), "112");
+        assert!(base58_decode("0OIl").is_err());
+    }
+
+    #[test]
+    fn java_strings_reject_lone_surrogates() {
+        assert_eq!(decode_java_string(b"abc").unwrap(), "abc");
+        // U+0000 is two bytes in modified UTF-8
+        assert_eq!(decode_java_string(&[0xc0, 0x80]).unwrap(), "\0");
+        // U+1F512 as a surrogate pair
+        let pair = [0xed, 0xa0, 0xbd, 0xed, 0xb4, 0x92];
+        assert_eq!(decode_java_string(&pair).unwrap(), "\u{1f512}");
+
+        // High surrogate alone, then low surrogate alone
+        assert!(matches!(
+            decode_java_string(&[b'a', 0xed, 0xa0, 0xbd]),
+            Err(UtilityError::InvalidString)
+        ));
+        assert!(matches!(
+            decode_java_string(&[0xed, 0xb4, 0x92, b'a']),
+            Err(UtilityError::InvalidString)
+        ));
+    }
+
+    #[test]
+    fn malformed_signature_is_an_error_and_wrong_one_is_false() {
+        let signing_key = vodozemac::Ed25519Keypair::new();
+        let public_key = signing_key.public_key();
+        let signature = signing_key.sign(b"message").to_bytes();
+
+        assert!(verify(public_key.as_bytes(), b"message", &signature).unwrap());
+
+        assert!(matches!(
+            verify(public_key.as_bytes(), b"message", &signature[..63]),
+            Err(UtilityError::InvalidSignatureLength(63))
+        ));
+        assert!(matches!(
+            verify(&public_key.as_bytes()[..31], b"message", &signature),
+            Err(UtilityError::VerificationFailed(_))
+        ));
+
+        // Right length, wrong signature
+        assert!(!verify(public_key.as_bytes(), b"other message", &signature).unwrap());
+        assert!(!verify(public_key.as_bytes(), b"message", &[0u8; 64]).unwrap());
+        let other = vodozemac::Ed25519Keypair::new().public_key();
+        assert!(!verify(other.as_bytes(), b"message", &signature).unwrap());
+    }
+
+    #[test]
+    fn signatures_from_generated_pair_verify() {
+        let pair = generate_signing_key_pair().unwrap();
+        let signature = sign(pair.private_key(), b"message").unwrap();
+        assert_eq!(signature.len(), 64);
+
+        assert!(verify(pair.public_key(), b"message", &signature).unwrap());
+        assert!(!verify(pair.public_key(), b"other message", &signature).unwrap());
+
+        // The public key is the one derived from the seed, not a stand-in
+        let other = generate_signing_key_pair().unwrap();
+        assert!(!verify(other.public_key(), b"message", &signature).unwrap());
+        assert!(matches!(sign(&[0u8; 31], b"message"), Err(UtilityError::SigningFailed(_))));
+    }
+
+    #[test]
+    fn key_fingerprint_is_grouped() {
+        let key = "nE6W2fCblxDcOFmeEtCHNl8/l8bXcu7GKyAswA4r3mM";
+
+        assert_eq!(
+            key_fingerprint(key, 4).unwrap(),
+            "nE6W 2fCb lxDc OFme EtCH Nl8/ l8bX cu7G KyAs wA4r 3mM"
+        );
+        let padded = format!("{}=", key);
+        assert_eq!(key_fingerprint(&padded, 4).unwrap(), key_fingerprint(key, 4).unwrap());
+        assert_eq!(
+            key_fingerprint(key, 11).unwrap(),
+            "nE6W2fCblxD cOFmeEtCHNl 8/l8bXcu7GK yAswA4r3mM"
+        );
+        assert_eq!(key_fingerprint(key, 0).unwrap(), key);
+
+        assert!(key_fingerprint("c2hvcnQ", 4).is_err());
+    }
 }
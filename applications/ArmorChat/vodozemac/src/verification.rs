@@ -0,0 +1,77 @@
+//! Interactive verification (SAS and QR) flow tracking
+//!
+//! The spec cancels a verification flow that hasn't finished ten minutes
+//! after it started. The app tracks each flow here and checks it before
+//! acting on a `m.key.verification.key` or `.mac` event, so a late step is
+//! rejected instead of completing a stale verification.
+
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+/// How long a verification flow may take, per the spec
+pub const VERIFICATION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Verification errors
+#[derive(Error, Debug, PartialEq)]
+pub enum VerificationError {
+    #[error("Verification {0} timed out")]
+    Expired(String),
+}
+
+/// A verification flow in progress
+pub struct VerificationSession {
+    /// The transaction id (or request event id) of the flow
+    flow_id: String,
+    started: Instant,
+}
+
+impl VerificationSession {
+    /// Start tracking a flow, from now
+    pub fn new(flow_id: &str) -> Self {
+        Self {
+            flow_id: flow_id.to_string(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Whether more than `timeout` has passed since the flow started
+    pub fn is_expired(&self, timeout: Duration) -> bool {
+        self.started.elapsed() > timeout
+    }
+
+    /// Check that the flow may still take its next step
+    ///
+    /// Call before handling a key or MAC event; an expired flow should be
+    /// cancelled with `m.timeout`.
+    pub fn check_step(&self, timeout: Duration) -> Result<(), VerificationError> {
+        if self.is_expired(timeout) {
+            return Err(VerificationError::Expired(self.flow_id.clone()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_expires_after_the_timeout() {
+        let session = VerificationSession::new("txn1");
+        let timeout = Duration::from_millis(50);
+
+        assert!(!session.is_expired(timeout));
+        assert!(session.check_step(timeout).is_ok());
+        assert!(!session.is_expired(VERIFICATION_TIMEOUT));
+
+        std::thread::sleep(Duration::from_millis(80));
+
+        assert!(session.is_expired(timeout));
+        assert_eq!(
+            session.check_step(timeout),
+            Err(VerificationError::Expired("txn1".into()))
+        );
+        assert!(!session.is_expired(VERIFICATION_TIMEOUT));
+    }
+}